@@ -0,0 +1,4 @@
+-- Add up migration script here
+-- Store the accumulated watt-hours of consolidated rows, so that energy totals
+-- are preserved after downsampling. Raw readings leave it NULL.
+ALTER TABLE energy_log ADD COLUMN wh REAL;
@@ -0,0 +1,3 @@
+-- Add up migration script here
+-- Most reads filter energy_log by token and a created_at range
+CREATE INDEX IF NOT EXISTS idx_energy_log_token_created ON energy_log (token, created_at);
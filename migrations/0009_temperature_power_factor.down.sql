@@ -0,0 +1,3 @@
+-- Add down migration script here
+ALTER TABLE energy_log DROP COLUMN temperature_c;
+ALTER TABLE energy_log DROP COLUMN power_factor;
@@ -0,0 +1,4 @@
+-- Add up migration script here
+-- Newer clamp meters also report temperature and power factor
+ALTER TABLE energy_log ADD COLUMN temperature_c REAL;
+ALTER TABLE energy_log ADD COLUMN power_factor REAL;
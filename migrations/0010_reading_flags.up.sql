@@ -0,0 +1,4 @@
+-- Add up migration script here
+-- Flags of the readings, as a bitset: 1 if the watts do not match
+-- amps * volts, see the consistency module
+ALTER TABLE energy_log ADD COLUMN flags INTEGER NOT NULL DEFAULT 0;
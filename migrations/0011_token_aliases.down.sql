@@ -0,0 +1,3 @@
+-- Add down migration script here
+DROP VIEW view_token_sensors;
+DROP TABLE token_aliases;
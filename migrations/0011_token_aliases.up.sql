@@ -0,0 +1,22 @@
+-- Add up migration script here
+-- When a sensor is replaced, the readings logged with the old token (alias)
+-- are read as if they belonged to the new one (token)
+CREATE TABLE token_aliases (
+    alias VARCHAR(255) PRIMARY KEY NOT NULL,
+    token VARCHAR(255) NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (alias) REFERENCES tokens(token),
+    FOREIGN KEY (token) REFERENCES tokens(token)
+);
+
+-- The sensor tokens whose readings each view token gives access to
+CREATE VIEW view_token_sensors AS
+SELECT vt.token AS view_token, tokens.token AS token FROM tokens
+INNER JOIN view_tokens vt
+ON vt.user_id = tokens.user_id
+UNION
+SELECT vt.token AS view_token, ta.alias AS token FROM token_aliases ta
+INNER JOIN tokens
+ON tokens.token = ta.token
+INNER JOIN view_tokens vt
+ON vt.user_id = tokens.user_id;
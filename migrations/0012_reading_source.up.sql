@@ -0,0 +1,3 @@
+-- Add up migration script here
+-- The route (or tool) that inserted the reading, e.g., post_token or post_import
+ALTER TABLE energy_log ADD COLUMN source TEXT;
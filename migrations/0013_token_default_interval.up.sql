@@ -0,0 +1,4 @@
+-- Add up migration script here
+-- The bucket size, in seconds, the plots of the sensor use when no interval is
+-- requested, e.g., to match its cadence (NULL to choose it from the range)
+ALTER TABLE tokens ADD COLUMN default_interval_secs INTEGER NULL;
@@ -0,0 +1,150 @@
+//! Structured access log, for ingestion by log aggregators.
+//!
+//! When `access_log = "json"` is set in the figment configuration
+//! (Rocket.toml), the [AccessLogFairing] logs one JSON object per request
+//! with the `access_log` target, such as:
+//!
+//! ```json
+//! {"method":"POST","route":"/log/<_>","token":"abcd...wxyz","client_ip":"192.0.2.1","status":200,"latency_ms":3.2,"request_id":"3mTQkx0dZp9yVb2L"}
+//! ```
+//!
+//! The route is logged as its template, so full tokens never end up in the
+//! access log. Otherwise, only the usual human-readable logs are emitted.
+
+use std::time::Instant;
+
+use crate::token::simplify_token_string;
+
+/// The time a request was received, cached in the request
+struct RequestStart(Option<Instant>);
+
+/// Fairing that logs every request as a JSON object
+pub struct AccessLogFairing;
+
+impl AccessLogFairing {
+    /// Returns a fairing attaching the [AccessLogFairing] if the access log
+    /// is enabled in the configuration.
+    pub fn if_enabled() -> rocket::fairing::AdHoc {
+        rocket::fairing::AdHoc::on_ignite("Structured access log", |rocket| async {
+            match rocket.figment().extract_inner::<String>("access_log").as_deref() {
+                Ok("json") => rocket.attach(AccessLogFairing),
+                Ok(format) => {
+                    log::warn!("Unknown access_log format {:?}, ignoring it", format);
+                    rocket
+                }
+                Err(_) => rocket,
+            }
+        })
+    }
+}
+
+#[rocket::async_trait]
+impl rocket::fairing::Fairing for AccessLogFairing {
+    fn info(&self) -> rocket::fairing::Info {
+        rocket::fairing::Info {
+            name: "JSON access log",
+            kind: rocket::fairing::Kind::Request | rocket::fairing::Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, request: &mut rocket::Request<'_>, _: &mut rocket::Data<'_>) {
+        request.local_cache(|| RequestStart(Some(Instant::now())));
+    }
+
+    async fn on_response<'r>(
+        &self,
+        request: &'r rocket::Request<'_>,
+        response: &mut rocket::Response<'r>,
+    ) {
+        log::info!(target: "access_log", "{}", entry(request, response));
+    }
+}
+
+/// Returns the access log entry of the request
+fn entry(request: &rocket::Request<'_>, response: &rocket::Response<'_>) -> serde_json::Value {
+    let latency_ms = request
+        .local_cache(|| RequestStart(None))
+        .0
+        .map(|start| start.elapsed().as_secs_f64() * 1000.0);
+    let route = request.route().map(|route| route.uri.path().to_string());
+    let token = request
+        .route()
+        .filter(|route| route.uri.path().starts_with("/log/<"))
+        .and_then(|_| request.routed_segment(1))
+        .filter(|token| token.len() >= 8)
+        .map(simplify_token_string);
+    let client_ip = request
+        .rocket()
+        .state::<crate::proxy::TrustedProxies>()
+        .and_then(|proxies| proxies.client_ip(request));
+
+    serde_json::json!({
+        "method": request.method().as_str(),
+        "route": route,
+        "token": token,
+        "client_ip": client_ip,
+        "status": response.status().code,
+        "latency_ms": latency_ms,
+        "request_id": crate::request_id::RequestId::of(request).to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use rocket::http::{ContentType, Header, Status};
+    use std::sync::Mutex;
+
+    /// The entries logged by the applications built by [client]
+    #[derive(Default)]
+    struct Entries(Mutex<Vec<serde_json::Value>>);
+
+    /// An application with the JSON access log, recording its entries
+    async fn client() -> testing::TestApp {
+        let rocket = crate::build(testing::figment().merge(("access_log", "json")))
+            .manage(Entries::default())
+            .attach(rocket::fairing::AdHoc::on_response(
+                "Record the access log",
+                |request, response| {
+                    Box::pin(async move {
+                        let entries = request.rocket().state::<Entries>().unwrap();
+                        entries.0.lock().unwrap().push(entry(request, response));
+                    })
+                },
+            ));
+        testing::client_of(rocket).await
+    }
+
+    #[rocket::async_test]
+    async fn logs_the_fields_of_the_request() {
+        let app = client().await;
+
+        let response = app
+            .post(format!("/log/{}", app.token))
+            .header(ContentType::JSON)
+            .header(Header::new("X-Request-Id", "sample-request"))
+            .body(r#"{"amps": 2.5, "volts": 230, "watts": 575}"#)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+
+        let entries = app.client.rocket().state::<Entries>().unwrap();
+        let mut entry = entries.0.lock().unwrap().pop().unwrap();
+        assert!(entry["latency_ms"].as_f64().unwrap() >= 0.0);
+        entry["latency_ms"].take();
+        assert_eq!(
+            entry,
+            serde_json::json!({
+                "method": "POST",
+                "route": "/log/<_>",
+                "token": simplify_token_string(&app.token),
+                "client_ip": app.remote.ip(),
+                "status": 200,
+                "latency_ms": null,
+                "request_id": "sample-request",
+            })
+        );
+        assert!(!entry.to_string().contains(&app.token));
+    }
+}
@@ -0,0 +1,937 @@
+//! Administration routes.
+//!
+//! These routes allow managing the application without editing the SQLite
+//! database by hand. They are all protected by the [AdminGuard], which checks
+//! the request carries the `admin_token` configured in the figment
+//! (Rocket.toml) as a bearer token, or as the password of HTTP Basic
+//! credentials:
+//!
+//! ```sh
+//! curl -H "Authorization: Bearer $ADMIN_TOKEN" http://localhost:8000/admin/view-tokens
+//! curl -u "admin:$ADMIN_TOKEN" http://localhost:8000/admin/view-tokens
+//! ```
+//!
+//! If no `admin_token` is configured, the admin routes are disabled and behave
+//! as if they did not exist.
+//!
+//! The available routes are:
+//! - GET /admin/view-tokens to list the view tokens and when they were last used
+//! - GET /admin/overview to get the latest reading of every sensor token
+//! - GET /admin/locations/:location/json to read the readings of a location
+//!   by its name, without knowing its tokens
+//! - POST /admin/view-tokens to create a (possibly expiring) view token
+//! - POST /admin/token-aliases to read the history of a replaced sensor token
+//!   as part of its new token
+//! - PUT /admin/tokens/:token/interval to set the default interval of the
+//!   plots of a sensor token, e.g., to match its cadence
+//! - DELETE /log/:token/rows to delete the readings of a sensor token within a
+//!   range, e.g., the spikes of a glitching sensor
+//! - GET /car/debug to inspect whether the car is detected near the charger,
+//!   see [car::routes](crate::car::routes)
+//! - GET /car/state to see the cached car and home state of the last charge
+//!   decision
+//! - POST /car/override to charge at fixed amps for a while regardless of the
+//!   budget, and DELETE /car/override to cancel it
+
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::{delete, get, post, put};
+use rocket_db_pools::Connection;
+use serde::Deserialize;
+
+use crate::db::QueryTimeout;
+use crate::form::{self, HtmlInputParseableDateTime};
+use crate::print_table::{self, MaxPageCount, Pagination};
+use crate::request_id::RequestId;
+use crate::token::generate_token;
+use crate::{Logs, LogsRead};
+
+/// The username expected in the HTTP Basic credentials, unless
+/// `admin_username` is configured
+const DEFAULT_ADMIN_USERNAME: &str = "admin";
+
+/// Request guard for the admin routes.
+///
+/// It succeeds only if the request has an `Authorization` header with either
+/// the `admin_token` from the figment as a `Bearer` token, or HTTP `Basic`
+/// credentials with the `admin_username` ("admin" by default) and the
+/// `admin_token` as the password, e.g., for a browser. Otherwise it forwards
+/// with a 401, or with a 404 if no admin token is configured at all.
+///
+/// The credentials are compared in constant time, see [constant_time_eq].
+pub struct AdminGuard(());
+
+/// Compares two secrets without stopping at the first difference, so that the
+/// response time does not tell how much of a guess was right. Only the length
+/// may leak.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let diff = a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y));
+    std::hint::black_box(diff) == 0
+}
+
+/// Returns true if the `Authorization` header value carries the admin
+/// credentials
+fn is_admin(authorization: &str, username: &str, admin_token: &str) -> bool {
+    if let Some(token) = authorization.strip_prefix("Bearer ") {
+        return constant_time_eq(token.trim().as_bytes(), admin_token.as_bytes());
+    }
+    let Some(encoded) = authorization.strip_prefix("Basic ") else {
+        return false;
+    };
+    use base64::Engine;
+    let Ok(decoded) = base64::engine::general_purpose::STANDARD.decode(encoded.trim()) else {
+        return false;
+    };
+    let Some(colon) = decoded.iter().position(|&c| c == b':') else {
+        return false;
+    };
+    // Check both, so the timing does not tell which one was wrong
+    let username_ok = constant_time_eq(&decoded[..colon], username.as_bytes());
+    let password_ok = constant_time_eq(&decoded[colon + 1..], admin_token.as_bytes());
+    username_ok & password_ok
+}
+
+#[rocket::async_trait]
+impl<'r> rocket::request::FromRequest<'r> for AdminGuard {
+    type Error = ();
+
+    async fn from_request(
+        request: &'r rocket::Request<'_>,
+    ) -> rocket::request::Outcome<Self, Self::Error> {
+        let admin_token: String = match request.rocket().figment().extract_inner("admin_token") {
+            Ok(admin_token) => admin_token,
+            Err(_) => return rocket::request::Outcome::Forward(Status::NotFound),
+        };
+
+        let username: String = request
+            .rocket()
+            .figment()
+            .extract_inner("admin_username")
+            .unwrap_or_else(|_| DEFAULT_ADMIN_USERNAME.to_string());
+
+        match request.headers().get_one("Authorization") {
+            Some(authorization)
+                if !admin_token.is_empty() && is_admin(authorization, &username, &admin_token) =>
+            {
+                rocket::request::Outcome::Success(AdminGuard(()))
+            }
+            _ => {
+                log::warn!("Rejected admin request to {}{}", request.uri(), RequestId::in_logs());
+                rocket::request::Outcome::Forward(Status::Unauthorized)
+            }
+        }
+    }
+}
+
+/// The response to requests without valid admin credentials, asking for them
+#[derive(rocket::Responder)]
+#[response(status = 401)]
+pub struct AdminChallenge {
+    body: &'static str,
+    challenge: rocket::http::Header<'static>,
+}
+
+/// Catcher for the requests rejected by the [AdminGuard], with a
+/// `WWW-Authenticate` header so that browsers prompt for the credentials.
+///
+/// It is only registered under /admin, see [delete_rows_unauthorized] for the
+/// admin route outside of it.
+#[rocket::catch(401)]
+pub fn unauthorized() -> AdminChallenge {
+    AdminChallenge {
+        body: "Missing or invalid admin credentials\n",
+        challenge: rocket::http::Header::new(
+            "WWW-Authenticate",
+            "Basic realm=\"amp-sensor-backend admin\", charset=\"UTF-8\"",
+        ),
+    }
+}
+
+/// Expected JSON body for the POST /admin/view-tokens route
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct NewViewToken {
+    /// The user (location) whose readings the token will give access to
+    user_id: i64,
+
+    /// If set, the token stops being valid after this many seconds
+    valid_for_secs: Option<i64>,
+}
+
+/// Route POST /admin/view-tokens will create a new view token for a user,
+/// optionally expiring after `valid_for_secs`, and return it.
+#[post("/admin/view-tokens", data = "<new_token>")]
+pub async fn create_view_token(
+    _admin: AdminGuard,
+    new_token: Json<NewViewToken>,
+    mut db: Connection<Logs>,
+) -> Result<Json<serde_json::Value>, (Status, String)> {
+    let user = sqlx::query!("SELECT location FROM users WHERE id = ?", new_token.user_id)
+        .fetch_optional(&mut **db)
+        .await
+        .unwrap()
+        .ok_or((Status::NotFound, format!("Unknown user {}", new_token.user_id)))?;
+
+    let token = generate_token();
+    let valid_until = new_token.valid_for_secs.map(|secs| {
+        (chrono::Utc::now() + chrono::Duration::seconds(secs))
+            .format("%Y-%m-%d %H:%M:%S")
+            .to_string()
+    });
+
+    sqlx::query!(
+        "INSERT INTO view_tokens (token, user_id, view_token_valid_until) VALUES (?, ?, ?)",
+        token,
+        new_token.user_id,
+        valid_until
+    )
+    .execute(&mut **db)
+    .await
+    .unwrap();
+
+    log::info!(
+        "Created view token for user {} ({}) valid until {:?}{}",
+        new_token.user_id,
+        user.location,
+        valid_until,
+        RequestId::in_logs()
+    );
+
+    Ok(Json(serde_json::json!({
+        "token": token,
+        "user_id": new_token.user_id,
+        "location": user.location,
+        "valid_until": valid_until,
+    })))
+}
+
+/// Route GET /admin/view-tokens will list all view tokens, with their expiry
+/// and last access times.
+#[get("/admin/view-tokens")]
+pub async fn list_view_tokens(
+    _admin: AdminGuard,
+    mut db: Connection<Logs>,
+) -> Json<serde_json::Value> {
+    let rows = sqlx::query!(
+        "SELECT vt.token, vt.user_id, u.location, vt.view_token_valid_until, vt.created_at, vt.last_accessed_at,
+        (vt.view_token_valid_until IS NOT NULL AND vt.view_token_valid_until <= datetime('now')) as \"expired: bool\"
+        FROM view_tokens vt
+        INNER JOIN users u
+        ON u.id = vt.user_id
+        ORDER BY vt.created_at DESC"
+    )
+    .fetch_all(&mut **db)
+    .await
+    .unwrap();
+
+    let tokens = rows
+        .into_iter()
+        .map(|row| {
+            serde_json::json!({
+                "token": row.token,
+                "user_id": row.user_id,
+                "location": row.location,
+                "valid_until": row.view_token_valid_until,
+                "expired": row.expired,
+                "created_at": row.created_at,
+                "last_accessed_at": row.last_accessed_at,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    Json(serde_json::json!({ "view_tokens": tokens }))
+}
+
+/// Route GET /admin/overview will return the latest reading of every sensor
+/// token, across all the locations, with its location and when it was logged.
+///
+/// The tokens that never logged are listed too, with null values. It uses a
+/// single query, numbering the readings of each token from the newest, so
+/// that readings logged within the same second are told apart by their id.
+#[get("/admin/overview")]
+pub async fn overview(_admin: AdminGuard, mut db: Connection<Logs>) -> Json<serde_json::Value> {
+    let rows = sqlx::query!(
+        "SELECT t.token, u.location, e.amps as \"amps?: f64\", e.watts as \"watts?: f64\",
+        e.created_at as \"last_seen?: chrono::NaiveDateTime\"
+        FROM tokens t
+        INNER JOIN users u
+        ON u.id = t.user_id
+        LEFT JOIN (
+            SELECT token, amps, watts, created_at,
+            ROW_NUMBER() OVER (PARTITION BY token ORDER BY created_at DESC, id DESC) as n
+            FROM energy_log
+        ) e
+        ON e.token = t.token AND e.n = 1
+        ORDER BY u.location, t.token"
+    )
+    .fetch_all(&mut **db)
+    .await
+    .unwrap();
+
+    let tokens = rows
+        .into_iter()
+        .map(|row| {
+            serde_json::json!({
+                "token": row.token,
+                "location": row.location,
+                "amps": row.amps,
+                "watts": row.watts,
+                "last_seen": row.last_seen,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    Json(serde_json::json!({ "tokens": tokens }))
+}
+
+/// Route GET /admin/locations/:location/json will return the readings of every
+/// sensor token of the users with that location, and of their aliases, newest
+/// first, as GET /log/:token/json does for a view token.
+///
+/// It takes the same `page`, `count`, `start`, `end`, `range` and `tz`
+/// parameters, and returns a 404 if no user has that location.
+#[get("/admin/locations/<location>/json?<page>&<count>&<start>&<end>&<range>&<tz>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn location_rows(
+    _admin: AdminGuard,
+    location: &str,
+    page: Option<i32>,
+    count: Option<i32>,
+    start: HtmlInputParseableDateTime,
+    end: HtmlInputParseableDateTime,
+    range: Option<form::Range>,
+    tz: form::Tz,
+    max_count: MaxPageCount,
+    query_timeout: QueryTimeout,
+    mut db: Connection<LogsRead>,
+) -> Result<Json<serde_json::Value>, (Status, String)> {
+    let tokens = print_table::get_tokens_for_location(&mut db, location).await;
+    if tokens.is_empty() {
+        return Err((Status::NotFound, format!("Unknown location {:?}", location)));
+    }
+
+    let pagination = Pagination {
+        start,
+        end,
+        interval: None,
+        page,
+        count,
+        tz: tz.0,
+        range,
+        max_count,
+    }
+    .result();
+    let (rows, has_next) = query_timeout
+        .run(print_table::get_paginated_rows_for_location(&mut db, location, &pagination, &tz.0))
+        .await?;
+
+    let next_url = if has_next {
+        format!(
+            "/admin/locations/{}/json?page={}&count={}",
+            rocket::http::RawStr::new(location).percent_encode(),
+            pagination.page.saturating_add(1),
+            pagination.count
+        )
+    } else {
+        "".to_string()
+    };
+
+    Ok(Json(serde_json::json!({
+        "location": location,
+        "tokens": tokens,
+        "next": next_url,
+        "rows": rows,
+    })))
+}
+
+/// Expected JSON body for the POST /admin/token-aliases route
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct NewTokenAlias {
+    /// The old sensor token, whose readings will be read as the new one's
+    alias: String,
+
+    /// The new sensor token
+    token: String,
+}
+
+/// Route POST /admin/token-aliases will make the readings logged with the
+/// `alias` sensor token visible through the view tokens of the `token` sensor,
+/// e.g., to keep the history of a circuit after replacing its sensor. The rows
+/// themselves are not modified.
+///
+/// Tokens already aliased to `alias` are moved to `token` too, so that aliases
+/// never chain.
+#[post("/admin/token-aliases", data = "<new_alias>")]
+pub async fn create_token_alias(
+    _admin: AdminGuard,
+    new_alias: Json<NewTokenAlias>,
+    mut db: Connection<Logs>,
+) -> Result<Json<serde_json::Value>, (Status, String)> {
+    if new_alias.alias == new_alias.token {
+        return Err((Status::BadRequest, "A token cannot alias itself".to_string()));
+    }
+    for token in [&new_alias.alias, &new_alias.token] {
+        let exists = sqlx::query!("SELECT token FROM tokens WHERE token = ?", token)
+            .fetch_optional(&mut **db)
+            .await
+            .unwrap()
+            .is_some();
+        if !exists {
+            return Err((Status::NotFound, format!("Unknown token {}", token)));
+        }
+    }
+    let target_is_alias = sqlx::query!("SELECT alias FROM token_aliases WHERE alias = ?", new_alias.token)
+        .fetch_optional(&mut **db)
+        .await
+        .unwrap()
+        .is_some();
+    if target_is_alias {
+        return Err((
+            Status::Conflict,
+            format!("{} is itself an alias, use the token it aliases", new_alias.token),
+        ));
+    }
+
+    let mut tx = sqlx::Acquire::begin(&mut **db).await.unwrap();
+    sqlx::query!(
+        "UPDATE token_aliases SET token = ? WHERE token = ?",
+        new_alias.token,
+        new_alias.alias
+    )
+    .execute(&mut *tx)
+    .await
+    .unwrap();
+    sqlx::query!(
+        "INSERT OR REPLACE INTO token_aliases (alias, token) VALUES (?, ?)",
+        new_alias.alias,
+        new_alias.token
+    )
+    .execute(&mut *tx)
+    .await
+    .unwrap();
+    tx.commit().await.unwrap();
+
+    log::info!(
+        "Aliased token {} to {}{}",
+        crate::token::simplify_token_string(&new_alias.alias),
+        crate::token::simplify_token_string(&new_alias.token),
+        RequestId::in_logs()
+    );
+
+    Ok(Json(serde_json::json!({
+        "alias": new_alias.alias,
+        "token": new_alias.token,
+    })))
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct DefaultInterval {
+    /// The bucket size in seconds, or `null` to choose it from the range
+    interval_secs: Option<i32>,
+}
+
+/// Route PUT /admin/tokens/:token/interval will set the interval the SVG plot
+/// of the sensor token uses when none is requested, e.g., `{"interval_secs":
+/// 60}` for a sensor that logs every minute, or `{"interval_secs": null}` to
+/// choose it from the range again (see
+/// [print_table::resolve_interval]).
+#[put("/admin/tokens/<token>/interval", data = "<default_interval>")]
+pub async fn set_default_interval(
+    _admin: AdminGuard,
+    token: &str,
+    default_interval: Json<DefaultInterval>,
+    mut db: Connection<Logs>,
+) -> Result<Json<serde_json::Value>, (Status, String)> {
+    let interval_secs = default_interval.interval_secs;
+    if interval_secs.is_some_and(|secs| secs <= 0) {
+        return Err((Status::BadRequest, "The interval must be positive".to_string()));
+    }
+
+    let updated = sqlx::query!(
+        "UPDATE tokens SET default_interval_secs = ? WHERE token = ?",
+        interval_secs,
+        token
+    )
+    .execute(&mut **db)
+    .await
+    .unwrap()
+    .rows_affected();
+    if updated == 0 {
+        return Err((Status::NotFound, "Unknown token".to_string()));
+    }
+
+    log::info!(
+        "Set the default interval of {} to {:?}{}",
+        crate::token::simplify_token_string(token),
+        interval_secs,
+        RequestId::in_logs()
+    );
+
+    Ok(Json(serde_json::json!({
+        "token": token,
+        "interval_secs": interval_secs,
+    })))
+}
+
+/// Route DELETE /log/:token/rows will delete the readings of the sensor token
+/// logged between `start` and `end` (both included), or the single reading
+/// logged at `at`, and return how many were deleted.
+///
+/// The timestamps are in UTC, as `%Y-%m-%d %H:%M:%S`, RFC 3339 or seconds
+/// since the Unix epoch, like the CSV import (see
+/// [csv_import](crate::csv_import)).
+///
+/// Note that cached read responses are only revalidated when a newer reading
+/// arrives, see [conditional](crate::conditional).
+#[delete("/log/<token>/rows?<start>&<end>&<at>")]
+pub async fn delete_rows(
+    _admin: AdminGuard,
+    token: &str,
+    start: Option<&str>,
+    end: Option<&str>,
+    at: Option<&str>,
+    db: &rocket::State<Logs>,
+) -> Result<Json<serde_json::Value>, (Status, String)> {
+    let parse = |name: &str, value: &str| {
+        crate::csv_import::parse_timestamp(value)
+            .ok_or((Status::BadRequest, format!("Invalid {} {:?}", name, value)))
+    };
+    let (start, end) = match (at, start, end) {
+        (Some(at), None, None) => {
+            let at = parse("at", at)?;
+            (at, at)
+        }
+        (None, Some(start), Some(end)) => (parse("start", start)?, parse("end", end)?),
+        _ => {
+            return Err((
+                Status::BadRequest,
+                "Pass either both start and end, or at".to_string(),
+            ))
+        }
+    };
+    if start > end {
+        return Err((Status::BadRequest, "The start is after the end".to_string()));
+    }
+
+    let exists = sqlx::query!("SELECT token FROM tokens WHERE token = ?", token)
+        .fetch_optional(&****db)
+        .await
+        .unwrap()
+        .is_some();
+    if !exists {
+        return Err((Status::NotFound, "Unknown token".to_string()));
+    }
+
+    // From every database, as the readings logged before sharding, or before
+    // a shard was added, are not in the shard of the token
+    let mut deleted = 0;
+    for db in db.databases() {
+        deleted += sqlx::query!(
+            "DELETE FROM main.energy_log WHERE token = ? AND created_at BETWEEN ? AND ?",
+            token,
+            start,
+            end
+        )
+        .execute(db)
+        .await
+        .unwrap()
+        .rows_affected();
+    }
+
+    log::warn!(
+        "Deleted {} readings of {} between {} and {}{}",
+        deleted,
+        crate::token::simplify_token_string(token),
+        start,
+        end,
+        RequestId::in_logs()
+    );
+
+    Ok(Json(serde_json::json!({ "deleted": deleted })))
+}
+
+/// Route DELETE /log/:token/rows when the [AdminGuard] of [delete_rows]
+/// rejected the request, asking for the credentials as the [unauthorized]
+/// catcher does under /admin. It is not found if no admin token is configured.
+#[delete("/log/<_>/rows", rank = 2)]
+pub fn delete_rows_unauthorized(
+    admin: rocket::request::Outcome<AdminGuard, ()>,
+) -> Option<AdminChallenge> {
+    match admin {
+        rocket::request::Outcome::Forward(status) if status == Status::Unauthorized => {
+            Some(unauthorized())
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing;
+    use rocket::http::{ContentType, Header, Status};
+
+    #[rocket::async_test]
+    async fn expiring_view_tokens_stop_working_once_expired() {
+        let app = testing::client_with(testing::admin_figment()).await;
+        let user_id: i64 = sqlx::query_scalar!("SELECT user_id FROM tokens WHERE token = ?", app.token)
+            .fetch_one(app.db())
+            .await
+            .unwrap();
+
+        let response = app
+            .post("/admin/view-tokens")
+            .header(testing::admin_authorization())
+            .header(ContentType::JSON)
+            .body(format!(r#"{{"user_id": {}, "valid_for_secs": 3600}}"#, user_id))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let created: serde_json::Value = response.into_json().await.unwrap();
+        assert!(created["valid_until"].is_string());
+        let view_token = created["token"].as_str().unwrap().to_string();
+
+        let response = app.get(format!("/log/{}/json", view_token)).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+
+        // As if the hour had passed
+        sqlx::query!(
+            "UPDATE view_tokens SET view_token_valid_until = datetime('now', '-1 seconds') WHERE token = ?",
+            view_token
+        )
+        .execute(app.db())
+        .await
+        .unwrap();
+
+        let response = app.get(format!("/log/{}/json", view_token)).dispatch().await;
+        assert_eq!(response.status(), Status::Gone);
+
+        let response = app
+            .get("/admin/view-tokens")
+            .header(testing::admin_authorization())
+            .dispatch()
+            .await;
+        let listed: serde_json::Value = response.into_json().await.unwrap();
+        let listed = listed["view_tokens"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|listed| listed["token"] == view_token.as_str())
+            .unwrap()
+            .clone();
+        assert_eq!(listed["expired"], true);
+    }
+
+    #[rocket::async_test]
+    async fn aliased_tokens_are_read_through_the_new_token() {
+        let app = testing::client_with(testing::admin_figment()).await;
+        let old = app.create_token("test").await;
+        app.insert_reading_for(&old, "2024-01-01 10:00:00", 1.0, 230.0, 230.0).await;
+        app.insert_reading("2024-01-01 10:01:00", 2.0, 230.0, 460.0).await;
+        app.insert_reading_for(&old, "2024-01-01 10:02:00", 3.0, 230.0, 690.0).await;
+        app.insert_reading("2024-01-01 10:03:00", 4.0, 230.0, 920.0).await;
+
+        let alias = |alias: &str, token: &str| {
+            app.post("/admin/token-aliases")
+                .header(testing::admin_authorization())
+                .header(ContentType::JSON)
+                .body(serde_json::json!({ "alias": alias, "token": token }).to_string())
+                .dispatch()
+        };
+        assert_eq!(alias(&old, &app.token).await.status(), Status::Ok);
+        assert_eq!(alias(&app.token, &app.token).await.status(), Status::BadRequest);
+        assert_eq!(alias(&app.token, &old).await.status(), Status::Conflict);
+
+        let page: serde_json::Value = app
+            .get(format!(
+                "/log/{}/json?start=2024-01-01T10:00&end=2024-01-01T11:00&tz=UTC",
+                app.token
+            ))
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        let rows: Vec<_> = page["rows"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|row| (row["amps"].as_f64().unwrap(), row["token"] == app.token.as_str()))
+            .collect();
+        assert_eq!(rows, vec![(4.0, true), (3.0, false), (2.0, true), (1.0, false)]);
+    }
+
+    #[rocket::async_test]
+    async fn deleting_a_spike_fixes_the_average() {
+        let app = testing::client_with(testing::admin_figment()).await;
+        for minute in 0..5 {
+            let created_at = format!("2024-01-01 10:0{}:00", minute);
+            app.insert_reading(&created_at, 5.0, 230.0, 1150.0).await;
+        }
+        app.insert_reading("2024-01-01 10:02:30", 500.0, 230.0, 115000.0).await;
+        let average = || async {
+            let buckets: serde_json::Value = app
+                .get(format!(
+                    "/log/{}/json?start=2024-01-01T10:00&end=2024-01-01T11:00&tz=UTC&interval=3600",
+                    app.token
+                ))
+                .dispatch()
+                .await
+                .into_json()
+                .await
+                .unwrap();
+            buckets["rows"][0]["amps"].as_f64().unwrap()
+        };
+        assert_eq!(average().await, 87.5);
+
+        let window = "start=2024-01-01T10:02:15Z&end=2024-01-01T10:02:45Z";
+        let uri = format!("/log/{}/rows?{}", app.token, window);
+        let response = app.client.delete(uri.clone()).dispatch().await;
+        assert_eq!(response.status(), Status::Unauthorized);
+        assert!(response.headers().get_one("WWW-Authenticate").is_some());
+        assert_eq!(average().await, 87.5);
+
+        let response = app
+            .client
+            .delete(uri)
+            .header(testing::admin_authorization())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let deleted: serde_json::Value = response.into_json().await.unwrap();
+        assert_eq!(deleted["deleted"], 1);
+        assert_eq!(average().await, 5.0);
+
+        // A single reading can be deleted by its timestamp too
+        let response = app
+            .client
+            .delete(format!("/log/{}/rows?at=2024-01-01T10:04:00Z", app.token))
+            .header(testing::admin_authorization())
+            .dispatch()
+            .await;
+        let deleted: serde_json::Value = response.into_json().await.unwrap();
+        assert_eq!(deleted["deleted"], 1);
+    }
+
+    #[test]
+    fn admin_credentials_are_checked() {
+        use super::is_admin;
+        use base64::Engine;
+        let basic = |credentials: &str| {
+            format!("Basic {}", base64::engine::general_purpose::STANDARD.encode(credentials))
+        };
+
+        assert!(is_admin("Bearer secret", "admin", "secret"));
+        assert!(is_admin(&basic("admin:secret"), "admin", "secret"));
+        // The password may have colons
+        assert!(is_admin(&basic("admin:se:cret"), "admin", "se:cret"));
+
+        assert!(!is_admin("Bearer secre", "admin", "secret"));
+        assert!(!is_admin("Bearer secret2", "admin", "secret"));
+        assert!(!is_admin(&basic("root:secret"), "admin", "secret"));
+        assert!(!is_admin(&basic("admin:wrong!"), "admin", "secret"));
+        assert!(!is_admin(&basic("adminsecret"), "admin", "secret"));
+        assert!(!is_admin("Basic not base64!", "admin", "secret"));
+        assert!(!is_admin("secret", "admin", "secret"));
+    }
+
+    #[rocket::async_test]
+    async fn admin_routes_require_the_credentials() {
+        use base64::Engine;
+        let app = testing::client_with(
+            testing::admin_figment().merge(("admin_username", "operator")),
+        )
+        .await;
+        let get = |authorization: Option<String>| {
+            let request = app.get("/admin/view-tokens");
+            match authorization {
+                Some(authorization) => request.header(Header::new("Authorization", authorization)),
+                None => request,
+            }
+            .dispatch()
+        };
+        let basic = |credentials: String| {
+            let encoded = base64::engine::general_purpose::STANDARD.encode(credentials);
+            Some(format!("Basic {}", encoded))
+        };
+
+        let bearer = Some(format!("Bearer {}", testing::ADMIN_TOKEN));
+        assert_eq!(get(bearer).await.status(), Status::Ok);
+        let response = get(basic(format!("operator:{}", testing::ADMIN_TOKEN))).await;
+        assert_eq!(response.status(), Status::Ok);
+
+        for authorization in [
+            None,
+            Some("Bearer wrong".to_string()),
+            basic(format!("admin:{}", testing::ADMIN_TOKEN)),
+            basic("operator:wrong".to_string()),
+        ] {
+            let response = get(authorization).await;
+            assert_eq!(response.status(), Status::Unauthorized);
+            assert!(response
+                .headers()
+                .get_one("WWW-Authenticate")
+                .is_some_and(|challenge| challenge.starts_with("Basic ")));
+        }
+    }
+
+    #[rocket::async_test]
+    async fn admin_routes_do_not_exist_without_an_admin_token() {
+        let app = testing::client().await;
+
+        let response = app
+            .get("/admin/view-tokens")
+            .header(testing::admin_authorization())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::NotFound);
+    }
+
+    #[rocket::async_test]
+    async fn deleting_rows_does_not_exist_without_an_admin_token() {
+        let app = testing::client().await;
+
+        let response = app
+            .client
+            .delete(format!("/log/{}/rows?at=2024-01-01T10:04:00Z", app.token))
+            .header(testing::admin_authorization())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::NotFound);
+        assert!(response.headers().get_one("WWW-Authenticate").is_none());
+    }
+
+    #[rocket::async_test]
+    async fn the_overview_has_the_latest_reading_of_every_token() {
+        let app = testing::client_with(testing::admin_figment()).await;
+        let garage = app.create_token("garage").await;
+        let silent = app.create_token("shed").await;
+        app.insert_reading("2024-01-01 10:00:00", 10.0, 230.0, 2300.0).await;
+        app.insert_reading("2024-01-01 12:00:00", 4.0, 230.0, 920.0).await;
+        app.insert_reading("2024-01-01 11:00:00", 8.0, 230.0, 1840.0).await;
+        // Within the same second, the last one logged is the latest
+        app.insert_reading_for(&garage, "2024-01-02 09:00:00", 1.0, 230.0, 230.0).await;
+        app.insert_reading_for(&garage, "2024-01-02 09:00:00", 2.0, 230.0, 460.0).await;
+
+        let response = app
+            .get("/admin/overview")
+            .header(testing::admin_authorization())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let overview: serde_json::Value = response.into_json().await.unwrap();
+        let tokens = overview["tokens"].as_array().unwrap();
+        let find = |token: &str| {
+            tokens
+                .iter()
+                .find(|row| row["token"] == token)
+                .unwrap_or_else(|| panic!("{} is listed", token))
+                .clone()
+        };
+        assert_eq!(tokens.len(), 3);
+
+        let home = find(&app.token);
+        assert_eq!(home["location"], testing::LOCATION);
+        assert_eq!(home["amps"], 4.0);
+        assert_eq!(home["watts"], 920.0);
+        assert_eq!(home["last_seen"], "2024-01-01T12:00:00");
+
+        let garage = find(&garage);
+        assert_eq!(garage["location"], "garage");
+        assert_eq!(garage["amps"], 2.0);
+        assert_eq!(garage["watts"], 460.0);
+
+        let silent = find(&silent);
+        assert_eq!(silent["location"], "shed");
+        assert!(silent["amps"].is_null());
+        assert!(silent["last_seen"].is_null());
+
+        let response = app.get("/admin/overview").dispatch().await;
+        assert_eq!(response.status(), Status::Unauthorized);
+    }
+
+    #[rocket::async_test]
+    async fn a_location_is_resolved_to_the_rows_of_its_tokens() {
+        let app = testing::client_with(testing::admin_figment()).await;
+        let second = app.create_token(testing::LOCATION).await;
+        let garage = app.create_token("Garage 1").await;
+        app.insert_reading("2024-01-01 10:00:00", 1.0, 230.0, 230.0).await;
+        app.insert_reading_for(&second, "2024-01-01 10:01:00", 2.0, 230.0, 460.0).await;
+        app.insert_reading_for(&garage, "2024-01-01 10:02:00", 3.0, 230.0, 690.0).await;
+        let get = |location: &str| {
+            app.get(format!(
+                "/admin/locations/{}/json?start=2024-01-01T00:00&end=2024-01-02T00:00&tz=UTC",
+                location
+            ))
+            .header(testing::admin_authorization())
+            .dispatch()
+        };
+        let amps = |body: &serde_json::Value| {
+            body["rows"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|row| row["amps"].as_f64().unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        let response = get(testing::LOCATION).await;
+        assert_eq!(response.status(), Status::Ok);
+        let body: serde_json::Value = response.into_json().await.unwrap();
+        assert_eq!(body["location"], testing::LOCATION);
+        assert_eq!(body["tokens"].as_array().unwrap().len(), 2);
+        // Newest first
+        assert_eq!(amps(&body), vec![2.0, 1.0]);
+
+        let body: serde_json::Value = get("Garage%201").await.into_json().await.unwrap();
+        assert_eq!(amps(&body), vec![3.0]);
+
+        assert_eq!(get("nowhere").await.status(), Status::NotFound);
+        let response = app.get("/admin/locations/test/json").dispatch().await;
+        assert_eq!(response.status(), Status::Unauthorized);
+    }
+
+    #[rocket::async_test]
+    async fn the_default_interval_of_a_token_is_used_when_none_is_requested() {
+        let app = testing::client_with(testing::admin_figment()).await;
+        for minute in 0..60 {
+            let created_at = format!("2024-01-01 10:{:02}:00", minute);
+            let amps = f64::from(minute % 7);
+            app.insert_reading(&created_at, amps, 230.0, amps * 230.0).await;
+        }
+        let app = &app;
+        let svg = |interval: &str| {
+            let uri = format!(
+                "/log/{}/svg?start=2024-01-01T10:00&end=2024-01-01T11:00&tz=UTC{}",
+                app.token, interval
+            );
+            async move { app.get(uri).dispatch().await.into_string().await.unwrap() }
+        };
+        let chosen_from_the_range = svg("").await;
+        let half_hourly = svg("&interval=1800").await;
+        assert_ne!(chosen_from_the_range, half_hourly);
+
+        let set_interval = |body: &'static str| async move {
+            app.client
+                .put(format!("/admin/tokens/{}/interval", app.token))
+                .header(testing::admin_authorization())
+                .header(ContentType::JSON)
+                .body(body)
+                .dispatch()
+                .await
+                .status()
+        };
+        assert_eq!(set_interval(r#"{"interval_secs": 0}"#).await, Status::BadRequest);
+        assert_eq!(set_interval(r#"{"interval_secs": 1800}"#).await, Status::Ok);
+        assert_eq!(svg("").await, half_hourly);
+        // A requested interval still wins
+        assert_ne!(svg("&interval=60").await, half_hourly);
+
+        assert_eq!(set_interval(r#"{"interval_secs": null}"#).await, Status::Ok);
+        assert_eq!(svg("").await, chosen_from_the_range);
+    }
+}
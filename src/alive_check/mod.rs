@@ -34,10 +34,11 @@ impl AliveCheckFairing {
 }
 
 /// This function initializes a second database connection pool to the Logs
-/// database for the AliveCheckFairing. This is necessary because the fairing
-/// runs on a separate task and it's not easy to share the database connection
-/// pool with the orbiting rocket.
-async fn get_database<D: Database>(rocket: &rocket::Rocket<rocket::Orbit>) -> D {
+/// database for the AliveCheckFairing (and any other fairing spawning its own
+/// task). This is necessary because the fairing runs on a separate task and
+/// it's not easy to share the database connection pool with the orbiting
+/// rocket.
+pub(crate) async fn get_database<D: Database>(rocket: &rocket::Rocket<rocket::Orbit>) -> D {
     let workers: usize = rocket
         .figment()
         .extract_inner(rocket::Config::WORKERS)
@@ -57,6 +58,32 @@ async fn get_database<D: Database>(rocket: &rocket::Rocket<rocket::Orbit>) -> D
     }
 }
 
+/// Sends a notification to the configured `webhook_url`, with the JSON payload
+/// if any, or an empty body otherwise. Does nothing if the URL is empty.
+///
+/// Failures are only logged, as there is nobody else to tell.
+pub(crate) async fn send_webhook(
+    client: &reqwest::Client,
+    webhook_url: &str,
+    payload: Option<&serde_json::Value>,
+) {
+    if webhook_url.is_empty() {
+        return;
+    }
+    let request = match payload {
+        Some(payload) => client.post(webhook_url).json(payload),
+        None => client.post(webhook_url),
+    };
+    match request.send().await {
+        Ok(res) => {
+            log::info!("Webhook response: {:?}", res);
+        }
+        Err(e) => {
+            log::error!("Failed to send webhook: {:?}", e);
+        }
+    }
+}
+
 #[rocket::async_trait]
 impl Fairing for AliveCheckFairing {
     fn info(&self) -> Info {
@@ -69,6 +96,7 @@ impl Fairing for AliveCheckFairing {
     async fn on_liftoff(&self, rocket: &rocket::Rocket<rocket::Orbit>) -> () {
         let db_conn = get_database::<crate::Logs>(rocket).await;
         let webhook_url: String = rocket.figment().extract_inner("webhook_url").unwrap_or_default();
+        let client = crate::http_client::shared().clone();
         let task = rocket::tokio::task::spawn(async move {
             loop {
                 rocket::tokio::time::sleep(std::time::Duration::from_secs(60)).await;
@@ -79,29 +107,20 @@ impl Fairing for AliveCheckFairing {
                 let rows = sqlx::query!(
                     "SELECT COUNT(*) as count FROM energy_log WHERE created_at > datetime('now', '-60 seconds')"
                 );
-                let count = rows.fetch_one(&*db_conn).await.unwrap().count;
+                let count = rows.fetch_one(&**db_conn).await.unwrap().count;
                 log::info!("Rows in the last 60 seconds: {}", count);
 
                 if count == 0 {
                     log::warn!("No rows in the last 60 seconds!");
-                    if !webhook_url.is_empty() {
-                        let client = reqwest::Client::new();
-                        let res = client.post(&webhook_url).send().await;
-                        match res {
-                            Ok(res) => {
-                                log::info!("Webhook response: {:?}", res);
-                            }
-                            Err(e) => {
-                                log::error!("Failed to send webhook: {:?}", e);
-                            }
-                        }
-                    }
+                    send_webhook(&client, &webhook_url, None).await;
                 }
             }
         });
         let old = self.task.lock().await.replace(task);
 
-        old.map(|f| f.abort());
+        if let Some(old) = old {
+            old.abort();
+        }
     }
 
     /// When the rocket is shutting down, we need to abort the task that checks
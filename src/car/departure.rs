@@ -0,0 +1,207 @@
+//! A daily departure time by which the car should reach a target charge.
+//!
+//! By default the car charges as fast as the budget allows. For the battery
+//! longevity, you can instead ask for a target battery level by a departure
+//! time in the figment configuration (Rocket.toml):
+//!
+//! ```toml
+//! # The time the car leaves every day, in charge_timezone (UTC by default)
+//! departure_time = "07:30"
+//! # The battery level (%) wanted by then, charge_limit_soc or the limit set
+//! # in the car by default
+//! departure_soc = 80
+//! # The usable capacity of the battery, to tell the energy still needed
+//! battery_capacity_kwh = 75
+//! ```
+//!
+//! The amps requested are then the ones that would spread the remaining
+//! energy evenly until an hour before the departure, which leaves a margin for
+//! the charging losses. They are still bounded by the budget, and once the
+//! margin is reached the car charges as fast as the budget allows.
+
+use chrono::{DateTime, NaiveTime, TimeZone, Utc};
+use rocket::figment::Figment;
+
+/// How long before the departure the charge should be complete, in seconds
+const DEPARTURE_MARGIN_SECS: i64 = 3600;
+
+/// The daily departure time and battery level to reach by then
+#[derive(Debug, Clone)]
+pub struct DepartureTarget {
+    timezone: chrono_tz::Tz,
+    time: NaiveTime,
+    soc: Option<usize>,
+    battery_capacity_kwh: f64,
+}
+
+impl DepartureTarget {
+    /// Read the departure target from the figment.
+    ///
+    /// Returns `Ok(None)` if no `departure_time` is configured, meaning the
+    /// car charges as fast as the budget allows.
+    pub fn from_figment(figment: &Figment) -> anyhow::Result<Option<Self>> {
+        let time: String = match figment.extract_inner("departure_time") {
+            Ok(time) => time,
+            Err(e) if e.missing() => return Ok(None),
+            Err(e) => return Err(anyhow::anyhow!("Invalid departure_time: {}", e)),
+        };
+        let time = NaiveTime::parse_from_str(&time, "%H:%M")
+            .map_err(|e| anyhow::anyhow!("Invalid departure_time {:?}, expected HH:MM: {}", time, e))?;
+
+        let soc: Option<usize> = match figment.extract_inner("departure_soc") {
+            Ok(soc) => Some(soc),
+            Err(e) if e.missing() => None,
+            Err(e) => return Err(anyhow::anyhow!("Invalid departure_soc: {}", e)),
+        };
+        if let Some(soc) = soc {
+            anyhow::ensure!(
+                (1..=100).contains(&soc),
+                "Invalid departure_soc {}, it must be a percentage",
+                soc
+            );
+        }
+
+        let battery_capacity_kwh: f64 = figment.extract_inner("battery_capacity_kwh").map_err(|e| {
+            if e.missing() {
+                anyhow::anyhow!("Missing battery_capacity_kwh, required by departure_time")
+            } else {
+                anyhow::anyhow!("Invalid battery_capacity_kwh: {}", e)
+            }
+        })?;
+        anyhow::ensure!(
+            battery_capacity_kwh > 0.0,
+            "Invalid battery_capacity_kwh {}, it must be positive",
+            battery_capacity_kwh
+        );
+
+        let timezone = match figment.extract_inner::<String>("charge_timezone") {
+            Ok(timezone) => timezone
+                .parse()
+                .map_err(|e| anyhow::anyhow!("Invalid charge_timezone: {}", e))?,
+            Err(e) if e.missing() => chrono_tz::UTC,
+            Err(e) => return Err(anyhow::anyhow!("Invalid charge_timezone: {}", e)),
+        };
+
+        Ok(Some(Self {
+            timezone,
+            time,
+            soc,
+            battery_capacity_kwh,
+        }))
+    }
+
+    /// Returns the next departure after the given instant
+    fn next_departure(&self, now: &DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let local = now.with_timezone(&self.timezone);
+        // Tomorrow's departure if today's already happened
+        (0..=1).find_map(|days| {
+            let date = local.date_naive() + chrono::Days::new(days);
+            let departure = self
+                .timezone
+                .from_local_datetime(&date.and_time(self.time))
+                .earliest()?
+                .with_timezone(&Utc);
+            (departure > *now).then_some(departure)
+        })
+    }
+
+    /// Returns the amps that would bring the battery from `battery_level` to
+    /// the target by the margin before the next departure, at `volts`.
+    ///
+    /// The target is the `departure_soc`, or else `charge_limit_soc`, the limit
+    /// requested to or reported by the car. Returns None if the target is
+    /// unknown or we are already within the margin, so that the budget alone
+    /// decides.
+    pub fn amps_needed(
+        &self,
+        now: &DateTime<Utc>,
+        battery_level: usize,
+        charge_limit_soc: Option<usize>,
+        volts: f64,
+    ) -> Option<f64> {
+        let target = self.soc.or(charge_limit_soc)?;
+        let remaining_secs = (self.next_departure(now)? - *now).num_seconds() - DEPARTURE_MARGIN_SECS;
+        if remaining_secs <= 0 {
+            return None;
+        }
+
+        let missing_kwh =
+            target.saturating_sub(battery_level) as f64 / 100.0 * self.battery_capacity_kwh;
+        let hours = remaining_secs as f64 / 3600.0;
+        Some(missing_kwh * 1000.0 / hours / volts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Leaving at 07:30 with 80% of a 75 kWh battery
+    fn target(timezone: &str) -> DepartureTarget {
+        let figment = Figment::new()
+            .merge(("departure_time", "07:30"))
+            .merge(("departure_soc", 80))
+            .merge(("battery_capacity_kwh", 75))
+            .merge(("charge_timezone", timezone));
+        DepartureTarget::from_figment(&figment).unwrap().unwrap()
+    }
+
+    fn at(datetime: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(datetime).unwrap().to_utc()
+    }
+
+    #[test]
+    fn the_missing_energy_is_spread_until_the_margin() {
+        let target = target("UTC");
+
+        // 30 kWh over the 10 hours until 06:30, at 250 V
+        let amps = target.amps_needed(&at("2024-01-01T20:30:00Z"), 40, None, 250.0);
+        assert_eq!(amps, Some(12.0));
+        // After today's departure, tomorrow's is used: 30 kWh over 20 hours
+        let amps = target.amps_needed(&at("2024-01-01T10:30:00Z"), 40, None, 250.0);
+        assert_eq!(amps, Some(6.0));
+        // Nothing is missing
+        let amps = target.amps_needed(&at("2024-01-01T20:30:00Z"), 90, None, 250.0);
+        assert_eq!(amps, Some(0.0));
+        // Within the margin, the budget alone decides
+        assert_eq!(target.amps_needed(&at("2024-01-01T07:00:00Z"), 40, None, 250.0), None);
+    }
+
+    #[test]
+    fn the_departure_is_in_the_charge_timezone() {
+        let target = target("Europe/Madrid");
+
+        // 07:30 in Madrid is 06:30 UTC in winter, so 9 hours to the margin
+        let amps = target.amps_needed(&at("2024-01-01T20:30:00Z"), 40, None, 250.0);
+        assert_eq!(amps, Some(30000.0 / 9.0 / 250.0));
+    }
+
+    #[test]
+    fn the_target_falls_back_to_the_charge_limit() {
+        let figment = Figment::new()
+            .merge(("departure_time", "07:30"))
+            .merge(("battery_capacity_kwh", 75));
+        let target = DepartureTarget::from_figment(&figment).unwrap().unwrap();
+        let now = at("2024-01-01T20:30:00Z");
+
+        assert_eq!(target.amps_needed(&now, 40, None, 250.0), None);
+        // 45 kWh over 10 hours
+        assert_eq!(target.amps_needed(&now, 40, Some(100), 250.0), Some(18.0));
+    }
+
+    #[test]
+    fn the_battery_capacity_is_required() {
+        let figment = Figment::new().merge(("departure_time", "07:30"));
+        assert!(DepartureTarget::from_figment(&figment).is_err());
+        assert!(DepartureTarget::from_figment(&Figment::new()).unwrap().is_none());
+    }
+
+    #[test]
+    fn an_unreadable_timezone_is_rejected() {
+        let figment = Figment::new()
+            .merge(("departure_time", "07:30"))
+            .merge(("battery_capacity_kwh", 75))
+            .merge(("charge_timezone", ["Europe/Madrid"]));
+        assert!(DepartureTarget::from_figment(&figment).is_err());
+    }
+}
@@ -15,7 +15,15 @@ use std::sync::Arc;
 
 use rocket::tokio::sync::Mutex;
 
-use super::EVChargeHandler;
+use crate::request_id::RequestId;
+use crate::token::Token;
+
+use super::geocode::Nominatim;
+use super::task::{CarDebugInfo, CarHandler, CarStateSummary, ChargeOverride};
+use super::{CarStatus, EVChargeHandler, IngestedReadings, ManagedCar};
+
+/// The names of the routes that log new readings, after which we check the car
+const INGEST_ROUTES: &[&str] = &["post_token", "post_influx"];
 
 /// This fairing checks if the car is nearby and if it's charging.
 ///
@@ -23,127 +31,432 @@ use super::EVChargeHandler;
 /// it was changed to run on every response to the Rocket app. This is because
 /// it actually makes sense to react to changes when we know of them happening.
 ///
-/// This also makes the implementation much simpler, as we don't need to worry
-/// about having a different DB pool.
+/// The readings that are not logged through the HTTP routes, e.g., from MQTT,
+/// are received from the [IngestedReadings] channel instead.
+///
+/// Since a sensor may stop reporting while the car keeps charging, the task can
+/// optionally be brought back by setting `car_check_interval_secs` in the
+/// figment. The timer then complements the on-response path, with the token
+/// configured for the car that logged last (see [CarTokens]), or else the
+/// token of the last logged reading. If no readings were logged over the last
+/// 30 seconds, the car amps are held or reduced, never raised.
+///
+/// Only the readings from the tokens configured for the car are used, see
+/// [CarTokens].
 ///
 /// Since requests can come in parallel, by using a Mutex we can ensure that
 /// only one request at a time will check the car status, and we can discard the
-/// other.
+/// other. The same applies to the timer task.
 pub struct EVChargeFairing<H: EVChargeHandler> {
-    handler: Arc<Mutex<Option<super::task::CarHandler<H>>>>,
+    handler: Arc<Mutex<Option<CarHandler<H>>>>,
+
+    /// The token of the last reading that triggered a check, used by the timer
+    last_token: Arc<Mutex<Option<String>>>,
+
+    /// This stores the timer task, if enabled
+    task: Arc<Mutex<Option<rocket::tokio::task::JoinHandle<()>>>>,
+
+    /// This stores the task receiving the [IngestedReadings]
+    listener: Arc<Mutex<Option<rocket::tokio::task::JoinHandle<()>>>>,
 }
 
-impl<'a, H: EVChargeHandler> EVChargeFairing<H>
-where
-    H::ConfigParams: From<&'a rocket::figment::Figment>,
-{
+impl<H: EVChargeHandler> EVChargeFairing<H> {
     pub fn new() -> Self {
         Self {
             handler: Arc::new(Mutex::new(None)),
+            last_token: Arc::new(Mutex::new(None)),
+            task: Arc::new(Mutex::new(None)),
+            listener: Arc::new(Mutex::new(None)),
         }
     }
+}
 
-    /// This function checks if the car is nearby and if it's charging.
-    ///
-    /// If it is, it will check the average amps drawn by the home from the
-    /// database over the last 30 seconds and update the car API accordingly to
-    /// not exceed the amp limit.
-    async fn check_on_response<'r>(&self, req: &rocket::Request<'r>) -> anyhow::Result<()> {
-        let _guard = match self.handler.try_lock() {
-            Ok(guard) => guard,
-            Err(_) => {
-                log::info!("Car handler is currently locked, skipping check on this response.");
-                return Ok(());
-            } // Ignore if the lock is currently being held elsewhere
+#[rocket::async_trait]
+impl<H: EVChargeHandler> CarStatus for Mutex<Option<CarHandler<H>>>
+where
+    H: Send + Sync + 'static,
+    H::InternalState: Send + Sync + 'static,
+{
+    async fn debug_info(&self) -> anyhow::Result<CarDebugInfo> {
+        match self.lock().await.as_ref() {
+            Some(handler) => handler.debug_info().await,
+            None => Err(anyhow::anyhow!("EV charge control is disabled")),
+        }
+    }
+
+    async fn state_summary(&self) -> CarStateSummary {
+        match self.lock().await.as_ref() {
+            Some(handler) => CarStateSummary {
+                car: handler.cached_car_state().await,
+                home: handler.last_home_state().await,
+                manual_override: handler.active_override().await,
+                charge_session: handler.charge_session().await,
+            },
+            None => CarStateSummary::default(),
+        }
+    }
+
+    async fn max_amps_car(&self) -> Option<usize> {
+        self.lock().await.as_ref().map(|handler| handler.max_amps_car())
+    }
+
+    async fn set_override(
+        &self,
+        amps: usize,
+        duration_secs: u32,
+    ) -> anyhow::Result<ChargeOverride> {
+        match self.lock().await.as_ref() {
+            Some(handler) => handler.set_override(amps, duration_secs).await,
+            None => Err(anyhow::anyhow!("EV charge control is disabled")),
+        }
+    }
+
+    async fn clear_override(&self) -> bool {
+        match self.lock().await.as_ref() {
+            Some(handler) => handler.clear_override().await,
+            None => false,
+        }
+    }
+}
+
+/// The sensor tokens whose readings feed the car budget, from the `car_tokens`
+/// list and the `car_location` in the figment.
+///
+/// In a deployment with sensors in several buildings, only the readings from
+/// the building where the car charges should be used. A token is accepted if
+/// it is listed in `car_tokens`, or if it belongs to the `car_location`. If
+/// neither is configured, every token is accepted.
+struct CarTokens {
+    tokens: Vec<String>,
+    location: Option<String>,
+}
+
+impl From<&rocket::figment::Figment> for CarTokens {
+    fn from(figment: &rocket::figment::Figment) -> Self {
+        Self {
+            tokens: figment.extract_inner("car_tokens").unwrap_or_default(),
+            location: figment.extract_inner("car_location").ok(),
+        }
+    }
+}
+
+impl CarTokens {
+    /// Returns true if the readings of the token should feed the car budget
+    async fn accepts(&self, db: &sqlx::SqlitePool, token: &str) -> bool {
+        if self.tokens.is_empty() && self.location.is_none() {
+            return true;
+        }
+        if self.tokens.iter().any(|car_token| car_token == token) {
+            return true;
+        }
+        let Some(location) = &self.location else {
+            return false;
         };
-        let handler = _guard.as_ref().unwrap();
-        // 1. Check that the car is nearby
-        // 2. Check if the car is charging
-        // 3. If the car is charging, check the amps drawn by the home from the database over the last 30 seconds and update the car API accordingly to not exceed the amp limit.
-
-        // Check if the car is nearby
-        if handler.is_car_nearby().await? {
-            log::info!("Car is nearby: TRUE");
-            // Check if the car is charging
-            let car_is_charging = handler.is_car_charging().await?;
-            log::info!("Is car charging? {:?}", car_is_charging);
-            if car_is_charging {
-                let (avg_amps, max_amps) = self.get_avg_amps_at_location(req).await?;
-                handler
-                    .set_current_home_consumption(avg_amps, max_amps)
-                    .await?;
-                log::info!(
-                    "Retrieved current home consumption as: {} amps (max={})",
-                    avg_amps,
-                    max_amps
+        let result = sqlx::query_scalar!(
+            "SELECT u.location FROM tokens t INNER JOIN users u ON u.id = t.user_id WHERE t.token = ?",
+            token
+        )
+        .fetch_optional(db)
+        .await;
+        match result {
+            Ok(token_location) => token_location.as_ref() == Some(location),
+            Err(e) => {
+                log::error!(
+                    "EV: Could not check the location of the token: {}{}",
+                    e,
+                    RequestId::in_logs()
                 );
-                handler.throttled_calculate_amps().await?;
+                false
             }
-        } else {
-            log::info!("Car is nearby: FALSE");
         }
+    }
 
-        Ok(())
+    /// Returns the configured token with the most recent reading, or any of
+    /// them if none has readings, for the timer to check the car even if the
+    /// sensor stopped reporting before a restart. Returns `None` if no tokens
+    /// are configured for the car.
+    async fn latest_token(&self, db: &sqlx::SqlitePool) -> anyhow::Result<Option<String>> {
+        if self.tokens.is_empty() && self.location.is_none() {
+            return Ok(None);
+        }
+        let tokens = serde_json::to_string(&self.tokens)?;
+        let token = sqlx::query_scalar!(
+            r#"SELECT t.token FROM tokens t INNER JOIN users u ON u.id = t.user_id
+            WHERE t.token IN (SELECT value FROM json_each(?)) OR u.location = ?
+            ORDER BY (SELECT MAX(created_at) FROM energy_log e WHERE e.token = t.token) DESC
+            LIMIT 1"#,
+            tokens,
+            self.location
+        )
+        .fetch_optional(db)
+        .await?;
+        Ok(token)
     }
+}
 
-    /// This function retrieves the average amps drawn at the location from the
-    /// database over the last 30 seconds.
-    ///
-    /// It returns a tuple with the average amps and the max amps drawn.
-    async fn get_avg_amps_at_location<'r>(
-        &self,
-        req: &rocket::Request<'r>,
-    ) -> anyhow::Result<(f64, f64)> {
-        let db = req.guard::<&crate::Logs>().await.unwrap();
-        let token = req.guard::<&crate::ValidDbToken>().await.unwrap();
+/// This function checks if the car is nearby and if it's charging.
+///
+/// If it is, it will check the average amps drawn by the home from the
+/// database over the consumption window and update the car API accordingly to
+/// not exceed the amp limit.
+///
+/// If the handler is currently locked by another check, this one is skipped.
+async fn check_car<H: EVChargeHandler>(
+    handler: &Mutex<Option<CarHandler<H>>>,
+    db: &crate::db::SqlitePool,
+    token: &str,
+) -> anyhow::Result<()> {
+    let _guard = match handler.try_lock() {
+        Ok(guard) => guard,
+        Err(_) => {
+            log::info!(
+                "Car handler is currently locked, skipping this check{}.",
+                RequestId::in_logs()
+            );
+            return Ok(());
+        } // Ignore if the lock is currently being held elsewhere
+    };
+    let handler = match _guard.as_ref() {
+        Some(handler) => handler,
+        None => return Ok(()), // The EV charge control is disabled
+    };
+    // 1. Check that the car is nearby
+    // 2. Check if the car is charging
+    // 3. If the car is charging, check the amps drawn by the home from the database over the consumption window and update the car API accordingly to not exceed the amp limit.
 
+    // Check if the car is nearby
+    if handler.is_car_nearby().await? {
+        log::info!("Car is nearby: TRUE{}", RequestId::in_logs());
+        handler.ensure_charge_limit().await?;
+        // Check if the car is charging
+        let car_is_charging = handler.is_car_charging().await?;
+        log::info!("Is car charging? {:?}{}", car_is_charging, RequestId::in_logs());
+        handler.track_charge_session(car_is_charging).await;
+        if car_is_charging {
+            let window_secs = handler.consumption_window_secs();
+            match get_avg_amps_at_location(db, token, window_secs).await? {
+                Some((avg_amps, max_amps)) => {
+                    handler
+                        .set_current_home_consumption(avg_amps, max_amps)
+                        .await?;
+                    log::info!(
+                        "Retrieved current home consumption as: {} amps (max={}){}",
+                        avg_amps,
+                        max_amps,
+                        RequestId::in_logs()
+                    );
+                    handler.throttled_calculate_amps().await?;
+                }
+                // The sensor stopped reporting, the home may be drawing more
+                None => handler.calculate_amps_without_readings().await?,
+            }
+        }
+    } else {
+        log::info!("Car is nearby: FALSE{}", RequestId::in_logs());
+        handler.track_charge_session(false).await;
+    }
+
+    Ok(())
+}
+
+/// Checks the car after a reading was logged for the token, described by
+/// `source` in the logs, unless the token does not feed the car budget.
+///
+/// The token is remembered for the timer task.
+async fn check_car_after_reading<H: EVChargeHandler>(
+    handler: &Mutex<Option<CarHandler<H>>>,
+    last_token: &Mutex<Option<String>>,
+    car_tokens: &CarTokens,
+    db: &crate::db::SqlitePool,
+    token: &str,
+    source: &str,
+) {
+    if !car_tokens.accepts(db, token).await {
         log::info!(
-            "Checking average amps drawn at location for token: {}",
-            token
-        );
-        let result = sqlx::query!("SELECT AVG(amps) as avg_amps, MAX(amps) as max_amps FROM energy_log WHERE token = ? AND created_at > datetime('now', '-30 seconds')", token)
-            .fetch_one(&**db)
-            .await?;
-        let avg_amps: f64 = result.avg_amps.unwrap_or(0.0);
-        let max_amps: f64 = result.max_amps.unwrap_or(0.0);
-        log::info!(
-            "Retrieved average amps: {} and max amps: {}",
-            avg_amps,
-            max_amps
+            "EV: Ignoring reading from {}, not configured for the car ({})",
+            crate::token::simplify_token_string(token),
+            source
         );
+        return;
+    }
+    last_token.lock().await.replace(token.to_string());
 
-        Ok((avg_amps, max_amps))
+    log::info!("EV: Checking the car after {}", source);
+    match check_car(handler, db, token).await {
+        Ok(_) => log::info!("Car check succeeded ({}).", source),
+        Err(e) => log::error!("Car check failure ({}): {}", source, e),
     }
 }
 
+/// This function retrieves the average amps drawn at the location from the
+/// database over the last `window_secs` seconds.
+///
+/// It returns a tuple with the average amps and the max amps drawn, or `None`
+/// if no readings were logged over the window.
+///
+/// The readings are read from the database of the token, where the recent
+/// ones are logged.
+async fn get_avg_amps_at_location(
+    db: &crate::db::SqlitePool,
+    token: &str,
+    window_secs: u32,
+) -> anyhow::Result<Option<(f64, f64)>> {
+    log::info!(
+        "Checking average amps drawn at location for token: <{}> over {} seconds{}",
+        crate::token::simplify_token_string(token),
+        window_secs,
+        RequestId::in_logs()
+    );
+    let modifier = format!("-{} seconds", window_secs);
+    let result = sqlx::query!("SELECT AVG(amps) as avg_amps, MAX(amps) as max_amps FROM energy_log WHERE token = ? AND created_at > datetime('now', ?)", token, modifier)
+        .fetch_one(db.for_token(token))
+        .await?;
+    let (Some(avg_amps), Some(max_amps)) = (result.avg_amps, result.max_amps) else {
+        log::warn!(
+            "No readings logged over the last {} seconds{}",
+            window_secs,
+            RequestId::in_logs()
+        );
+        return Ok(None);
+    };
+    log::info!(
+        "Retrieved average amps: {} and max amps: {}{}",
+        avg_amps,
+        max_amps,
+        RequestId::in_logs()
+    );
+
+    Ok(Some((avg_amps, max_amps)))
+}
+
 #[rocket::async_trait]
-impl<'a, H: EVChargeHandler> rocket::fairing::Fairing for EVChargeFairing<H>
+impl<H: EVChargeHandler> rocket::fairing::Fairing for EVChargeFairing<H>
 where
     H: Send + Sync + 'static,
     H::ConfigParams: Send + Sync + 'static,
     H::InternalState: Send + Sync + 'static,
-    H::ConfigParams: From<&'a rocket::figment::Figment>,
 {
     fn info(&self) -> rocket::fairing::Info {
         let type_name = H::get_name();
         let name = Box::new(format!("EV Charge Fairing ({})", &type_name)).leak();
         rocket::fairing::Info {
-            name: name,
-            kind: rocket::fairing::Kind::Response | rocket::fairing::Kind::Ignite,
+            name,
+            kind: rocket::fairing::Kind::Response
+                | rocket::fairing::Kind::Ignite
+                | rocket::fairing::Kind::Liftoff
+                | rocket::fairing::Kind::Shutdown,
         }
     }
 
-    /// We initialize the [super::task::CarHandler] and store it in the fairing when the
+    /// We initialize the [CarHandler] and store it in the fairing when the
     /// Rocket app is ignited.
+    ///
+    /// If the car configuration is missing, the fairing stays inert instead of
+    /// aborting the launch, as the energy logger is useful on its own.
+    /// Otherwise, the handler is also managed as a [ManagedCar] for the routes.
     async fn on_ignite(
         &self,
         rocket: rocket::Rocket<rocket::Build>,
     ) -> rocket::fairing::Result<rocket::Rocket<rocket::Build>> {
-        let handler = super::task::CarHandler::from(rocket.figment());
-        let mut guard = self.handler.lock().await;
-        *guard = Some(handler);
+        let geocoder = Nominatim::from(rocket.figment());
+        match CarHandler::from_figment(rocket.figment(), &geocoder).await {
+            Ok(handler) => {
+                let mut guard = self.handler.lock().await;
+                *guard = Some(handler);
+            }
+            Err(e) => {
+                log::warn!("EV: Charge control is disabled ({})", e);
+                return Ok(rocket);
+            }
+        }
+
+        Ok(rocket.manage(ManagedCar(self.handler.clone())))
+    }
+
+    /// We spawn a task that checks the car after the readings received from
+    /// the [IngestedReadings] channel.
+    ///
+    /// If `car_check_interval_secs` is configured, we also spawn a task that
+    /// will periodically check the car even if no new readings are being
+    /// logged.
+    async fn on_liftoff(&self, rocket: &rocket::Rocket<rocket::Orbit>) -> () {
+        if self.handler.lock().await.is_none() {
+            return;
+        }
+
+        if let Some(ingested) = rocket.state::<IngestedReadings>() {
+            let mut receiver = ingested.subscribe();
+            let db_conn = crate::alive_check::get_database::<crate::Logs>(rocket).await;
+            let car_tokens = CarTokens::from(rocket.figment());
+            let handler = self.handler.clone();
+            let last_token = self.last_token.clone();
+            let listener = rocket::tokio::task::spawn(async move {
+                use rocket::tokio::sync::broadcast::error::RecvError;
+                loop {
+                    let token = match receiver.recv().await {
+                        Ok(token) => token,
+                        Err(RecvError::Closed) => break,
+                        Err(RecvError::Lagged(skipped)) => {
+                            log::warn!("EV: Skipped the car check of {} readings", skipped);
+                            continue;
+                        }
+                    };
+                    check_car_after_reading(
+                        &handler,
+                        &last_token,
+                        &car_tokens,
+                        &db_conn,
+                        &token,
+                        "ingested reading",
+                    )
+                    .await;
+                }
+            });
+            if let Some(old) = self.listener.lock().await.replace(listener) {
+                old.abort();
+            }
+        }
+
+        let interval_secs: u64 = match rocket.figment().extract_inner("car_check_interval_secs") {
+            Ok(0) => return,
+            Ok(interval_secs) => interval_secs,
+            Err(e) if e.missing() => return,
+            Err(e) => {
+                log::error!("EV: Ignoring invalid car_check_interval_secs: {}", e);
+                return;
+            }
+        };
+        log::info!("EV: Checking the car every {} seconds", interval_secs);
+
+        let db_conn = crate::alive_check::get_database::<crate::Logs>(rocket).await;
+        let car_tokens = CarTokens::from(rocket.figment());
+        let handler = self.handler.clone();
+        let last_token = self.last_token.clone();
+        let task = rocket::tokio::task::spawn(async move {
+            loop {
+                rocket::tokio::time::sleep(std::time::Duration::from_secs(interval_secs)).await;
+                let token = match car_tokens.latest_token(&db_conn).await {
+                    Ok(Some(token)) => Some(token),
+                    Ok(None) => last_token.lock().await.clone(),
+                    Err(e) => {
+                        log::error!("EV: Could not resolve the tokens of the car: {}", e);
+                        continue;
+                    }
+                };
+                let Some(token) = token else {
+                    log::info!("EV: No reading logged yet, skipping timed car check.");
+                    continue;
+                };
+                match check_car(&handler, &db_conn, &token).await {
+                    Ok(_) => log::info!("Timed car check succeeded."),
+                    Err(e) => log::error!("Timed car check failure: {}", e),
+                }
+            }
+        });
 
-        Ok(rocket)
+        if let Some(old) = self.task.lock().await.replace(task) {
+            old.abort();
+        }
     }
 
     async fn on_response<'r>(
@@ -154,14 +467,252 @@ where
         // Is this a request to log info?
         let route_name = req
             .route()
-            .map(|route| route.name.as_deref())
-            .flatten()
+            .and_then(|route| route.name.as_deref())
             .unwrap_or("");
-        if route_name == "post_token" {
-            match self.check_on_response(req).await {
-                Ok(_) => log::info!("Car check succeeded."),
-                Err(e) => log::error!("Car check failure: {}", e),
-            }
+        if INGEST_ROUTES.contains(&route_name) {
+            let db = req.guard::<&crate::Logs>().await.unwrap();
+            // Requests with an unknown or missing token were not logged
+            let Some(token) = req.guard::<&crate::ValidDbToken>().await.succeeded() else {
+                return;
+            };
+            // The logs of the check include the request ID, see RequestId::in_logs
+            let request_id = RequestId::of(req);
+            let car_tokens = CarTokens::from(req.rocket().figment());
+            let source = format!("request {}", request_id);
+            let check = check_car_after_reading(
+                &self.handler,
+                &self.last_token,
+                &car_tokens,
+                db,
+                token.full_token(),
+                &source,
+            );
+            request_id.clone().scope(check).await;
+        }
+    }
+
+    /// When the rocket is shutting down, we abort the tasks, if any.
+    async fn on_shutdown(&self, _: &rocket::Rocket<rocket::Orbit>) -> () {
+        if let Some(task) = self.task.lock().await.take() {
+            task.abort();
+        }
+        if let Some(listener) = self.listener.lock().await.take() {
+            listener.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::car::task::CarHandler;
+    use crate::car::simulation;
+    use crate::testing;
+    use rocket::fairing::Fairing;
+    use rocket::http::{ContentType, Status};
+
+    /// The UTC timestamp of `minutes` ago, as logged in the database
+    fn minutes_ago(minutes: i64) -> String {
+        (chrono::Utc::now() - chrono::Duration::minutes(minutes))
+            .format("%Y-%m-%d %H:%M:%S")
+            .to_string()
+    }
+
+    /// The timestamp of the home state of the last car check
+    async fn last_check(app: &testing::TestApp) -> i64 {
+        let response = app
+            .get("/car/state")
+            .header(testing::admin_authorization())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let state: serde_json::Value = response.into_json().await.unwrap();
+        state["home"]["timestamp"].as_i64().expect("the car should have been checked")
+    }
+
+    #[rocket::async_test]
+    async fn launches_with_the_fairing_inert_without_car_config() {
+        let app = testing::client_with(testing::database_figment()).await;
+
+        let response = app
+            .post(format!("/log/{}", app.token))
+            .header(ContentType::JSON)
+            .body(r#"{"amps": 4, "volts": 230, "watts": 920}"#)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+    }
+
+    #[rocket::async_test]
+    async fn timer_checks_the_car_without_new_readings() {
+        let figment = testing::figment()
+            .merge(("charger_location", "43.363056,-8.838417"))
+            .merge(("max_amps", 20))
+            .merge(("max_amps_car", 16))
+            .merge(("simulation.amps", 6))
+            .merge(("car_check_interval_secs", 1));
+        let app = testing::client_with(figment.clone()).await;
+        // The home draws 14 A besides the 6 A of the car
+        app.insert_reading(&minutes_ago(0), 20.0, 230.0, 4600.0).await;
+
+        let fairing = EVChargeFairing::<simulation::Handler>::new();
+        fairing
+            .handler
+            .lock()
+            .await
+            .replace(
+                CarHandler::from_figment(&figment, &Nominatim::from(&figment))
+                    .await
+                    .unwrap(),
+            );
+        fairing.last_token.lock().await.replace(app.token.clone());
+        fairing.on_liftoff(app.client.rocket()).await;
+        rocket::tokio::time::sleep(std::time::Duration::from_millis(1500)).await;
+        fairing.on_shutdown(app.client.rocket()).await;
+
+        let guard = fairing.handler.lock().await;
+        let handler = guard.as_ref().unwrap();
+        handler.invalidate_state_cache().await;
+        // (20 A - 14 A) * 0.95, rounded down
+        assert_eq!(handler.get_amps().await, 5.0);
+    }
+
+    #[rocket::async_test]
+    async fn only_the_tokens_of_the_car_feed_its_budget() {
+        let app = testing::client().await;
+        let garage = app.create_token("garage").await;
+        let figment = rocket::figment::Figment::new();
+
+        let everyone = CarTokens::from(&figment);
+        assert!(everyone.accepts(app.db(), &app.token).await);
+
+        let by_location = CarTokens::from(&figment.clone().merge(("car_location", "garage")));
+        assert!(by_location.accepts(app.db(), &garage).await);
+        assert!(!by_location.accepts(app.db(), &app.token).await);
+        assert!(!by_location.accepts(app.db(), "unknown-token").await);
+
+        let listed = CarTokens::from(&figment.merge(("car_tokens", vec![&app.token])));
+        assert!(listed.accepts(app.db(), &app.token).await);
+        assert!(!listed.accepts(app.db(), &garage).await);
+    }
+
+    #[rocket::async_test]
+    async fn the_timer_uses_the_token_of_the_car_that_logged_last() {
+        let app = testing::client().await;
+        let garage = app.create_token("garage").await;
+        let other = app.create_token("garage").await;
+        let figment = rocket::figment::Figment::new();
+
+        assert_eq!(CarTokens::from(&figment).latest_token(app.db()).await.unwrap(), None);
+
+        // The sensor stopped reporting before a restart
+        sqlx::query("INSERT INTO energy_log (token, amps, volts, watts, created_at) VALUES (?, 4, 230, 920, ?)")
+            .bind(&other)
+            .bind(minutes_ago(10))
+            .execute(app.db())
+            .await
+            .unwrap();
+        let by_location = CarTokens::from(&figment.clone().merge(("car_location", "garage")));
+        assert_eq!(by_location.latest_token(app.db()).await.unwrap(), Some(other));
+
+        let listed = CarTokens::from(&figment.merge(("car_tokens", vec![&garage])));
+        assert_eq!(listed.latest_token(app.db()).await.unwrap(), Some(garage));
+    }
+
+    #[rocket::async_test]
+    async fn readings_from_unrelated_tokens_do_not_check_the_car() {
+        let figment = testing::admin_figment()
+            .merge(("ev_handler", "simulate"))
+            .merge(("charger_location", "43.363056,-8.838417"))
+            .merge(("max_amps", 20))
+            .merge(("max_amps_car", 16))
+            .merge(("car_location", "garage"));
+        let app = testing::client_with(figment).await;
+        let post = |token: String| {
+            app.post(format!("/log/{}", token))
+                .header(ContentType::JSON)
+                .body(r#"{"amps": 4, "volts": 230, "watts": 920}"#)
+                .dispatch()
+        };
+
+        // The token of the application is in another location
+        assert_eq!(post(app.token.clone()).await.status(), Status::Ok);
+        let response = app
+            .get("/car/state")
+            .header(testing::admin_authorization())
+            .dispatch()
+            .await;
+        let state: serde_json::Value = response.into_json().await.unwrap();
+        assert!(state["home"]["timestamp"].is_null(), "{}", state);
+
+        let garage = app.create_token("garage").await;
+        assert_eq!(post(garage).await.status(), Status::Ok);
+        last_check(&app).await;
+    }
+
+    #[rocket::async_test]
+    async fn the_consumption_window_bounds_the_average() {
+        let app = testing::client().await;
+        let ago = |secs| {
+            (chrono::Utc::now() - chrono::Duration::seconds(secs))
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string()
+        };
+        app.insert_reading(&ago(10), 4.0, 230.0, 920.0).await;
+        app.insert_reading(&ago(90), 10.0, 230.0, 2300.0).await;
+
+        let average =
+            |window_secs| super::get_avg_amps_at_location(app.logs(), &app.token, window_secs);
+        assert_eq!(average(5).await.unwrap(), None);
+        assert_eq!(average(30).await.unwrap(), Some((4.0, 4.0)));
+        assert_eq!(average(120).await.unwrap(), Some((7.0, 10.0)));
+    }
+
+    #[rocket::async_test]
+    async fn an_invalid_consumption_window_fails_the_launch() {
+        for window in [0, 3601] {
+            let figment = testing::figment()
+                .merge(("ev_handler", "simulate"))
+                .merge(("charger_location", "43.363056,-8.838417"))
+                .merge(("max_amps", 20))
+                .merge(("max_amps_car", 16))
+                .merge(("consumption_window_secs", window));
+            let error = crate::build(figment).ignite().await.expect_err("the launch should fail");
+            assert!(matches!(
+                error.kind(),
+                rocket::error::ErrorKind::FailedFairings(_)
+            ));
         }
     }
+
+    #[rocket::async_test]
+    async fn every_check_tracks_the_charge_session() {
+        let session_started = |charging: bool| async move {
+            let figment = testing::admin_figment()
+                .merge(("ev_handler", "simulate"))
+                .merge(("charger_location", "43.363056,-8.838417"))
+                .merge(("simulation.charging", charging))
+                .merge(("max_amps", 20))
+                .merge(("max_amps_car", 16))
+                .merge(("max_charge_session_secs", 3600));
+            let app = testing::client_with(figment).await;
+            let response = app
+                .post(format!("/log/{}", app.token))
+                .header(ContentType::JSON)
+                .body(r#"{"amps": 4, "volts": 230, "watts": 920}"#)
+                .dispatch()
+                .await;
+            assert_eq!(response.status(), Status::Ok);
+            let response = app
+                .get("/car/state")
+                .header(testing::admin_authorization())
+                .dispatch()
+                .await;
+            let state: serde_json::Value = response.into_json().await.unwrap();
+            state["charge_session"]["started"].clone()
+        };
+
+        assert!(session_started(true).await.is_i64());
+        assert!(session_started(false).await.is_null());
+    }
 }
@@ -0,0 +1,191 @@
+//! Resolution of the charger location from a postal address.
+//!
+//! The charger location is usually configured with its coordinates, but an
+//! address can be configured instead. It is geocoded once, when the
+//! [EVChargeFairing](super::fairing::EVChargeFairing) is ignited. In the figment
+//! configuration (Rocket.toml):
+//!
+//! ```toml
+//! # Used only if charger_location is not set
+//! charger_address = "Rúa Real 1, A Coruña, Spain"
+//! # The Nominatim-compatible geocoding service (OpenStreetMap by default)
+//! geocoder_url = "https://nominatim.openstreetmap.org"
+//! ```
+//!
+//! If you want to use a different service, implement the [Geocoder] trait.
+
+use rocket::figment::Figment;
+use serde::Deserialize;
+
+use super::LatLon;
+
+/// The geocoding service used unless `geocoder_url` is configured
+const DEFAULT_GEOCODER_URL: &str = "https://nominatim.openstreetmap.org";
+
+/// A service that resolves addresses into coordinates
+pub trait Geocoder {
+    /// Returns the coordinates of the address, failing if it is not found
+    fn geocode(
+        &self,
+        address: &str,
+    ) -> impl std::future::Future<Output = anyhow::Result<LatLon>> + std::marker::Send;
+}
+
+/// Returns the charger location from the figment.
+///
+/// The `charger_location` coordinates win if they are set. Otherwise, the
+/// `charger_address` is resolved with the geocoder.
+pub async fn resolve_charger_location(
+    figment: &Figment,
+    geocoder: &impl Geocoder,
+) -> anyhow::Result<LatLon> {
+    match figment.extract_inner::<String>("charger_location") {
+        Ok(location) => {
+            return LatLon::try_from(location)
+                .map_err(|e| anyhow::anyhow!("Invalid charger location: {}", e))
+        }
+        Err(e) if !e.missing() => return Err(e.into()),
+        Err(_) => {}
+    }
+
+    let address: String = figment.extract_inner("charger_address").map_err(|e| {
+        if e.missing() {
+            anyhow::anyhow!("Neither charger_location nor charger_address are set")
+        } else {
+            e.into()
+        }
+    })?;
+    let location = geocoder
+        .geocode(&address)
+        .await
+        .map_err(|e| anyhow::anyhow!("Could not geocode charger_address {:?}: {}", address, e))?;
+    log::info!(
+        "EV: Resolved charger_address {:?} to {},{}",
+        address,
+        location.lat,
+        location.lon
+    );
+    Ok(location)
+}
+
+/// Geocoder for the [Nominatim](https://nominatim.org/release-docs/latest/api/Search/)
+/// search API, as run by OpenStreetMap.
+pub struct Nominatim {
+    base_url: String,
+    client: reqwest::Client,
+}
+
+impl From<&Figment> for Nominatim {
+    fn from(figment: &Figment) -> Self {
+        let base_url = figment
+            .extract_inner("geocoder_url")
+            .unwrap_or_else(|_| DEFAULT_GEOCODER_URL.to_string());
+        let client = crate::http_client::shared().clone();
+        Self { base_url, client }
+    }
+}
+
+/// A search result from Nominatim, which reports the coordinates as strings
+#[derive(Deserialize)]
+struct NominatimPlace {
+    lat: String,
+    lon: String,
+}
+
+impl Geocoder for Nominatim {
+    async fn geocode(&self, address: &str) -> anyhow::Result<LatLon> {
+        let url = format!("{}/search", self.base_url.trim_end_matches('/'));
+        // The Nominatim usage policy requires identifying the application
+        let places: Vec<NominatimPlace> = self
+            .client
+            .get(url)
+            .query(&[("q", address), ("format", "jsonv2"), ("limit", "1")])
+            .header(
+                reqwest::header::USER_AGENT,
+                concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION")),
+            )
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let place = places
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("address not found"))?;
+        Ok(LatLon {
+            lat: place.lat.parse()?,
+            lon: place.lon.parse()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MockServer;
+    use std::sync::Mutex;
+
+    /// A geocoder that knows a single address, and records what it is asked
+    #[derive(Default)]
+    struct StubGeocoder {
+        addresses: Mutex<Vec<String>>,
+    }
+
+    impl Geocoder for StubGeocoder {
+        async fn geocode(&self, address: &str) -> anyhow::Result<LatLon> {
+            self.addresses.lock().unwrap().push(address.to_string());
+            match address {
+                "Rúa Real 1, A Coruña" => Ok(LatLon {
+                    lat: 43.3703,
+                    lon: -8.3970,
+                }),
+                _ => Err(anyhow::anyhow!("address not found")),
+            }
+        }
+    }
+
+    fn coordinates(location: &LatLon) -> (f64, f64) {
+        (location.lat, location.lon)
+    }
+
+    #[rocket::async_test]
+    async fn the_address_is_geocoded_unless_there_are_coordinates() {
+        let geocoder = StubGeocoder::default();
+        let figment = Figment::new().merge(("charger_address", "Rúa Real 1, A Coruña"));
+
+        let location = resolve_charger_location(&figment, &geocoder).await.unwrap();
+        assert_eq!(coordinates(&location), (43.3703, -8.3970));
+
+        let figment = figment.merge(("charger_location", "43.363056,-8.838417"));
+        let location = resolve_charger_location(&figment, &geocoder).await.unwrap();
+        assert_eq!(coordinates(&location), (43.363056, -8.838417));
+        assert_eq!(*geocoder.addresses.lock().unwrap(), vec!["Rúa Real 1, A Coruña"]);
+    }
+
+    #[rocket::async_test]
+    async fn unknown_or_missing_addresses_fail() {
+        let geocoder = StubGeocoder::default();
+
+        let figment = Figment::new().merge(("charger_address", "Nowhere"));
+        assert!(resolve_charger_location(&figment, &geocoder).await.is_err());
+        assert!(resolve_charger_location(&Figment::new(), &geocoder).await.is_err());
+    }
+
+    #[rocket::async_test]
+    async fn nominatim_searches_the_address() {
+        let nominatim = MockServer::start(200, r#"[{"lat": "43.3703", "lon": "-8.3970"}]"#).await;
+        let geocoder = Nominatim::from(&Figment::new().merge(("geocoder_url", &nominatim.url)));
+
+        let location = geocoder.geocode("Rúa Real 1").await.unwrap();
+        assert_eq!(coordinates(&location), (43.3703, -8.3970));
+        assert_eq!(
+            nominatim.requests(),
+            vec!["GET /search?q=R%C3%BAa+Real+1&format=jsonv2&limit=1 HTTP/1.1"]
+        );
+
+        let nominatim = MockServer::start(200, "[]").await;
+        let geocoder = Nominatim::from(&Figment::new().merge(("geocoder_url", &nominatim.url)));
+        assert!(geocoder.geocode("Nowhere").await.is_err());
+    }
+}
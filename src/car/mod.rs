@@ -9,12 +9,24 @@
 //! If you want to implement your own EV charge handler, you should implement
 //! the [EVChargeHandler] and [EVChargeInternalState] traits in this module. You
 //! can look at the [tessie] source code for an example implementation.
+//!
+//! The handler to use is selected at runtime with the `ev_handler` setting in
+//! the figment configuration (Rocket.toml), which defaults to `"tessie"`. To
+//! make your own handler selectable, add it to [handler_fairing].
+
+use std::sync::Arc;
 
 use serde::{Deserialize, Serialize};
 
+pub mod departure;
 pub mod fairing;
+pub mod geocode;
+pub mod routes;
+pub mod schedule;
+pub mod simulation;
 pub mod tessie;
 pub mod task;
+pub mod units;
 
 /// The internal state of the EV charge handler.
 /// 
@@ -37,12 +49,36 @@ pub trait EVChargeInternalState: std::fmt::Debug + Clone {
     /// Returns the max amps that we requested the charge to use
     fn get_last_requested_amps(&self) -> usize;
 
+    /// Returns the last known position of the car
+    fn get_car_location(&self) -> LatLon;
+
     /// Returns the distance in kilometers between the car and a point
-    fn get_car_distance_to_point_km(&self, point: &LatLon) -> f64;
+    fn get_car_distance_to_point_km(&self, point: &LatLon) -> f64 {
+        self.get_car_location().distance(point)
+    }
+
+    /// Returns the battery level (state of charge) at which the car will stop
+    /// charging, if the platform reports it.
+    fn get_charge_limit_soc(&self) -> Option<usize> {
+        None
+    }
+
+    /// Returns the battery level (state of charge) in percent, if the platform
+    /// reports it.
+    fn get_battery_level(&self) -> Option<usize> {
+        None
+    }
+
+    /// Returns false if the car is known to be unplugged from the charger.
+    ///
+    /// Platforms that do not report it are assumed to be plugged in.
+    fn is_plugged_in(&self) -> bool {
+        true
+    }
 }
 
 pub trait EVChargeHandler {
-    type ConfigParams: for<'a> From<&'a rocket::figment::Figment>;
+    type ConfigParams: for<'a> TryFrom<&'a rocket::figment::Figment, Error = rocket::figment::Error>;
     type InternalState: EVChargeInternalState;
 
     /// Get the name of the EV charge handler
@@ -63,7 +99,9 @@ pub trait EVChargeHandler {
     /// 
     /// The configuration parameters should be extractable from the Rocket.toml
     /// file, so the implementation for the [EVChargeHandler::ConfigParams] must
-    /// implement the `From<&'a rocket::figment::Figment>` trait.
+    /// implement the `TryFrom<&'a rocket::figment::Figment>` trait. If the
+    /// configuration is missing, the [EVChargeFairing](fairing::EVChargeFairing)
+    /// will disable itself instead of preventing the launch.
     fn new(config: Self::ConfigParams) -> Self;
 
     /// Get the current state of the EV
@@ -80,8 +118,124 @@ pub trait EVChargeHandler {
 
     /// Request the car to charge with a specific amount of amps
     fn request_charge_amps(&self, amps: usize) -> impl std::future::Future<Output = anyhow::Result<()>> + std::marker::Send;
+
+    /// Request the car to stop charging once the battery reaches `soc` percent
+    ///
+    /// Not every platform supports this, so by default it does nothing.
+    fn request_charge_limit(&self, _soc: usize) -> impl std::future::Future<Output = anyhow::Result<()>> + std::marker::Send {
+        async { Ok(()) }
+    }
+}
+
+
+/// Type-erased access to the car handler, for use from the routes.
+///
+/// The [EVChargeHandler] trait is not object-safe, so the
+/// [EVChargeFairing](fairing::EVChargeFairing) manages its handler as a
+/// [ManagedCar] implementing this trait instead.
+#[rocket::async_trait]
+pub trait CarStatus: Send + Sync {
+    /// Returns the information used to decide whether the car is nearby
+    async fn debug_info(&self) -> anyhow::Result<task::CarDebugInfo>;
+
+    /// Returns the cached state of the car and the home the last charge
+    /// decision was based on
+    async fn state_summary(&self) -> task::CarStateSummary;
+
+    /// Returns the most amps that may be requested to the car, or `None` if
+    /// the EV charge control is disabled
+    async fn max_amps_car(&self) -> Option<usize>;
+
+    /// Requests the car to charge at `amps` for `duration_secs` seconds,
+    /// regardless of the budget
+    async fn set_override(
+        &self,
+        amps: usize,
+        duration_secs: u32,
+    ) -> anyhow::Result<task::ChargeOverride>;
+
+    /// Cancels the manual override, returning whether one was active
+    async fn clear_override(&self) -> bool;
 }
 
+/// The car handler, managed as Rocket state when the EV charge control is
+/// enabled.
+pub struct ManagedCar(pub Arc<dyn CarStatus>);
+
+/// How many tokens are buffered for the car check before it lags behind
+const INGESTED_CHANNEL_CAPACITY: usize = 64;
+
+/// The broadcast channel of the sensor tokens that logged a reading outside of
+/// the HTTP ingest routes, e.g., from MQTT, managed as Rocket state.
+///
+/// The [EVChargeFairing](fairing::EVChargeFairing) notices the HTTP ingest on
+/// response, and listens on this channel for the other ingests, so that their
+/// readings also trigger the car check.
+#[derive(Clone)]
+pub struct IngestedReadings {
+    sender: rocket::tokio::sync::broadcast::Sender<String>,
+}
+
+impl IngestedReadings {
+    pub fn new() -> Self {
+        let (sender, _) = rocket::tokio::sync::broadcast::channel(INGESTED_CHANNEL_CAPACITY);
+        Self { sender }
+    }
+
+    /// Notify that a reading was inserted for the given sensor token.
+    ///
+    /// This does nothing if the car check is not listening.
+    // Only the MQTT ingest, behind the `mqtt` feature, notifies for now
+    #[cfg_attr(not(feature = "mqtt"), allow(dead_code))]
+    pub fn notify(&self, token: &str) {
+        let _ = self.sender.send(token.to_string());
+    }
+
+    fn subscribe(&self) -> rocket::tokio::sync::broadcast::Receiver<String> {
+        self.sender.subscribe()
+    }
+}
+
+/// Returns the [EVChargeFairing](fairing::EVChargeFairing) for the handler
+/// registered under the given name, or `None` if there is no such handler.
+///
+/// Each handler gets its own monomorphized fairing, so the [EVChargeHandler]
+/// trait does not need to be object-safe.
+pub fn handler_fairing(name: &str) -> Option<Arc<dyn rocket::fairing::Fairing>> {
+    match name {
+        "tessie" => Some(Arc::new(fairing::EVChargeFairing::<tessie::Handler>::new())),
+        "simulate" => Some(Arc::new(
+            fairing::EVChargeFairing::<simulation::Handler>::new(),
+        )),
+        _ => None,
+    }
+}
+
+/// Fairing that attaches the [EVChargeFairing](fairing::EVChargeFairing) for
+/// the handler selected with the `ev_handler` setting, when the Rocket app is
+/// ignited.
+///
+/// Setting `ev_handler = "none"` disables the EV charge control altogether.
+pub fn selected_handler_fairing() -> rocket::fairing::AdHoc {
+    rocket::fairing::AdHoc::try_on_ignite("Select EV charge handler", |rocket| async {
+        let name: String = rocket
+            .figment()
+            .extract_inner("ev_handler")
+            .unwrap_or_else(|_| "tessie".to_string());
+        if name == "none" {
+            log::info!("EV: Charge control is disabled by configuration");
+            return Ok(rocket);
+        }
+
+        match handler_fairing(&name) {
+            Some(fairing) => Ok(rocket.attach(fairing)),
+            None => {
+                log::error!("EV: Unknown ev_handler {:?}", name);
+                Err(rocket)
+            }
+        }
+    })
+}
 
 /// A simple struct to store latitude and longitude
 #[derive(Clone, Debug, Default, Serialize, Deserialize)]
@@ -110,3 +264,45 @@ impl LatLon {
         EARTH_RADIUS * c
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn selects_the_handler_by_name() {
+        let tessie = handler_fairing("tessie").expect("tessie is registered");
+        assert_eq!(tessie.info().name, "EV Charge Fairing (Tessie)");
+        assert!(handler_fairing("unknown").is_none());
+    }
+
+    #[rocket::async_test]
+    async fn launches_with_the_selected_handler() {
+        let figment = testing::figment()
+            .merge(("ev_handler", "tessie"))
+            .merge(("car_vin", "VIN123"))
+            .merge(("tessie_token", "secret"))
+            .merge(("charger_location", "43.363056,-8.838417"))
+            .merge(("max_amps", 20))
+            .merge(("max_amps_car", 16));
+        let app = testing::client_with(figment).await;
+        assert!(app.client.rocket().state::<ManagedCar>().is_some());
+
+        let app = testing::client().await;
+        assert!(app.client.rocket().state::<ManagedCar>().is_none());
+    }
+
+    #[rocket::async_test]
+    async fn unknown_handlers_fail_the_launch() {
+        let figment = testing::figment().merge(("ev_handler", "unknown"));
+        let error = crate::build(figment).ignite().await.expect_err("the launch should fail");
+
+        match error.kind() {
+            rocket::error::ErrorKind::FailedFairings(failures) => {
+                assert!(failures.iter().any(|f| f.name == "Select EV charge handler"), "{:?}", failures)
+            }
+            kind => panic!("unexpected error {:?}", kind),
+        }
+    }
+}
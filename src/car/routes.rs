@@ -0,0 +1,305 @@
+//! Routes to inspect the EV charge control.
+//!
+//! They are protected by the [AdminGuard], as they expose the location of the
+//! car, and are only useful when the EV charge control is enabled.
+
+use rocket::{delete, get, post};
+use rocket::http::Status;
+use rocket::serde::json::Json;
+
+use crate::admin::AdminGuard;
+use crate::request_id::RequestId;
+
+use super::task::{CarDebugInfo, CarStateSummary, ChargeOverride};
+use super::ManagedCar;
+
+/// Request guard for the car handler, which forwards with a 404 if the EV
+/// charge control is disabled.
+///
+/// We cannot use `Option<&State<ManagedCar>>`, as Rocket would refuse to
+/// launch without the state being managed.
+#[rocket::async_trait]
+impl<'r> rocket::request::FromRequest<'r> for &'r ManagedCar {
+    type Error = ();
+
+    async fn from_request(
+        request: &'r rocket::Request<'_>,
+    ) -> rocket::request::Outcome<Self, Self::Error> {
+        match request.rocket().state::<ManagedCar>() {
+            Some(car) => rocket::request::Outcome::Success(car),
+            None => rocket::request::Outcome::Forward(Status::NotFound),
+        }
+    }
+}
+
+/// Route GET /car/debug will return the car position, the charger position,
+/// the distance between them and whether the car is considered nearby.
+///
+/// This is useful to tell a GPS issue apart from a threshold issue when the
+/// car is not being detected as nearby.
+#[get("/car/debug")]
+pub async fn car_debug(
+    _admin: AdminGuard,
+    car: &ManagedCar,
+) -> Result<Json<CarDebugInfo>, (Status, String)> {
+    car.0.debug_info().await.map(Json).map_err(|e| {
+        log::error!("EV: Failed to retrieve car debug info: {}", e);
+        (Status::BadGateway, format!("Failed to retrieve the car state: {}", e))
+    })
+}
+
+/// Route GET /car/state will return the cached car state and the last home
+/// state that the charge control based its decision on.
+///
+/// Unlike [car_debug], this never calls the car API, so it is cheap enough to
+/// be polled by a dashboard. The `car` is null until the first check.
+#[get("/car/state")]
+pub async fn car_state(_admin: AdminGuard, car: &ManagedCar) -> Json<CarStateSummary> {
+    Json(car.0.state_summary().await)
+}
+
+/// The longest manual override allowed, in seconds
+const MAX_OVERRIDE_SECS: u32 = 24 * 3600;
+
+/// Route POST /car/override?amps=N&duration_secs=M will request the car to
+/// charge at N amps for the next M seconds, regardless of the budget, e.g.,
+/// to charge at full power before leaving for a trip.
+///
+/// The amps are requested right away, and the budget control resumes once the
+/// override expires, or it is cancelled with DELETE /car/override. The
+/// duration must be between 1 second and 24 hours, and the amps must not be
+/// over `max_amps_car`.
+#[post("/car/override?<amps>&<duration_secs>")]
+pub async fn set_override(
+    _admin: AdminGuard,
+    car: &ManagedCar,
+    amps: usize,
+    duration_secs: u32,
+) -> Result<Json<ChargeOverride>, (Status, String)> {
+    if !(1..=MAX_OVERRIDE_SECS).contains(&duration_secs) {
+        return Err((
+            Status::BadRequest,
+            format!("duration_secs must be between 1 and {}", MAX_OVERRIDE_SECS),
+        ));
+    }
+    if let Some(max_amps_car) = car.0.max_amps_car().await.filter(|max| amps > *max) {
+        return Err((
+            Status::BadRequest,
+            format!("amps must not be over max_amps_car ({})", max_amps_car),
+        ));
+    }
+    car.0.set_override(amps, duration_secs).await.map(Json).map_err(|e| {
+        log::error!(
+            "EV: Failed to request the override amps: {}{}",
+            e,
+            RequestId::in_logs()
+        );
+        (
+            Status::BadGateway,
+            format!("Override set, but failed to request the amps to the car, it will be retried on the next check: {}", e),
+        )
+    })
+}
+
+/// Route DELETE /car/override cancels the manual override, so the next check
+/// resumes the budget control. It returns 404 if no override was active.
+#[delete("/car/override")]
+pub async fn clear_override(_admin: AdminGuard, car: &ManagedCar) -> Status {
+    if car.0.clear_override().await {
+        Status::NoContent
+    } else {
+        Status::NotFound
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::car::LatLon;
+    use crate::testing::{self, MockServer};
+    use rocket::http::{ContentType, Status};
+
+    /// The Tessie state of a car parked at 43.37,-8.84, about 780 m from the
+    /// charger at A Coruña
+    const TESSIE_STATE: &str = r#"{
+        "access_type": "OWNER",
+        "api_version": 71,
+        "state": "online",
+        "vehicle_name": null,
+        "display_name": null,
+        "drive_state": {
+            "gps_as_of": 1792174015,
+            "latitude": 43.37,
+            "longitude": -8.84,
+            "heading": null,
+            "speed": null,
+            "timestamp": 1792174015,
+            "power": null
+        },
+        "charge_state": {
+            "charge_amps": 0.0,
+            "charge_current_request": 16,
+            "charge_enable_request": false,
+            "charge_energy_added": 0.0,
+            "charge_limit_soc": 80,
+            "charge_limit_soc_max": 100,
+            "charge_limit_soc_min": 50,
+            "charge_limit_soc_std": 80,
+            "charge_miles_added_ideal": 0.0,
+            "charge_miles_added_rated": 0.0,
+            "charge_port_cold_weather_mode": false,
+            "charge_port_door_open": false,
+            "charge_port_latch": "Engaged",
+            "charge_rate": 0.0,
+            "charger_actual_current": 0.0,
+            "charger_phases": null,
+            "charger_pilot_current": 16.0,
+            "charger_power": 0.0,
+            "charger_voltage": 0.0,
+            "charging_state": "Disconnected",
+            "conn_charge_cable": "IEC",
+            "fast_charger_brand": "<invalid>",
+            "fast_charger_present": false
+        }
+    }"#;
+
+    /// The application with the car reported by a mock of the Tessie API, and
+    /// the charger at A Coruña
+    async fn app(tessie: &MockServer) -> testing::TestApp {
+        let figment = testing::admin_figment()
+            .merge(("ev_handler", "tessie"))
+            .merge(("tessie_url", &tessie.url))
+            .merge(("car_vin", "VIN123"))
+            .merge(("tessie_token", "secret"))
+            .merge(("charger_location", "43.363056,-8.838417"))
+            .merge(("max_amps", 20))
+            .merge(("max_amps_car", 16));
+        testing::client_with(figment).await
+    }
+
+    #[rocket::async_test]
+    async fn debug_reports_the_distance_to_the_charger() {
+        let tessie = MockServer::start(200, TESSIE_STATE).await;
+        let app = app(&tessie).await;
+
+        let response = app
+            .get("/car/debug")
+            .header(testing::admin_authorization())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let debug: serde_json::Value = response.into_json().await.unwrap();
+
+        let car = LatLon { lat: 43.37, lon: -8.84 };
+        let charger = LatLon { lat: 43.363056, lon: -8.838417 };
+        assert_eq!(debug["car_location"]["lat"], 43.37);
+        assert_eq!(debug["charger_location"]["lon"], -8.838417);
+        assert_eq!(debug["distance_km"].as_f64().unwrap(), car.distance(&charger));
+        // About 780 m, over the default 100 m
+        assert_eq!(debug["nearby"], false);
+    }
+
+    #[rocket::async_test]
+    async fn debug_requires_the_admin_credentials() {
+        let tessie = MockServer::start(200, TESSIE_STATE).await;
+        let app = app(&tessie).await;
+
+        let response = app.get("/car/debug").dispatch().await;
+        assert_eq!(response.status(), Status::Unauthorized);
+        assert!(tessie.requests().is_empty());
+    }
+
+    #[rocket::async_test]
+    async fn state_reflects_the_last_charge_decision() {
+        let figment = testing::admin_figment()
+            .merge(("ev_handler", "simulate"))
+            .merge(("charger_location", "43.363056,-8.838417"))
+            .merge(("simulation.amps", 6))
+            .merge(("max_amps", 20))
+            .merge(("max_amps_car", 16));
+        let app = testing::client_with(figment).await;
+        let state = || async {
+            let response = app
+                .get("/car/state")
+                .header(testing::admin_authorization())
+                .dispatch()
+                .await;
+            assert_eq!(response.status(), Status::Ok);
+            response.into_json::<serde_json::Value>().await.unwrap()
+        };
+
+        // Nothing was decided before the first reading
+        let before = state().await;
+        assert!(before["car"].is_null());
+        assert!(before["home"].is_null());
+
+        let response = app
+            .post(format!("/log/{}", app.token))
+            .header(ContentType::JSON)
+            .body(r#"{"amps": 10, "volts": 230, "watts": 2300}"#)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+
+        let after = state().await;
+        let car = &after["car"];
+        assert_eq!(car["is_charging"], true);
+        assert_eq!(car["current_amps"], 6.0);
+        assert_eq!(car["last_amps_requested"], 6);
+        assert_eq!(car["distance_km"], 0.0);
+        let home = &after["home"];
+        assert_eq!(home["avg_amps"], 10.0);
+        assert_eq!(home["max_amps"], 10.0);
+        assert_eq!(home["car_amps"], 6.0);
+        assert!(home["timestamp"].as_i64() >= car["last_update"].as_i64());
+        assert!(after["manual_override"].is_null());
+
+        let response = app.get("/car/state").dispatch().await;
+        assert_eq!(response.status(), Status::Unauthorized);
+    }
+
+    #[rocket::async_test]
+    async fn a_failed_override_request_is_a_bad_gateway() {
+        let tessie = MockServer::start(200, "<html>Bad gateway</html>").await;
+        let figment = testing::admin_figment()
+            .merge(("ev_handler", "tessie"))
+            .merge(("tessie_url", &tessie.url))
+            .merge(("car_vin", "VIN123"))
+            .merge(("tessie_token", "secret"))
+            .merge(("charger_location", "43.363056,-8.838417"))
+            .merge(("max_amps", 20))
+            .merge(("max_amps_car", 16));
+        let app = testing::client_with(figment).await;
+
+        let response = app
+            .post("/car/override?amps=12&duration_secs=60")
+            .header(testing::admin_authorization())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::BadGateway);
+        assert_eq!(
+            tessie.requests(),
+            vec!["POST /VIN123/command/set_charging_amps?wait_for_completion=true&amps=12 HTTP/1.1"]
+        );
+    }
+
+    #[rocket::async_test]
+    async fn an_override_over_max_amps_car_is_rejected() {
+        let figment = testing::admin_figment()
+            .merge(("ev_handler", "simulate"))
+            .merge(("charger_location", "43.363056,-8.838417"))
+            .merge(("max_amps", 20))
+            .merge(("max_amps_car", 16));
+        let app = testing::client_with(figment).await;
+        let set_override = |amps: usize| {
+            app.post(format!("/car/override?amps={}&duration_secs=60", amps))
+                .header(testing::admin_authorization())
+                .dispatch()
+        };
+
+        assert_eq!(set_override(17).await.status(), Status::BadRequest);
+        let response = set_override(16).await;
+        assert_eq!(response.status(), Status::Ok);
+        let manual_override: serde_json::Value = response.into_json().await.unwrap();
+        assert_eq!(manual_override["amps"], 16);
+    }
+}
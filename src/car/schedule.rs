@@ -0,0 +1,163 @@
+//! Time windows in which the car is allowed to charge.
+//!
+//! By default the car may charge at any time, as long as there is budget left.
+//! If you only want to charge during off-peak hours, you can configure a
+//! schedule in the figment configuration (Rocket.toml):
+//!
+//! ```toml
+//! charge_timezone = "Europe/Madrid"
+//! charge_schedule = [
+//!     { days = ["Mon", "Tue", "Wed", "Thu", "Fri"], from = 0, to = 8 },
+//!     { days = ["Sat", "Sun"], from = 0, to = 24 },
+//! ]
+//! ```
+//!
+//! Each window covers the hours `from` (inclusive) to `to` (exclusive) in the
+//! configured timezone (UTC by default), for the given weekdays. A window may
+//! wrap around midnight (e.g., `from = 22, to = 6`), in which case it matches
+//! from 22:00 until midnight and from midnight until 06:00 on the listed days.
+//!
+//! Outside every window the car is asked to charge at 0A.
+
+use chrono::{DateTime, Datelike, Timelike, Utc, Weekday};
+use rocket::figment::Figment;
+use serde::Deserialize;
+
+/// A single charging window
+#[derive(Debug, Clone, Deserialize)]
+pub struct ChargeWindow {
+    /// The weekdays this window applies to
+    days: Vec<Weekday>,
+
+    /// The first hour of the window (0-23)
+    from: u32,
+
+    /// The hour at which the window ends (1-24), not included
+    to: u32,
+}
+
+impl ChargeWindow {
+    fn contains(&self, weekday: Weekday, hour: u32) -> bool {
+        if !self.days.contains(&weekday) {
+            return false;
+        }
+        if self.from <= self.to {
+            self.from <= hour && hour < self.to
+        } else {
+            hour >= self.from || hour < self.to
+        }
+    }
+}
+
+/// The allowed charging schedule, in the configured timezone
+#[derive(Debug, Clone)]
+pub struct ChargeSchedule {
+    timezone: chrono_tz::Tz,
+    windows: Vec<ChargeWindow>,
+}
+
+impl ChargeSchedule {
+    /// Read the schedule from the figment.
+    ///
+    /// Returns `Ok(None)` if no `charge_schedule` is configured, meaning the
+    /// car may charge at any time.
+    pub fn from_figment(figment: &Figment) -> anyhow::Result<Option<Self>> {
+        let windows: Vec<ChargeWindow> = match figment.extract_inner("charge_schedule") {
+            Ok(windows) => windows,
+            Err(e) if e.missing() => return Ok(None),
+            Err(e) => return Err(anyhow::anyhow!("Invalid charge_schedule: {}", e)),
+        };
+        for window in &windows {
+            anyhow::ensure!(
+                window.from < 24 && window.to <= 24,
+                "Invalid charge_schedule window {}-{}, hours must be between 0 and 24",
+                window.from,
+                window.to
+            );
+        }
+
+        let timezone = match figment.extract_inner::<String>("charge_timezone") {
+            Ok(timezone) => timezone
+                .parse()
+                .map_err(|e| anyhow::anyhow!("Invalid charge_timezone: {}", e))?,
+            Err(e) if e.missing() => chrono_tz::UTC,
+            Err(e) => return Err(anyhow::anyhow!("Invalid charge_timezone: {}", e)),
+        };
+
+        Ok(Some(Self { timezone, windows }))
+    }
+
+    /// Returns true if charging is allowed at the given instant
+    pub fn allows(&self, at: &DateTime<Utc>) -> bool {
+        let local = at.with_timezone(&self.timezone);
+        self.windows
+            .iter()
+            .any(|window| window.contains(local.weekday(), local.hour()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schedule(timezone: &str, windows: serde_json::Value) -> ChargeSchedule {
+        let figment = Figment::new()
+            .merge(("charge_timezone", timezone))
+            .merge(rocket::figment::providers::Serialized::default("charge_schedule", windows));
+        ChargeSchedule::from_figment(&figment).unwrap().unwrap()
+    }
+
+    fn at(datetime: &str) -> DateTime<Utc> {
+        datetime.parse().unwrap()
+    }
+
+    #[test]
+    fn windows_apply_in_the_configured_timezone() {
+        let schedule = schedule(
+            "Europe/Madrid",
+            serde_json::json!([{ "days": ["Mon", "Tue", "Wed", "Thu", "Fri"], "from": 0, "to": 8 }]),
+        );
+
+        // Thursday 22:30 UTC is already Friday 00:30 in Madrid (UTC+2)
+        assert!(schedule.allows(&at("2026-10-15T22:30:00Z")));
+        // Friday 06:30 UTC is 08:30 in Madrid, after the window
+        assert!(!schedule.allows(&at("2026-10-16T06:30:00Z")));
+        // Friday 23:30 UTC is Saturday 01:30 in Madrid, not a listed day
+        assert!(!schedule.allows(&at("2026-10-16T23:30:00Z")));
+        // Monday 01:00 UTC is 03:00 in Madrid
+        assert!(schedule.allows(&at("2026-10-19T01:00:00Z")));
+    }
+
+    #[test]
+    fn windows_wrap_around_midnight() {
+        let schedule = schedule("UTC", serde_json::json!([{ "days": ["Fri"], "from": 22, "to": 6 }]));
+
+        assert!(schedule.allows(&at("2026-10-16T23:00:00Z")));
+        assert!(schedule.allows(&at("2026-10-16T05:59:59Z")));
+        assert!(!schedule.allows(&at("2026-10-16T06:00:00Z")));
+        assert!(!schedule.allows(&at("2026-10-16T21:59:59Z")));
+        // The early hours of Saturday belong to Saturday
+        assert!(!schedule.allows(&at("2026-10-17T01:00:00Z")));
+    }
+
+    #[test]
+    fn rejects_hours_out_of_range() {
+        let figment = Figment::new().merge(rocket::figment::providers::Serialized::default(
+            "charge_schedule",
+            serde_json::json!([{ "days": ["Mon"], "from": 0, "to": 25 }]),
+        ));
+        assert!(ChargeSchedule::from_figment(&figment).is_err());
+        assert!(ChargeSchedule::from_figment(&Figment::new()).unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_an_unreadable_timezone() {
+        let figment = Figment::new()
+            .merge(rocket::figment::providers::Serialized::default(
+                "charge_schedule",
+                serde_json::json!([{ "days": ["Mon"], "from": 0, "to": 6 }]),
+            ))
+            .merge(("charge_timezone", ["Europe/Madrid"]));
+        assert!(ChargeSchedule::from_figment(&figment).is_err());
+    }
+}
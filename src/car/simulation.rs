@@ -0,0 +1,239 @@
+//! Simulated implementation of the [EVChargeHandler] trait, for dry runs.
+//!
+//! Selecting `ev_handler = "simulate"` runs the EV charge control against the
+//! real home consumption, but instead of calling any EV API it only logs the
+//! amps it would request. It is useful to validate the budget configuration
+//! before trusting it with a real car.
+//!
+//! The simulated car follows every request immediately, drawing exactly the
+//! requested amps. Its position and whether it is charging can be scripted in
+//! the figment configuration (Rocket.toml):
+//!
+//! ```toml
+//! [default.simulation]
+//! # "lat,lon" of the car, the charger_location by default
+//! location = "43.363056,-8.838417"
+//! # Whether the car is plugged in and charging, true by default
+//! charging = true
+//! # Whether the car is plugged in at all, true by default
+//! plugged_in = true
+//! # The amps the car starts drawing, 0 by default
+//! amps = 6
+//! # The battery level (%) reported, none by default
+//! battery_level = 40
+//! ```
+
+use std::sync::Arc;
+
+use rocket::figment::Figment;
+use rocket::tokio::sync::Mutex;
+use serde::Deserialize;
+
+use crate::request_id::RequestId;
+
+use super::{EVChargeHandler, EVChargeInternalState, LatLon};
+
+/// How many of the past requests are shown in the logs
+const LOGGED_REQUESTS: usize = 10;
+
+/// The `simulation` table of the figment configuration
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct SimulationSettings {
+    location: Option<String>,
+    charging: Option<bool>,
+    plugged_in: Option<bool>,
+    amps: usize,
+    battery_level: Option<usize>,
+}
+
+/// The scripted initial state of the simulated car
+pub struct SimulationConfig {
+    initial_state: SimulationState,
+}
+
+impl TryFrom<&Figment> for SimulationConfig {
+    type Error = rocket::figment::Error;
+
+    fn try_from(figment: &Figment) -> Result<Self, Self::Error> {
+        let settings: SimulationSettings = match figment.extract_inner("simulation") {
+            Ok(settings) => settings,
+            Err(e) if e.missing() => SimulationSettings::default(),
+            Err(e) => return Err(e),
+        };
+        let location = match settings.location {
+            Some(location) => location,
+            None => figment.extract_inner("charger_location")?,
+        };
+        let location = LatLon::try_from(location)
+            .map_err(|e| format!("Invalid simulation location: {}", e))?;
+
+        Ok(Self {
+            initial_state: SimulationState {
+                location,
+                charging: settings.charging.unwrap_or(true),
+                plugged_in: settings.plugged_in.unwrap_or(true),
+                amps: settings.amps,
+                charge_limit_soc: None,
+                battery_level: settings.battery_level,
+            },
+        })
+    }
+}
+
+/// The state of the simulated car
+#[derive(Debug, Clone)]
+pub struct SimulationState {
+    location: LatLon,
+    charging: bool,
+    plugged_in: bool,
+    amps: usize,
+    charge_limit_soc: Option<usize>,
+    battery_level: Option<usize>,
+}
+
+impl EVChargeInternalState for SimulationState {
+    fn is_charging(&self) -> bool {
+        self.charging
+    }
+
+    fn is_charge_starting(&self) -> bool {
+        false
+    }
+
+    fn get_current_charge(&self) -> f64 {
+        if self.charging {
+            self.amps as f64
+        } else {
+            0.0
+        }
+    }
+
+    fn get_last_requested_amps(&self) -> usize {
+        self.amps
+    }
+
+    fn get_car_location(&self) -> LatLon {
+        self.location.clone()
+    }
+
+    fn get_charge_limit_soc(&self) -> Option<usize> {
+        self.charge_limit_soc
+    }
+
+    fn get_battery_level(&self) -> Option<usize> {
+        self.battery_level
+    }
+
+    fn is_plugged_in(&self) -> bool {
+        self.plugged_in
+    }
+}
+
+/// The simulated handler, which records the requested amps instead of sending
+/// them to a car.
+pub struct Handler {
+    state: Arc<Mutex<SimulationState>>,
+    requests: Arc<Mutex<Vec<usize>>>,
+}
+
+impl EVChargeHandler for Handler {
+    type ConfigParams = SimulationConfig;
+    type InternalState = SimulationState;
+
+    fn get_name() -> &'static str {
+        "Simulation"
+    }
+
+    fn new(config: Self::ConfigParams) -> Self {
+        log::warn!("EV: Using the simulated car, no charge requests will be sent");
+        Self {
+            state: Arc::new(Mutex::new(config.initial_state)),
+            requests: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    async fn get_state(&self) -> anyhow::Result<Self::InternalState> {
+        Ok(self.state.lock().await.clone())
+    }
+
+    async fn request_charge_amps(&self, amps: usize) -> anyhow::Result<()> {
+        self.state.lock().await.amps = amps;
+        let mut requests = self.requests.lock().await;
+        requests.push(amps);
+        let recent = &requests[requests.len().saturating_sub(LOGGED_REQUESTS)..];
+        log::info!(
+            "EV: Simulation would request {}A (request #{}, recent: {:?}){}",
+            amps,
+            requests.len(),
+            recent,
+            RequestId::in_logs()
+        );
+        Ok(())
+    }
+
+    async fn request_charge_limit(&self, soc: usize) -> anyhow::Result<()> {
+        self.state.lock().await.charge_limit_soc = Some(soc);
+        log::info!("EV: Simulation would set the charge limit to {}%{}", soc, RequestId::in_logs());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl Handler {
+    /// The amps requested so far, oldest first
+    pub async fn requests(&self) -> Vec<usize> {
+        self.requests.lock().await.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handler(figment: Figment) -> Handler {
+        Handler::new(SimulationConfig::try_from(&figment).unwrap())
+    }
+
+    #[rocket::async_test]
+    async fn the_state_is_scripted_in_the_figment() {
+        let handler = handler(
+            Figment::new()
+                .merge(("charger_location", "43.363056,-8.838417"))
+                .merge(("simulation.location", "43.37,-8.84"))
+                .merge(("simulation.charging", false))
+                .merge(("simulation.amps", 6))
+                .merge(("simulation.battery_level", 40)),
+        );
+
+        let state = handler.get_state().await.unwrap();
+        assert_eq!((state.get_car_location().lat, state.get_car_location().lon), (43.37, -8.84));
+        assert!(!state.is_charging());
+        assert!(state.is_plugged_in());
+        assert_eq!(state.get_current_charge(), 0.0);
+        assert_eq!(state.get_last_requested_amps(), 6);
+        assert_eq!(state.get_battery_level(), Some(40));
+    }
+
+    #[rocket::async_test]
+    async fn the_car_follows_the_recorded_requests() {
+        let handler = handler(Figment::new().merge(("charger_location", "43.363056,-8.838417")));
+        let state = handler.get_state().await.unwrap();
+        assert_eq!(state.get_car_location().lat, 43.363056);
+        assert_eq!(state.get_current_charge(), 0.0);
+
+        handler.request_charge_amps(10).await.unwrap();
+        handler.request_charge_amps(8).await.unwrap();
+        handler.request_charge_limit(80).await.unwrap();
+
+        let state = handler.get_state().await.unwrap();
+        assert_eq!(state.get_current_charge(), 8.0);
+        assert_eq!(state.get_charge_limit_soc(), Some(80));
+        assert_eq!(handler.requests().await, vec![10, 8]);
+    }
+
+    #[test]
+    fn the_location_is_required() {
+        assert!(SimulationConfig::try_from(&Figment::new()).is_err());
+    }
+}
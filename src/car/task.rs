@@ -16,16 +16,21 @@
 //! If you want to implement an additional platform, head over to the
 //! [EVChargeHandler] trait documentation to get started.
 
-use std::{
-    cmp::{max, min},
-    sync::Arc,
-};
+use std::{cmp::min, sync::Arc};
 
 use rocket::{figment::Figment, tokio::sync::Mutex};
+use serde::{Deserialize, Serialize};
 
 use crate::car::EVChargeInternalState;
+use crate::request_id::RequestId;
 
-use super::{EVChargeHandler, LatLon};
+use super::{
+    departure::DepartureTarget,
+    geocode::{resolve_charger_location, Geocoder},
+    schedule::ChargeSchedule,
+    units::{kw_to_amps, Distance, DistanceUnit},
+    EVChargeHandler, LatLon,
+};
 
 /// A simple struct to store the car state and the last update time
 ///
@@ -65,22 +70,187 @@ impl TryFrom<String> for LatLon {
 
 /// A store for the home state
 ///
-/// This is used to calculate the power budget for the car to charge.
-#[derive(Debug, Clone)]
+/// This is used to calculate the power budget for the car to charge. Some
+/// fields are only kept for the debug logs and the
+/// [car state route](super::routes::car_state).
+#[derive(Debug, Clone, Serialize)]
 pub struct HomeState {
-    /// Average amps drawn by the home (including the car) over the last 30 seconds
+    /// Average amps drawn by the home (including the car) over the consumption
+    /// window (30 seconds by default)
+    ///
+    /// This is negative while the home is exporting to the grid.
     pub avg_amps: f64,
 
-    /// Maximum amps drawn by the home (including the car) over the last 30 seconds
+    /// Maximum amps drawn by the home (including the car) over the consumption
+    /// window
     pub max_amps: f64,
 
-    /// Amps drawn by the car over the last 30 seconds
+    /// Amps drawn by the car, as of its cached state
     pub car_amps: f64,
 
     /// Timestamp of the measurement
     pub timestamp: i64,
 }
 
+/// The voltage the kW budgets are converted to amps with, unless
+/// `nominal_volts` is configured. It matches the voltage assumed for the
+/// readings without one.
+const DEFAULT_NOMINAL_VOLTS: f64 = 220.0;
+
+/// Reads a budget configured either in amps under `amps_key`, or in kW under
+/// `kw_key` converted at `volts`, failing if both or none are configured.
+fn amps_or_kw(figment: &Figment, amps_key: &str, kw_key: &str, volts: f64) -> anyhow::Result<f64> {
+    let optional = |key: &str| match figment.extract_inner::<f64>(key) {
+        Ok(value) => Ok(Some(value)),
+        Err(e) if e.missing() => Ok(None),
+        Err(e) => Err(anyhow::anyhow!("Invalid {}: {}", key, e)),
+    };
+    let (amps, kw) = (optional(amps_key)?, optional(kw_key)?);
+    match (amps, kw) {
+        (Some(amps), None) => Ok(amps),
+        (None, Some(kw)) => Ok(kw_to_amps(kw, volts)),
+        (Some(_), Some(_)) => Err(anyhow::anyhow!(
+            "Configure either {} or {}, not both",
+            amps_key,
+            kw_key
+        )),
+        (None, None) => Err(anyhow::anyhow!("Missing {} (or {})", amps_key, kw_key)),
+    }
+}
+
+/// How the budget for the car is rounded to whole amps, with the
+/// `amps_rounding` setting
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AmpsRounding {
+    /// Round down, never going over the budget (the default)
+    #[default]
+    Floor,
+    /// Round to the nearest amp, using up to half an amp over the budget
+    Nearest,
+    /// Round up, using up to an amp over the budget. The result is still
+    /// capped by `max_amps_car` and `absolute_max_home_amps`
+    Ceil,
+}
+
+impl AmpsRounding {
+    /// Rounds the budget to whole amps, saturating negative budgets to 0
+    pub fn round(self, amps: f64) -> usize {
+        let rounded = match self {
+            AmpsRounding::Floor => amps.floor(),
+            AmpsRounding::Nearest => amps.round(),
+            AmpsRounding::Ceil => amps.ceil(),
+        };
+        rounded as usize
+    }
+}
+
+/// The share of the remaining budget requested for the car, unless
+/// `budget_safety_factor` is configured
+const DEFAULT_BUDGET_SAFETY_FACTOR: f64 = 0.95;
+
+/// The window the home consumption is averaged over, in seconds, unless
+/// `consumption_window_secs` is configured
+const DEFAULT_CONSUMPTION_WINDOW_SECS: u32 = 30;
+
+/// The longest window the home consumption may be averaged over, in seconds
+pub(crate) const MAX_CONSUMPTION_WINDOW_SECS: u32 = 3600;
+
+/// The car is considered nearby the charger below this distance in kilometers,
+/// unless `nearby_distance` is configured
+const DEFAULT_NEARBY_DISTANCE_KM: f64 = 0.1;
+
+/// The inputs and outcome of the [CarHandler::is_car_nearby] decision, for
+/// debugging purposes.
+#[derive(Debug, Clone, Serialize)]
+pub struct CarDebugInfo {
+    /// Last known position of the car
+    pub car_location: LatLon,
+
+    /// Configured position of the charger
+    pub charger_location: LatLon,
+
+    /// Distance between the car and the charger in kilometers
+    pub distance_km: f64,
+
+    /// Distance between the car and the charger, in the display unit
+    pub distance: String,
+
+    /// The distance below which the car is considered nearby, in the display
+    /// unit
+    pub nearby_distance: String,
+
+    /// Whether the car is considered nearby the charger
+    pub nearby: bool,
+}
+
+/// The cached car state the last charge decision was based on, as returned by
+/// [CarHandler::cached_car_state].
+#[derive(Debug, Clone, Serialize)]
+pub struct CachedCarState {
+    /// Whether the car was charging
+    pub is_charging: bool,
+
+    /// Amps drawn by the car
+    pub current_amps: f64,
+
+    /// The amps last requested to the car
+    pub last_amps_requested: usize,
+
+    /// When the amps were last requested, as a UNIX timestamp
+    pub last_amps_requested_time: i64,
+
+    /// Distance between the car and the charger in kilometers
+    pub distance_km: f64,
+
+    /// The battery level in percent, if the car reports it
+    pub battery_level: Option<usize>,
+
+    /// When the state was retrieved from the car API, as a UNIX timestamp
+    pub last_update: i64,
+}
+
+/// The live state of the charge control, without calling the car API.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CarStateSummary {
+    /// The cached car state, if it was already retrieved
+    pub car: Option<CachedCarState>,
+
+    /// The last home state used to calculate the budget, if any
+    pub home: Option<HomeState>,
+
+    /// The manual override of the charge amps, if one is active
+    pub manual_override: Option<ChargeOverride>,
+
+    /// The current charging session, if `max_charge_session_secs` is
+    /// configured
+    pub charge_session: Option<ChargeSession>,
+}
+
+/// A manual charge target, requested regardless of the budget until it
+/// expires. See [CarHandler::set_override].
+#[derive(Debug, Clone, Serialize)]
+pub struct ChargeOverride {
+    /// The amps requested to the car
+    pub amps: usize,
+
+    /// When the budget control resumes, as a UNIX timestamp
+    pub until: i64,
+}
+
+/// The charging session tracked for `max_charge_session_secs`, see
+/// [CarHandler::track_charge_session].
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ChargeSession {
+    /// When the car was first seen charging in this session, as a UNIX
+    /// timestamp
+    pub started: Option<i64>,
+
+    /// If set, the session went over the limit and no charge is requested
+    /// until then (the next midnight in `charge_timezone`), as a UNIX timestamp
+    pub capped_until: Option<i64>,
+}
+
 /// A simple cache to store the last home states to log them.
 pub struct HomeStateWrapper {
     state: Vec<HomeState>,
@@ -92,6 +262,47 @@ struct CarHandlerConfig {
     charger_location: LatLon,
     max_amps: f64,
     max_amps_car: usize,
+
+    /// The share of the remaining budget requested for the car, to leave a
+    /// margin for sudden changes of the home consumption (0.95 by default)
+    budget_safety_factor: f64,
+
+    /// If set, the hard ceiling for the peak home consumption including the
+    /// car, enforced regardless of the budget
+    absolute_max_home_amps: Option<f64>,
+
+    /// How the budget is rounded to whole amps (down by default)
+    amps_rounding: AmpsRounding,
+
+    /// If set, the battery level (%) at which the car should stop charging
+    charge_limit_soc: Option<usize>,
+
+    /// If set, the car is only allowed to charge within these time windows
+    schedule: Option<ChargeSchedule>,
+
+    /// If set, the charge is slowed down to reach the target by the departure
+    departure: Option<DepartureTarget>,
+
+    /// If set, a charging session longer than this many seconds is stopped
+    /// until the next day
+    max_charge_session_secs: Option<u32>,
+
+    /// The timezone the next day starts in for `max_charge_session_secs`
+    charge_timezone: chrono_tz::Tz,
+
+    /// The voltage the amps are converted to power with
+    nominal_volts: f64,
+
+    /// The window the home consumption is averaged over, in seconds. The car
+    /// state is cached for as long, so the car amps subtracted from the home
+    /// average are as recent as it (30 by default)
+    consumption_window_secs: u32,
+
+    /// The car is considered nearby the charger below this distance
+    nearby_distance: Distance,
+
+    /// The unit to display distances in
+    distance_unit: DistanceUnit,
 }
 
 /// The main struct to handle information about the car.
@@ -109,37 +320,155 @@ pub struct CarHandler<H: EVChargeHandler> {
     config: CarHandlerConfig,
     last_state: Arc<Mutex<Option<CarStateWrapper<H::InternalState>>>>,
     home_state: Arc<Mutex<HomeStateWrapper>>,
+    manual_override: Arc<Mutex<Option<ChargeOverride>>>,
+    charge_session: Arc<Mutex<ChargeSession>>,
 }
 
-impl<H: EVChargeHandler> From<&Figment> for CarHandler<H> {
-    fn from(figment: &Figment) -> Self {
-        let params: H::ConfigParams = figment.into();
+impl<H: EVChargeHandler> CarHandler<H> {
+    /// Build the handler from the figment, failing if any of the car
+    /// configuration is missing or invalid.
+    ///
+    /// The geocoder is only used if the charger location is configured with
+    /// its `charger_address`, see the [geocode](super::geocode) module.
+    pub async fn from_figment(figment: &Figment, geocoder: &impl Geocoder) -> anyhow::Result<Self> {
+        let params = H::ConfigParams::try_from(figment)?;
         let api = H::new(params);
+        let charger_location = resolve_charger_location(figment, geocoder).await?;
         let config = {
-            let charger_location_str: String = figment
-                .extract_inner("charger_location")
-                .unwrap_or_else(|_| panic!("Missing charger location"));
-            let charger_location = LatLon::try_from(charger_location_str)
-                .unwrap_or_else(|_| panic!("Invalid charger location"));
-            let max_amps = figment
-                .extract_inner("max_amps")
-                .unwrap_or_else(|_| panic!("Missing max amps"));
-            let max_amps_car = figment
-                .extract_inner("max_amps_car")
-                .unwrap_or_else(|_| panic!("Missing max amps car"));
+            let nominal_volts: f64 = match figment.extract_inner("nominal_volts") {
+                Ok(volts) => volts,
+                Err(e) if e.missing() => DEFAULT_NOMINAL_VOLTS,
+                Err(e) => return Err(anyhow::anyhow!("Invalid nominal_volts: {}", e)),
+            };
+            anyhow::ensure!(
+                nominal_volts > 0.0,
+                "Invalid nominal_volts {}, it must be positive",
+                nominal_volts
+            );
+            let max_amps = amps_or_kw(figment, "max_amps", "max_kw", nominal_volts)?;
+            // The car can only be requested whole amps
+            let max_amps_car = amps_or_kw(figment, "max_amps_car", "max_kw_car", nominal_volts)?;
+            anyhow::ensure!(
+                max_amps_car >= 0.0,
+                "Invalid max_amps_car {}, it must not be negative",
+                max_amps_car
+            );
+            let max_amps_car = max_amps_car.floor() as usize;
+            log::info!(
+                "EV: Budget of {:.1} A for the home and {} A for the car",
+                max_amps,
+                max_amps_car
+            );
+            let budget_safety_factor: f64 = match figment.extract_inner("budget_safety_factor") {
+                Ok(factor) => factor,
+                Err(e) if e.missing() => DEFAULT_BUDGET_SAFETY_FACTOR,
+                Err(e) => return Err(anyhow::anyhow!("Invalid budget_safety_factor: {}", e)),
+            };
+            anyhow::ensure!(
+                budget_safety_factor > 0.0 && budget_safety_factor <= 1.0,
+                "Invalid budget_safety_factor {}, it must be in (0, 1]",
+                budget_safety_factor
+            );
+            let absolute_max_home_amps: Option<f64> =
+                match figment.extract_inner("absolute_max_home_amps") {
+                    Ok(amps) => Some(amps),
+                    Err(e) if e.missing() => None,
+                    Err(e) => return Err(anyhow::anyhow!("Invalid absolute_max_home_amps: {}", e)),
+                };
+            if let Some(amps) = absolute_max_home_amps {
+                anyhow::ensure!(
+                    amps > 0.0,
+                    "Invalid absolute_max_home_amps {}, it must be positive",
+                    amps
+                );
+            }
+            let amps_rounding = match figment.extract_inner("amps_rounding") {
+                Ok(rounding) => rounding,
+                Err(e) if e.missing() => AmpsRounding::default(),
+                Err(e) => return Err(anyhow::anyhow!("Invalid amps_rounding: {}", e)),
+            };
+            let charge_limit_soc: Option<usize> = match figment.extract_inner("charge_limit_soc") {
+                Ok(soc) => Some(soc),
+                Err(e) if e.missing() => None,
+                Err(e) => return Err(anyhow::anyhow!("Invalid charge_limit_soc: {}", e)),
+            };
+            if let Some(soc) = charge_limit_soc {
+                anyhow::ensure!(
+                    (1..=100).contains(&soc),
+                    "Invalid charge_limit_soc {}, it must be a percentage",
+                    soc
+                );
+            }
+            let schedule = ChargeSchedule::from_figment(figment)?;
+            let departure = DepartureTarget::from_figment(figment)?;
+            let max_charge_session_secs: Option<u32> =
+                match figment.extract_inner("max_charge_session_secs") {
+                    Ok(secs) => Some(secs),
+                    Err(e) if e.missing() => None,
+                    Err(e) => return Err(anyhow::anyhow!("Invalid max_charge_session_secs: {}", e)),
+                };
+            if let Some(secs) = max_charge_session_secs {
+                anyhow::ensure!(
+                    secs > 0,
+                    "Invalid max_charge_session_secs {}, it must be positive",
+                    secs
+                );
+            }
+            let charge_timezone = match figment.extract_inner::<String>("charge_timezone") {
+                Ok(timezone) => timezone
+                    .parse()
+                    .map_err(|e| anyhow::anyhow!("Invalid charge_timezone: {}", e))?,
+                Err(e) if e.missing() => chrono_tz::UTC,
+                Err(e) => return Err(anyhow::anyhow!("Invalid charge_timezone: {}", e)),
+            };
+            let consumption_window_secs: u32 = match figment.extract_inner("consumption_window_secs") {
+                Ok(window) => window,
+                Err(e) if e.missing() => DEFAULT_CONSUMPTION_WINDOW_SECS,
+                Err(e) => return Err(anyhow::anyhow!("Invalid consumption_window_secs: {}", e)),
+            };
+            anyhow::ensure!(
+                (1..=MAX_CONSUMPTION_WINDOW_SECS).contains(&consumption_window_secs),
+                "Invalid consumption_window_secs {}, it must be between 1 and {}",
+                consumption_window_secs,
+                MAX_CONSUMPTION_WINDOW_SECS
+            );
+            let nearby_distance = match figment.extract_inner("nearby_distance") {
+                Ok(distance) => distance,
+                Err(e) if e.missing() => Distance::from_km(DEFAULT_NEARBY_DISTANCE_KM),
+                Err(e) => return Err(anyhow::anyhow!("Invalid nearby_distance: {}", e)),
+            };
+            let distance_unit = match figment.extract_inner("distance_unit") {
+                Ok(unit) => unit,
+                Err(e) if e.missing() => DistanceUnit::default(),
+                Err(e) => return Err(anyhow::anyhow!("Invalid distance_unit: {}", e)),
+            };
             CarHandlerConfig {
                 charger_location,
                 max_amps,
                 max_amps_car,
+                budget_safety_factor,
+                absolute_max_home_amps,
+                amps_rounding,
+                charge_limit_soc,
+                schedule,
+                departure,
+                max_charge_session_secs,
+                charge_timezone,
+                nominal_volts,
+                consumption_window_secs,
+                nearby_distance,
+                distance_unit,
             }
         };
 
-        Self {
+        Ok(Self {
             inner: api,
             config,
             last_state: Arc::new(Mutex::new(None)),
             home_state: Arc::new(Mutex::new(HomeStateWrapper { state: Vec::new() })),
-        }
+            manual_override: Arc::new(Mutex::new(None)),
+            charge_session: Arc::new(Mutex::new(ChargeSession::default())),
+        })
     }
 }
 
@@ -165,7 +494,7 @@ impl<H: EVChargeHandler> CarHandler<H> {
             .map(|x| (x.last_amps_requested, x.last_amps_requested_time))
             .unwrap_or((0, 0));
         let state = self.inner.get_state().await?;
-        log::info!("EV: Updated state cache {:?}", state);
+        log::info!("EV: Updated state cache {:?}{}", state, RequestId::in_logs());
         let mut guard = self.last_state.lock().await;
 
         // Check if somebody outside of this function has requested a different charge
@@ -174,8 +503,9 @@ impl<H: EVChargeHandler> CarHandler<H> {
             last_amps_requested = last_requested_amps_according_to_api;
             last_amps_requested_time = chrono::Utc::now().timestamp() - 30; // Allow immediate update if required
             log::info!(
-                "EV: External Amps change: last requested {}A",
-                last_amps_requested
+                "EV: External Amps change: last requested {}A{}",
+                last_amps_requested,
+                RequestId::in_logs()
             );
         }
 
@@ -199,9 +529,10 @@ impl<H: EVChargeHandler> CarHandler<H> {
     /// Wrapper to get the state from the car API, using the cache if possible
     pub async fn get_state(&self) -> anyhow::Result<H::InternalState> {
         // Check if the state is already cached
-        // if so, return the cached state unless force=true or the state is older than 30 secs
+        // if so, return the cached state unless it is older than the consumption window
+        let ttl = i64::from(self.config.consumption_window_secs);
         if let Some(state) = self.last_state.lock().await.as_ref() {
-            if state.last_update > (chrono::Utc::now().timestamp() - 30) {
+            if state.last_update > (chrono::Utc::now().timestamp() - ttl) {
                 return Ok(state.state.clone());
             }
         }
@@ -209,6 +540,16 @@ impl<H: EVChargeHandler> CarHandler<H> {
         self.force_update_state_cache().await
     }
 
+    /// The most amps that may be requested to the car
+    pub fn max_amps_car(&self) -> usize {
+        self.config.max_amps_car
+    }
+
+    /// The window the home consumption is averaged over, in seconds
+    pub fn consumption_window_secs(&self) -> u32 {
+        self.config.consumption_window_secs
+    }
+
     /// Get the distance from the car to the charger, as configured from the
     /// figment.
     ///
@@ -220,10 +561,51 @@ impl<H: EVChargeHandler> CarHandler<H> {
     }
 
     /// Uses [CarHandler::get_car_distance_to_charger] to check if the car
-    /// is nearby, returning true if the distance is less than 0.1km.
+    /// is nearby, returning true if the distance is less than the configured
+    /// `nearby_distance` (0.1km by default).
     pub async fn is_car_nearby(&self) -> anyhow::Result<bool> {
         let distance = self.get_car_distance_to_charger().await?;
-        Ok(distance < 0.1)
+        Ok(distance < self.config.nearby_distance.km())
+    }
+
+    /// Returns the values [CarHandler::is_car_nearby] bases its decision on
+    pub async fn debug_info(&self) -> anyhow::Result<CarDebugInfo> {
+        let state = self.get_state().await?;
+        let distance_km = state.get_car_distance_to_point_km(&self.config.charger_location);
+        let unit = self.config.distance_unit;
+        Ok(CarDebugInfo {
+            car_location: state.get_car_location(),
+            charger_location: self.config.charger_location.clone(),
+            distance_km,
+            distance: Distance::from_km(distance_km).display(unit),
+            nearby_distance: self.config.nearby_distance.display(unit),
+            nearby: distance_km < self.config.nearby_distance.km(),
+        })
+    }
+
+    /// Returns the cached car state, or `None` if it was not retrieved yet.
+    ///
+    /// Unlike [CarHandler::get_state], this never calls the car API.
+    pub async fn cached_car_state(&self) -> Option<CachedCarState> {
+        let guard = self.last_state.lock().await;
+        let cached = guard.as_ref()?;
+        Some(CachedCarState {
+            is_charging: cached.state.is_charging(),
+            current_amps: cached.state.get_current_charge(),
+            last_amps_requested: cached.last_amps_requested,
+            last_amps_requested_time: cached.last_amps_requested_time,
+            distance_km: cached
+                .state
+                .get_car_distance_to_point_km(&self.config.charger_location),
+            battery_level: cached.state.get_battery_level(),
+            last_update: cached.last_update,
+        })
+    }
+
+    /// Returns the last home state stored with
+    /// [CarHandler::set_current_home_consumption], if any
+    pub async fn last_home_state(&self) -> Option<HomeState> {
+        self.home_state.lock().await.state.last().cloned()
     }
 
     pub async fn is_car_charging(&self) -> anyhow::Result<bool> {
@@ -249,6 +631,97 @@ impl<H: EVChargeHandler> CarHandler<H> {
         self.inner.request_charge_amps(amps).await
     }
 
+    /// Set the charging amps to the car, and remember them as the last
+    /// requested amps in the cached state, if any.
+    ///
+    /// Nothing is sent if the cached state shows the car unplugged, as the
+    /// request would only wake the car up for nothing.
+    async fn request_amps(&self, amps: usize, now: i64) -> anyhow::Result<()> {
+        let mut guard = self.last_state.lock().await;
+        if guard.as_ref().is_some_and(|x| !x.state.is_plugged_in()) {
+            log::debug!(
+                "Not requesting car charge to {}A, the car is not plugged in{}",
+                amps,
+                RequestId::in_logs()
+            );
+            return Ok(());
+        }
+        log::info!("Requesting car charge to {}A{}", amps, RequestId::in_logs());
+        self.set_amps(amps).await?;
+        if let Some(x) = guard.as_mut() {
+            x.last_amps_requested = amps;
+            x.last_amps_requested_time = now;
+        }
+        Ok(())
+    }
+
+    /// Returns the manual override of the charge amps, if one is active
+    pub async fn active_override(&self) -> Option<ChargeOverride> {
+        let now = chrono::Utc::now().timestamp();
+        self.manual_override
+            .lock()
+            .await
+            .clone()
+            .filter(|o| o.until > now)
+    }
+
+    /// Request the car to charge at `amps` for the next `duration_secs`
+    /// seconds, regardless of the budget, the ceiling and the schedule.
+    ///
+    /// The amps are requested right away, and again on every check while the
+    /// override is active. Once it expires, [CarHandler::throttled_calculate_amps]
+    /// resumes the budget control.
+    pub async fn set_override(&self, amps: usize, duration_secs: u32) -> anyhow::Result<ChargeOverride> {
+        let now = chrono::Utc::now().timestamp();
+        let manual_override = ChargeOverride {
+            amps,
+            until: now + i64::from(duration_secs),
+        };
+        log::info!(
+            "EV: Manual override to {}A for {} seconds{}",
+            amps,
+            duration_secs,
+            RequestId::in_logs()
+        );
+        self.manual_override
+            .lock()
+            .await
+            .replace(manual_override.clone());
+        self.request_amps(amps, now).await?;
+        Ok(manual_override)
+    }
+
+    /// Cancel the manual override, if any, so the next check resumes the budget
+    /// control. Returns whether an override was active.
+    pub async fn clear_override(&self) -> bool {
+        let active = self.active_override().await.is_some();
+        self.manual_override.lock().await.take();
+        if active {
+            log::info!(
+                "EV: Manual override cancelled, resuming budget control{}",
+                RequestId::in_logs()
+            );
+        }
+        active
+    }
+
+    /// Request the configured charge limit to the car, if any, unless the car
+    /// reports it is already set.
+    pub async fn ensure_charge_limit(&self) -> anyhow::Result<()> {
+        let Some(soc) = self.config.charge_limit_soc else {
+            return Ok(());
+        };
+        let state = self.get_state().await?;
+        if state.get_charge_limit_soc() == Some(soc) {
+            return Ok(());
+        }
+
+        log::info!("Requesting car charge limit to {}%{}", soc, RequestId::in_logs());
+        self.inner.request_charge_limit(soc).await?;
+        self.invalidate_state_cache().await;
+        Ok(())
+    }
+
     /// Set the current home consumption to the cache
     ///
     /// This function is used to be able to calculate the power budget remaining
@@ -278,14 +751,75 @@ impl<H: EVChargeHandler> CarHandler<H> {
     /// necessary
     ///
     /// This function will calculate the average amps drawn by the home over the
-    /// last 30 seconds, and request the car to charge accordingly. It will
+    /// consumption window, and request the car to charge accordingly. It will
     /// request the car to charge to the maximum of the configured max_amps_car
     /// and the remaining budget after the home consumption.
     ///
+    /// If the home without the car is exporting (negative consumption), the
+    /// exported amps are added to the budget so the car can absorb them.
+    ///
+    /// If `absolute_max_home_amps` is configured, the request is also capped so
+    /// that the peak home consumption without the car plus the car amps stays
+    /// under it, even if the average leaves more budget (e.g., a spiky load).
+    ///
+    /// If a departure time is configured, the request is also lowered to the
+    /// amps that reach the target battery level in time, see the
+    /// [departure](super::departure) module.
+    ///
+    /// If a charging schedule is configured and we are outside all of its
+    /// windows, the car is requested to charge at 0A regardless of the budget.
+    ///
+    /// The budget is rounded to whole amps as configured with `amps_rounding`
+    /// (down by default), while the `absolute_max_home_amps` cap is always
+    /// rounded down.
+    ///
     /// The function will only request the car to change the amps if the last
     /// request was higher (because this means we are immediately over-budget),
     /// or at least 30 seconds have passed since the last request.
+    ///
+    /// While a manual override is active (see [CarHandler::set_override]), its
+    /// amps are requested instead, and the budget is not calculated at all.
+    ///
+    /// If `max_charge_session_secs` is configured, 0 A are requested once the
+    /// car has been charging for longer, until the next day.
     pub async fn throttled_calculate_amps(&self) -> anyhow::Result<()> {
+        self.calculate_amps(true).await
+    }
+
+    /// Like [CarHandler::throttled_calculate_amps], when no readings were
+    /// logged over the consumption window, e.g., the sensor stopped reporting.
+    ///
+    /// The budget is calculated from the last home consumption recorded, but
+    /// the amps are only held or reduced, never raised, as the home may be
+    /// drawing more by now.
+    pub async fn calculate_amps_without_readings(&self) -> anyhow::Result<()> {
+        self.calculate_amps(false).await
+    }
+
+    /// See [CarHandler::throttled_calculate_amps]. Unless there are `readings`
+    /// over the consumption window, no more than the last requested amps are
+    /// requested.
+    async fn calculate_amps(&self, readings: bool) -> anyhow::Result<()> {
+        let now = chrono::Utc::now().timestamp();
+        {
+            let mut guard = self.manual_override.lock().await;
+            match guard.as_ref() {
+                Some(o) if o.until > now => {
+                    let amps = o.amps;
+                    drop(guard);
+                    return self.request_override_amps(amps, now).await;
+                }
+                Some(_) => {
+                    log::info!(
+                        "EV: Manual override expired, resuming budget control{}",
+                        RequestId::in_logs()
+                    );
+                    guard.take();
+                }
+                None => {}
+            }
+        }
+
         // Only change amps if they are *less* or at least 30 seconds have passed since the last change
         let (last_amps_requested, last_amps_requested_time) = self
             .last_state
@@ -295,41 +829,130 @@ impl<H: EVChargeHandler> CarHandler<H> {
             .map(|x| (x.last_amps_requested, x.last_amps_requested_time))
             .unwrap_or((0, 0));
 
-        // Calculate the average amps over the last 30 seconds
-        let now = chrono::Utc::now().timestamp();
-
-        let home_amps_without_car = {
+        // Calculate the average amps over the consumption window
+        let (home_amps_without_car, peak_home_amps_without_car) = {
             let guard = self.home_state.lock().await;
-            let state = guard.state.last().unwrap();
-            log::info!("Home states: {:?}", guard.state);
+            let state = guard
+                .state
+                .last()
+                .ok_or_else(|| anyhow::anyhow!("No home consumption recorded yet"))?;
+            log::info!("Home states: {:?}{}", guard.state, RequestId::in_logs());
             log::info!(
-                "Home amps without car: {} (avg home={}, car={})",
+                "Home amps without car: {} (avg home={}, car={}){}",
                 state.avg_amps - state.car_amps,
                 state.avg_amps,
-                state.car_amps
+                state.car_amps,
+                RequestId::in_logs()
             );
 
-            if state.avg_amps - state.car_amps < 0.0 {
-                0.0
-            } else {
-                state.avg_amps - state.car_amps
-            }
+            // A negative value means we are exporting, which adds to the budget
+            (
+                state.avg_amps - state.car_amps,
+                state.max_amps.max(state.avg_amps) - state.car_amps,
+            )
         };
 
         let amps_to_request = min(
             self.config.max_amps_car,
-            max(
-                0,
-                ((self.config.max_amps - home_amps_without_car) * 0.95) as usize,
+            self.config.amps_rounding.round(
+                (self.config.max_amps - home_amps_without_car) * self.config.budget_safety_factor,
             ),
         );
 
+        let amps_to_request = match self.config.absolute_max_home_amps {
+            Some(ceiling) => {
+                // Negative headroom saturates to 0 when converted to usize
+                let headroom = (ceiling - peak_home_amps_without_car) as usize;
+                if headroom < amps_to_request {
+                    log::info!(
+                        "Capping car charge to {}A, the peak home consumption of {}A without the car is near absolute_max_home_amps ({}A){}",
+                        headroom,
+                        peak_home_amps_without_car,
+                        ceiling,
+                        RequestId::in_logs()
+                    );
+                }
+                min(amps_to_request, headroom)
+            }
+            None => amps_to_request,
+        };
+
+        let amps_to_request = match &self.config.departure {
+            Some(departure) => {
+                let levels = self
+                    .last_state
+                    .lock()
+                    .await
+                    .as_ref()
+                    .map(|x| (x.state.get_battery_level(), x.state.get_charge_limit_soc()));
+                let charge_limit_soc = self
+                    .config
+                    .charge_limit_soc
+                    .or(levels.and_then(|(_, limit)| limit));
+                let needed = levels.and_then(|(level, _)| level).and_then(|level| {
+                    departure.amps_needed(
+                        &chrono::Utc::now(),
+                        level,
+                        charge_limit_soc,
+                        self.config.nominal_volts,
+                    )
+                });
+                match needed {
+                    Some(needed) if (needed.ceil() as usize) < amps_to_request => {
+                        log::info!(
+                            "Slowing car charge to {}A, enough to reach the target by the departure{}",
+                            needed.ceil() as usize,
+                            RequestId::in_logs()
+                        );
+                        needed.ceil() as usize
+                    }
+                    _ => amps_to_request,
+                }
+            }
+            None => amps_to_request,
+        };
+
+        let amps_to_request = match self.charge_session_cap(now).await {
+            Some(_) if amps_to_request > 0 => {
+                log::info!(
+                    "Charge session limit reached, requesting 0A until the next day{}",
+                    RequestId::in_logs()
+                );
+                0
+            }
+            _ => amps_to_request,
+        };
+
+        let amps_to_request = if readings || amps_to_request <= last_amps_requested {
+            amps_to_request
+        } else {
+            log::warn!(
+                "No readings over the consumption window, holding car charge at {}A{}",
+                last_amps_requested,
+                RequestId::in_logs()
+            );
+            last_amps_requested
+        };
+
+        let in_schedule = self
+            .config
+            .schedule
+            .as_ref()
+            .is_none_or(|schedule| schedule.allows(&chrono::Utc::now()));
+        let amps_to_request = if in_schedule {
+            amps_to_request
+        } else {
+            log::info!("Outside of the charging schedule, requesting 0A{}", RequestId::in_logs());
+            0
+        };
+
         // If amps to request are equal to the last requested amps, do nothing
         if amps_to_request == last_amps_requested {
             log::info!(
-                "Skipping request car charge to {}A, equal to last request {} seconds ago.",
+                "Skipping request car charge to {}A, equal to last request {} seconds ago.{}",
                 amps_to_request,
-                now - last_amps_requested_time
+                now - last_amps_requested_time,
+                RequestId::in_logs()
             );
             return Ok(());
         }
@@ -337,22 +960,439 @@ impl<H: EVChargeHandler> CarHandler<H> {
         // If we are diminishing the amps, do this immediately
         // Otherwise, ask the API only every 30 seconds at most
         if amps_to_request < last_amps_requested || last_amps_requested_time < now - 30 {
-            let mut guard = self.last_state.lock().await;
-            guard.as_mut().map(|x| {
-                x.last_amps_requested = amps_to_request;
-                x.last_amps_requested_time = now;
-            });
-            log::info!("Requesting car charge to {}A", amps_to_request);
-            self.set_amps(amps_to_request).await?;
+            self.request_amps(amps_to_request, now).await?;
         } else {
             log::info!(
-                "Skipping request car charge to {}A. We requested {}A {} seconds ago.",
+                "Skipping request car charge to {}A. We requested {}A {} seconds ago.{}",
                 amps_to_request,
                 last_amps_requested,
-                now - last_amps_requested_time
+                now - last_amps_requested_time,
+                RequestId::in_logs()
             );
         }
 
         Ok(())
     }
+
+    /// Returns the current charging session, if `max_charge_session_secs` is
+    /// configured
+    pub async fn charge_session(&self) -> Option<ChargeSession> {
+        self.config.max_charge_session_secs?;
+        Some(self.charge_session.lock().await.clone())
+    }
+
+    /// Tracks the charging session for `max_charge_session_secs`, from
+    /// whether the car was just seen charging.
+    ///
+    /// A session starts when the car is first seen charging, and ends when it
+    /// is seen not charging, e.g., once unplugged. It is called on every
+    /// check, so that the end of a session is noticed even if no charge is
+    /// calculated meanwhile. While the charge is stopped over the limit, no
+    /// session starts.
+    pub async fn track_charge_session(&self, charging: bool) {
+        if self.config.max_charge_session_secs.is_none() {
+            return;
+        }
+        let now = chrono::Utc::now().timestamp();
+        let mut session = self.charge_session.lock().await;
+        if session.capped_until.is_some_and(|until| until > now) {
+            return;
+        }
+        match (charging, session.started) {
+            (true, None) => session.started = Some(now),
+            (false, Some(_)) => {
+                log::info!("EV: The charging session ended{}", RequestId::in_logs());
+                session.started = None;
+            }
+            _ => {}
+        }
+    }
+
+    /// Returns until when the charge is stopped if the session tracked with
+    /// [CarHandler::track_charge_session] went over `max_charge_session_secs`.
+    ///
+    /// Once over the limit, the charge is stopped until the next midnight in
+    /// `charge_timezone`, whatever the budget.
+    async fn charge_session_cap(&self, now: i64) -> Option<i64> {
+        let max_secs = i64::from(self.config.max_charge_session_secs?);
+
+        let mut session = self.charge_session.lock().await;
+        if let Some(until) = session.capped_until {
+            if until > now {
+                return Some(until);
+            }
+            log::info!(
+                "EV: A new day started, lifting the charge session limit{}",
+                RequestId::in_logs()
+            );
+            *session = ChargeSession::default();
+        }
+
+        let started = session.started?;
+        if now - started < max_secs {
+            return None;
+        }
+
+        let until = next_midnight(now, &self.config.charge_timezone);
+        log::warn!(
+            "EV: The car has been charging for {}s, over max_charge_session_secs ({}s), stopping it until {}{}",
+            now - started,
+            max_secs,
+            chrono::DateTime::from_timestamp(until, 0)
+                .unwrap_or_default()
+                .with_timezone(&self.config.charge_timezone),
+            RequestId::in_logs()
+        );
+        *session = ChargeSession {
+            started: None,
+            capped_until: Some(until),
+        };
+        Some(until)
+    }
+
+    /// Request the amps of an active manual override, unless they are already
+    /// the last requested amps
+    async fn request_override_amps(&self, amps: usize, now: i64) -> anyhow::Result<()> {
+        let last_amps_requested = self
+            .last_state
+            .lock()
+            .await
+            .as_ref()
+            .map(|x| x.last_amps_requested);
+        if last_amps_requested == Some(amps) {
+            log::info!(
+                "Manual override active, car already requested {}A{}",
+                amps,
+                RequestId::in_logs()
+            );
+            return Ok(());
+        }
+        log::info!("Manual override active, ignoring the budget{}", RequestId::in_logs());
+        self.request_amps(amps, now).await
+    }
+}
+
+/// Returns the next midnight in `tz` after the UNIX timestamp `now`, as a UNIX
+/// timestamp.
+///
+/// If the midnight does not exist because of a DST change, the day starts an
+/// hour later.
+fn next_midnight(now: i64, tz: &chrono_tz::Tz) -> i64 {
+    use chrono::TimeZone;
+
+    let now = chrono::DateTime::from_timestamp(now, 0).unwrap_or_default();
+    let tomorrow = now.with_timezone(tz).date_naive() + chrono::Days::new(1);
+    let midnight = tomorrow.and_hms_opt(0, 0, 0).expect("valid midnight");
+    tz.from_local_datetime(&midnight)
+        .earliest()
+        .or_else(|| tz.from_local_datetime(&(midnight + chrono::Duration::hours(1))).earliest())
+        .map_or(now.timestamp() + 86400, |midnight| midnight.timestamp())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::car::geocode::Nominatim;
+    use crate::car::simulation;
+    use rocket::figment::providers::Serialized;
+
+    /// A budget of 20 A for the home and 16 A for the car, with the stub car
+    /// at the charger, drawing 20 A
+    fn car_figment() -> Figment {
+        Figment::new()
+            .merge(("charger_location", "43.363056,-8.838417"))
+            .merge(("max_amps", 20))
+            .merge(("max_amps_car", 16))
+            .merge(("simulation.amps", 20))
+    }
+
+    async fn handler(figment: Figment) -> CarHandler<simulation::Handler> {
+        CarHandler::from_figment(&figment, &Nominatim::from(&figment))
+            .await
+            .unwrap()
+    }
+
+    /// Runs a check with the home drawing `home_amps` besides the car, and
+    /// returns the amps requested to the car
+    async fn check(handler: &CarHandler<simulation::Handler>, home_amps: f64) -> Vec<usize> {
+        let car_amps = handler.get_amps().await;
+        handler
+            .set_current_home_consumption(home_amps + car_amps, home_amps + car_amps)
+            .await
+            .unwrap();
+        handler.throttled_calculate_amps().await.unwrap();
+        handler.inner.requests().await
+    }
+
+    #[rocket::async_test]
+    async fn decreases_are_immediate_and_increases_throttled() {
+        let handler = handler(car_figment()).await;
+
+        // Down from the 20 A the car draws, right away
+        assert_eq!(check(&handler, 4.0).await, vec![15]);
+        // Up to the 16 A of max_amps_car, but not within 30 seconds
+        assert_eq!(check(&handler, 0.0).await, vec![15]);
+        // Down again, right away
+        assert_eq!(check(&handler, 10.0).await, vec![15, 9]);
+    }
+
+    #[rocket::async_test]
+    async fn the_budget_applies_within_the_schedule() {
+        let handler = handler(car_figment().merge(Serialized::default(
+            "charge_schedule",
+            serde_json::json!([{ "days": ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"], "from": 0, "to": 24 }]),
+        )))
+        .await;
+
+        // (20 A - 4 A) * 0.95, rounded down
+        assert_eq!(check(&handler, 4.0).await, vec![15]);
+    }
+
+    #[rocket::async_test]
+    async fn no_charge_is_requested_outside_the_schedule() {
+        let handler = handler(car_figment().merge(Serialized::default(
+            "charge_schedule",
+            serde_json::json!([{ "days": [], "from": 0, "to": 24 }]),
+        )))
+        .await;
+
+        assert_eq!(check(&handler, 4.0).await, vec![0]);
+    }
+
+    #[rocket::async_test]
+    async fn without_readings_the_amps_are_not_raised() {
+        let handler = handler(car_figment().merge(("simulation.amps", 6))).await;
+        let car_amps = handler.get_amps().await;
+        // As if the 6 A were requested a while ago, so an increase is due
+        if let Some(x) = handler.last_state.lock().await.as_mut() {
+            x.last_amps_requested_time -= 60;
+        }
+        // The last consumption recorded leaves room for more than the 6 A
+        handler
+            .set_current_home_consumption(car_amps - 5.0, car_amps - 5.0)
+            .await
+            .unwrap();
+        handler.calculate_amps_without_readings().await.unwrap();
+        assert_eq!(handler.inner.requests().await, Vec::<usize>::new());
+        handler.throttled_calculate_amps().await.unwrap();
+        assert_eq!(handler.inner.requests().await, vec![16]);
+
+        // But they are still reduced
+        let car_amps = handler.get_amps().await;
+        handler
+            .set_current_home_consumption(car_amps + 10.0, car_amps + 10.0)
+            .await
+            .unwrap();
+        handler.calculate_amps_without_readings().await.unwrap();
+        assert_eq!(handler.inner.requests().await, vec![16, 9]);
+    }
+
+    #[rocket::async_test]
+    async fn exporting_to_the_grid_adds_to_the_budget() {
+        let figment = car_figment().merge(("max_amps", 10));
+
+        // 10 A * 0.95, rounded down
+        assert_eq!(check(&handler(figment.clone()).await, 0.0).await, vec![9]);
+        // (10 A + 5 A exported) * 0.95, rounded down
+        assert_eq!(check(&handler(figment).await, -5.0).await, vec![14]);
+    }
+
+    #[rocket::async_test]
+    async fn an_invalid_charge_limit_is_rejected() {
+        for soc in [serde_json::json!("80%"), serde_json::json!(-1), serde_json::json!(101)] {
+            let figment = car_figment().merge(Serialized::default("charge_limit_soc", &soc));
+            let result =
+                CarHandler::<simulation::Handler>::from_figment(&figment, &Nominatim::from(&figment))
+                    .await;
+            assert!(result.is_err(), "{}", soc);
+        }
+    }
+
+    #[rocket::async_test]
+    async fn an_invalid_charge_timezone_is_rejected() {
+        for timezone in [serde_json::json!("Mars/Olympus"), serde_json::json!(["Europe/Madrid"])] {
+            let figment = car_figment().merge(Serialized::default("charge_timezone", &timezone));
+            let result =
+                CarHandler::<simulation::Handler>::from_figment(&figment, &Nominatim::from(&figment))
+                    .await;
+            assert!(result.is_err(), "{}", timezone);
+        }
+    }
+
+    #[rocket::async_test]
+    async fn a_nearby_distance_in_miles_is_compared_in_km() {
+        // About 780 m (0.486 mi) away from the charger
+        let figment = car_figment()
+            .merge(("simulation.location", "43.370000,-8.840000"))
+            .merge(("distance_unit", "mi"));
+
+        let far = handler(figment.clone().merge(("nearby_distance", "0.4mi"))).await;
+        assert!(!far.is_car_nearby().await.unwrap());
+        let near = handler(figment.merge(("nearby_distance", "0.5 mi"))).await;
+        assert!(near.is_car_nearby().await.unwrap());
+
+        let debug = near.debug_info().await.unwrap();
+        assert_eq!(debug.nearby_distance, "0.500 mi");
+        assert_eq!(debug.distance, "0.486 mi");
+    }
+
+    #[rocket::async_test]
+    async fn the_budget_safety_factor_scales_the_budget() {
+        for (factor, amps) in [(0.5, 8), (0.95, 15), (1.0, 16)] {
+            let handler = handler(car_figment().merge(("budget_safety_factor", factor))).await;
+            // (20 A - 4 A) * factor, rounded down
+            assert_eq!(check(&handler, 4.0).await, vec![amps], "factor {}", factor);
+        }
+
+        for factor in [0.0, 1.5] {
+            let figment = car_figment().merge(("budget_safety_factor", factor));
+            let handler =
+                CarHandler::<simulation::Handler>::from_figment(&figment, &Nominatim::from(&figment)).await;
+            assert!(handler.is_err(), "factor {}", factor);
+        }
+    }
+
+    #[rocket::async_test]
+    async fn the_absolute_ceiling_caps_a_spiky_home() {
+        // Averaging 2 A with spikes of 15 A besides the car
+        async fn spiky_check(handler: &CarHandler<simulation::Handler>) -> Vec<usize> {
+            let car_amps = handler.get_amps().await;
+            handler
+                .set_current_home_consumption(2.0 + car_amps, 15.0 + car_amps)
+                .await
+                .unwrap();
+            handler.throttled_calculate_amps().await.unwrap();
+            handler.inner.requests().await
+        }
+        let figment = car_figment().merge(("max_amps", 30));
+
+        // (30 A - 2 A) * 0.95 leaves room for the 16 A of max_amps_car
+        assert_eq!(spiky_check(&handler(figment.clone()).await).await, vec![16]);
+        // But only 20 A - 15 A fit under the ceiling at the peaks
+        let capped = handler(figment.merge(("absolute_max_home_amps", 20))).await;
+        assert_eq!(spiky_check(&capped).await, vec![5]);
+    }
+
+    #[rocket::async_test]
+    async fn kw_budgets_are_converted_at_the_nominal_volts() {
+        let figment = Figment::new()
+            .merge(("charger_location", "43.363056,-8.838417"))
+            .merge(("simulation.amps", 20))
+            .merge(("max_kw", 4.6))
+            .merge(("max_kw_car", 2.3));
+
+        // 20 A and 10 A at 230 V: (20 A - 4 A) * 0.95 is capped to 10 A
+        let at_230 = handler(figment.clone().merge(("nominal_volts", 230))).await;
+        assert_eq!(check(&at_230, 4.0).await, vec![10]);
+        // 30 A and 10 A at the default 220 V: (30 A - 20 A) * 0.95, rounded down
+        let at_220 = figment.clone().merge(("max_kw", 6.6)).merge(("max_kw_car", 2.2));
+        assert_eq!(check(&handler(at_220).await, 20.0).await, vec![9]);
+
+        let both = figment.merge(("max_amps", 20));
+        let handler =
+            CarHandler::<simulation::Handler>::from_figment(&both, &Nominatim::from(&both)).await;
+        assert!(handler.is_err());
+    }
+
+    #[rocket::async_test]
+    async fn an_active_override_bypasses_the_budget() {
+        let handler = handler(car_figment()).await;
+        handler.set_override(16, 60).await.unwrap();
+
+        // The budget would be (20 A - 10 A) * 0.95, rounded down
+        assert_eq!(check(&handler, 10.0).await, vec![16]);
+        assert_eq!(check(&handler, 10.0).await, vec![16]);
+
+        assert!(handler.clear_override().await);
+        assert_eq!(check(&handler, 10.0).await, vec![16, 9]);
+    }
+
+    #[rocket::async_test]
+    async fn with_ample_time_the_departure_slows_the_charge_down() {
+        // 30 kWh missing, 40% to 80% of 75 kWh, with the 16 A of max_amps_car
+        let departure_in = |duration: chrono::Duration| {
+            let departure = (chrono::Utc::now() + duration).format("%H:%M").to_string();
+            car_figment()
+                .merge(("simulation.battery_level", 40))
+                .merge(("departure_time", departure))
+                .merge(("departure_soc", 80))
+                .merge(("battery_capacity_kwh", 75))
+        };
+
+        // About 30 kWh over 10 hours at 220 V, rounded up
+        let ample = handler(departure_in(chrono::Duration::hours(11))).await;
+        assert_eq!(check(&ample, 0.0).await, vec![14]);
+
+        // Within the last hour, the budget alone decides
+        let close = handler(departure_in(chrono::Duration::minutes(30))).await;
+        assert_eq!(check(&close, 0.0).await, vec![16]);
+    }
+
+    #[test]
+    fn amps_are_rounded_as_configured() {
+        assert_eq!(AmpsRounding::Floor.round(14.7), 14);
+        assert_eq!(AmpsRounding::Nearest.round(14.7), 15);
+        assert_eq!(AmpsRounding::Nearest.round(14.2), 14);
+        assert_eq!(AmpsRounding::Ceil.round(14.2), 15);
+        for rounding in [AmpsRounding::Floor, AmpsRounding::Nearest, AmpsRounding::Ceil] {
+            assert_eq!(rounding.round(-3.5), 0);
+        }
+    }
+
+    #[rocket::async_test]
+    async fn the_same_budget_is_rounded_as_configured() {
+        let rounded = |rounding: &str| car_figment().merge(("amps_rounding", rounding));
+
+        // (20 A - 4.5 A) * 0.95 is 14.725 A
+        assert_eq!(check(&handler(car_figment()).await, 4.5).await, vec![14]);
+        assert_eq!(check(&handler(rounded("floor")).await, 4.5).await, vec![14]);
+        assert_eq!(check(&handler(rounded("nearest")).await, 4.5).await, vec![15]);
+        // Still capped by max_amps_car: (20 A - 2.5 A) * 0.95 is 16.625 A
+        assert_eq!(check(&handler(rounded("ceil")).await, 2.5).await, vec![16]);
+    }
+
+    #[rocket::async_test]
+    async fn no_amps_are_requested_while_the_car_is_unplugged() {
+        let unplugged = handler(car_figment().merge(("simulation.plugged_in", false))).await;
+
+        // The budget would be (20 A - 4 A) * 0.95, rounded down
+        assert!(check(&unplugged, 4.0).await.is_empty());
+        unplugged.set_override(16, 60).await.unwrap();
+        assert!(unplugged.inner.requests().await.is_empty());
+
+        let plugged = handler(car_figment()).await;
+        assert_eq!(check(&plugged, 4.0).await, vec![15]);
+    }
+
+    #[rocket::async_test]
+    async fn unplugging_ends_the_charge_session() {
+        let figment = car_figment().merge(("max_charge_session_secs", 60));
+        let backdate_session = |handler: &CarHandler<simulation::Handler>| {
+            let charge_session = handler.charge_session.clone();
+            async move {
+                let two_minutes_ago = chrono::Utc::now().timestamp() - 120;
+                charge_session.lock().await.started = Some(two_minutes_ago);
+            }
+        };
+
+        // Charging for longer than the limit stops the charge
+        let charging = handler(figment.clone()).await;
+        charging.track_charge_session(true).await;
+        backdate_session(&charging).await;
+        assert_eq!(check(&charging, 4.0).await, vec![0]);
+        assert!(charging.charge_session().await.unwrap().capped_until.is_some());
+
+        // Unplugged, and plugged in again, a new session starts
+        let replugged = handler(figment).await;
+        replugged.track_charge_session(true).await;
+        backdate_session(&replugged).await;
+        replugged.track_charge_session(false).await;
+        assert_eq!(replugged.charge_session().await.unwrap().started, None);
+        replugged.track_charge_session(true).await;
+        let session = replugged.charge_session().await.unwrap();
+        assert!(session.started >= Some(chrono::Utc::now().timestamp() - 1));
+        // (20 A - 4 A) * 0.95, rounded down
+        assert_eq!(check(&replugged, 4.0).await, vec![15]);
+        assert_eq!(replugged.charge_session().await.unwrap().capped_until, None);
+    }
 }
@@ -12,11 +12,17 @@
 use serde::{Deserialize, Serialize};
 
 use crate::car::LatLon;
+use crate::request_id::RequestId;
 
+/// The Tessie API, unless `tessie_url` is configured
+const DEFAULT_TESSIE_URL: &str = "https://api.tessie.com";
 
 
 /// The possible charging states of the car as reported by the Tessie API.
-#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+///
+/// Any state we do not know about is kept as [ChargingState::Unknown], and
+/// treated as not charging, instead of failing to parse the whole state.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum ChargingState {
     Complete,
     Charging,
@@ -24,6 +30,9 @@ pub enum ChargingState {
     Pending,
     Starting,
     Stopped,
+
+    #[serde(untagged)]
+    Unknown(String),
 }
 
 
@@ -39,32 +48,35 @@ pub enum ChargePortLatch {
 
 /// The charging state of the car as reported by the Tessie API.
 /// 
-/// This is only an excerpt of the full state.
+/// This is only an excerpt of the full state. Only the fields used to control
+/// the charge are required, as Tessie may omit the others (e.g., while the car
+/// is asleep or updating its firmware).
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TessieChargeState {
+    pub battery_level: Option<usize>,
     pub charge_amps: f64,
     pub charge_current_request: usize,
-    pub charge_enable_request: bool,
-    pub charge_energy_added: f64,
-    pub charge_limit_soc: usize,
-    pub charge_limit_soc_max: usize,
-    pub charge_limit_soc_min: usize,
-    pub charge_limit_soc_std: usize,
-    pub charge_miles_added_ideal: f64,
-    pub charge_miles_added_rated: f64,
-    pub charge_port_cold_weather_mode: bool,
-    pub charge_port_door_open: bool,
-    pub charge_port_latch: ChargePortLatch,
-    pub charge_rate: f64,
-    pub charger_actual_current: f64,
+    pub charge_enable_request: Option<bool>,
+    pub charge_energy_added: Option<f64>,
+    pub charge_limit_soc: Option<usize>,
+    pub charge_limit_soc_max: Option<usize>,
+    pub charge_limit_soc_min: Option<usize>,
+    pub charge_limit_soc_std: Option<usize>,
+    pub charge_miles_added_ideal: Option<f64>,
+    pub charge_miles_added_rated: Option<f64>,
+    pub charge_port_cold_weather_mode: Option<bool>,
+    pub charge_port_door_open: Option<bool>,
+    pub charge_port_latch: Option<ChargePortLatch>,
+    pub charge_rate: Option<f64>,
+    pub charger_actual_current: Option<f64>,
     pub charger_phases: Option<usize>,
-    pub charger_pilot_current: f64,
-    pub charger_power: f64,
-    pub charger_voltage: f64,
+    pub charger_pilot_current: Option<f64>,
+    pub charger_power: Option<f64>,
+    pub charger_voltage: Option<f64>,
     pub charging_state: ChargingState,
-    pub conn_charge_cable: String,
-    pub fast_charger_brand: String,
-    pub fast_charger_present: bool,
+    pub conn_charge_cable: Option<String>,
+    pub fast_charger_brand: Option<String>,
+    pub fast_charger_present: Option<bool>,
 }
 
 
@@ -73,12 +85,12 @@ pub struct TessieChargeState {
 /// This is only an excerpt of the full state.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TessieDriveState {
-    pub gps_as_of: i64,
+    pub gps_as_of: Option<i64>,
     pub latitude: f64,
     pub longitude: f64,
     pub heading: Option<usize>,
     pub speed: Option<usize>,
-    pub timestamp: i64,
+    pub timestamp: Option<i64>,
     pub power: Option<i32>,
 }
 
@@ -88,17 +100,21 @@ pub enum TessieCarWakeState {
     Asleep,
     WaitingForSleep,
     Online,
+
+    #[serde(untagged)]
+    Unknown(String),
 }
 
 
 /// The state of the car as reported by the Tessie API.
 /// 
-/// This is only an excerpt of the full state.
+/// This is only an excerpt of the full state, of which only the position and
+/// the charge state are required.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TessieCarState {
-    access_type: String,
-    api_version: usize,
-    state: TessieCarWakeState,
+    access_type: Option<String>,
+    api_version: Option<usize>,
+    state: Option<TessieCarWakeState>,
     vehicle_name: Option<String>,
     display_name: Option<String>,
     pub drive_state: TessieDriveState,
@@ -106,9 +122,11 @@ pub struct TessieCarState {
 }
 
 
-/// The result of the [set_charging_amps](TessieAPIHandler::set_charging_amps) method.
+/// The result of the command methods, such as
+/// [set_charging_amps](TessieAPIHandler::set_charging_amps) or
+/// [set_charge_limit](TessieAPIHandler::set_charge_limit).
 #[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct SetChargingAmpsResult {
+pub struct CommandResult {
     pub result: bool,
 
     /// This field is only present in the response if the car was woken up
@@ -124,23 +142,34 @@ pub struct SetChargingAmpsResult {
 /// the Tesla API, and it abstracts the complexity of refreshing the Tesla OAuth
 /// Tokens and the awake/asleep state of the EV itself.
 pub struct TessieAPIHandler {
+    base_url: String,
     vin: String,
     token: String,
+    client: reqwest::Client,
 }
 
 
 
 
-impl From<&rocket::figment::Figment> for TessieAPIHandler {
+impl TryFrom<&rocket::figment::Figment> for TessieAPIHandler {
+    type Error = rocket::figment::Error;
+
     #[inline(always)]
-    fn from(figment: &rocket::figment::Figment) -> Self {
-        let vin = figment
-            .extract_inner("car_vin")
-            .unwrap_or_else(|_| panic!("Missing VIN"));
-        let token = figment
-            .extract_inner("tessie_token")
-            .unwrap_or_else(|_| panic!("Missing token"));
-        Self { vin, token }
+    fn try_from(figment: &rocket::figment::Figment) -> Result<Self, Self::Error> {
+        let base_url = match figment.extract_inner("tessie_url") {
+            Ok(url) => url,
+            Err(e) if e.missing() => DEFAULT_TESSIE_URL.to_string(),
+            Err(e) => return Err(e),
+        };
+        let vin = figment.extract_inner("car_vin")?;
+        let token = figment.extract_inner("tessie_token")?;
+        let client = crate::http_client::shared().clone();
+        Ok(Self {
+            base_url,
+            vin,
+            token,
+            client,
+        })
     }
 }
 
@@ -151,10 +180,14 @@ impl TessieAPIHandler {
         method: reqwest::Method,
         body: Option<String>,
     ) -> Result<reqwest::Response, reqwest::Error> {
-        let client = reqwest::Client::new();
-        let url = format!("https://api.tessie.com/{}/{}", self.vin, endpoint);
+        let url = format!(
+            "{}/{}/{}",
+            self.base_url.trim_end_matches('/'),
+            self.vin,
+            endpoint
+        );
         let request = fix_optional_body(
-            client
+            self.client
                 .request(method.clone(), &url)
                 .header(
                     reqwest::header::AUTHORIZATION,
@@ -165,7 +198,7 @@ impl TessieAPIHandler {
             body,
         )
         .build()?;
-        client.execute(request).await
+        self.client.execute(request).await
     }
 
     pub async fn get_state(&self) -> anyhow::Result<TessieCarState> {
@@ -173,22 +206,35 @@ impl TessieAPIHandler {
         let content = response.text().await?;
         serde_json::from_str(&content)
             .map_err(|e| {
-                log::info!("Tessie: Failed to parse response: {}", e);
-                log::info!("Tessie: Response was: {}", content);
+                log::info!("Tessie: Failed to parse response: {}{}", e, RequestId::in_logs());
+                log::info!("Tessie: Response was: {}{}", content, RequestId::in_logs());
                 anyhow::anyhow!("Failed to parse response: {}", e)
             })
     }
 
-    pub async fn set_charging_amps(&self, amps: usize) -> anyhow::Result<SetChargingAmpsResult> {
+    pub async fn set_charging_amps(&self, amps: usize) -> anyhow::Result<CommandResult> {
         let endpoint = format!(
             "command/set_charging_amps?wait_for_completion=true&amps={}",
             amps
         );
-        log::info!("Tessie: Sending request to endpoint: {}", endpoint);
+        log::info!("Tessie: Sending request to endpoint: {}{}", endpoint, RequestId::in_logs());
         let response = self.request(&endpoint, reqwest::Method::POST, None).await?;
-        let bytes = response.error_for_status()?.text().await;
-        log::info!("Tessie: Received response: {}", bytes.as_ref().unwrap());
-        serde_json::from_str(&bytes.unwrap())
+        let content = response.error_for_status()?.text().await?;
+        log::info!("Tessie: Received response: {}{}", content, RequestId::in_logs());
+        serde_json::from_str(&content)
+            .map_err(|e| anyhow::anyhow!("Failed to parse response: {}", e))
+    }
+
+    pub async fn set_charge_limit(&self, percent: usize) -> anyhow::Result<CommandResult> {
+        let endpoint = format!(
+            "command/set_charge_limit?wait_for_completion=true&percent={}",
+            percent
+        );
+        log::info!("Tessie: Sending request to endpoint: {}{}", endpoint, RequestId::in_logs());
+        let response = self.request(&endpoint, reqwest::Method::POST, None).await?;
+        let content = response.error_for_status()?.text().await?;
+        log::info!("Tessie: Received response: {}{}", content, RequestId::in_logs());
+        serde_json::from_str(&content)
             .map_err(|e| anyhow::anyhow!("Failed to parse response: {}", e))
     }
 }
@@ -21,8 +21,9 @@
 //! For example, the [TessieDriveState](api::TessieDriveState) struct is
 //! only minimally implemented, and the
 //! [TessieAPIHandler] struct only implements the
-//! [get_state](api::TessieAPIHandler::get_state) and the
-//! [set_charging_amps](api::TessieAPIHandler::set_charging_amps)
+//! [get_state](api::TessieAPIHandler::get_state), the
+//! [set_charging_amps](api::TessieAPIHandler::set_charging_amps) and the
+//! [set_charge_limit](api::TessieAPIHandler::set_charge_limit)
 //! methods, as they are the only ones needed for the current use case.
 //! 
 //! [tessie-web]: https://developer.tessie.com/docs/about/
@@ -31,6 +32,8 @@ use std::sync::Arc;
 use api::{ChargingState, TessieAPIHandler, TessieCarState};
 use rocket::tokio::sync::Mutex;
 
+use crate::request_id::RequestId;
+
 use super::{EVChargeHandler, EVChargeInternalState};
 
 pub mod api;
@@ -80,36 +83,29 @@ impl EVChargeHandler for Handler {
         Ok(new_state)
     }
     async fn request_charge_amps(&self, amps: usize) -> anyhow::Result<()> {
-        let result = self.api.set_charging_amps(amps).await;
-        log::info!("Setting charging amps to {}A: {:?}", amps, result);
+        let result = self.api.set_charging_amps(amps).await?;
+        log::info!("Setting charging amps to {}A: {:?}{}", amps, result, RequestId::in_logs());
+        Ok(())
+    }
+    async fn request_charge_limit(&self, soc: usize) -> anyhow::Result<()> {
+        let result = self.api.set_charge_limit(soc).await?;
+        log::info!("Setting charge limit to {}%: {:?}{}", soc, result, RequestId::in_logs());
         Ok(())
     }
 }
 
 impl EVChargeInternalState for TessieCarState {
 
-    fn get_car_distance_to_point_km(&self, point: &super::LatLon) -> f64 {
-        let car_position = {
-                let api::TessieDriveState {
-                    longitude,
-                    latitude,
-                    ..
-                } = self.drive_state;
-                super::LatLon {
-                    lat: latitude,
-                    lon: longitude,
-                }
-        };
-
-        car_position.distance(&point)
+    fn get_car_location(&self) -> super::LatLon {
+        self.drive_state.clone().into()
     }
 
     #[inline(always)]
     fn is_charging(&self) -> bool {
-        let charging_state = self.charge_state.charging_state;
-                charging_state == ChargingState::Charging
-                    || charging_state == ChargingState::Starting
-                    || charging_state == ChargingState::Pending
+        let charging_state = &self.charge_state.charging_state;
+                *charging_state == ChargingState::Charging
+                    || *charging_state == ChargingState::Starting
+                    || *charging_state == ChargingState::Pending
     }
 
     #[inline(always)]
@@ -127,4 +123,116 @@ impl EVChargeInternalState for TessieCarState {
         self.charge_state.charge_current_request
     }
 
+    #[inline(always)]
+    fn get_charge_limit_soc(&self) -> Option<usize> {
+        self.charge_state.charge_limit_soc
+    }
+
+    #[inline(always)]
+    fn is_plugged_in(&self) -> bool {
+        self.charge_state.charging_state != ChargingState::Disconnected
+    }
+
+    fn get_battery_level(&self) -> Option<usize> {
+        self.charge_state.battery_level
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MockServer;
+
+    fn handler(url: &str) -> Handler {
+        let figment = rocket::figment::Figment::new()
+            .merge(("tessie_url", url))
+            .merge(("car_vin", "VIN123"))
+            .merge(("tessie_token", "secret"));
+        Handler::new(TessieAPIHandler::try_from(&figment).unwrap())
+    }
+
+    #[rocket::async_test]
+    async fn requests_the_charge_limit_and_amps_commands() {
+        let tessie = MockServer::start(200, r#"{"result": true}"#).await;
+        let handler = handler(&tessie.url);
+
+        handler.request_charge_limit(80).await.unwrap();
+        handler.request_charge_amps(12).await.unwrap();
+
+        assert_eq!(
+            tessie.requests(),
+            vec![
+                "POST /VIN123/command/set_charge_limit?wait_for_completion=true&percent=80 HTTP/1.1",
+                "POST /VIN123/command/set_charging_amps?wait_for_completion=true&amps=12 HTTP/1.1",
+            ]
+        );
+    }
+
+    #[rocket::async_test]
+    async fn a_malformed_command_response_is_an_error() {
+        let tessie = MockServer::start(200, "<html>Bad gateway</html>").await;
+        let handler = handler(&tessie.url);
+
+        assert!(handler.request_charge_amps(12).await.is_err());
+        assert!(handler.request_charge_limit(80).await.is_err());
+    }
+
+    /// A state response with only the fields required to control the charge
+    fn minimal_state(charging_state: &str) -> String {
+        serde_json::json!({
+            "drive_state": { "latitude": 43.363056, "longitude": -8.838417 },
+            "charge_state": {
+                "charge_amps": 16,
+                "charge_current_request": 16,
+                "charging_state": charging_state,
+            },
+        })
+        .to_string()
+    }
+
+    #[test]
+    fn unknown_charging_states_are_not_charging() {
+        let state: TessieCarState = serde_json::from_str(&minimal_state("Levitating")).unwrap();
+        assert_eq!(
+            state.charge_state.charging_state,
+            ChargingState::Unknown("Levitating".to_string())
+        );
+        assert!(!state.is_charging());
+        assert!(!state.is_charge_starting());
+
+        let state: TessieCarState = serde_json::from_str(&minimal_state("Charging")).unwrap();
+        assert_eq!(state.charge_state.charging_state, ChargingState::Charging);
+        assert!(state.is_charging());
+        assert!(state.is_plugged_in());
+
+        let state: TessieCarState = serde_json::from_str(&minimal_state("Disconnected")).unwrap();
+        assert!(!state.is_plugged_in());
+    }
+
+    #[rocket::async_test]
+    async fn a_minimal_state_without_cosmetic_fields_is_parsed() {
+        let tessie = MockServer::start(200, minimal_state("Charging")).await;
+        let handler = handler(&tessie.url);
+
+        let state = handler.get_state().await.unwrap();
+        assert!(state.is_charging());
+        assert!(state.is_plugged_in());
+        assert_eq!(state.get_current_charge(), 16.0);
+        assert_eq!(state.get_battery_level(), None);
+        assert_eq!(state.get_charge_limit_soc(), None);
+        assert_eq!(state.get_car_location().lat, 43.363056);
+        assert_eq!(tessie.requests(), vec!["GET /VIN123/state HTTP/1.1"]);
+    }
+
+    #[rocket::async_test]
+    async fn a_state_without_the_charge_state_fails() {
+        let tessie = MockServer::start(
+            200,
+            r#"{"drive_state": {"latitude": 43.363056, "longitude": -8.838417}}"#,
+        )
+        .await;
+
+        assert!(handler(&tessie.url).get_state().await.is_err());
+    }
 }
@@ -0,0 +1,153 @@
+//! Distance units for the car configuration and display.
+//!
+//! Distances are always computed in kilometers internally (see
+//! [LatLon::distance](super::LatLon::distance)), but they can be configured
+//! and displayed in miles too. In the figment configuration (Rocket.toml):
+//!
+//! ```toml
+//! # The car is considered nearby the charger below this distance
+//! nearby_distance = "0.06mi"
+//! # The unit used to display distances, "km" (the default) or "mi"
+//! distance_unit = "mi"
+//! ```
+//!
+//! A distance without a suffix is taken to be in kilometers.
+//!
+//! Likewise, the power budgets are always computed in amps, but they can be
+//! configured in kW (`max_kw` and `max_kw_car`), which are converted with the
+//! `nominal_volts` of the installation.
+
+use serde::Deserialize;
+
+/// Kilometers in an international mile
+const KM_PER_MILE: f64 = 1.609344;
+
+/// Converts a power in kW to the amps it draws at the given voltage
+pub fn kw_to_amps(kw: f64, volts: f64) -> f64 {
+    kw * 1000.0 / volts
+}
+
+/// A unit to configure or display a distance in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DistanceUnit {
+    #[default]
+    Km,
+    Mi,
+}
+
+impl DistanceUnit {
+    /// Converts a value in this unit to kilometers
+    pub fn to_km(self, value: f64) -> f64 {
+        match self {
+            DistanceUnit::Km => value,
+            DistanceUnit::Mi => value * KM_PER_MILE,
+        }
+    }
+
+    /// Converts a value in kilometers to this unit
+    pub fn convert_km(self, km: f64) -> f64 {
+        match self {
+            DistanceUnit::Km => km,
+            DistanceUnit::Mi => km / KM_PER_MILE,
+        }
+    }
+
+    fn suffix(self) -> &'static str {
+        match self {
+            DistanceUnit::Km => "km",
+            DistanceUnit::Mi => "mi",
+        }
+    }
+}
+
+/// A non-negative distance, stored in kilometers
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Distance {
+    km: f64,
+}
+
+impl Distance {
+    pub fn from_km(km: f64) -> Self {
+        Self { km }
+    }
+
+    pub fn km(&self) -> f64 {
+        self.km
+    }
+
+    /// Formats the distance in the given unit, e.g., `0.062 mi`
+    pub fn display(&self, unit: DistanceUnit) -> String {
+        format!("{:.3} {}", unit.convert_km(self.km), unit.suffix())
+    }
+}
+
+impl std::str::FromStr for Distance {
+    type Err = anyhow::Error;
+
+    /// Parses a distance with an optional `km` or `mi` suffix, e.g., `0.1km`
+    /// or `0.06 mi`. Without a suffix, the distance is in kilometers.
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let value = value.trim();
+        let (number, unit) = if let Some(number) = value.strip_suffix("km") {
+            (number, DistanceUnit::Km)
+        } else if let Some(number) = value.strip_suffix("mi") {
+            (number, DistanceUnit::Mi)
+        } else {
+            (value, DistanceUnit::Km)
+        };
+        let number: f64 = number.trim().parse()?;
+        anyhow::ensure!(
+            number.is_finite() && number >= 0.0,
+            "Invalid distance {:?}, it must be a non-negative number",
+            value
+        );
+        Ok(Self::from_km(unit.to_km(number)))
+    }
+}
+
+/// A distance as written in the configuration, either as a bare number or as
+/// a string with a unit suffix.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawDistance {
+    Number(f64),
+    Text(String),
+}
+
+impl<'de> Deserialize<'de> for Distance {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        match RawDistance::deserialize(deserializer)? {
+            RawDistance::Number(km) => format!("{}", km).parse(),
+            RawDistance::Text(text) => text.parse(),
+        }
+        .map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_distances_with_a_unit_suffix() {
+        assert_eq!("0.1km".parse::<Distance>().unwrap().km(), 0.1);
+        assert_eq!("0.1".parse::<Distance>().unwrap().km(), 0.1);
+        assert_eq!("1 mi".parse::<Distance>().unwrap().km(), KM_PER_MILE);
+        assert!("-1km".parse::<Distance>().is_err());
+        assert!("1 ft".parse::<Distance>().is_err());
+    }
+
+    #[test]
+    fn displays_distances_in_the_unit() {
+        let distance = Distance::from_km(KM_PER_MILE);
+        assert_eq!(distance.display(DistanceUnit::Km), "1.609 km");
+        assert_eq!(distance.display(DistanceUnit::Mi), "1.000 mi");
+    }
+
+    #[test]
+    fn converts_kw_to_amps() {
+        assert_eq!(kw_to_amps(2.3, 230.0), 10.0);
+        assert_eq!(kw_to_amps(6.6, 220.0), 30.0);
+    }
+}
@@ -0,0 +1,212 @@
+// Backs up the configured database into a new file, and verifies the copy
+
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePool};
+use std::env;
+use std::path::Path;
+use std::process;
+use std::str::FromStr;
+
+/// Back up the configured database into `dest`, and check the integrity of
+/// the copy.
+///
+/// The database is the `databases.sqlite_logs.url` from the figment
+/// configuration (Rocket.toml), the same one the server uses. The copy is made
+/// with `VACUUM INTO`, so it is a consistent snapshot even if the server is
+/// running and logging readings meanwhile.
+///
+/// If the database is sharded, each shard is backed up next to the copy of
+/// the main database, into `<destination>.shard-<index>`, so that the copies
+/// can be configured as the shards in the same order.
+///
+/// The destinations are not overwritten unless `--force` is given.
+///
+/// # Usage
+///
+/// ```sh
+/// cargo run backup <destination> [--force]
+/// ```
+pub async fn backup_cli() -> () {
+    let args: Vec<String> = env::args().collect();
+    let (dest, force) = match args.get(2..).unwrap_or_default() {
+        [dest] => (dest, false),
+        [dest, flag] if flag == "--force" => (dest, true),
+        _ => {
+            eprintln!("Usage: {} backup <destination> [--force]", args[0]);
+            process::exit(1);
+        }
+    };
+
+    let db = match crate::db::SqlitePool::connect_configured(&rocket::Config::figment()).await {
+        Ok(db) => db,
+        Err(e) => {
+            eprintln!("Error: cannot open the configured database: {}", e);
+            process::exit(1);
+        }
+    };
+
+    for index in 0..db.databases().count() {
+        let copy = copy_path(dest, index);
+        let path = Path::new(&copy);
+        if path.exists() {
+            if !force {
+                eprintln!("Error: {} already exists, use --force to overwrite it", path.display());
+                process::exit(1);
+            }
+            // VACUUM INTO refuses to write into a non-empty file
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    eprintln!("Backing up the configured database into {}", dest);
+    let copies = match backup(&db, dest).await {
+        Ok(copies) => copies,
+        Err(e) => {
+            eprintln!("Error: backup failed: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let mut rows = 0;
+    for copy in copies {
+        let pool = SqlitePool::connect_with(
+            SqliteConnectOptions::from_str(&copy).unwrap().read_only(true),
+        )
+        .await
+        .unwrap();
+        match verify(&pool).await {
+            Ok(copy_rows) => rows += copy_rows,
+            Err(e) => {
+                eprintln!("Error: the backup {} failed verification: {}", copy, e);
+                process::exit(1);
+            }
+        }
+    }
+    eprintln!("Backup complete and verified, {} readings", rows);
+}
+
+/// The path of the copy of the database at `index` in
+/// [databases](crate::db::SqlitePool::databases): `dest` for the main
+/// database, and `<dest>.shard-<index>` for the shards.
+fn copy_path(dest: &str, index: usize) -> String {
+    match index {
+        0 => dest.to_string(),
+        index => format!("{}.shard-{}", dest, index - 1),
+    }
+}
+
+/// Writes a consistent snapshot of the main database into `dest`, and of each
+/// shard next to it (see [copy_path]), none of which may exist yet. Returns
+/// the paths of the copies.
+pub async fn backup(db: &crate::db::SqlitePool, dest: &str) -> Result<Vec<String>, sqlx::Error> {
+    // The view over the shards would make VACUUM INTO fail, as it recreates
+    // the indexes of the energy_log table
+    let main = db.main_without_view().await?;
+    let mut copies = Vec::new();
+    for (index, database) in db.databases().enumerate() {
+        let database = if index == 0 { &main } else { database };
+        let copy = copy_path(dest, index);
+        sqlx::query!("VACUUM INTO ?", copy).execute(database).await?;
+        copies.push(copy);
+    }
+    Ok(copies)
+}
+
+/// Runs an integrity check on a backup, returning the number of readings in
+/// it if it passes.
+pub async fn verify(copy: &SqlitePool) -> anyhow::Result<i64> {
+    let result: String = sqlx::query_scalar("PRAGMA integrity_check")
+        .fetch_one(copy)
+        .await?;
+    anyhow::ensure!(result == "ok", "integrity check: {}", result);
+
+    let rows = sqlx::query!("SELECT COUNT(*) as count FROM energy_log")
+        .fetch_one(copy)
+        .await?
+        .count;
+    Ok(rows.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Logs a reading of `token` into its database
+    async fn insert_reading(db: &crate::db::SqlitePool, token: &str, amps: f64) {
+        sqlx::query("INSERT INTO energy_log (token, amps, volts, watts) VALUES (?, ?, 230, ?)")
+            .bind(token)
+            .bind(amps)
+            .bind(amps * 230.0)
+            .execute(db.for_token(token))
+            .await
+            .unwrap();
+    }
+
+    #[rocket::async_test]
+    async fn the_backup_has_the_rows_of_the_source() {
+        let dir = crate::testing::TempDir::new("backup");
+        let db = crate::db::SqlitePool::connect(&dir.path("logs.db"), &[]).await.unwrap();
+        db.migrate(&sqlx::migrate!("./migrations")).await.unwrap();
+        let (_, token) = crate::cli::create_token::create_token(&db, "test").await.unwrap();
+        for amps in [1.0, 2.0, 3.0] {
+            insert_reading(&db, &token, amps).await;
+        }
+
+        let dest = dir.path("backup.db");
+        assert_eq!(backup(&db, &dest).await.unwrap(), vec![dest.clone()]);
+        let copy = SqlitePool::connect_with(SqliteConnectOptions::from_str(&dest).unwrap().read_only(true))
+            .await
+            .unwrap();
+        assert_eq!(verify(&copy).await.unwrap(), 3);
+        let tokens: Vec<String> = sqlx::query_scalar("SELECT token FROM tokens")
+            .fetch_all(&copy)
+            .await
+            .unwrap();
+        assert_eq!(tokens, vec![token]);
+
+        // An existing destination is not overwritten
+        assert!(backup(&db, &dest).await.is_err());
+
+        copy.close().await;
+        db.close().await;
+    }
+
+    #[rocket::async_test]
+    async fn each_shard_is_backed_up_next_to_the_main_database() {
+        let dir = crate::testing::TempDir::new("backup-shards");
+        let shards = vec![dir.path("shard-0.db"), dir.path("shard-1.db")];
+        let db = crate::db::SqlitePool::connect(&dir.path("logs.db"), &shards).await.unwrap();
+        db.migrate(&sqlx::migrate!("./migrations")).await.unwrap();
+        for (token, amps) in [("token-a", 1.0), ("token-b", 2.0), ("token-b", 3.0)] {
+            insert_reading(&db, token, amps).await;
+        }
+
+        let dest = dir.path("backup.db");
+        let copies = backup(&db, &dest).await.unwrap();
+        assert_eq!(
+            copies,
+            vec![dest.clone(), format!("{}.shard-0", dest), format!("{}.shard-1", dest)]
+        );
+
+        let mut rows = 0;
+        for copy in &copies {
+            let copy = SqlitePool::connect_with(SqliteConnectOptions::from_str(copy).unwrap().read_only(true))
+                .await
+                .unwrap();
+            rows += verify(&copy).await.unwrap();
+            copy.close().await;
+        }
+        assert_eq!(rows, 3);
+
+        // The copies are a sharded database with the same readings
+        let copy = crate::db::SqlitePool::connect(&copies[0], &copies[1..]).await.unwrap();
+        let amps: Vec<f64> = sqlx::query_scalar("SELECT amps FROM energy_log ORDER BY amps")
+            .fetch_all(&*copy)
+            .await
+            .unwrap();
+        assert_eq!(amps, vec![1.0, 2.0, 3.0]);
+        assert_eq!(copy.index_for_token("token-a"), db.index_for_token("token-a"));
+
+        copy.close().await;
+        db.close().await;
+    }
+}
@@ -2,6 +2,7 @@
 // And makes it so that for every log entry from yesterday or before only the average of each minute is stored in the database
 
 use super::types::DbRow;
+use crate::print_table::CalendarBucket;
 use sqlx::migrate::MigrateDatabase;
 use sqlx::sqlite::SqlitePool;
 use std::collections::hash_map::Entry;
@@ -20,29 +21,60 @@ use std::process;
 /// After this, the consolidated database will contain the same data as the source database, but with
 /// logs consolidated by minute. You can then use the consolidated database for analysis.
 ///
-/// You can delete old contents from the source database after running this script with the following SQL:
+/// Each consolidated row also stores the watt-hours consumed during its minute in the `wh` column,
+/// integrated from the original samples (see [watt_hours]), so energy totals stay accurate even if
+/// the sampling within the minute was uneven.
+///
+/// You can delete old contents from the source database after running this script with the following SQL,
+/// on the main database and on every shard:
 /// ```sql
 /// DELETE FROM energy_log WHERE created_at < strftime('%s', 'now', '-1 day');
 /// VACUUM;
 /// ```
 ///
+/// By default, the logs older than a day are consolidated. With `--tz`, the
+/// logs before the last midnight in that timezone are, so that no local day is
+/// left partially consolidated.
+///
+/// If the source database is sharded, give each of its shards with `--shard`,
+/// in the order of `databases.sqlite_logs.shards`. The logs of every shard are
+/// consolidated into the single consolidated database.
+///
 /// # Usage
 ///
 /// ```sh
-/// cargo run consolidate_logs <sqlite database> <consolidated sqlite database>
+/// cargo run consolidate_logs <sqlite database> <consolidated sqlite database> [--tz <timezone>] [--shard <path>]...
 /// ```
 pub async fn consolidate_logs_cli() -> () {
     let args: Vec<String> = env::args().collect();
-    if args.len() != 4 {
+    let usage = || -> ! {
         eprintln!(
-            "Usage: {} consolidate_logs <sqlite database> <consolidated sqlite database>",
+            "Usage: {} consolidate_logs <sqlite database> <consolidated sqlite database> [--tz <timezone>] [--shard <path>]...",
             args[0]
         );
         process::exit(1);
+    };
+    let [db_path, db_consolidated_path, options @ ..] = args.get(2..).unwrap_or_default() else {
+        usage();
+    };
+    let mut tz = None;
+    let mut shards = Vec::new();
+    for option in options.chunks(2) {
+        match option {
+            [flag, value] if flag == "--tz" => match value.parse::<chrono_tz::Tz>() {
+                Ok(value) => tz = Some(value),
+                Err(e) => {
+                    eprintln!("Error: invalid timezone {:?}: {}", value, e);
+                    process::exit(1);
+                }
+            },
+            [flag, path] if flag == "--shard" => shards.push(path.clone()),
+            _ => usage(),
+        }
     }
 
-    let db_path = Path::new(&args[2]);
-    let db_consolidated_path = Path::new(&args[3]);
+    let db_path = Path::new(db_path);
+    let db_consolidated_path = Path::new(db_consolidated_path);
 
     if !db_path.exists() {
         eprintln!("Error: {} does not exist", db_path.display());
@@ -57,7 +89,7 @@ pub async fn consolidate_logs_cli() -> () {
             "Error: {} does not exist yet. Creating.",
             db_consolidated_path.display()
         );
-        sqlx::Sqlite::create_database(&db_consolidated_path.to_str().unwrap())
+        sqlx::Sqlite::create_database(db_consolidated_path.to_str().unwrap())
             .await
             .unwrap();
     }
@@ -72,13 +104,36 @@ pub async fn consolidate_logs_cli() -> () {
         .unwrap();
     eprintln!("Migrations complete. Database ready to use.");
 
-    let db = SqlitePool::connect(db_path.to_str().unwrap()).await.unwrap();
+    for shard in &shards {
+        if !Path::new(shard).exists() {
+            eprintln!("Error: {} does not exist", shard);
+            process::exit(1);
+        }
+    }
+    // Reads the logs of every shard through the energy_log view
+    let db = crate::db::SqlitePool::connect(db_path.to_str().unwrap(), &shards)
+        .await
+        .unwrap();
 
     ensure_users_and_tokens_exist(&db, &db_consolidated)
         .await
         .expect("Error ensuring users and tokens exist");
 
-    consolidate_logs(&db, &db_consolidated).await;
+    let cutoff = consolidation_cutoff(chrono::Utc::now(), tz.as_ref());
+    eprintln!("Consolidating the logs before {}", cutoff);
+    consolidate_logs(&db, &db_consolidated, cutoff).await;
+}
+
+/// Returns the instant before which the logs are consolidated: the last
+/// midnight in `tz`, or a day ago if no timezone is given.
+pub fn consolidation_cutoff(
+    now: chrono::DateTime<chrono::Utc>,
+    tz: Option<&chrono_tz::Tz>,
+) -> chrono::DateTime<chrono::Utc> {
+    match tz {
+        Some(tz) => CalendarBucket::Day.start_of(&now, tz),
+        None => now - chrono::Duration::days(1),
+    }
 }
 
 async fn ensure_users_and_tokens_exist(
@@ -92,7 +147,7 @@ async fn ensure_users_and_tokens_exist(
         .fetch_all(db_consolidated)
         .await?
         .iter()
-        .map(|row| row.id.clone())
+        .map(|row| row.id)
         .collect::<Vec<i64>>();
 
     for user in users {
@@ -131,11 +186,14 @@ async fn ensure_users_and_tokens_exist(
     Ok(())
 }
 
-async fn consolidate_logs(db: &SqlitePool, db_consolidated: &SqlitePool) {
-    let now = chrono::Utc::now();
-    let yesterday = now - chrono::Duration::days(1);
-
-    let old_logs: Vec<DbRow> = sqlx::query!("SELECT token, amps, volts, watts, created_at, user_agent, client_ip FROM energy_log WHERE created_at < ?", yesterday)
+async fn consolidate_logs(
+    db: &SqlitePool,
+    db_consolidated: &SqlitePool,
+    cutoff: chrono::DateTime<chrono::Utc>,
+) {
+    // Stored as naive UTC text, so compare against the same format
+    let cutoff = cutoff.naive_utc();
+    let old_logs: Vec<DbRow> = sqlx::query!("SELECT token, amps, volts, watts, created_at, user_agent, client_ip FROM energy_log WHERE created_at < ?", cutoff)
         .fetch_all(db)
         .await
         .unwrap().iter().map(|row| DbRow::new(
@@ -175,7 +233,10 @@ async fn consolidate_logs(db: &SqlitePool, db_consolidated: &SqlitePool) {
         .await
         .unwrap();
 
-    for (minute, rows) in map {
+    for (minute, mut rows) in map {
+        // Integrate the energy before averaging the samples away
+        let wh = watt_hours(&mut rows, (minute + 1) * 60);
+
         // Calculate the "average row"
         let rows_len = rows.len();
         let sum_rows: DbRow = rows.into_iter().sum();
@@ -184,11 +245,12 @@ async fn consolidate_logs(db: &SqlitePool, db_consolidated: &SqlitePool) {
         // Insert the average row into the database
         let created_at = chrono::DateTime::<chrono::Utc>::from_timestamp(minute * 60, 0);
         let result = sqlx::query!(
-            "INSERT INTO energy_log (token, amps, volts, watts, created_at, user_agent, client_ip) VALUES (?, ?, ?, ?, ?, ?, ?)",
+            "INSERT INTO energy_log (token, amps, volts, watts, wh, created_at, user_agent, client_ip, source) VALUES (?, ?, ?, ?, ?, ?, ?, ?, 'consolidate_logs')",
             avg_row.token,
             avg_row.amps,
             avg_row.volts,
             avg_row.watts,
+            wh,
             created_at,
             "amp-consolidate-logs",
             avg_row.client_ip,
@@ -239,3 +301,126 @@ async fn consolidate_logs(db: &SqlitePool, db_consolidated: &SqlitePool) {
             .count
     );
 }
+
+/// Integrate the watts of the samples of a bucket into watt-hours.
+///
+/// Each sample is considered to hold its reading until the next sample arrives,
+/// and the last one until the end of the bucket (`bucket_end`, as a UNIX
+/// timestamp). This way unevenly spaced samples are weighted by the time they
+/// actually represent, instead of counting equally as in an average.
+///
+/// The rows are sorted by their timestamp in place.
+fn watt_hours(rows: &mut [DbRow], bucket_end: i64) -> f64 {
+    rows.sort_by_key(|row| row.created_at);
+
+    let ends = rows
+        .iter()
+        .skip(1)
+        .map(|row| row.created_at.timestamp())
+        .chain(std::iter::once(bucket_end));
+
+    rows.iter()
+        .zip(ends)
+        .map(|(row, end)| row.watts * (end - row.created_at.timestamp()) as f64 / 3600.0)
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(created_at: &str, watts: f64) -> DbRow {
+        let created_at =
+            chrono::NaiveDateTime::parse_from_str(created_at, "%Y-%m-%d %H:%M:%S").unwrap();
+        DbRow::new("token".to_string(), watts / 230.0, 230.0, watts, created_at, &None, &None)
+    }
+
+    #[test]
+    fn integrates_unevenly_spaced_samples() {
+        // Out of order, and each holding its reading for 10, 30 and 20 seconds
+        let mut rows = vec![
+            row("2026-10-16 10:00:10", 1200.0),
+            row("2026-10-16 10:00:00", 600.0),
+            row("2026-10-16 10:00:40", 300.0),
+        ];
+        let bucket_end = row("2026-10-16 10:01:00", 0.0).created_at.timestamp();
+
+        let wh = watt_hours(&mut rows, bucket_end);
+
+        // 600 W * 10 s + 1200 W * 30 s + 300 W * 20 s, while the average (700 W
+        // over the minute) would give 11.67 Wh
+        let expected = (600.0 * 10.0 + 1200.0 * 30.0 + 300.0 * 20.0) / 3600.0;
+        assert!((wh - expected).abs() < 1e-9, "{} != {}", wh, expected);
+        assert!((wh - 13.333).abs() < 1e-3);
+    }
+
+    #[test]
+    fn a_single_sample_holds_until_the_end_of_the_bucket() {
+        let mut rows = vec![row("2026-10-16 10:00:30", 3600.0)];
+        let bucket_end = row("2026-10-16 10:01:00", 0.0).created_at.timestamp();
+
+        assert_eq!(watt_hours(&mut rows, bucket_end), 30.0);
+    }
+
+    #[rocket::async_test]
+    async fn the_logs_of_every_shard_are_consolidated() {
+        let dir = crate::testing::TempDir::new("consolidate-shards");
+        let shards = vec![dir.path("shard-0.db"), dir.path("shard-1.db")];
+        let db = crate::db::SqlitePool::connect(&dir.path("logs.db"), &shards).await.unwrap();
+        db.migrate(&sqlx::migrate!("./migrations")).await.unwrap();
+        let consolidated = SqlitePool::connect(&format!("{}?mode=rwc", dir.path("consolidated.db")))
+            .await
+            .unwrap();
+        sqlx::migrate!("./migrations").run(&consolidated).await.unwrap();
+
+        let (_, first) = crate::cli::create_token::create_token(&db, "home").await.unwrap();
+        let (_, second) = crate::cli::create_token::create_token(&db, "garage").await.unwrap();
+        let insert = "INSERT INTO main.energy_log (token, amps, volts, watts, created_at) VALUES (?, 1, 230, 230, ?)";
+        // Logged before sharding, and into the shard of each token
+        sqlx::query(insert).bind(&first).bind("2024-01-01 10:00:00").execute(&*db).await.unwrap();
+        for (token, created_at) in [(&first, "2024-01-01 10:00:30"), (&second, "2024-01-01 10:05:00")] {
+            sqlx::query(insert)
+                .bind(token)
+                .bind(created_at)
+                .execute(db.for_token(token))
+                .await
+                .unwrap();
+        }
+
+        ensure_users_and_tokens_exist(&db, &consolidated).await.unwrap();
+        let cutoff = chrono::DateTime::parse_from_rfc3339("2024-01-02T00:00:00Z").unwrap().to_utc();
+        consolidate_logs(&db, &consolidated, cutoff).await;
+
+        let mut rows: Vec<(String, f64)> =
+            sqlx::query_as("SELECT token, wh FROM energy_log WHERE source = 'consolidate_logs'")
+                .fetch_all(&consolidated)
+                .await
+                .unwrap();
+        rows.sort_by(|a, b| a.0.cmp(&b.0));
+        let mut expected = vec![(first, 230.0 / 60.0), (second, 230.0 / 60.0)];
+        expected.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(rows, expected);
+
+        consolidated.close().await;
+        db.close().await;
+    }
+
+    #[test]
+    fn the_cutoff_is_the_last_local_midnight() {
+        let at = |datetime: &str| chrono::DateTime::parse_from_rfc3339(datetime).unwrap().to_utc();
+        let now = at("2024-07-10T21:30:00Z");
+
+        // 23:30 in Madrid, at UTC+2 in summer
+        let madrid: chrono_tz::Tz = "Europe/Madrid".parse().unwrap();
+        let cutoff = consolidation_cutoff(now, Some(&madrid));
+        assert_eq!(cutoff, at("2024-07-09T22:00:00Z"));
+        assert_eq!(cutoff.with_timezone(&madrid).to_rfc3339(), "2024-07-10T00:00:00+02:00");
+
+        // Already the next day in Tokyo, at UTC+9
+        let tokyo: chrono_tz::Tz = "Asia/Tokyo".parse().unwrap();
+        assert_eq!(consolidation_cutoff(now, Some(&tokyo)), at("2024-07-10T15:00:00Z"));
+
+        // A day ago without a timezone
+        assert_eq!(consolidation_cutoff(now, None), at("2024-07-09T21:30:00Z"));
+    }
+}
@@ -0,0 +1,120 @@
+// Provisions a new sensor by creating a token for a location, without the
+// HTTP admin routes
+
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePool};
+use std::env;
+use std::process;
+use std::str::FromStr;
+
+use crate::token::generate_token;
+
+/// Create a sensor token for a location in the configured database.
+///
+/// The database is the `databases.sqlite_logs.url` from the figment
+/// configuration (Rocket.toml), the same one the server uses. The user for the
+/// location is created if no user has that location yet, and the new token is
+/// printed to stdout.
+///
+/// # Usage
+///
+/// ```sh
+/// cargo run create-token <location>
+/// ```
+pub async fn create_token_cli() -> () {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 3 {
+        eprintln!("Usage: {} create-token <location>", args[0]);
+        process::exit(1);
+    }
+    let location = &args[2];
+
+    let url: String = match rocket::Config::figment().extract_inner("databases.sqlite_logs.url") {
+        Ok(url) => url,
+        Err(e) => {
+            eprintln!("Error: no database configured: {}", e);
+            process::exit(1);
+        }
+    };
+    let options = SqliteConnectOptions::from_str(&url)
+        .unwrap()
+        .create_if_missing(true);
+    let db = SqlitePool::connect_with(options).await.unwrap();
+
+    eprintln!("Ensuring migrations are up to date");
+    sqlx::migrate!("./migrations").run(&db).await.unwrap();
+
+    let (user_id, token) = create_token(&db, location)
+        .await
+        .expect("Error creating the token");
+    eprintln!("Created token for user {} ({})", user_id, location);
+    println!("{}", token);
+}
+
+/// Creates a new sensor token for the location, creating its user if needed.
+///
+/// Returns the user id and the token.
+pub async fn create_token(db: &SqlitePool, location: &str) -> Result<(i64, String), sqlx::Error> {
+    let mut tx = db.begin().await?;
+
+    let existing = sqlx::query!("SELECT id FROM users WHERE location = ? ORDER BY id LIMIT 1", location)
+        .fetch_optional(&mut *tx)
+        .await?;
+    let user_id = match existing {
+        Some(user) => user.id,
+        None => sqlx::query!("INSERT INTO users (location) VALUES (?)", location)
+            .execute(&mut *tx)
+            .await?
+            .last_insert_rowid(),
+    };
+
+    let token = generate_token();
+    sqlx::query!(
+        "INSERT INTO tokens (token, user_id) VALUES (?, ?)",
+        token,
+        user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+    Ok((user_id, token))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use rocket::http::{ContentType, Status};
+
+    #[rocket::async_test]
+    async fn created_tokens_validate() {
+        let dir = testing::TempDir::new("create-token");
+        let url = format!("sqlite:{}", dir.path("logs.db"));
+        let options = SqliteConnectOptions::from_str(&url)
+            .unwrap()
+            .create_if_missing(true);
+        let db = SqlitePool::connect_with(options).await.unwrap();
+        sqlx::migrate!("./migrations").run(&db).await.unwrap();
+
+        let (user_id, token) = create_token(&db, "garage").await.unwrap();
+        let (same_user_id, other_token) = create_token(&db, "garage").await.unwrap();
+        assert_eq!(user_id, same_user_id);
+        assert_ne!(token, other_token);
+        db.close().await;
+
+        let app = testing::client_with(
+            testing::figment()
+                .merge(("databases.sqlite_logs.url", &url))
+                .merge(("databases.sqlite_logs_read.url", &url)),
+        )
+        .await;
+        let status = app
+            .post(format!("/log/{}", token))
+            .header(ContentType::JSON)
+            .body(r#"{"amps": 2.5, "volts": 230, "watts": 575}"#)
+            .dispatch()
+            .await
+            .status();
+        assert_eq!(status, Status::Ok);
+    }
+}
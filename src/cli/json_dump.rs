@@ -0,0 +1,473 @@
+// Dumps the whole database as JSON, and loads such a dump into a fresh
+// database, to migrate the data to another server or storage
+
+use rocket::futures::TryStreamExt;
+use serde::{Deserialize, Serialize};
+use sqlx::sqlite::SqlitePool;
+use std::env;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::process;
+
+/// The version of the dump format, written in its header
+const DUMP_VERSION: u32 = 1;
+
+/// One line of the dump.
+///
+/// The dump is a JSON Lines file: a [Record::Header] first, and then one JSON
+/// object per row, tagged with its table, e.g.:
+///
+/// ```json
+/// {"table":"header","version":1}
+/// {"table":"users","id":1,"location":"default"}
+/// {"table":"tokens","token":"...","user_id":1}
+/// {"table":"energy_log","id":1,"token":"...","amps":3.2,"volts":230.0,...}
+/// ```
+///
+/// The tables are written in the order of their foreign keys, so that the
+/// dump can be loaded one line at a time without holding it in memory.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "table", rename_all = "snake_case")]
+enum Record {
+    Header { version: u32 },
+    Users(User),
+    Tokens(Token),
+    TokenAliases(TokenAlias),
+    ViewTokens(ViewToken),
+    EnergyLog(Reading),
+}
+
+#[derive(Serialize, Deserialize)]
+struct User {
+    id: i64,
+    location: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Token {
+    token: String,
+    user_id: i64,
+    // Missing in the dumps made before the default intervals were stored
+    #[serde(default)]
+    default_interval_secs: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct TokenAlias {
+    alias: String,
+    token: String,
+    created_at: chrono::NaiveDateTime,
+}
+
+/// A view token. Its `id` is not kept, as the column is not an alias of the
+/// rowid and nothing refers to it.
+#[derive(Serialize, Deserialize)]
+struct ViewToken {
+    token: String,
+    user_id: i64,
+    view_token_valid_until: Option<chrono::NaiveDateTime>,
+    created_at: chrono::NaiveDateTime,
+    last_accessed_at: chrono::NaiveDateTime,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Reading {
+    id: i64,
+    token: String,
+    amps: f64,
+    volts: f64,
+    watts: f64,
+    created_at: chrono::NaiveDateTime,
+    user_agent: Option<String>,
+    client_ip: Option<String>,
+    wh: Option<f64>,
+    temperature_c: Option<f64>,
+    power_factor: Option<f64>,
+    source: Option<String>,
+    flags: i64,
+}
+
+/// Connects to the `databases.sqlite_logs` database from the figment
+/// configuration (Rocket.toml), and its shards, the same databases the server
+/// uses. Unless `create` is set, they are opened read-only, and must exist.
+async fn connect_configured(create: bool) -> crate::db::SqlitePool {
+    let figment = rocket::Config::figment().merge(("databases.sqlite_logs.read_only", !create));
+    match crate::db::SqlitePool::connect_configured(&figment).await {
+        Ok(db) => db,
+        Err(e) => {
+            eprintln!("Error: cannot open the configured database: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+/// Dump the users, tokens, token aliases, view tokens and readings of the
+/// configured database into `dest`, as JSON Lines (see [Record]). The readings
+/// of every shard are dumped along with those of the main database.
+///
+/// The readings are streamed from the database into the file, so the dump
+/// does not need to fit in memory. The destination is not overwritten unless
+/// `--force` is given.
+///
+/// # Usage
+///
+/// ```sh
+/// cargo run export-json <destination> [--force]
+/// ```
+pub async fn export_json_cli() -> () {
+    let args: Vec<String> = env::args().collect();
+    let (dest, force) = match args.get(2..).unwrap_or_default() {
+        [dest] => (dest, false),
+        [dest, flag] if flag == "--force" => (dest, true),
+        _ => {
+            eprintln!("Usage: {} export-json <destination> [--force]", args[0]);
+            process::exit(1);
+        }
+    };
+
+    let dest_path = Path::new(dest);
+    if dest_path.exists() && !force {
+        eprintln!("Error: {} already exists, use --force to overwrite it", dest_path.display());
+        process::exit(1);
+    }
+
+    let db = connect_configured(false).await;
+    let mut out = BufWriter::new(File::create(dest_path).unwrap());
+    match export(&db, &mut out).await {
+        Ok(rows) => eprintln!("Exported {} rows into {}", rows, dest_path.display()),
+        Err(e) => {
+            eprintln!("Error: export failed: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+/// Writes one record as a line of the dump
+fn write_record(out: &mut impl Write, record: &Record) -> anyhow::Result<()> {
+    serde_json::to_writer(&mut *out, record)?;
+    out.write_all(b"\n")?;
+    Ok(())
+}
+
+/// Writes every row of the database into `out`, returning how many rows were
+/// written.
+pub async fn export(db: &SqlitePool, out: &mut impl Write) -> anyhow::Result<u64> {
+    // A single transaction, so the dump is a consistent snapshot even if the
+    // server keeps logging readings meanwhile
+    let mut tx = db.begin().await?;
+    let mut rows = 0;
+    write_record(out, &Record::Header { version: DUMP_VERSION })?;
+
+    let mut users = sqlx::query_as!(User, "SELECT id, location FROM users ORDER BY id").fetch(&mut *tx);
+    while let Some(user) = users.try_next().await? {
+        write_record(out, &Record::Users(user))?;
+        rows += 1;
+    }
+    drop(users);
+
+    let mut tokens =
+        sqlx::query_as!(
+        Token,
+        "SELECT token, user_id, default_interval_secs FROM tokens ORDER BY token"
+    ).fetch(&mut *tx);
+    while let Some(token) = tokens.try_next().await? {
+        write_record(out, &Record::Tokens(token))?;
+        rows += 1;
+    }
+    drop(tokens);
+
+    let mut aliases = sqlx::query_as!(
+        TokenAlias,
+        "SELECT alias, token, created_at FROM token_aliases ORDER BY alias"
+    )
+    .fetch(&mut *tx);
+    while let Some(alias) = aliases.try_next().await? {
+        write_record(out, &Record::TokenAliases(alias))?;
+        rows += 1;
+    }
+    drop(aliases);
+
+    let mut view_tokens = sqlx::query_as!(
+        ViewToken,
+        r#"SELECT token, user_id, view_token_valid_until as "view_token_valid_until?: chrono::NaiveDateTime", created_at, last_accessed_at FROM view_tokens ORDER BY rowid"#
+    )
+    .fetch(&mut *tx);
+    while let Some(view_token) = view_tokens.try_next().await? {
+        write_record(out, &Record::ViewTokens(view_token))?;
+        rows += 1;
+    }
+    drop(view_tokens);
+
+    let mut readings = sqlx::query_as!(
+        Reading,
+        "SELECT id, token, amps, volts, watts, created_at, user_agent, client_ip, wh, temperature_c, power_factor, source, flags FROM energy_log ORDER BY id"
+    )
+    .fetch(&mut *tx);
+    while let Some(reading) = readings.try_next().await? {
+        write_record(out, &Record::EnergyLog(reading))?;
+        rows += 1;
+    }
+    drop(readings);
+
+    out.flush()?;
+    tx.commit().await?;
+    Ok(rows)
+}
+
+/// Load a dump made with `export-json` into the configured database.
+///
+/// The database is created and migrated if needed, and must not have any
+/// tokens or readings yet. The seeded `default` user of a fresh database is
+/// replaced by the users in the dump. The whole load is a single transaction
+/// on each database, so a failed import leaves them as they were.
+///
+/// If the database is sharded, each reading is loaded into the shard of its
+/// token. The readings keep their ids, so load a dump of a sharded database
+/// into the same number of shards, as the ids of each shard start at its own
+/// offset (see [migrate](crate::db::SqlitePool::migrate)).
+///
+/// # Usage
+///
+/// ```sh
+/// cargo run import-json <dump>
+/// ```
+pub async fn import_json_cli() -> () {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 3 {
+        eprintln!("Usage: {} import-json <dump>", args[0]);
+        process::exit(1);
+    }
+    let source = match File::open(&args[2]) {
+        Ok(file) => BufReader::new(file),
+        Err(e) => {
+            eprintln!("Error: cannot open {}: {}", args[2], e);
+            process::exit(1);
+        }
+    };
+
+    let db = connect_configured(true).await;
+    eprintln!("Ensuring migrations are up to date");
+    db.migrate(&sqlx::migrate!("./migrations")).await.unwrap();
+
+    match import(&db, source).await {
+        Ok(rows) => eprintln!("Imported {} rows from {}", rows, args[2]),
+        Err(e) => {
+            eprintln!("Error: import failed: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+/// Loads every record of the dump into the database, which must be fresh,
+/// returning how many rows were inserted.
+pub async fn import(db: &crate::db::SqlitePool, source: impl BufRead) -> anyhow::Result<u64> {
+    let mut tx = db.begin().await?;
+    // The readings go into the shard of their token, in a transaction of
+    // its own that commits along with the main one
+    let mut shards = Vec::new();
+    for shard in db.databases().skip(1) {
+        shards.push(shard.begin().await?);
+    }
+    let existing = sqlx::query_scalar!(
+        "SELECT (SELECT COUNT(*) FROM tokens) + (SELECT COUNT(*) FROM energy_log)"
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    anyhow::ensure!(
+        existing == Some(0),
+        "the database already has tokens or readings, import into a fresh one"
+    );
+    sqlx::query!("DELETE FROM view_tokens").execute(&mut *tx).await?;
+    sqlx::query!("DELETE FROM users").execute(&mut *tx).await?;
+
+    let mut rows = 0;
+    for (number, line) in source.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: Record = serde_json::from_str(&line)
+            .map_err(|e| anyhow::anyhow!("line {}: {}", number + 1, e))?;
+        match record {
+            Record::Header { version } => {
+                anyhow::ensure!(
+                    version == DUMP_VERSION,
+                    "unsupported dump version {}, expected {}",
+                    version,
+                    DUMP_VERSION
+                );
+                continue;
+            }
+            Record::Users(user) => {
+                sqlx::query!("INSERT INTO users (id, location) VALUES (?, ?)", user.id, user.location)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            Record::Tokens(token) => {
+                sqlx::query!(
+                    "INSERT INTO tokens (token, user_id, default_interval_secs) VALUES (?, ?, ?)",
+                    token.token,
+                    token.user_id,
+                    token.default_interval_secs
+                )
+                .execute(&mut *tx)
+                .await?;
+            }
+            Record::TokenAliases(alias) => {
+                sqlx::query!(
+                    "INSERT INTO token_aliases (alias, token, created_at) VALUES (?, ?, ?)",
+                    alias.alias,
+                    alias.token,
+                    alias.created_at
+                )
+                .execute(&mut *tx)
+                .await?;
+            }
+            Record::ViewTokens(view_token) => {
+                sqlx::query!(
+                    "INSERT INTO view_tokens (token, user_id, view_token_valid_until, created_at, last_accessed_at) VALUES (?, ?, ?, ?, ?)",
+                    view_token.token,
+                    view_token.user_id,
+                    view_token.view_token_valid_until,
+                    view_token.created_at,
+                    view_token.last_accessed_at
+                )
+                .execute(&mut *tx)
+                .await?;
+            }
+            Record::EnergyLog(reading) => {
+                let shard = match db.index_for_token(&reading.token) {
+                    0 => &mut tx,
+                    index => &mut shards[index - 1],
+                };
+                sqlx::query!(
+                    "INSERT INTO energy_log (id, token, amps, volts, watts, created_at, user_agent, client_ip, wh, temperature_c, power_factor, source, flags) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+                    reading.id,
+                    reading.token,
+                    reading.amps,
+                    reading.volts,
+                    reading.watts,
+                    reading.created_at,
+                    reading.user_agent,
+                    reading.client_ip,
+                    reading.wh,
+                    reading.temperature_c,
+                    reading.power_factor,
+                    reading.source,
+                    reading.flags
+                )
+                .execute(&mut **shard)
+                .await?;
+            }
+        }
+        rows += 1;
+    }
+
+    for shard in shards {
+        shard.commit().await?;
+    }
+    tx.commit().await?;
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    /// A fresh, migrated database in the directory, with the given shards
+    async fn fresh_database(dir: &testing::TempDir, name: &str, shards: &[&str]) -> crate::db::SqlitePool {
+        let shards: Vec<String> = shards.iter().map(|shard| dir.path(shard)).collect();
+        let db = crate::db::SqlitePool::connect(&dir.path(name), &shards).await.unwrap();
+        db.migrate(&sqlx::migrate!("./migrations")).await.unwrap();
+        db
+    }
+
+    #[rocket::async_test]
+    async fn a_dump_is_imported_as_it_was_exported() {
+        let app = testing::client().await;
+        let old_token = app.create_token(testing::LOCATION).await;
+        let garage = app.create_token("garage").await;
+        sqlx::query("INSERT INTO token_aliases (alias, token) VALUES (?, ?)")
+            .bind(&old_token)
+            .bind(&app.token)
+            .execute(app.db())
+            .await
+            .unwrap();
+        app.insert_reading("2024-01-01 10:00:00", 10.0, 230.0, 2300.0).await;
+        app.insert_reading("2024-01-01 10:01:00", 8.5, 231.0, 1963.5).await;
+        app.insert_reading_for(&garage, "2024-01-01 10:00:30", 2.0, 229.0, 458.0).await;
+        sqlx::query("UPDATE energy_log SET wh = 38.3, source = 'consolidated' WHERE amps = 10")
+            .execute(app.db())
+            .await
+            .unwrap();
+
+        let mut dump = Vec::new();
+        let exported = export(app.db(), &mut dump).await.unwrap();
+        // The users and view tokens, 3 tokens, an alias and 3 readings
+        let users_and_view_tokens: i64 = sqlx::query_scalar(
+            "SELECT (SELECT COUNT(*) FROM users) + (SELECT COUNT(*) FROM view_tokens)",
+        )
+        .fetch_one(app.db())
+        .await
+        .unwrap();
+        assert_eq!(exported, users_and_view_tokens as u64 + 3 + 1 + 3);
+
+        let dir = testing::TempDir::new("json-dump");
+        let copy = fresh_database(&dir, "copy.db", &[]).await;
+        assert_eq!(import(&copy, dump.as_slice()).await.unwrap(), exported);
+
+        let mut copy_dump = Vec::new();
+        export(&copy, &mut copy_dump).await.unwrap();
+        assert_eq!(String::from_utf8(copy_dump).unwrap(), String::from_utf8(dump.clone()).unwrap());
+
+        // The copy is no longer fresh
+        assert!(import(&copy, dump.as_slice()).await.is_err());
+        copy.close().await;
+    }
+
+    #[rocket::async_test]
+    async fn a_dump_is_loaded_into_the_shards_of_its_tokens() {
+        let app = testing::client().await;
+        let garage = app.create_token("garage").await;
+        app.insert_reading("2024-01-01 10:00:00", 10.0, 230.0, 2300.0).await;
+        app.insert_reading_for(&garage, "2024-01-01 10:00:30", 2.0, 229.0, 458.0).await;
+        let mut dump = Vec::new();
+        export(app.db(), &mut dump).await.unwrap();
+
+        let dir = testing::TempDir::new("json-dump-shards");
+        let copy = fresh_database(&dir, "copy.db", &["shard-0.db", "shard-1.db"]).await;
+        import(&copy, dump.as_slice()).await.unwrap();
+        for token in [&app.token, &garage] {
+            let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM energy_log WHERE token = ?")
+                .bind(token)
+                .fetch_one(copy.for_token(token))
+                .await
+                .unwrap();
+            assert_eq!(count, 1);
+        }
+        copy.close().await;
+
+        // The export reads every shard, from the configured databases
+        let figment = rocket::figment::Figment::new()
+            .merge(("databases.sqlite_logs.url", dir.path("copy.db")))
+            .merge(("databases.sqlite_logs.shards", [dir.path("shard-0.db"), dir.path("shard-1.db")]))
+            .merge(("databases.sqlite_logs.read_only", true));
+        let copy = crate::db::SqlitePool::connect_configured(&figment).await.unwrap();
+        let mut copy_dump = Vec::new();
+        export(&copy, &mut copy_dump).await.unwrap();
+        assert_eq!(String::from_utf8(copy_dump).unwrap(), String::from_utf8(dump).unwrap());
+        copy.close().await;
+    }
+
+    #[rocket::async_test]
+    async fn a_dump_of_another_version_is_refused() {
+        let dir = testing::TempDir::new("json-dump-version");
+        let db = fresh_database(&dir, "logs.db", &[]).await;
+
+        let dump = format!("{{\"table\":\"header\",\"version\":{}}}\n", DUMP_VERSION + 1);
+        assert!(import(&db, dump.as_bytes()).await.is_err());
+        db.close().await;
+    }
+}
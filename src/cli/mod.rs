@@ -1,2 +1,5 @@
+pub(crate) mod backup;
 pub(crate) mod consolidate_logs;
+pub(crate) mod create_token;
+pub(crate) mod json_dump;
 mod types;
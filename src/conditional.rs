@@ -0,0 +1,290 @@
+//! Conditional requests for the read routes, to let dashboards poll cheaply.
+//!
+//! The responses carry an `ETag` derived from the request URI and the time of
+//! the most recent reading for the view token, and a `Last-Modified` with that
+//! same time. If a client sends back a matching `If-None-Match` (or, lacking
+//! it, an `If-Modified-Since` that is not older than the last reading), the
+//! route answers with a 304 Not Modified instead of running the full query.
+//!
+//! Note that the freshness only depends on the newest reading, so a relative
+//! range (e.g., the last 24 hours by default) is considered unchanged until a
+//! new reading arrives, or until the data becomes stale.
+//!
+//! The data is stale when the newest reading is older than `stale_data_secs`
+//! (15 minutes by default) in the figment configuration (Rocket.toml), e.g.,
+//! because the sensor is dead. The read routes then point it out, so the last
+//! flat line of a plot is not mistaken for current data.
+
+use std::hash::{Hash, Hasher};
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use rocket::http::{Header, Status};
+use rocket_db_pools::Connection;
+
+use crate::token::ValidViewToken;
+
+/// The date format for the `Last-Modified` and `If-Modified-Since` headers
+const HTTP_DATE_FORMAT: &str = "%a, %d %b %Y %H:%M:%S GMT";
+
+/// The age after which the data is stale, unless configured otherwise
+const DEFAULT_STALE_DATA_SECS: i64 = 900;
+
+/// Request guard with the conditional headers sent by the client
+pub struct Conditional {
+    uri: String,
+    if_none_match: Option<String>,
+    if_modified_since: Option<DateTime<Utc>>,
+    stale_data_secs: i64,
+}
+
+#[rocket::async_trait]
+impl<'r> rocket::request::FromRequest<'r> for Conditional {
+    type Error = ();
+
+    async fn from_request(
+        request: &'r rocket::Request<'_>,
+    ) -> rocket::request::Outcome<Self, Self::Error> {
+        let headers = request.headers();
+        let if_modified_since = headers
+            .get_one("If-Modified-Since")
+            .and_then(|value| NaiveDateTime::parse_from_str(value, HTTP_DATE_FORMAT).ok())
+            .map(|dt| dt.and_utc());
+
+        let stale_data_secs = request
+            .rocket()
+            .figment()
+            .extract_inner("stale_data_secs")
+            .unwrap_or(DEFAULT_STALE_DATA_SECS);
+
+        rocket::request::Outcome::Success(Conditional {
+            uri: request.uri().to_string(),
+            if_none_match: headers.get_one("If-None-Match").map(str::to_string),
+            if_modified_since,
+            stale_data_secs,
+        })
+    }
+}
+
+impl Conditional {
+    /// Computes the freshness of the data for the view token, with a single
+    /// `MAX(created_at)` query, and checks it against the client headers.
+    pub async fn freshness(
+        &self,
+        db: &mut Connection<crate::LogsRead>,
+        token: &ValidViewToken,
+    ) -> Freshness {
+        let last_modified = sqlx::query!(
+            "SELECT MAX(created_at) as \"last_modified: NaiveDateTime\" FROM energy_log
+            WHERE token IN (
+                SELECT token FROM view_token_sensors
+                WHERE view_token = ?
+            )",
+            token
+        )
+        .fetch_one(&mut ***db)
+        .await
+        .unwrap()
+        .last_modified
+        .map(|dt| dt.and_utc());
+        let data_age_secs = last_modified.map(|last| (Utc::now() - last).num_seconds().max(0));
+        let stale = data_age_secs.is_some_and(|age| age > self.stale_data_secs);
+
+        // The response changes when the data becomes stale, even without new
+        // readings
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        self.uri.hash(&mut hasher);
+        last_modified.hash(&mut hasher);
+        stale.hash(&mut hasher);
+        let etag = format!("\"{:016x}\"", hasher.finish());
+
+        let not_modified = match (&self.if_none_match, self.if_modified_since) {
+            (Some(if_none_match), _) => if_none_match
+                .split(',')
+                .map(|tag| tag.trim().trim_start_matches("W/"))
+                .any(|tag| tag == "*" || tag == etag),
+            (None, Some(since)) => last_modified.is_some_and(|last| last.timestamp() <= since.timestamp()),
+            (None, None) => false,
+        };
+
+        Freshness {
+            etag,
+            last_modified,
+            not_modified,
+            data_age_secs,
+            stale,
+        }
+    }
+}
+
+/// The validators for a response, and whether the client already has it
+pub struct Freshness {
+    etag: String,
+    last_modified: Option<DateTime<Utc>>,
+    not_modified: bool,
+    data_age_secs: Option<i64>,
+    stale: bool,
+}
+
+impl Freshness {
+    /// Returns true if the client copy is still fresh, so the route can answer
+    /// with [Cached::NotModified] without building the response.
+    pub fn is_not_modified(&self) -> bool {
+        self.not_modified
+    }
+
+    /// Returns the seconds elapsed since the most recent reading, if any
+    pub fn data_age_secs(&self) -> Option<i64> {
+        self.data_age_secs
+    }
+
+    /// Returns the age of the most recent reading if it is older than the
+    /// configured `stale_data_secs`
+    pub fn stale_age_secs(&self) -> Option<i64> {
+        self.data_age_secs.filter(|_| self.stale)
+    }
+}
+
+/// Responder that adds the `ETag` and `Last-Modified` headers to the inner
+/// response, or answers with an empty 304 Not Modified.
+pub enum Cached<R> {
+    Fresh(Freshness, R),
+    NotModified(Freshness),
+}
+
+impl<'r, 'o: 'r, R: rocket::response::Responder<'r, 'o>> rocket::response::Responder<'r, 'o>
+    for Cached<R>
+{
+    fn respond_to(self, request: &'r rocket::Request<'_>) -> rocket::response::Result<'o> {
+        let (freshness, mut response) = match self {
+            Cached::Fresh(freshness, inner) => (freshness, inner.respond_to(request)?),
+            Cached::NotModified(freshness) => (
+                freshness,
+                rocket::Response::build().status(Status::NotModified).finalize(),
+            ),
+        };
+
+        response.set_header(Header::new("ETag", freshness.etag));
+        if let Some(last_modified) = freshness.last_modified {
+            response.set_header(Header::new(
+                "Last-Modified",
+                last_modified.format(HTTP_DATE_FORMAT).to_string(),
+            ));
+        }
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing;
+    use rocket::http::{Header, Status};
+
+    #[rocket::async_test]
+    async fn repeated_requests_with_the_etag_are_not_modified() {
+        let app = testing::client().await;
+        app.insert_reading("2024-01-01 10:00:00", 1.0, 230.0, 230.0).await;
+        let uri = format!(
+            "/log/{}/json?start=2024-01-01T09:00&end=2024-01-01T12:00&tz=UTC",
+            app.token
+        );
+
+        let response = app.get(&uri).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let etag = response.headers().get_one("ETag").unwrap().to_string();
+        assert_eq!(
+            response.headers().get_one("Last-Modified"),
+            Some("Mon, 01 Jan 2024 10:00:00 GMT")
+        );
+
+        let response = app
+            .get(&uri)
+            .header(Header::new("If-None-Match", etag.clone()))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::NotModified);
+        assert_eq!(response.headers().get_one("ETag"), Some(etag.as_str()));
+        assert!(response.into_string().await.is_none());
+
+        // A new reading changes the ETag
+        app.insert_reading("2024-01-01 10:05:00", 2.0, 230.0, 460.0).await;
+        let response = app
+            .get(&uri)
+            .header(Header::new("If-None-Match", etag.clone()))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_ne!(response.headers().get_one("ETag"), Some(etag.as_str()));
+    }
+
+    #[rocket::async_test]
+    async fn if_modified_since_the_last_reading_is_not_modified() {
+        let app = testing::client().await;
+        app.insert_reading("2024-01-01 10:00:00", 1.0, 230.0, 230.0).await;
+        let uri = format!("/log/{}/json", app.token);
+
+        let response = app
+            .get(&uri)
+            .header(Header::new("If-Modified-Since", "Mon, 01 Jan 2024 10:00:00 GMT"))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::NotModified);
+
+        let response = app
+            .get(&uri)
+            .header(Header::new("If-Modified-Since", "Mon, 01 Jan 2024 09:59:59 GMT"))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+    }
+
+    /// The UTC datetime the given seconds ago, as stored in the database
+    fn secs_ago(secs: i64) -> String {
+        (chrono::Utc::now() - chrono::Duration::seconds(secs))
+            .format("%Y-%m-%d %H:%M:%S")
+            .to_string()
+    }
+
+    #[rocket::async_test]
+    async fn old_data_reports_its_age_and_is_annotated_as_stale() {
+        let app = testing::client().await;
+        app.insert_reading(&secs_ago(7500), 1.0, 230.0, 230.0).await;
+
+        let json: serde_json::Value = app
+            .get(format!("/log/{}/json", app.token))
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        let age = json["data_age_secs"].as_i64().unwrap();
+        assert!((7500..7510).contains(&age), "{}", age);
+
+        let svg = app
+            .get(format!("/log/{}/svg", app.token))
+            .dispatch()
+            .await
+            .into_string()
+            .await
+            .unwrap();
+        assert!(svg.contains("(last reading 2h 5m ago)"));
+    }
+
+    #[rocket::async_test]
+    async fn the_stale_data_threshold_is_configurable() {
+        for (stale_data_secs, stale) in [(900, false), (30, true)] {
+            let app =
+                testing::client_with(testing::figment().merge(("stale_data_secs", stale_data_secs)))
+                    .await;
+            app.insert_reading(&secs_ago(60), 1.0, 230.0, 230.0).await;
+
+            let svg = app
+                .get(format!("/log/{}/svg", app.token))
+                .dispatch()
+                .await
+                .into_string()
+                .await
+                .unwrap();
+            assert_eq!(svg.contains("(last reading 1m ago)"), stale);
+        }
+    }
+}
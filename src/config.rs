@@ -0,0 +1,387 @@
+//! Validation of the whole configuration when starting up.
+//!
+//! Each setting is read where it is used, and many of them fall back to a
+//! default, or disable their feature, when they are invalid. So that a typo in
+//! Rocket.toml is not only noticed once the feature misbehaves, [Config::validate]
+//! checks every known setting on ignite, and the launch fails with a report of
+//! all the problems at once:
+//!
+//! ```text
+//! 3 problems in the configuration:
+//!   - Missing car_vin
+//!   - Missing tessie_token
+//!   - Invalid budget_safety_factor 1.5, it must be in (0, 1]
+//! ```
+//!
+//! The car settings are only required if the EV charge control is enabled,
+//! that is, if `ev_handler` is set to a handler, or if any of the car settings
+//! is present. Otherwise, the charge control stays disabled as before.
+
+use rocket::figment::Figment;
+use serde::de::DeserializeOwned;
+
+use crate::car::{self, EVChargeHandler, LatLon};
+
+/// The settings that enable the EV charge control when present, even if the
+/// `ev_handler` is left to its default
+const CAR_KEYS: &[&str] = &[
+    "car_vin",
+    "tessie_token",
+    "charger_location",
+    "charger_address",
+    "max_amps",
+    "max_kw",
+    "max_amps_car",
+    "max_kw_car",
+];
+
+/// The problems found in the configuration, one per line
+#[derive(Debug)]
+pub struct ConfigReport(Vec<String>);
+
+impl std::fmt::Display for ConfigReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let plural = if self.0.len() == 1 { "" } else { "s" };
+        write!(f, "{} problem{} in the configuration:", self.0.len(), plural)?;
+        for problem in &self.0 {
+            write!(f, "\n  - {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+/// Validates the configuration, collecting the problems instead of stopping
+/// at the first one
+pub struct Config<'a> {
+    figment: &'a Figment,
+    problems: Vec<String>,
+}
+
+impl<'a> Config<'a> {
+    /// Checks every known setting in the figment, returning all the missing
+    /// or invalid ones.
+    pub fn validate(figment: &'a Figment) -> Result<(), ConfigReport> {
+        let mut config = Config {
+            figment,
+            problems: Vec::new(),
+        };
+        config.validate_server();
+        config.validate_car();
+        if config.problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigReport(config.problems))
+        }
+    }
+
+    /// Returns the setting if it is present and of the right type, recording
+    /// a problem if it is of the wrong type
+    fn optional<T: DeserializeOwned>(&mut self, key: &str) -> Option<T> {
+        match self.figment.extract_inner(key) {
+            Ok(value) => Some(value),
+            Err(e) if e.missing() => None,
+            Err(e) => {
+                self.problems.push(format!("Invalid {}: {}", key, e));
+                None
+            }
+        }
+    }
+
+    /// Like [Config::optional], but the setting must also satisfy `valid`,
+    /// described by `requirement`
+    fn checked<T: DeserializeOwned + std::fmt::Debug>(
+        &mut self,
+        key: &str,
+        valid: impl FnOnce(&T) -> bool,
+        requirement: &str,
+    ) -> Option<T> {
+        let value = self.optional(key)?;
+        if valid(&value) {
+            Some(value)
+        } else {
+            self.problems
+                .push(format!("Invalid {} {:?}, it must be {}", key, value, requirement));
+            None
+        }
+    }
+
+    /// Records a problem if the setting is missing
+    fn required<T: DeserializeOwned>(&mut self, key: &str) -> Option<T> {
+        if !self.is_set(key) {
+            self.problems.push(format!("Missing {}", key));
+            return None;
+        }
+        self.optional(key)
+    }
+
+    fn is_set(&self, key: &str) -> bool {
+        self.figment.contains(key)
+    }
+
+    /// Checks the settings of the logger itself, which are all optional
+    fn validate_server(&mut self) {
+        if !self.is_set("databases.sqlite_logs.url") {
+            self.problems.push("Missing databases.sqlite_logs.url".to_string());
+        }
+        self.checked::<bool>(
+            "databases.sqlite_logs.read_only",
+            |&read_only| !read_only,
+            "false, the ingest writes to it",
+        );
+        if self.is_set("databases.sqlite_logs_read") && !self.is_set("databases.sqlite_logs_read.url") {
+            self.problems.push("Missing databases.sqlite_logs_read.url".to_string());
+        }
+        self.optional::<bool>("databases.sqlite_logs_read.read_only");
+        let shards = if self.is_set("databases.sqlite_logs.shards") {
+            self.checked::<Vec<String>>(
+                "databases.sqlite_logs.shards",
+                |shards| shards.len() <= crate::db::MAX_SHARDS && shards.iter().all(|path| !path.is_empty()),
+                &format!("at most {} non-empty paths", crate::db::MAX_SHARDS),
+            )
+        } else {
+            Some(Vec::new())
+        };
+        let read_shards = self
+            .optional::<Vec<String>>("databases.sqlite_logs_read.shards")
+            .unwrap_or_default();
+        if shards.is_some_and(|shards| shards.len() != read_shards.len()) {
+            self.problems.push(
+                "databases.sqlite_logs_read.shards must list the shards of databases.sqlite_logs \
+                 (or their replicas), in the same order"
+                    .to_string(),
+            );
+        }
+        self.checked::<u64>("databases.sqlite_logs_read.query_timeout", |&secs| secs > 0, "positive");
+        if self.is_set("databases.sqlite_logs.query_timeout") {
+            self.problems.push(
+                "databases.sqlite_logs.query_timeout would interrupt the ingest, set it in \
+                 databases.sqlite_logs_read instead"
+                    .to_string(),
+            );
+        }
+        for key in ["rate_limit_per_second", "rate_limit_burst"] {
+            self.checked::<u32>(key, |&value| value > 0, "a positive integer");
+        }
+        for (key, name) in [
+            ("trusted_proxies", "trusted proxy"),
+            ("rate_limit_exempt", "rate limit exemption"),
+        ] {
+            for ip in self.optional::<Vec<String>>(key).unwrap_or_default() {
+                if crate::proxy::parse_proxy(&ip).is_none() {
+                    self.problems.push(format!(
+                        "Invalid {} {:?}, it must be an IP address or a CIDR",
+                        name, ip
+                    ));
+                }
+            }
+        }
+        self.checked::<String>("admin_token", |token| !token.is_empty(), "non-empty");
+        self.checked::<String>(
+            "admin_username",
+            |username| !username.is_empty() && !username.contains(':'),
+            "non-empty and without a colon",
+        );
+        self.checked::<String>("access_log", |format| format == "json", "\"json\"");
+        self.optional::<Vec<String>>("cors_allowed_origins");
+        self.optional::<u64>("shutdown_drain_secs");
+        self.optional::<u32>("raw_retention_days");
+        self.checked::<f64>("threshold_amps", |amps| amps.is_finite(), "a number");
+        self.checked::<u32>("threshold_window_secs", |&secs| secs > 0, "positive");
+        self.checked::<f64>("max_readings_per_minute", |&rate| rate > 0.0, "positive");
+        self.checked::<u64>("http_timeout_secs", |&secs| secs > 0, "positive");
+        self.checked::<i64>("nearest_reading_tolerance_secs", |&secs| secs >= 0, "not negative");
+        self.checked::<u32>("display_precision", |&precision| precision <= 15, "at most 15");
+        self.checked::<i32>("max_page_count", |&count| count > 0, "positive");
+        self.checked::<i64>("stale_data_secs", |&secs| secs >= 0, "not negative");
+        self.checked::<f64>("watts_tolerance_percent", |&percent| percent >= 0.0, "not negative");
+        self.optional::<crate::consistency::MismatchAction>("watts_mismatch_action");
+        self.checked::<String>(
+            "webhook_url",
+            |url| reqwest::Url::parse(url).is_ok(),
+            "an absolute URL",
+        );
+
+        #[cfg(feature = "mqtt")]
+        if let Err(e) = crate::mqtt::MqttSettings::from_figment(self.figment) {
+            self.problems.push(e);
+        }
+        #[cfg(not(feature = "mqtt"))]
+        if self.is_set("mqtt_url") {
+            self.problems
+                .push("mqtt_url is set, but the server was built without the mqtt feature".to_string());
+        }
+    }
+
+    /// Checks the EV charge control settings, if it is enabled
+    fn validate_car(&mut self) {
+        let handler: Option<String> = self.checked(
+            "ev_handler",
+            |name: &String| name == "none" || car::handler_fairing(name).is_some(),
+            "\"tessie\", \"simulate\" or \"none\"",
+        );
+        let enabled = match handler.as_deref() {
+            Some("none") => false,
+            Some(_) => true,
+            None => CAR_KEYS.iter().any(|key| self.is_set(key)),
+        };
+        if !enabled {
+            return;
+        }
+
+        match handler.as_deref().unwrap_or("tessie") {
+            "tessie" => {
+                self.required::<String>("car_vin");
+                self.required::<String>("tessie_token");
+                self.optional::<String>("tessie_url");
+            }
+            "simulate" => {
+                if let Err(e) =
+                    <car::simulation::Handler as EVChargeHandler>::ConfigParams::try_from(self.figment)
+                {
+                    self.problems.push(format!("Invalid simulation: {}", e));
+                }
+            }
+            _ => {}
+        }
+
+        if self.is_set("charger_location") {
+            self.checked::<String>(
+                "charger_location",
+                |location| LatLon::try_from(location.clone()).is_ok(),
+                "\"lat,lon\"",
+            );
+        } else if self.is_set("charger_address") {
+            self.optional::<String>("charger_address");
+        } else {
+            self.problems
+                .push("Missing charger_location (or charger_address)".to_string());
+        }
+        self.optional::<String>("geocoder_url");
+
+        self.checked::<f64>("nominal_volts", |&volts| volts > 0.0, "positive");
+        self.amps_or_kw("max_amps", "max_kw");
+        self.amps_or_kw("max_amps_car", "max_kw_car");
+        self.checked::<f64>("budget_safety_factor", |&f| f > 0.0 && f <= 1.0, "in (0, 1]");
+        self.checked::<f64>("absolute_max_home_amps", |&amps| amps > 0.0, "positive");
+        self.optional::<car::task::AmpsRounding>("amps_rounding");
+        self.checked::<usize>("charge_limit_soc", |soc| (1..=100).contains(soc), "a percentage");
+        if let Err(e) = car::schedule::ChargeSchedule::from_figment(self.figment) {
+            self.problems.push(e.to_string());
+        }
+        if let Err(e) = car::departure::DepartureTarget::from_figment(self.figment) {
+            self.problems.push(e.to_string());
+        }
+        self.checked::<u32>("max_charge_session_secs", |&secs| secs > 0, "positive");
+        self.checked::<String>(
+            "charge_timezone",
+            |timezone| timezone.parse::<chrono_tz::Tz>().is_ok(),
+            "a timezone name, e.g., \"Europe/Madrid\"",
+        );
+        self.checked::<u32>(
+            "consumption_window_secs",
+            |secs| (1..=car::task::MAX_CONSUMPTION_WINDOW_SECS).contains(secs),
+            &format!("between 1 and {}", car::task::MAX_CONSUMPTION_WINDOW_SECS),
+        );
+        self.optional::<u64>("car_check_interval_secs");
+        self.optional::<car::units::Distance>("nearby_distance");
+        self.optional::<car::units::DistanceUnit>("distance_unit");
+        self.optional::<Vec<String>>("car_tokens");
+        self.optional::<String>("car_location");
+    }
+
+    /// Checks a budget configured either in amps or in kW, but not both
+    fn amps_or_kw(&mut self, amps_key: &str, kw_key: &str) {
+        match (self.is_set(amps_key), self.is_set(kw_key)) {
+            (true, true) => self.problems.push(format!(
+                "Configure either {} or {}, not both",
+                amps_key, kw_key
+            )),
+            (false, false) => self
+                .problems
+                .push(format!("Missing {} (or {})", amps_key, kw_key)),
+            (true, false) => {
+                self.checked::<f64>(amps_key, |&amps| amps >= 0.0, "not negative");
+            }
+            (false, true) => {
+                self.checked::<f64>(kw_key, |&kw| kw >= 0.0, "not negative");
+            }
+        }
+    }
+}
+
+/// Fairing that validates the configuration on ignite, failing the launch
+/// with a report of every problem found.
+pub fn validate_on_ignite() -> rocket::fairing::AdHoc {
+    rocket::fairing::AdHoc::try_on_ignite("Validate configuration", |rocket| async {
+        match Config::validate(rocket.figment()) {
+            Ok(()) => Ok(rocket),
+            Err(report) => {
+                log::error!("{}", report);
+                Err(rocket)
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn the_test_configuration_is_valid() {
+        assert!(Config::validate(&testing::figment()).is_ok());
+        assert!(Config::validate(&testing::database_figment()).is_ok());
+    }
+
+    #[test]
+    fn every_problem_is_reported() {
+        let figment = testing::database_figment()
+            .merge(("charger_location", "43.363056,-8.838417"))
+            .merge(("max_amps_car", 16))
+            .merge(("trusted_proxies", ["10.0.0.0/33"]))
+            .merge(("display_precision", "two"))
+            .merge(("budget_safety_factor", 1.5));
+
+        let report = Config::validate(&figment).unwrap_err();
+        assert_eq!(report.0.len(), 6, "{}", report);
+        assert!(report.0.contains(&"Missing car_vin".to_string()));
+        assert!(report.0.contains(&"Missing tessie_token".to_string()));
+        assert!(report.0.contains(&"Missing max_amps (or max_kw)".to_string()));
+        assert!(report.0.contains(
+            &"Invalid trusted proxy \"10.0.0.0/33\", it must be an IP address or a CIDR".to_string()
+        ));
+        assert!(report.0.iter().any(|problem| problem.starts_with("Invalid display_precision: ")));
+        assert!(report.0.contains(
+            &"Invalid budget_safety_factor 1.5, it must be in (0, 1]".to_string()
+        ));
+        assert!(report.to_string().starts_with("6 problems in the configuration:\n  - "));
+    }
+
+    #[test]
+    fn the_car_settings_are_only_required_with_a_handler() {
+        let figment = testing::database_figment().merge(("ev_handler", "simulate"));
+        let report = Config::validate(&figment).unwrap_err();
+        assert_eq!(
+            report.0,
+            vec![
+                "Invalid simulation: missing field `charger_location`",
+                "Missing charger_location (or charger_address)",
+                "Missing max_amps (or max_kw)",
+                "Missing max_amps_car (or max_kw_car)",
+            ]
+        );
+    }
+
+    #[rocket::async_test]
+    async fn an_invalid_configuration_fails_the_launch() {
+        let figment = testing::figment().merge(("http_timeout_secs", 0));
+
+        let error = crate::build(figment).ignite().await.expect_err("invalid config");
+        assert!(matches!(
+            error.kind(),
+            rocket::error::ErrorKind::FailedFairings(fairings)
+                if fairings.iter().any(|f| f.name == "Validate configuration")
+        ));
+    }
+}
@@ -0,0 +1,168 @@
+//! Optional consistency check between the reported amps, volts and watts.
+//!
+//! A miscalibrated or wrongly-wired clamp may report `watts` that do not match
+//! `amps * volts`. When `watts_tolerance_percent` is set in the figment
+//! configuration (Rocket.toml), the readings logged to POST /log/:token whose
+//! watts differ from the expected ones by more than that percentage are either
+//! stored with the `suspect_watts` flag set (the default, see [crate::quality]),
+//! or rejected with a 422 if `watts_mismatch_action = "reject"`.
+//!
+//! Note that the check uses the assumed 220 V for readings without `volts`.
+//! It is disabled by default, as single-channel sensors may only report
+//! meaningful values for one of amps or watts.
+
+use rocket::figment::Figment;
+
+use crate::request_id::RequestId;
+
+/// What to do with the readings that fail the check
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MismatchAction {
+    /// Store the reading, flagged as suspect
+    #[default]
+    Tag,
+    /// Do not store the reading
+    Reject,
+}
+
+/// The result of checking a reading
+#[derive(Debug, PartialEq)]
+pub enum Consistency {
+    Consistent,
+    Suspect,
+    Rejected,
+}
+
+/// Request guard with the consistency check configuration, or None if the
+/// check is disabled.
+pub struct WattsCheck(Option<(f64, MismatchAction)>);
+
+impl From<&Figment> for WattsCheck {
+    fn from(figment: &Figment) -> Self {
+        let Ok(tolerance_percent) = figment.extract_inner::<f64>("watts_tolerance_percent") else {
+            return WattsCheck(None);
+        };
+        let action = figment
+            .extract_inner("watts_mismatch_action")
+            .unwrap_or_default();
+        WattsCheck(Some((tolerance_percent, action)))
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> rocket::request::FromRequest<'r> for WattsCheck {
+    type Error = ();
+
+    async fn from_request(
+        request: &'r rocket::Request<'_>,
+    ) -> rocket::request::Outcome<Self, Self::Error> {
+        rocket::request::Outcome::Success(WattsCheck::from(request.rocket().figment()))
+    }
+}
+
+impl WattsCheck {
+    /// Checks that the watts are within the tolerance of `amps * volts`.
+    ///
+    /// The difference is relative to the expected watts, so a reading of 0 A
+    /// is only consistent with (almost) 0 W.
+    pub fn check(&self, amps: f64, volts: f64, watts: f64) -> Consistency {
+        let Some((tolerance_percent, action)) = self.0 else {
+            return Consistency::Consistent;
+        };
+        let expected = amps * volts;
+        let difference = (watts - expected).abs();
+        if difference <= expected.abs() * tolerance_percent / 100.0 + f64::EPSILON {
+            return Consistency::Consistent;
+        }
+
+        log::warn!(
+            "Reading of {} W does not match {} A * {} V = {} W{}",
+            watts,
+            amps,
+            volts,
+            expected,
+            RequestId::in_logs()
+        );
+        match action {
+            MismatchAction::Tag => Consistency::Suspect,
+            MismatchAction::Reject => Consistency::Rejected,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use rocket::http::{ContentType, Status};
+
+    fn watts_check(figment: Figment) -> WattsCheck {
+        WattsCheck::from(&figment.merge(("watts_tolerance_percent", 10)))
+    }
+
+    #[test]
+    fn checks_the_watts_within_the_tolerance() {
+        let tag = watts_check(Figment::new());
+        assert_eq!(tag.check(10.0, 230.0, 2300.0), Consistency::Consistent);
+        assert_eq!(tag.check(10.0, 230.0, 2520.0), Consistency::Consistent);
+        assert_eq!(tag.check(10.0, 230.0, 2080.0), Consistency::Consistent);
+        assert_eq!(tag.check(10.0, 230.0, 2600.0), Consistency::Suspect);
+        assert_eq!(tag.check(0.0, 230.0, 0.0), Consistency::Consistent);
+        assert_eq!(tag.check(0.0, 230.0, 50.0), Consistency::Suspect);
+
+        let reject = watts_check(Figment::new().merge(("watts_mismatch_action", "reject")));
+        assert_eq!(reject.check(10.0, 230.0, 2300.0), Consistency::Consistent);
+        assert_eq!(reject.check(10.0, 230.0, 2600.0), Consistency::Rejected);
+    }
+
+    #[test]
+    fn the_check_is_disabled_by_default() {
+        let check = WattsCheck::from(&Figment::new());
+        assert_eq!(check.check(10.0, 230.0, 0.0), Consistency::Consistent);
+    }
+
+    /// Posts a reading of 10 A at 230 V with the given watts
+    async fn post(app: &testing::TestApp, watts: u32) -> Status {
+        app.post(format!("/log/{}", app.token))
+            .header(ContentType::JSON)
+            .body(format!(r#"{{"amps": 10, "volts": 230, "watts": {}}}"#, watts))
+            .dispatch()
+            .await
+            .status()
+    }
+
+    /// The flags of the latest reading
+    async fn latest_flags(app: &testing::TestApp) -> serde_json::Value {
+        let latest = app.get(format!("/log/{}/latest", app.token)).dispatch().await;
+        let latest: serde_json::Value = latest.into_json().await.unwrap();
+        latest["flags"].clone()
+    }
+
+    #[rocket::async_test]
+    async fn mismatching_readings_are_flagged_as_suspect() {
+        let app =
+            testing::client_with(testing::figment().merge(("watts_tolerance_percent", 10))).await;
+
+        assert_eq!(post(&app, 2300).await, Status::Ok);
+        assert_eq!(latest_flags(&app).await, serde_json::Value::Null);
+        assert_eq!(post(&app, 3000).await, Status::Ok);
+        assert_eq!(latest_flags(&app).await, serde_json::json!(["suspect_watts"]));
+    }
+
+    #[rocket::async_test]
+    async fn mismatching_readings_can_be_rejected() {
+        let app = testing::client_with(
+            testing::figment()
+                .merge(("watts_tolerance_percent", 10))
+                .merge(("watts_mismatch_action", "reject")),
+        )
+        .await;
+
+        assert_eq!(post(&app, 3000).await, Status::UnprocessableEntity);
+        let latest = app.get(format!("/log/{}/latest", app.token)).dispatch().await;
+        assert_eq!(latest.status(), Status::NotFound);
+        assert_eq!(post(&app, 2300).await, Status::Ok);
+        assert_eq!(latest_flags(&app).await, serde_json::Value::Null);
+    }
+}
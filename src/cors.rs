@@ -0,0 +1,196 @@
+//! CORS headers for the read routes, for dashboards served from another
+//! origin.
+//!
+//! CORS is disabled unless the allowed origins are listed in the figment
+//! configuration (Rocket.toml):
+//!
+//! ```toml
+//! cors_allowed_origins = ["https://dashboard.example.com"]
+//! ```
+//!
+//! `"*"` allows any origin. Only the GET routes under `/log/` get the
+//! `Access-Control-Allow-Origin` header, and only GET is allowed on the
+//! `OPTIONS` preflight, so browsers on other origins still cannot POST
+//! readings to the ingest routes.
+
+use rocket::http::{Header, Method, Status};
+use rocket::options;
+
+/// Fairing that adds the CORS headers to the read routes for the allowed
+/// origins
+pub struct CorsFairing {
+    origins: Vec<String>,
+}
+
+impl CorsFairing {
+    /// Returns a fairing attaching the [CorsFairing], and mounting the
+    /// preflight route, if `cors_allowed_origins` is configured.
+    pub fn if_enabled() -> rocket::fairing::AdHoc {
+        rocket::fairing::AdHoc::on_ignite("CORS for the read routes", |rocket| async {
+            let origins: Vec<String> = match rocket.figment().extract_inner("cors_allowed_origins") {
+                Ok(origins) => origins,
+                Err(e) if e.missing() => return rocket,
+                Err(e) => {
+                    log::error!("Ignoring invalid cors_allowed_origins: {}", e);
+                    return rocket;
+                }
+            };
+            if origins.is_empty() {
+                return rocket;
+            }
+            log::info!("Allowing cross-origin reads from {:?}", origins);
+            rocket
+                .attach(CorsFairing { origins })
+                .mount("/", rocket::routes![preflight])
+        })
+    }
+
+    /// Returns the value of `Access-Control-Allow-Origin` for the origin, or
+    /// `None` if it is not allowed
+    fn allow_origin<'a>(&self, origin: &'a str) -> Option<&'a str> {
+        self.origins
+            .iter()
+            .any(|allowed| allowed == "*" || allowed == origin)
+            .then_some(origin)
+    }
+}
+
+/// Route OPTIONS /log/... answers the CORS preflight, the headers are added by
+/// the [CorsFairing]
+#[options("/log/<_..>")]
+fn preflight() -> Status {
+    Status::NoContent
+}
+
+#[rocket::async_trait]
+impl rocket::fairing::Fairing for CorsFairing {
+    fn info(&self) -> rocket::fairing::Info {
+        rocket::fairing::Info {
+            name: "CORS headers",
+            kind: rocket::fairing::Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(
+        &self,
+        request: &'r rocket::Request<'_>,
+        response: &mut rocket::Response<'r>,
+    ) {
+        if !request.uri().path().starts_with("/log/") {
+            return;
+        }
+        let Some(origin) = request
+            .headers()
+            .get_one("Origin")
+            .and_then(|origin| self.allow_origin(origin))
+        else {
+            return;
+        };
+
+        match request.method() {
+            Method::Get | Method::Head => {}
+            Method::Options => {
+                // Only the reads may be preflighted
+                if request.headers().get_one("Access-Control-Request-Method") != Some("GET") {
+                    return;
+                }
+                response.set_header(Header::new("Access-Control-Allow-Methods", "GET"));
+                if let Some(headers) = request.headers().get_one("Access-Control-Request-Headers") {
+                    response.set_header(Header::new(
+                        "Access-Control-Allow-Headers",
+                        headers.to_string(),
+                    ));
+                }
+                response.set_header(Header::new("Access-Control-Max-Age", "86400"));
+            }
+            _ => return,
+        }
+        response.set_header(Header::new("Access-Control-Allow-Origin", origin.to_string()));
+        response.adjoin_header(Header::new("Vary", "Origin"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing;
+    use rocket::http::{ContentType, Header, Status};
+
+    const DASHBOARD: &str = "https://dashboard.example.com";
+
+    fn origin(origin: &'static str) -> Header<'static> {
+        Header::new("Origin", origin)
+    }
+
+    #[rocket::async_test]
+    async fn only_allowed_origins_get_the_header() {
+        let app = testing::client_with(
+            testing::figment().merge(("cors_allowed_origins", [DASHBOARD])),
+        )
+        .await;
+        let uri = format!("/log/{}/json", app.token);
+
+        let response = app.get(&uri).header(origin(DASHBOARD)).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(
+            response.headers().get_one("Access-Control-Allow-Origin"),
+            Some(DASHBOARD)
+        );
+        assert_eq!(response.headers().get_one("Vary"), Some("Origin"));
+
+        let response = app
+            .get(&uri)
+            .header(origin("https://evil.example.com"))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.headers().get_one("Access-Control-Allow-Origin"), None);
+    }
+
+    #[rocket::async_test]
+    async fn only_reads_can_be_preflighted() {
+        let app = testing::client_with(
+            testing::figment().merge(("cors_allowed_origins", [DASHBOARD])),
+        )
+        .await;
+        let uri = format!("/log/{}/json", app.token);
+        let preflight = |method: &'static str| {
+            app.client
+                .options(uri.clone())
+                .header(origin(DASHBOARD))
+                .header(Header::new("Access-Control-Request-Method", method))
+                .dispatch()
+        };
+
+        let response = preflight("GET").await;
+        assert_eq!(response.status(), Status::NoContent);
+        assert_eq!(response.headers().get_one("Access-Control-Allow-Methods"), Some("GET"));
+        assert_eq!(
+            response.headers().get_one("Access-Control-Allow-Origin"),
+            Some(DASHBOARD)
+        );
+
+        let response = preflight("POST").await;
+        assert_eq!(response.headers().get_one("Access-Control-Allow-Origin"), None);
+        let response = app
+            .post(format!("/log/{}", app.token))
+            .header(origin(DASHBOARD))
+            .header(ContentType::JSON)
+            .body(r#"{"amps": 4, "volts": 230, "watts": 920}"#)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.headers().get_one("Access-Control-Allow-Origin"), None);
+    }
+
+    #[rocket::async_test]
+    async fn cors_is_disabled_by_default() {
+        let app = testing::client().await;
+
+        let response = app
+            .get(format!("/log/{}/json", app.token))
+            .header(origin(DASHBOARD))
+            .dispatch()
+            .await;
+        assert_eq!(response.headers().get_one("Access-Control-Allow-Origin"), None);
+    }
+}
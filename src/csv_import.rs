@@ -0,0 +1,137 @@
+//! Parser for historical readings imported as CSV.
+//!
+//! This allows seeding the database with data from another logger, such as a
+//! spreadsheet. The body is expected to have the columns:
+//!
+//! ```text
+//! timestamp,amps,volts,watts
+//! 2024-01-31 23:59:00,3.2,230,736
+//! ```
+//!
+//! The header line is optional. The timestamp may be given in UTC as
+//! `%Y-%m-%d %H:%M:%S`, as RFC 3339 (e.g., `2024-01-31T23:59:00+01:00`) or as
+//! seconds since the Unix epoch. The `volts` column may be left empty.
+
+use chrono::{NaiveDateTime, SubsecRound};
+
+use crate::request_id::RequestId;
+
+/// A reading parsed from a single CSV line
+#[derive(Debug, PartialEq)]
+pub struct CsvReading {
+    pub timestamp: NaiveDateTime,
+    pub amps: f64,
+    pub volts: Option<f64>,
+    pub watts: f64,
+}
+
+/// Parse a timestamp in any of the supported formats, in UTC and truncated to
+/// seconds, as the database stores them.
+pub(crate) fn parse_timestamp(value: &str) -> Option<NaiveDateTime> {
+    let timestamp = NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S")
+        .ok()
+        .or_else(|| {
+            chrono::DateTime::parse_from_rfc3339(value)
+                .ok()
+                .map(|dt| dt.naive_utc())
+        })
+        .or_else(|| {
+            value
+                .parse::<i64>()
+                .ok()
+                .and_then(|secs| chrono::DateTime::from_timestamp(secs, 0))
+                .map(|dt| dt.naive_utc())
+        })?;
+    Some(timestamp.trunc_subsecs(0))
+}
+
+/// Parse a single CSV line
+fn parse_line(line: &str) -> Option<CsvReading> {
+    let columns: Vec<&str> = line.split(',').map(str::trim).collect();
+    let [timestamp, amps, volts, watts] = columns.as_slice() else {
+        return None;
+    };
+
+    let volts = match *volts {
+        "" => None,
+        volts => Some(volts.parse().ok()?),
+    };
+    Some(CsvReading {
+        timestamp: parse_timestamp(timestamp)?,
+        amps: amps.parse().ok()?,
+        volts,
+        watts: watts.parse().ok()?,
+    })
+}
+
+/// Parse a CSV body into readings.
+///
+/// Empty lines and the header are skipped. Invalid lines do not reject the
+/// whole body, but are counted and returned as the second element.
+pub fn parse_lines(body: &str) -> (Vec<CsvReading>, usize) {
+    let mut readings = Vec::new();
+    let mut invalid = 0;
+    for (i, line) in body.lines().map(str::trim).enumerate() {
+        if line.is_empty() || (i == 0 && line.starts_with("timestamp")) {
+            continue;
+        }
+        match parse_line(line) {
+            Some(reading) => readings.push(reading),
+            None => {
+                log::info!(
+                    "Skipping invalid CSV line {}: {:?}{}",
+                    i + 1,
+                    line,
+                    RequestId::in_logs()
+                );
+                invalid += 1;
+            }
+        }
+    }
+    (readings, invalid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_timestamps_in_every_format_as_utc() {
+        let expected = NaiveDateTime::parse_from_str("2024-01-31 22:59:00", "%Y-%m-%d %H:%M:%S").ok();
+        assert_eq!(parse_timestamp("2024-01-31 22:59:00"), expected);
+        assert_eq!(parse_timestamp("2024-01-31T23:59:00+01:00"), expected);
+        assert_eq!(parse_timestamp("2024-01-31T22:59:00.750Z"), expected);
+        assert_eq!(parse_timestamp("1706741940"), expected);
+        assert_eq!(parse_timestamp("31/01/2024 22:59"), None);
+    }
+
+    #[test]
+    fn counts_the_invalid_lines() {
+        let (readings, invalid) = parse_lines(
+            "timestamp,amps,volts,watts\n\
+            2024-01-31 22:59:00,3.2,230,736\n\
+            \n\
+            2024-01-31 23:00:00,3.0,,690\n\
+            2024-01-31 23:01:00,lots,230,690\n\
+            2024-01-31 23:02:00,3.0,230\n",
+        );
+        assert_eq!(invalid, 2);
+        assert_eq!(
+            readings,
+            vec![
+                CsvReading {
+                    timestamp: parse_timestamp("2024-01-31 22:59:00").unwrap(),
+                    amps: 3.2,
+                    volts: Some(230.0),
+                    watts: 736.0,
+                },
+                CsvReading {
+                    timestamp: parse_timestamp("2024-01-31 23:00:00").unwrap(),
+                    amps: 3.0,
+                    volts: None,
+                    watts: 690.0,
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,710 @@
+//! SQLite pool for the [Logs](crate::Logs) database.
+//!
+//! The default `rocket_db_pools` pool uses the default SQLite journal mode,
+//! where writers and readers block each other, so concurrent ingest and reads
+//! may fail with `database is locked`. This pool is configured the same way,
+//! but its connections use the WAL journal mode and a configurable busy
+//! timeout, read from the database configuration (Rocket.toml):
+//!
+//! ```toml
+//! [default.databases.sqlite_logs]
+//! url = "./sqlite.db"
+//! # How long to wait for a lock before failing, in seconds
+//! # (defaults to connect_timeout)
+//! busy_timeout = 5
+//! ```
+//!
+//! The read routes use a second pool, [LogsRead](crate::LogsRead), so that
+//! plotting a long range does not take the connections the ingest needs. It
+//! can point to a read replica of the database (e.g., kept up to date with
+//! Litestream), or to the same file opened read-only:
+//!
+//! ```toml
+//! [default.databases.sqlite_logs_read]
+//! url = "./replica.db"
+//! read_only = true
+//! ```
+//!
+//! If it is not configured, it uses the same settings as `sqlite_logs` (see
+//! [with_read_pool_default]). The migrations only run on the primary pool, so
+//! a replica must be kept up to date by other means.
+//!
+//! So that a huge range does not hold a read connection for long, the read
+//! pool can also limit how long the queries of a request may take:
+//!
+//! ```toml
+//! [default.databases.sqlite_logs_read]
+//! url = "./sqlite.db"
+//! # In seconds
+//! query_timeout = 10
+//! ```
+//!
+//! The range routes then answer with a 503 once it is over (see
+//! [QueryTimeout]), and SQLite interrupts the query shortly after, which
+//! frees the connection.
+//!
+//! When a single file becomes a bottleneck, the readings can be spread over
+//! several SQLite files, by a hash of their token:
+//!
+//! ```toml
+//! [default.databases.sqlite_logs]
+//! url = "./sqlite.db"
+//! shards = ["./shard-0.db", "./shard-1.db"]
+//! ```
+//!
+//! The users, tokens and view tokens stay in the main database, and each
+//! token logs its readings into the `energy_log` table of its shard (see
+//! [SqlitePool::for_token]). Every connection attaches the shards, and hides
+//! the `energy_log` table of the main database behind a temporary view over
+//! it and every shard, so the queries keep reading all the readings as if
+//! they were in a single table. The view cannot be modified, so the readings
+//! are deleted from each database instead (see [SqlitePool::databases]). The
+//! readings logged before sharding stay in the main database.
+//!
+//! Shards can be added later, as the readings already logged are still read
+//! where they are, but not removed. SQLite attaches at most
+//! [MAX_SHARDS] databases.
+
+use std::time::{Duration, Instant};
+
+use rocket::figment::providers::Serialized;
+use rocket::figment::Figment;
+use rocket::http::Status;
+use rocket_db_pools::{Config, Error};
+use sqlx::migrate::{MigrateError, Migrator};
+use sqlx::sqlite::{
+    Sqlite, SqliteConnectOptions, SqliteConnection, SqliteJournalMode, SqlitePoolOptions,
+};
+use sqlx::ConnectOptions;
+
+/// The settings for this pool, on top of the `rocket_db_pools` [Config]
+#[derive(serde::Deserialize)]
+struct ExtraConfig {
+    busy_timeout: Option<u64>,
+    /// Open the database read-only, without creating it or changing its
+    /// journal mode
+    #[serde(default)]
+    read_only: bool,
+    /// How long the queries of a request may take, in seconds
+    query_timeout: Option<u64>,
+    /// The paths of the databases the readings are spread over
+    #[serde(default)]
+    shards: Vec<String>,
+}
+
+/// How many shards SQLite can attach to a connection
+pub const MAX_SHARDS: usize = 10;
+
+/// The ids of the readings of each shard start after this many bits, so that
+/// they do not collide with those of the main database or other shards
+const SHARD_ID_BITS: u32 = 48;
+
+/// How much longer than the `query_timeout` a query runs before SQLite
+/// interrupts it, so that the route answers first (see [QueryTimeout])
+const QUERY_TIMEOUT_GRACE: Duration = Duration::from_secs(1);
+
+/// How many SQLite virtual machine instructions run between the checks of
+/// the query deadline
+const PROGRESS_HANDLER_OPS: i32 = 1000;
+
+/// A [sqlx::SqlitePool] whose connections use the WAL journal mode and the
+/// configured busy timeout, and optionally the query timeout. If sharded, it
+/// also keeps a pool for each shard.
+pub struct SqlitePool(sqlx::SqlitePool, Option<Duration>, Vec<sqlx::SqlitePool>);
+
+impl std::ops::Deref for SqlitePool {
+    type Target = sqlx::SqlitePool;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+#[rocket::async_trait]
+impl rocket_db_pools::Pool for SqlitePool {
+    type Connection = sqlx::pool::PoolConnection<Sqlite>;
+    type Error = Error<sqlx::Error>;
+
+    async fn init(figment: &Figment) -> Result<Self, Self::Error> {
+        let config: Config = figment.extract()?;
+        let extra: ExtraConfig = figment.extract()?;
+        let busy_timeout = extra.busy_timeout.unwrap_or(config.connect_timeout);
+        if extra.shards.len() > MAX_SHARDS || extra.shards.iter().any(|path| path.is_empty()) {
+            return Err(Error::Config(
+                format!("shards must be at most {} non-empty paths", MAX_SHARDS).into(),
+            ));
+        }
+
+        let connect_options = |options: SqliteConnectOptions| {
+            let options = options
+                .busy_timeout(Duration::from_secs(busy_timeout))
+                .disable_statement_logging();
+            // Setting the journal mode writes to the database, so a read-only
+            // connection keeps the one the writer chose
+            if extra.read_only {
+                options.read_only(true)
+            } else {
+                options
+                    .create_if_missing(true)
+                    .journal_mode(SqliteJournalMode::Wal)
+            }
+        };
+        let pool_options = || {
+            SqlitePoolOptions::new()
+                .max_connections(config.max_connections as u32)
+                .acquire_timeout(Duration::from_secs(config.connect_timeout))
+                .idle_timeout(config.idle_timeout.map(Duration::from_secs))
+        };
+
+        // The readings of a shard reference the tokens of the main database,
+        // which SQLite cannot check across files. The shards are only
+        // connected to when a reading is logged.
+        let shards = extra
+            .shards
+            .iter()
+            .map(|path| {
+                let options = SqliteConnectOptions::new().filename(path).foreign_keys(false);
+                pool_options().connect_lazy_with(connect_options(options))
+            })
+            .collect();
+
+        let paths = std::sync::Arc::new(extra.shards);
+        let options = connect_options(config.url.parse().map_err(Error::Init)?);
+        let mut main_options = pool_options()
+            .min_connections(config.min_connections.unwrap_or_default())
+            .after_connect(move |connection, _| {
+                let paths = paths.clone();
+                Box::pin(async move { attach_shards(connection, &paths).await })
+            });
+        if extra.query_timeout.is_some() {
+            // The deadline of the request that last held the connection has
+            // passed, and would interrupt the queries of whoever acquires it
+            // next, including those that acquire it outside of a request
+            main_options = main_options.before_acquire(|connection, _| {
+                Box::pin(async move {
+                    connection.lock_handle().await?.remove_progress_handler();
+                    Ok(true)
+                })
+            });
+        }
+        main_options
+            .connect_with(options)
+            .await
+            .map(|pool| SqlitePool(pool, extra.query_timeout.map(Duration::from_secs), shards))
+            .map_err(Error::Init)
+    }
+
+    /// Acquires a connection, whose queries are interrupted once the query
+    /// timeout (plus [QUERY_TIMEOUT_GRACE]) has passed, if it is configured.
+    async fn get(&self) -> Result<Self::Connection, Self::Error> {
+        let mut connection = self.0.acquire().await.map_err(Error::Get)?;
+        if let Some(timeout) = self.1 {
+            // The connection is held for a single request, and the deadline is
+            // removed before it is acquired again
+            let deadline = Instant::now() + timeout + QUERY_TIMEOUT_GRACE;
+            connection
+                .lock_handle()
+                .await
+                .map_err(Error::Get)?
+                .set_progress_handler(PROGRESS_HANDLER_OPS, move || Instant::now() < deadline);
+        }
+        Ok(connection)
+    }
+
+    async fn close(&self) {
+        self.0.close().await;
+        for shard in &self.2 {
+            shard.close().await;
+        }
+    }
+}
+
+impl SqlitePool {
+    /// Returns the pool to log the readings of `token` into: its shard, by a
+    /// hash of the token, or the main database if it is not sharded.
+    pub fn for_token(&self, token: &str) -> &sqlx::SqlitePool {
+        self.databases()
+            .nth(self.index_for_token(token))
+            .expect("the index should be within the databases")
+    }
+
+    /// Returns the position of the pool of [for_token](Self::for_token) in
+    /// [databases](Self::databases), e.g., to pick a transaction per database.
+    pub fn index_for_token(&self, token: &str) -> usize {
+        if self.2.is_empty() {
+            return 0;
+        }
+        1 + (shard_hash(token) % self.2.len() as u64) as usize
+    }
+
+    /// Returns the pools of the main database and of every shard, to delete
+    /// readings wherever they are. The statements must name the table
+    /// `main.energy_log`, as `energy_log` is the view over the shards in the
+    /// main database.
+    pub fn databases(&self) -> impl Iterator<Item = &sqlx::SqlitePool> {
+        std::iter::once(&self.0).chain(&self.2)
+    }
+
+    /// Connects to the `databases.sqlite_logs` database of the figment
+    /// configuration (Rocket.toml) and its shards, with the settings the
+    /// server uses, for the command line tools.
+    pub async fn connect_configured(figment: &Figment) -> Result<Self, Error<sqlx::Error>> {
+        let figment = figment
+            .focus("databases.sqlite_logs")
+            .merge(("max_connections", 1))
+            .join(("connect_timeout", 5));
+        <Self as rocket_db_pools::Pool>::init(&figment).await
+    }
+
+    /// Connects to the database at `url` and its `shards` outside of the
+    /// server, with a single connection to each.
+    pub async fn connect(url: &str, shards: &[String]) -> Result<Self, Error<sqlx::Error>> {
+        let figment = Figment::new()
+            .merge(("url", url))
+            .merge(("shards", shards))
+            .merge(("max_connections", 1))
+            .merge(("connect_timeout", 5));
+        <Self as rocket_db_pools::Pool>::init(&figment).await
+    }
+
+    /// Runs the migrations on the main database and on every shard.
+    ///
+    /// The readings of a shard get ids from `(index + 1) << 48` on, so that
+    /// they do not collide with those of other databases in the view.
+    pub async fn migrate(&self, migrator: &Migrator) -> Result<(), MigrateError> {
+        for index in 0..self.2.len() {
+            let shard = &self.2[index];
+            migrator.run(shard).await?;
+            let first_id = ((index + 1) as i64) << SHARD_ID_BITS;
+            sqlx::query("UPDATE sqlite_sequence SET seq = MAX(seq, ?) WHERE name = 'energy_log'")
+                .bind(first_id)
+                .execute(shard)
+                .await?;
+            sqlx::query(
+                "INSERT INTO sqlite_sequence (name, seq) SELECT 'energy_log', ?
+                WHERE NOT EXISTS (SELECT 1 FROM sqlite_sequence WHERE name = 'energy_log')",
+            )
+            .bind(first_id)
+            .execute(shard)
+            .await?;
+        }
+        if self.2.is_empty() {
+            return migrator.run(&self.0).await;
+        }
+
+        // The view over the shards would hide the energy_log table from the
+        // migrations, so they run on a connection without it
+        let main = self.main_without_view().await?;
+        let result = migrator.run(&main).await;
+        main.close().await;
+        result
+    }
+
+    /// Opens a single connection to the main database without the view over
+    /// the shards, for the statements that would resolve `energy_log` to it
+    /// and fail. The pool should be closed once done.
+    pub async fn main_without_view(&self) -> Result<sqlx::SqlitePool, sqlx::Error> {
+        let options = self.0.connect_options().as_ref().clone();
+        SqlitePoolOptions::new().max_connections(1).connect_with(options).await
+    }
+}
+
+/// FNV-1a, so that a token is logged into the same shard across restarts and
+/// builds, unlike with the [std::hash::Hasher] of the standard library
+fn shard_hash(token: &str) -> u64 {
+    token.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Attaches the shards to the connection as `shard_<index>`, and creates the
+/// temporary `energy_log` view over the table of the main database and those
+/// of the shards, which shadows the table.
+async fn attach_shards(connection: &mut SqliteConnection, paths: &[String]) -> Result<(), sqlx::Error> {
+    if paths.is_empty() {
+        return Ok(());
+    }
+
+    let mut selects = vec!["SELECT * FROM main.energy_log".to_string()];
+    for (index, path) in paths.iter().enumerate() {
+        sqlx::query(&format!("ATTACH DATABASE ? AS shard_{}", index))
+            .bind(path)
+            .execute(&mut *connection)
+            .await?;
+        selects.push(format!("SELECT * FROM shard_{}.energy_log", index));
+    }
+    sqlx::query(&format!(
+        "CREATE TEMP VIEW energy_log AS {}",
+        selects.join(" UNION ALL ")
+    ))
+    .execute(&mut *connection)
+    .await?;
+    Ok(())
+}
+
+/// Configures the read pool (`databases.sqlite_logs_read`) with the settings
+/// of the primary one (`databases.sqlite_logs`), unless it is configured.
+pub fn with_read_pool_default(figment: Figment) -> Figment {
+    if figment.contains("databases.sqlite_logs_read") {
+        return figment;
+    }
+    match figment.find_value("databases.sqlite_logs") {
+        Ok(primary) => figment.join(Serialized::default("databases.sqlite_logs_read", primary)),
+        // The missing primary database is reported by the config validation
+        Err(_) => figment,
+    }
+}
+
+/// Request guard with the `query_timeout` of the read pool, which bounds how
+/// long the range queries of the request may take.
+pub struct QueryTimeout(Option<Instant>);
+
+#[rocket::async_trait]
+impl<'r> rocket::request::FromRequest<'r> for QueryTimeout {
+    type Error = ();
+
+    async fn from_request(
+        request: &'r rocket::Request<'_>,
+    ) -> rocket::request::Outcome<Self, Self::Error> {
+        let deadline = request
+            .rocket()
+            .figment()
+            .extract_inner::<u64>("databases.sqlite_logs_read.query_timeout")
+            .ok()
+            .map(|secs| Instant::now() + Duration::from_secs(secs));
+        rocket::request::Outcome::Success(QueryTimeout(deadline))
+    }
+}
+
+impl QueryTimeout {
+    /// Awaits `query`, or fails with a 503 Service Unavailable if the request
+    /// runs out of time first.
+    pub async fn run<T>(
+        &self,
+        query: impl std::future::Future<Output = T>,
+    ) -> Result<T, (Status, String)> {
+        let Some(deadline) = self.0 else {
+            return Ok(query.await);
+        };
+        rocket::tokio::time::timeout_at(deadline.into(), query)
+            .await
+            .map_err(|_| {
+                log::warn!("A read query ran out of time, answering 503");
+                (
+                    Status::ServiceUnavailable,
+                    "The range is too large, narrow your query".to_string(),
+                )
+            })
+    }
+}
+
+/// Explains a failed migration to the operator: which migration it was, and
+/// what can be done about it.
+pub fn describe_migrate_error(migrator: &Migrator, error: &MigrateError) -> String {
+    let name = |version: i64| {
+        migrator
+            .iter()
+            .find(|migration| migration.version == version)
+            .map_or_else(
+                || format!("{}", version),
+                |migration| format!("{} ({})", version, migration.description),
+            )
+    };
+    match error {
+        MigrateError::VersionMismatch(version) => format!(
+            "Migration {} was applied with a different checksum: its file has been \
+             modified since. Restore the original migrations/ file, as applied \
+             migrations must not be edited.",
+            name(*version)
+        ),
+        MigrateError::VersionMissing(version) => format!(
+            "Migration {} was applied to this database but is unknown to this build. \
+             The database was probably migrated by a newer version, so upgrade \
+             instead of downgrading, or restore a backup.",
+            version
+        ),
+        MigrateError::Dirty(version) => format!(
+            "Migration {} was only partially applied. Fix the schema by hand and \
+             remove its row from the _sqlx_migrations table.",
+            name(*version)
+        ),
+        error => format!("{}", error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket_db_pools::{Database, Pool};
+
+    #[test]
+    fn shard_hash_is_fnv1a() {
+        assert_eq!(shard_hash(""), 0xcbf29ce484222325);
+        assert_eq!(shard_hash("a"), 0xaf63dc4c8601ec8c);
+    }
+
+    #[rocket::async_test]
+    async fn tokens_in_different_shards_are_logged_into_different_files() {
+        let dir = crate::testing::TempDir::new("shards");
+        let path = |name: &str| dir.path(name);
+        let figment = Figment::new()
+            .merge(("url", path("main.db")))
+            .merge(("shards", vec![path("shard-0.db"), path("shard-1.db")]))
+            .merge(("max_connections", 2))
+            .merge(("connect_timeout", 5));
+        let pool = SqlitePool::init(&figment).await.unwrap();
+        pool.migrate(&sqlx::migrate!("./migrations")).await.unwrap();
+
+        let (first, second) = ("token-a", "token-b");
+        assert!(!std::ptr::eq(pool.for_token(first), pool.for_token(second)));
+        for token in [first, second] {
+            sqlx::query("INSERT INTO energy_log (token, amps, volts, watts) VALUES (?, 1, 230, 230)")
+                .bind(token)
+                .execute(pool.for_token(token))
+                .await
+                .unwrap();
+        }
+
+        // Each file only has the reading of its token
+        for token in [first, second] {
+            let tokens: Vec<String> = sqlx::query_scalar("SELECT token FROM energy_log")
+                .fetch_all(pool.for_token(token))
+                .await
+                .unwrap();
+            assert_eq!(tokens, vec![token.to_string()]);
+        }
+        // The main pool reads both, with ids that do not collide
+        let ids: Vec<i64> = sqlx::query_scalar("SELECT id FROM energy_log ORDER BY id")
+            .fetch_all(&*pool)
+            .await
+            .unwrap();
+        assert_eq!(ids, vec![(1 << SHARD_ID_BITS) + 1, (2 << SHARD_ID_BITS) + 1]);
+        // And deletes from every database
+        for db in pool.databases() {
+            sqlx::query("DELETE FROM main.energy_log WHERE token = ?")
+                .bind(first)
+                .execute(db)
+                .await
+                .unwrap();
+        }
+        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM energy_log")
+            .fetch_one(&*pool)
+            .await
+            .unwrap();
+        assert_eq!(count, 1);
+
+        pool.close().await;
+    }
+
+    #[rocket::async_test]
+    async fn connections_use_wal_and_the_busy_timeout() {
+        let dir = crate::testing::TempDir::new("pragmas");
+        let url = dir.path("logs.db");
+        let figment = Figment::new()
+            .merge(("url", &url))
+            .merge(("max_connections", 2))
+            .merge(("connect_timeout", 5))
+            .merge(("busy_timeout", 3));
+
+        let pool = SqlitePool::init(&figment).await.unwrap();
+        let mut connection = pool.get().await.unwrap();
+        let journal_mode: String = sqlx::query_scalar("PRAGMA journal_mode")
+            .fetch_one(&mut *connection)
+            .await
+            .unwrap();
+        assert_eq!(journal_mode, "wal");
+        let busy_timeout: i64 = sqlx::query_scalar("PRAGMA busy_timeout")
+            .fetch_one(&mut *connection)
+            .await
+            .unwrap();
+        assert_eq!(busy_timeout, 3000);
+        drop(connection);
+
+        // A read-only pool keeps the journal mode of the writer
+        let read_only = SqlitePool::init(&figment.clone().merge(("read_only", true)))
+            .await
+            .unwrap();
+        let journal_mode: String = sqlx::query_scalar("PRAGMA journal_mode")
+            .fetch_one(&*read_only)
+            .await
+            .unwrap();
+        assert_eq!(journal_mode, "wal");
+
+        read_only.close().await;
+        pool.close().await;
+    }
+
+    #[test]
+    fn migrate_errors_name_the_migration_and_the_fix() {
+        let migrator = sqlx::migrate!("./migrations");
+
+        let modified = describe_migrate_error(&migrator, &MigrateError::VersionMismatch(2));
+        assert!(modified.starts_with("Migration 2 (add token) was applied with a different"));
+        assert!(modified.contains("Restore the original migrations/ file"));
+
+        let unknown = describe_migrate_error(&migrator, &MigrateError::VersionMissing(9999));
+        assert!(unknown.starts_with("Migration 9999 was applied to this database but is unknown"));
+
+        let dirty = describe_migrate_error(&migrator, &MigrateError::Dirty(1));
+        assert!(dirty.starts_with("Migration 1 (init) was only partially applied"));
+    }
+
+    #[rocket::async_test]
+    async fn read_routes_use_the_read_pool() {
+        use crate::testing;
+        use rocket::http::{ContentType, Status};
+
+        let figment = testing::figment();
+        let primary = testing::client_with(figment.clone()).await;
+        primary.insert_reading("2024-01-01 10:00:00", 1.0, 230.0, 230.0).await;
+        primary.insert_reading("2024-01-01 10:01:00", 2.0, 230.0, 460.0).await;
+        // A replica that falls behind the primary
+        let dir = testing::TempDir::new("read-pool");
+        let replica = dir.path("replica.db");
+        // The copy would otherwise inherit the mode=memory of the primary
+        let dest = format!("file:{}?mode=rwc", replica);
+        crate::cli::backup::backup(primary.logs(), &dest).await.unwrap();
+        primary.insert_reading("2024-01-01 10:02:00", 3.0, 230.0, 690.0).await;
+
+        let app = testing::client_with(
+            figment
+                .merge(("databases.sqlite_logs_read.url", &replica))
+                .merge(("databases.sqlite_logs_read.read_only", true)),
+        )
+        .await;
+        let token = &primary.token;
+        let count = || async {
+            let response = app
+                .get(format!("/log/{}/count?start=2024-01-01T00:00&tz=UTC", token))
+                .dispatch()
+                .await;
+            assert_eq!(response.status(), Status::Ok);
+            response.into_json::<serde_json::Value>().await.unwrap()["count"].clone()
+        };
+        assert_eq!(count().await, 2);
+
+        // The ingest writes to the primary
+        let response = app
+            .post(format!("/log/{}", token))
+            .header(ContentType::JSON)
+            .body(r#"{"amps": 4, "volts": 230, "watts": 920}"#)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let rows: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM energy_log WHERE token = ?")
+            .bind(token)
+            .fetch_one(app.db())
+            .await
+            .unwrap();
+        assert_eq!(rows, 4);
+        assert_eq!(count().await, 2);
+    }
+
+    #[test]
+    fn the_read_pool_defaults_to_the_primary() {
+        let figment = Figment::new().merge(("databases.sqlite_logs.url", "sqlite:logs.db"));
+        let figment = with_read_pool_default(figment);
+        let url: String = figment.extract_inner("databases.sqlite_logs_read.url").unwrap();
+        assert_eq!(url, "sqlite:logs.db");
+
+        let figment = figment.merge(("databases.sqlite_logs_read.url", "sqlite:replica.db"));
+        let figment = with_read_pool_default(figment);
+        let url: String = figment.extract_inner("databases.sqlite_logs_read.url").unwrap();
+        assert_eq!(url, "sqlite:replica.db");
+    }
+
+    #[rocket::async_test]
+    async fn slow_queries_run_out_of_time() {
+        let app = crate::testing::client_with(
+            crate::testing::figment().merge(("databases.sqlite_logs_read.query_timeout", 1)),
+        )
+        .await;
+        let pool = crate::LogsRead::fetch(app.client.rocket()).unwrap();
+        let mut connection = pool.get().await.unwrap();
+        // Counts forever, until it is interrupted
+        let slow = "WITH RECURSIVE c(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM c) \
+                    SELECT COUNT(*) FROM c";
+
+        let query_timeout = QueryTimeout(Some(Instant::now() + Duration::from_millis(100)));
+        let result = query_timeout
+            .run(sqlx::query_scalar::<_, i64>(slow).fetch_one(&mut *connection))
+            .await;
+        let (status, message) = result.unwrap_err();
+        assert_eq!(status, Status::ServiceUnavailable);
+        assert_eq!(message, "The range is too large, narrow your query");
+
+        // SQLite interrupts the query once the grace period is over, which
+        // frees the connection
+        let error = sqlx::query_scalar::<_, i64>(slow)
+            .fetch_one(&mut *connection)
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("interrupted"), "{}", error);
+        let one: i64 = sqlx::query_scalar("SELECT 1")
+            .fetch_one(&mut *connection)
+            .await
+            .unwrap();
+        assert_eq!(one, 1);
+    }
+
+    #[rocket::async_test]
+    async fn released_connections_do_not_keep_the_deadline() {
+        use rocket::http::ContentType;
+
+        // A single connection, so that it is reused once released
+        let app = crate::testing::client_with(
+            crate::testing::figment()
+                .merge(("databases.sqlite_logs_read.query_timeout", 1))
+                .merge(("databases.sqlite_logs_read.max_connections", 1)),
+        )
+        .await;
+        let pool = crate::LogsRead::fetch(app.client.rocket()).unwrap();
+        let slow = "WITH RECURSIVE c(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM c) \
+                    SELECT COUNT(*) FROM c";
+        let mut connection = pool.get().await.unwrap();
+        let query_timeout = QueryTimeout(Some(Instant::now() + Duration::from_millis(100)));
+        let result = query_timeout
+            .run(sqlx::query_scalar::<_, i64>(slow).fetch_one(&mut *connection))
+            .await;
+        assert!(result.is_err());
+        // Runs until the deadline of the connection has passed
+        let error = sqlx::query_scalar::<_, i64>(slow)
+            .fetch_one(&mut *connection)
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("interrupted"), "{}", error);
+        drop(connection);
+
+        let response = app
+            .post(format!("/log/{}", app.token))
+            .header(ContentType::JSON)
+            .body(r#"{"amps": 4, "volts": 230, "watts": 920}"#)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        // Outside of a request, and longer than the progress handler interval
+        let read: &sqlx::SqlitePool = pool;
+        let count: i64 = sqlx::query_scalar(
+            "WITH RECURSIVE c(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM c WHERE x < 100000) \
+            SELECT COUNT(*) FROM c",
+        )
+        .fetch_one(read)
+        .await
+        .unwrap();
+        assert_eq!(count, 100000);
+    }
+
+    #[rocket::async_test]
+    async fn ranges_within_the_timeout_are_answered() {
+        let app = crate::testing::client_with(
+            crate::testing::figment().merge(("databases.sqlite_logs_read.query_timeout", 10)),
+        )
+        .await;
+        app.insert_reading("2024-01-01 10:00:00", 1.0, 230.0, 230.0).await;
+        let response = app
+            .get(format!("/log/{}/count?start=2024-01-01T00:00&tz=UTC", app.token))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+    }
+}
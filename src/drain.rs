@@ -0,0 +1,190 @@
+//! Graceful shutdown drain for the in-flight ingests.
+//!
+//! The ingest routes take a [WriteInProgress] guard, which counts the requests
+//! that may still be writing to the database. When the Rocket app shuts down,
+//! the [DrainFairing] waits for that count to go down to zero before the
+//! database pool is closed, so that a deploy does not lose the readings being
+//! inserted.
+//!
+//! The wait is bounded by `shutdown_drain_secs` in the figment configuration
+//! (Rocket.toml), 10 seconds by default.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::tokio::sync::Notify;
+
+/// How long the shutdown waits for the writes, unless `shutdown_drain_secs`
+/// is configured
+const DEFAULT_DRAIN_SECS: u64 = 10;
+
+/// The number of requests that may be writing to the database
+#[derive(Default)]
+pub struct InFlightWrites {
+    count: AtomicUsize,
+    idle: Notify,
+}
+
+impl InFlightWrites {
+    /// Counts a write as in progress until the returned guard is dropped
+    fn start(self: &Arc<Self>) -> WriteInProgress {
+        self.count.fetch_add(1, Ordering::SeqCst);
+        WriteInProgress(self.clone())
+    }
+
+    /// Waits for the writes in progress to complete, for up to `timeout`.
+    ///
+    /// Returns false if some were still in progress when giving up.
+    async fn drain(&self, timeout: Duration) -> bool {
+        rocket::tokio::time::timeout(timeout, self.wait_idle())
+            .await
+            .is_ok()
+    }
+
+    /// Waits until there are no writes in progress
+    async fn wait_idle(&self) {
+        loop {
+            // Created before checking the count, so a write finishing in
+            // between still wakes us up
+            let idle = self.idle.notified();
+            if self.count.load(Ordering::SeqCst) == 0 {
+                return;
+            }
+            idle.await;
+        }
+    }
+}
+
+/// Request guard that counts the request as writing to the database until it
+/// is dropped, along with the rest of the request.
+pub struct WriteInProgress(Arc<InFlightWrites>);
+
+impl Drop for WriteInProgress {
+    fn drop(&mut self) {
+        if self.0.count.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.idle.notify_waiters();
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> rocket::request::FromRequest<'r> for WriteInProgress {
+    type Error = ();
+
+    async fn from_request(
+        request: &'r rocket::Request<'_>,
+    ) -> rocket::request::Outcome<Self, Self::Error> {
+        match request.rocket().state::<Arc<InFlightWrites>>() {
+            Some(writes) => rocket::request::Outcome::Success(writes.start()),
+            None => rocket::request::Outcome::Error((rocket::http::Status::InternalServerError, ())),
+        }
+    }
+}
+
+/// This fairing manages the [InFlightWrites] counter, and waits for it to
+/// drain when the Rocket app shuts down.
+pub struct DrainFairing {
+    writes: Arc<InFlightWrites>,
+}
+
+impl DrainFairing {
+    pub fn new() -> Self {
+        Self {
+            writes: Arc::new(InFlightWrites::default()),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for DrainFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Drain In-Flight Writes",
+            kind: Kind::Ignite | Kind::Shutdown,
+        }
+    }
+
+    async fn on_ignite(&self, rocket: rocket::Rocket<rocket::Build>) -> rocket::fairing::Result {
+        Ok(rocket.manage(self.writes.clone()))
+    }
+
+    /// Rocket stops accepting requests before calling this, and only closes
+    /// the remaining connections once every shutdown callback has completed,
+    /// so waiting here lets the in-flight writes finish.
+    async fn on_shutdown(&self, rocket: &rocket::Rocket<rocket::Orbit>) -> () {
+        let drain_secs = rocket
+            .figment()
+            .extract_inner("shutdown_drain_secs")
+            .unwrap_or(DEFAULT_DRAIN_SECS);
+        let pending = self.writes.count.load(Ordering::SeqCst);
+        if pending == 0 {
+            return;
+        }
+
+        log::info!("Waiting up to {}s for {} in-flight writes", drain_secs, pending);
+        if self.writes.drain(Duration::from_secs(drain_secs)).await {
+            log::info!("In-flight writes completed");
+        } else {
+            log::warn!(
+                "Gave up waiting for {} in-flight writes",
+                self.writes.count.load(Ordering::SeqCst)
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use rocket::http::{ContentType, Status};
+
+    #[rocket::async_test]
+    async fn a_slow_insert_completes_during_the_drain() {
+        let app = testing::client().await;
+        let writes = Arc::new(InFlightWrites::default());
+
+        let write = writes.start();
+        let db = app.db().clone();
+        let token = app.token.clone();
+        rocket::tokio::spawn(async move {
+            rocket::tokio::time::sleep(Duration::from_millis(300)).await;
+            let insert = "INSERT INTO energy_log (token, amps, volts, watts) VALUES (?, 1, 230, 230)";
+            sqlx::query(insert).bind(token).execute(&db).await.unwrap();
+            drop(write);
+        });
+
+        assert!(writes.drain(Duration::from_secs(5)).await);
+        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM energy_log")
+            .fetch_one(app.db())
+            .await
+            .unwrap();
+        assert_eq!(count, 1);
+    }
+
+    #[rocket::async_test]
+    async fn the_drain_gives_up_after_the_timeout() {
+        let writes = Arc::new(InFlightWrites::default());
+        assert!(writes.drain(Duration::ZERO).await);
+
+        let _stuck = writes.start();
+        assert!(!writes.drain(Duration::from_millis(50)).await);
+    }
+
+    #[rocket::async_test]
+    async fn the_ingest_routes_count_as_writes_until_they_respond() {
+        let app = testing::client().await;
+        let response = app
+            .post(format!("/log/{}", app.token))
+            .header(ContentType::JSON)
+            .body(r#"{"amps": 4, "volts": 230, "watts": 920}"#)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+
+        let writes = app.client.rocket().state::<Arc<InFlightWrites>>().unwrap();
+        assert_eq!(writes.count.load(Ordering::SeqCst), 0);
+    }
+}
@@ -18,11 +18,10 @@ pub enum HtmlInputParseableDateTime {
 impl HtmlInputParseableDateTime {
     /// Check if the datetime is set
     pub fn is_some(&self) -> bool {
-        match self {
-            HtmlInputParseableDateTime::Naive(Some(_)) => true,
-            HtmlInputParseableDateTime::WithTz(Some(_)) => true,
-            _ => false,
-        }
+        matches!(
+            self,
+            HtmlInputParseableDateTime::Naive(Some(_)) | HtmlInputParseableDateTime::WithTz(Some(_))
+        )
     }
 
     /// Check if the datetime is not set
@@ -145,3 +144,66 @@ impl core::ops::Deref for Tz {
         &self.0
     }
 }
+
+/// Custom form field for a relative time range ending now, such as `24h`,
+/// `7d` or `2w` (hours, days or weeks).
+///
+/// It only sets the default start of the read routes, so an explicit `start`
+/// or `end` still overrides it.
+#[derive(Debug, Clone, Copy)]
+pub struct Range(pub chrono::Duration);
+
+impl FromStr for Range {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid range {:?}, expected e.g. 24h, 7d or 2w", value);
+        let (split, _) = value.char_indices().last().ok_or_else(invalid)?;
+        let (number, unit) = value.split_at(split);
+        let number: i64 = number.parse().map_err(|_| invalid())?;
+        if number <= 0 {
+            return Err(invalid());
+        }
+        let duration = match unit {
+            "h" => chrono::Duration::try_hours(number),
+            "d" => chrono::Duration::try_days(number),
+            "w" => chrono::Duration::try_weeks(number),
+            _ => None,
+        };
+        duration.map(Range).ok_or_else(invalid)
+    }
+}
+
+impl<'r> rocket::form::FromFormField<'r> for Range {
+    fn from_value(field: rocket::form::ValueField<'r>) -> rocket::form::Result<'r, Self> {
+        field
+            .value
+            .parse()
+            .map_err(|e: String| rocket::form::Error::validation(e).into())
+    }
+}
+
+/// Returns the default start of the read routes: `range` ago if given, or
+/// one day ago otherwise.
+pub fn default_start(range: Option<&Range>) -> chrono::DateTime<chrono::Utc> {
+    let duration = range.map_or(chrono::Duration::days(1), |range| range.0);
+    chrono::Utc::now()
+        .checked_sub_signed(duration)
+        .unwrap_or(chrono::DateTime::<chrono::Utc>::MIN_UTC)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_relative_ranges() {
+        let parse = |value: &str| value.parse::<Range>().map(|range| range.0);
+        assert_eq!(parse("24h"), Ok(chrono::Duration::hours(24)));
+        assert_eq!(parse("7d"), Ok(chrono::Duration::days(7)));
+        assert_eq!(parse("2w"), Ok(chrono::Duration::weeks(2)));
+        for invalid in ["", "h", "24", "0h", "-1d", "1.5d", "3m", "1ñ"] {
+            assert!(parse(invalid).is_err(), "{:?} should be rejected", invalid);
+        }
+    }
+}
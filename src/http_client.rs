@@ -0,0 +1,125 @@
+//! The HTTP client for the outbound calls to the Tessie API, the geocoder and
+//! the webhooks.
+//!
+//! A single [reqwest::Client] is shared by all of them, so that its connection
+//! pool keeps the connections (and their TLS sessions) alive between calls,
+//! instead of opening new ones for every check.
+//!
+//! The requests time out after `http_timeout_secs` in the figment
+//! configuration (Rocket.toml), 10 seconds by default, so that a hung
+//! external service cannot block the fairing tasks forever.
+
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use rocket::figment::Figment;
+
+/// How long an outbound request may take, unless `http_timeout_secs` is
+/// configured
+const DEFAULT_HTTP_TIMEOUT_SECS: u64 = 10;
+
+/// How long an unused connection is kept in the pool. It covers the usual
+/// check intervals, so the periodic calls find their connection still open.
+const POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// The client shared by the outbound calls, built on ignite from the figment
+pub static HTTP_CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+
+/// Builds a client with the configured timeout.
+pub fn from_figment(figment: &Figment) -> reqwest::Client {
+    let timeout_secs = figment
+        .extract_inner("http_timeout_secs")
+        .unwrap_or(DEFAULT_HTTP_TIMEOUT_SECS);
+    reqwest::Client::builder()
+        .timeout(Duration::from_secs(timeout_secs))
+        .pool_idle_timeout(POOL_IDLE_TIMEOUT)
+        .build()
+        .expect("the HTTP client configuration is valid")
+}
+
+/// Returns the shared client. Cloning it is cheap, and the clones share the
+/// same connection pool.
+///
+/// If it has not been built from the figment yet, it is built with the
+/// defaults.
+pub fn shared() -> &'static reqwest::Client {
+    HTTP_CLIENT.get_or_init(|| from_figment(&Figment::new()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MockServer;
+    use std::time::Instant;
+
+    #[rocket::async_test]
+    async fn a_hung_service_times_out() {
+        let server = MockServer::silent().await;
+        let client = from_figment(&Figment::new().merge(("http_timeout_secs", 1)));
+
+        let started = Instant::now();
+        let error = client.get(&server.url).send().await.unwrap_err();
+        assert!(error.is_timeout(), "{:?}", error);
+        assert!(started.elapsed() >= Duration::from_secs(1));
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(server.requests(), vec!["GET / HTTP/1.1"]);
+    }
+
+    #[rocket::async_test]
+    async fn a_hung_webhook_does_not_block_the_sender() {
+        let server = MockServer::silent().await;
+        let client = from_figment(&Figment::new().merge(("http_timeout_secs", 1)));
+
+        let sent = rocket::tokio::time::timeout(
+            Duration::from_secs(5),
+            crate::alive_check::send_webhook(&client, &server.url, None),
+        )
+        .await;
+        assert!(sent.is_ok(), "the webhook should give up after the timeout");
+    }
+
+    /// Starts a server that keeps the connections alive between requests, and
+    /// returns its URL and the number of connections accepted so far
+    async fn keep_alive_server() -> (String, std::sync::Arc<std::sync::atomic::AtomicUsize>) {
+        use rocket::tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let listener = rocket::tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let connections = std::sync::Arc::new(AtomicUsize::new(0));
+        let accepted = connections.clone();
+        rocket::tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                accepted.fetch_add(1, Ordering::SeqCst);
+                rocket::tokio::spawn(async move {
+                    let mut stream = BufReader::new(stream);
+                    let mut line = String::new();
+                    while stream.read_line(&mut line).await.is_ok_and(|read| read > 0) {
+                        if line == "\r\n" {
+                            let response = "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n{}";
+                            stream.get_mut().write_all(response.as_bytes()).await.unwrap();
+                        }
+                        line.clear();
+                    }
+                });
+            }
+        });
+        (url, connections)
+    }
+
+    #[rocket::async_test]
+    async fn the_outbound_calls_share_one_client_and_its_connections() {
+        assert!(std::ptr::eq(shared(), shared()));
+
+        let (url, connections) = keep_alive_server().await;
+        // As held by the Tessie handler and the geocoder
+        let (tessie, geocoder) = (shared().clone(), shared().clone());
+        for client in [&tessie, &geocoder, &tessie] {
+            let response = client.get(&url).send().await.unwrap();
+            assert_eq!(response.text().await.unwrap(), "{}");
+            // The connection goes back to the pool in the background
+            rocket::tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert_eq!(connections.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,233 @@
+//! Support for the `Idempotency-Key` header on the ingest route.
+//!
+//! Sensors on flaky connections may retry a POST whose response was lost,
+//! logging the same reading twice. If they send an `Idempotency-Key` header
+//! (e.g., a counter or a random id per reading), we remember the key for the
+//! token for a while, with the outcome of the request that used it:
+//!
+//! - While it is pending, a retry is answered with a 409 Conflict, as the
+//!   reading may or may not be inserted yet.
+//! - Once the reading is inserted, a retry is answered with the original
+//!   result without inserting the reading again.
+//! - If the insert failed, a retry inserts the reading.
+//!
+//! The keys are only kept in memory, for up to [KEY_TTL] and at most
+//! [MAX_KEYS] of them, evicting the oldest first.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How long a key is remembered after the first request that used it
+pub const KEY_TTL: Duration = Duration::from_secs(600);
+
+/// The maximum number of keys remembered across all the tokens
+pub const MAX_KEYS: usize = 4096;
+
+/// The value of the `Idempotency-Key` header, if any
+pub struct IdempotencyKey(pub Option<String>);
+
+#[rocket::async_trait]
+impl<'r> rocket::request::FromRequest<'r> for IdempotencyKey {
+    type Error = ();
+
+    async fn from_request(
+        request: &'r rocket::Request<'_>,
+    ) -> rocket::request::Outcome<Self, Self::Error> {
+        let key = request
+            .headers()
+            .get_one("Idempotency-Key")
+            .map(str::trim)
+            .filter(|key| !key.is_empty())
+            .map(str::to_string);
+        rocket::request::Outcome::Success(IdempotencyKey(key))
+    }
+}
+
+/// A (token, key) pair
+type Entry = (String, String);
+
+/// The outcome of the request that used a key
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Outcome {
+    Pending,
+    Succeeded,
+    Failed,
+}
+
+/// The entries seen recently, with the time they were first seen and the
+/// outcome of their request, and in the order they were seen.
+///
+/// As every key lives for the same TTL, the insertion order is also the
+/// expiration order, so a queue is enough to evict them.
+#[derive(Default)]
+struct Entries {
+    seen: HashMap<Entry, (Instant, Outcome)>,
+    order: VecDeque<Entry>,
+}
+
+/// The (token, key) pairs seen recently, managed as Rocket state.
+pub struct IdempotencyCache {
+    entries: Mutex<Entries>,
+}
+
+/// What to do with a request that has an idempotency key, see
+/// [IdempotencyCache::begin]
+pub enum Begin<'a> {
+    /// Insert the reading, and record the outcome with the [Attempt]
+    First(Attempt<'a>),
+    /// Another request with the key is still inserting the reading
+    Pending,
+    /// A request with the key already inserted the reading
+    Succeeded,
+}
+
+/// The request inserting the reading of a key, which is recorded as failed
+/// when dropped, unless it [succeeded](Attempt::succeeded).
+pub struct Attempt<'a> {
+    cache: &'a IdempotencyCache,
+    entry: Entry,
+    succeeded: bool,
+}
+
+impl Attempt<'_> {
+    /// Records that the reading was inserted, once it is committed
+    pub fn succeeded(mut self) {
+        self.succeeded = true;
+    }
+}
+
+impl Drop for Attempt<'_> {
+    fn drop(&mut self) {
+        let outcome = if self.succeeded {
+            Outcome::Succeeded
+        } else {
+            Outcome::Failed
+        };
+        self.cache.record(&self.entry, outcome);
+    }
+}
+
+impl IdempotencyCache {
+    pub fn new() -> Self {
+        Self {
+            entries: Mutex::new(Entries::default()),
+        }
+    }
+
+    /// Records the key for the token as pending, unless a request that used
+    /// it within the TTL is pending or succeeded, i.e., if this is a retry.
+    pub fn begin(&self, token: &str, key: &str) -> Begin<'_> {
+        self.begin_at(token, key, Instant::now())
+    }
+
+    /// Like [begin](Self::begin), at the given time
+    fn begin_at(&self, token: &str, key: &str, now: Instant) -> Begin<'_> {
+        let mut guard = self.entries.lock().unwrap();
+        let Entries { seen, order } = &mut *guard;
+
+        // Forget the expired keys, and the oldest ones if we are over capacity
+        while let Some(oldest) = order.front() {
+            let expired = seen
+                .get(oldest)
+                .is_none_or(|(at, _)| now.duration_since(*at) >= KEY_TTL);
+            if !expired && order.len() < MAX_KEYS {
+                break;
+            }
+            let oldest = order.pop_front().unwrap();
+            seen.remove(&oldest);
+        }
+
+        let entry = (token.to_string(), key.to_string());
+        match seen.get_mut(&entry) {
+            Some((_, Outcome::Pending)) => return Begin::Pending,
+            Some((_, Outcome::Succeeded)) => return Begin::Succeeded,
+            // The retry of a failed request inserts the reading
+            Some((_, outcome)) => *outcome = Outcome::Pending,
+            None => {
+                seen.insert(entry.clone(), (now, Outcome::Pending));
+                order.push_back(entry.clone());
+            }
+        }
+        Begin::First(Attempt {
+            cache: self,
+            entry,
+            succeeded: false,
+        })
+    }
+
+    /// Records the outcome of the request of the entry, unless it was evicted
+    /// meanwhile
+    fn record(&self, entry: &Entry, outcome: Outcome) {
+        let mut guard = self.entries.lock().unwrap();
+        if let Some((_, recorded)) = guard.seen.get_mut(entry) {
+            *recorded = outcome;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Begins a request with the key, and records its outcome
+    fn request(cache: &IdempotencyCache, token: &str, key: &str, now: Instant, succeeds: bool) -> bool {
+        match cache.begin_at(token, key, now) {
+            Begin::First(attempt) => {
+                if succeeds {
+                    attempt.succeeded();
+                }
+                true
+            }
+            Begin::Pending | Begin::Succeeded => false,
+        }
+    }
+
+    #[test]
+    fn keys_are_remembered_per_token_until_the_ttl() {
+        let cache = IdempotencyCache::new();
+        let now = Instant::now();
+
+        assert!(request(&cache, "token", "1", now, true));
+        assert!(!request(&cache, "token", "1", now + Duration::from_secs(1), true));
+        assert!(request(&cache, "other", "1", now, true));
+        assert!(request(&cache, "token", "1", now + KEY_TTL, true));
+    }
+
+    #[test]
+    fn retries_of_pending_keys_wait_for_the_outcome() {
+        let cache = IdempotencyCache::new();
+        let now = Instant::now();
+
+        let Begin::First(attempt) = cache.begin_at("token", "1", now) else {
+            panic!("the key should be new");
+        };
+        assert!(matches!(cache.begin_at("token", "1", now), Begin::Pending));
+        attempt.succeeded();
+        assert!(matches!(cache.begin_at("token", "1", now), Begin::Succeeded));
+    }
+
+    #[test]
+    fn failed_keys_are_retried() {
+        let cache = IdempotencyCache::new();
+        let now = Instant::now();
+
+        assert!(request(&cache, "token", "1", now, false));
+        assert!(request(&cache, "token", "1", now, true));
+        assert!(!request(&cache, "token", "1", now, true));
+    }
+
+    #[test]
+    fn the_oldest_keys_are_evicted_over_capacity() {
+        let cache = IdempotencyCache::new();
+        let now = Instant::now();
+
+        for key in 0..MAX_KEYS {
+            assert!(request(&cache, "token", &key.to_string(), now, true));
+        }
+        // Making room for the new key forgets the oldest one
+        assert!(request(&cache, "token", "new", now, true));
+        assert!(request(&cache, "token", "0", now, true));
+        assert!(!request(&cache, "token", "new", now, true));
+    }
+}
@@ -0,0 +1,187 @@
+//! Parser for readings sent in the [InfluxDB line protocol][line-protocol].
+//!
+//! This allows sensors that already speak the line protocol to log data
+//! without reformatting it as JSON. Each line is expected to look like:
+//!
+//! ```text
+//! energy,sensor=kitchen amps=3.2,volts=230,watts=736 1700000000
+//! ```
+//!
+//! The measurement name and tags are ignored, as the token already identifies
+//! the sensor. The `amps` and `watts` fields are required, `volts` is optional
+//! and any other field is ignored. The timestamp is optional too, and its
+//! precision is given by the `precision` query parameter, as in InfluxDB.
+//!
+//! [line-protocol]: https://docs.influxdata.com/influxdb/v2/reference/syntax/line-protocol/
+
+use chrono::{NaiveDateTime, SubsecRound};
+
+/// A reading parsed from a single line of the line protocol
+#[derive(Debug, PartialEq)]
+pub struct InfluxReading {
+    pub amps: f64,
+    pub volts: Option<f64>,
+    pub watts: f64,
+    pub timestamp: Option<NaiveDateTime>,
+}
+
+/// The precision of the timestamps in the body
+#[derive(Debug, Clone, Copy, Default, rocket::FromFormField)]
+pub enum Precision {
+    #[default]
+    S,
+    Ms,
+    Us,
+    Ns,
+}
+
+impl Precision {
+    fn to_datetime(self, value: i64) -> Option<NaiveDateTime> {
+        let datetime = match self {
+            Precision::S => chrono::DateTime::from_timestamp(value, 0),
+            Precision::Ms => chrono::DateTime::from_timestamp_millis(value),
+            Precision::Us => chrono::DateTime::from_timestamp_micros(value),
+            Precision::Ns => Some(chrono::DateTime::from_timestamp_nanos(value)),
+        };
+        // Truncated to seconds, as the database stores them
+        datetime.map(|dt| dt.naive_utc().trunc_subsecs(0))
+    }
+}
+
+/// Split a line protocol section on a separator, respecting backslash escapes
+/// and double-quoted strings.
+fn split_unescaped(input: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut escaped = false;
+    let mut quoted = false;
+    for (i, c) in input.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => quoted = !quoted,
+            c if c == separator && !quoted => {
+                parts.push(&input[start..i]);
+                start = i + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+    parts.push(&input[start..]);
+    parts
+}
+
+/// Parse a field value, which may carry an integer suffix (`i` or `u`)
+fn parse_field_value(value: &str) -> Option<f64> {
+    value
+        .strip_suffix(['i', 'u'])
+        .unwrap_or(value)
+        .parse::<f64>()
+        .ok()
+}
+
+/// Parse a single line of the line protocol
+fn parse_line(line: &str, precision: Precision) -> Result<InfluxReading, String> {
+    let sections: Vec<&str> = split_unescaped(line, ' ')
+        .into_iter()
+        .filter(|s| !s.is_empty())
+        .collect();
+    let (fields, timestamp) = match sections.as_slice() {
+        [_measurement, fields] => (*fields, None),
+        [_measurement, fields, timestamp] => (*fields, Some(*timestamp)),
+        _ => return Err("expected `measurement fields [timestamp]`".to_string()),
+    };
+
+    let (mut amps, mut volts, mut watts) = (None, None, None);
+    for field in split_unescaped(fields, ',') {
+        let (key, value) = field
+            .split_once('=')
+            .ok_or_else(|| format!("invalid field `{}`", field))?;
+        let target = match key {
+            "amps" => &mut amps,
+            "volts" => &mut volts,
+            "watts" => &mut watts,
+            _ => continue,
+        };
+        *target = Some(parse_field_value(value).ok_or_else(|| format!("invalid value for `{}`", key))?);
+    }
+
+    let timestamp = match timestamp {
+        Some(timestamp) => Some(
+            timestamp
+                .parse::<i64>()
+                .ok()
+                .and_then(|t| precision.to_datetime(t))
+                .ok_or_else(|| format!("invalid timestamp `{}`", timestamp))?,
+        ),
+        None => None,
+    };
+
+    Ok(InfluxReading {
+        amps: amps.ok_or("missing required field `amps`")?,
+        volts,
+        watts: watts.ok_or("missing required field `watts`")?,
+        timestamp,
+    })
+}
+
+/// Parse a line protocol body into readings.
+///
+/// Empty lines and comments are skipped. If any line is invalid, the whole
+/// body is rejected with an error pointing at the offending line.
+pub fn parse_lines(body: &str, precision: Precision) -> Result<Vec<InfluxReading>, String> {
+    body.lines()
+        .enumerate()
+        .map(|(i, line)| (i, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(i, line)| parse_line(line, precision).map_err(|e| format!("line {}: {}", i + 1, e)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn datetime(value: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S").unwrap()
+    }
+
+    #[test]
+    fn parses_fields_and_skips_comments() {
+        let body = "# a comment\n\nenergy,sensor=kitchen amps=3.2,volts=230i,watts=736,other=\"x y\"\n";
+        let readings = parse_lines(body, Precision::S).unwrap();
+        assert_eq!(
+            readings,
+            vec![InfluxReading {
+                amps: 3.2,
+                volts: Some(230.0),
+                watts: 736.0,
+                timestamp: None,
+            }]
+        );
+    }
+
+    #[test]
+    fn truncates_timestamps_to_seconds() {
+        let expected = Some(datetime("2026-10-16 18:06:55"));
+        for (precision, timestamp) in [
+            (Precision::S, "1792174015"),
+            (Precision::Ms, "1792174015953"),
+            (Precision::Us, "1792174015953095"),
+            (Precision::Ns, "1792174015953095915"),
+        ] {
+            let body = format!("energy amps=1,watts=230 {}", timestamp);
+            let readings = parse_lines(&body, precision).unwrap();
+            assert_eq!(readings[0].timestamp, expected, "{:?}", precision);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_lines() {
+        let error = parse_lines("energy amps=1,watts=230\nenergy volts=230,watts=230", Precision::S)
+            .unwrap_err();
+        assert_eq!(error, "line 2: missing required field `amps`");
+        assert!(parse_lines("energy amps=1,watts=230 soon", Precision::S).is_err());
+        assert!(parse_lines("energy", Precision::S).is_err());
+    }
+}
@@ -0,0 +1,110 @@
+//! Body size limits for the ingest routes.
+//!
+//! Each ingest route reads its body up to its own named data limit, which can
+//! be configured with Rocket's `limits` in the figment configuration
+//! (Rocket.toml):
+//!
+//! ```toml
+//! [default.limits]
+//! # A single reading to POST /log/:token
+//! log = "16KiB"
+//! # A batch of readings to POST /log/:token/influx
+//! influx = "1MiB"
+//! # A historical CSV to POST /log/:token/import
+//! csv = "16MiB"
+//! ```
+//!
+//! Bodies over the limit are rejected with a 413 Payload Too Large.
+
+use rocket::data::{ByteUnit, Data, Limits};
+use rocket::http::Status;
+
+/// The data limit of a single reading, unless `limits.log` is configured
+pub const DEFAULT_LOG_LIMIT: ByteUnit = ByteUnit::Kibibyte(16);
+
+/// The data limit of an InfluxDB batch, unless `limits.influx` is configured
+pub const DEFAULT_INFLUX_LIMIT: ByteUnit = ByteUnit::Mebibyte(1);
+
+/// The data limit of a CSV import, unless `limits.csv` is configured
+pub const DEFAULT_CSV_LIMIT: ByteUnit = ByteUnit::Mebibyte(16);
+
+/// Reads the whole body as a string, up to the data limit with the given
+/// name, or `default` if it is not configured.
+///
+/// Fails with a 413 if the body is over the limit, or with a 400 if it cannot
+/// be read.
+pub async fn read_body(
+    data: Data<'_>,
+    limits: &Limits,
+    name: &str,
+    default: ByteUnit,
+) -> Result<String, (Status, String)> {
+    let limit = limits.get(name).unwrap_or(default);
+    let body = data
+        .open(limit)
+        .into_string()
+        .await
+        .map_err(|e| (Status::BadRequest, e.to_string()))?;
+    if !body.is_complete() {
+        return Err((
+            Status::PayloadTooLarge,
+            format!("The body is over the {} limit of {}", name, limit),
+        ));
+    }
+    Ok(body.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use rocket::http::ContentType;
+
+    const READING: &str = r#"{"amps": 2.5, "volts": 230, "watts": 575}"#;
+
+    #[rocket::async_test]
+    async fn bodies_over_the_default_limit_are_rejected() {
+        let app = testing::client().await;
+        let post = |body: String| {
+            app.post(format!("/log/{}", app.token))
+                .header(ContentType::JSON)
+                .body(body)
+                .dispatch()
+        };
+
+        assert_eq!(post(READING.to_string()).await.status(), Status::Ok);
+        let padding = " ".repeat(DEFAULT_LOG_LIMIT.as_u64() as usize);
+        assert_eq!(
+            post(format!("{}{}", READING, padding)).await.status(),
+            Status::PayloadTooLarge
+        );
+    }
+
+    #[rocket::async_test]
+    async fn the_limits_are_configurable_per_route() {
+        let app = testing::client_with(
+            testing::figment()
+                .merge(("limits.log", "64B"))
+                .merge(("limits.csv", "64B")),
+        )
+        .await;
+
+        let response = app
+            .post(format!("/log/{}", app.token))
+            .header(ContentType::JSON)
+            .body(format!("{:<70}", READING))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::PayloadTooLarge);
+
+        let import = |body: &str| {
+            app.post(format!("/log/{}/import", app.token))
+                .header(ContentType::CSV)
+                .body(body)
+                .dispatch()
+        };
+        assert_eq!(import("2024-01-01 10:00:00,1,230,230\n").await.status(), Status::Ok);
+        let csv = "2024-01-01 10:00:00,1,230,230\n".repeat(3);
+        assert_eq!(import(&csv).await.status(), Status::PayloadTooLarge);
+    }
+}
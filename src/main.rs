@@ -3,12 +3,41 @@
 //!
 //! The application has a few routes:
 //! - POST /log/:token/ to insert data into the database
+//! - POST /log/:token/influx to insert data in InfluxDB line protocol
+//! - POST /log/:token/import to import historical data from a CSV file
+//! - GET /log/:token to get the data as JSON, HTML or SVG, per the Accept header
 //! - GET /log/:token/html to get the data in HTML format
-//! - GET /log/:token/json to get the data in JSON format
+//! - GET /log/:token/json to get the data in JSON format (optionally bucketed with ?interval or ?bucket)
+//! - GET /log/:token/ndjson to get the data as newline-delimited JSON
+//! - GET /log/:token/count to get how many readings a range has, e.g., to paginate
+//! - GET /log/:token/latest to get the most recent reading in JSON format
+//! - GET /log/:token/locations to list the locations the view token can see
+//! - GET /log/:token/at to get the reading nearest to a given instant
+//! - GET /log/:token/peak to get the highest average consumption over a window
+//! - GET /log/:token/histogram to get the distribution of the amps readings
+//! - GET /log/:token/report to get a summary of the energy, load and peaks
+//! - GET /log/:token/check to check a token is valid and when it last logged
+//! - GET /log/:token/stream to receive new readings as Server-Sent Events
+//! - GET /log/compare/svg?tokens=a,b to plot several tokens in the same chart
+//! - GET /healthz to check the database is reachable, for readiness checks
 //!
-//! There is no built-in token administration or rotation yet. You have to
-//! manually add tokens to the database using the SQLite CLI or a SQLite
-//! database management tool like DB Browser for SQLite.
+//! The read routes take the time range as `start` and `end` datetimes, or as a
+//! relative `range` ending now, such as `?range=24h`, `?range=7d` or
+//! `?range=2w` (see [form::Range]). If the read database has a
+//! `query_timeout`, those querying a range answer with a 503 once it is over
+//! (see [db::QueryTimeout]).
+//!
+//! The JSON, NDJSON and SVG routes, and the compare plot, take
+//! `normalize=watts` to return the watts as amps × volts, and to plot them
+//! instead of the amps, so that circuits wired on different voltages compare
+//! on one scale (see [print_table::Normalize]).
+//!
+//! There is no built-in token rotation yet. Sensor tokens can be created with
+//! the `create-token <location>` subcommand (see [cli::create_token]), or
+//! manually added to the database using the SQLite CLI or a SQLite database
+//! management tool like DB Browser for SQLite. View tokens and the
+//! aliases of replaced sensor tokens can be managed through the [admin]
+//! routes, if an `admin_token` is configured.
 //!
 //! We recommend using a tool such as Python's secrets module to generate
 //! cryptographically secure tokens.
@@ -19,58 +48,223 @@
 //! print(token)
 //! ```
 //!
+//! The database can be backed up while the server is running with the
+//! `backup <destination>` subcommand (see [cli::backup]). To migrate the data
+//! to another server, the `export-json <destination>` and `import-json <dump>`
+//! subcommands dump it as JSON and load it into a fresh database (see
+//! [cli::json_dump]).
+//!
 //! The application uses the rocket-governor crate to rate limit the POST
 //! requests to 4 requests per second per IP address, to prevent abuse.
+//! Trusted gateways forwarding many sensors can be exempted, see [RateLimit].
 //!
 //! The application also uses the rocket-db-pools crate to manage the SQLite
-//! database connection pool.
+//! database connection pools: [Logs] for the ingest, and [LogsRead] for the
+//! read routes, which may point to a read replica (see [db]).
 //!
 //! There are a few custom fairings in the application:
 //! - The [AliveCheckFairing](alive_check::AliveCheckFairing) checks if the
 //!   sensor is alive by checking if there has been any input in the last 60
 //!   seconds. If there hasn't been any input, it sends a message via webhook.
+//! - The [DrainFairing](drain::DrainFairing) lets the in-flight ingests finish
+//!   writing to the database when the server shuts down.
+//! - The [RetentionFairing](retention::RetentionFairing) optionally deletes
+//!   readings older than `raw_retention_days` to keep the database bounded.
+//! - The [ThresholdAlertFairing](threshold_alert::ThresholdAlertFairing)
+//!   optionally sends a message via webhook when a sensor's recent average
+//!   goes over `threshold_amps`.
+//! - The [SamplingRateFairing](sampling_rate::SamplingRateFairing) optionally
+//!   warns when a sensor logs more than `max_readings_per_minute`.
+//! - With the `mqtt` feature, the `MqttFairing` of the `mqtt` module
+//!   optionally ingests the readings published to an MQTT broker.
 //! - The [EVChargeFairing](car::fairing::EVChargeFairing) automatically
 //!   requests an EV to charge according to a maximum charge budget, dynamically
 //!   adjusted depending on the total energy consumption of the house. It
-//!   requires an [car::EVChargeHandler] as a type parameter, and the current
-//!   implementation uses [car::tessie]
+//!   requires an [car::EVChargeHandler] as a type parameter, selected at
+//!   runtime with the `ev_handler` setting, and the current implementation
+//!   uses [car::tessie]
 //! - New fairings like the EVChargeFairing could be implmented in the future to
 //!   add add other IoT devices or additional functionality.
 //!
+use conditional::{Cached, Conditional};
 use form::HtmlInputParseableDateTime;
 use governor::Quota;
 use print_table::{
-    get_avg_max_rows_for_token, get_paginated_rows_for_token, NoRowsError, Pagination,
+    get_avg_max_rows_for_token, get_calendar_rows_for_token, get_latest_row_for_token,
+    get_nearest_row_for_token, get_paginated_rows_for_token, CalendarBucket, MaxPageCount,
+    NoRowsError, Pagination, RowInfo,
 };
-use rocket::http::ContentType;
+use rocket::http::{ContentType, Status};
+use rocket::response::stream::TextStream;
 use rocket::serde::{json::Json, Deserialize};
-use rocket::{catchers, fairing, get, launch, post, routes};
+use rocket::{catch, catchers, fairing, get, post, routes, State};
 use rocket_db_pools::{sqlx, Connection, Database};
-use rocket_governor::{rocket_governor_catcher, RocketGovernable, RocketGovernor};
+use request_id::RequestId;
+use rocket_governor::{LimitError, RocketGovernable, RocketGovernor};
+use std::num::NonZeroU32;
+use std::sync::OnceLock;
 use token::{Token, ValidDbToken, ValidViewToken};
 
+mod access_log;
+mod admin;
 mod alive_check;
 mod car;
 mod cli;
+mod conditional;
+mod config;
+mod consistency;
+mod cors;
+mod csv_import;
+mod db;
+mod drain;
 pub mod form;
+mod http_client;
+mod idempotency;
+mod influx;
+mod limits;
+#[cfg(feature = "mqtt")]
+mod mqtt;
 mod print_table;
+mod proxy;
+mod quality;
+mod request_id;
+mod retention;
+mod sampling_rate;
+mod stream;
+#[cfg(test)]
+mod testing;
+mod threshold_alert;
 mod token;
 
 /// The energy log database pool
 #[derive(Database)]
 #[database("sqlite_logs")]
-struct Logs(sqlx::SqlitePool);
+struct Logs(db::SqlitePool);
+
+/// The pool the read routes query, which may be a read replica of [Logs], or
+/// the same database opened read-only (see [db::with_read_pool_default])
+#[derive(Database)]
+#[database("sqlite_logs_read")]
+struct LogsRead(db::SqlitePool);
+
+/// The rate limit quota as (requests per second, burst), loaded from the
+/// figment when the Rocket app is ignited.
+///
+/// [RocketGovernable::quota] cannot access the Rocket state, so we keep it in
+/// a static instead.
+static RATE_LIMIT_QUOTA: OnceLock<(NonZeroU32, NonZeroU32)> = OnceLock::new();
 
-/// Rate limit guard implementation, allowing 4 requests per second per IP
-/// address, bursting up to 15 requests.
+/// Rate limit guard implementation, allowing by default 4 requests per second
+/// per IP address, bursting up to 15 requests.
+///
+/// These can be configured with `rate_limit_per_second` and
+/// `rate_limit_burst` in the figment, see [load_rate_limit_quota].
 pub struct RateLimitGuard;
 
 impl<'r> RocketGovernable<'r> for RateLimitGuard {
     fn quota(_method: rocket_governor::Method, _route_name: &str) -> governor::Quota {
-        Quota::per_second(Self::nonzero(4u32)).allow_burst(Self::nonzero(15u32))
+        let (per_second, burst) = RATE_LIMIT_QUOTA
+            .get()
+            .copied()
+            .unwrap_or((Self::nonzero(4u32), Self::nonzero(15u32)));
+        Quota::per_second(per_second).allow_burst(burst)
+    }
+}
+
+/// The IP addresses or CIDRs exempt from the rate limit, from
+/// `rate_limit_exempt` in the figment, e.g., a gateway forwarding the
+/// readings of many sensors:
+///
+/// ```toml
+/// rate_limit_exempt = ["192.168.1.10", "10.1.0.0/16"]
+/// ```
+struct RateLimitExempt(Vec<ipnet::IpNet>);
+
+impl From<&rocket::figment::Figment> for RateLimitExempt {
+    fn from(figment: &rocket::figment::Figment) -> Self {
+        let exempt: Vec<String> = figment.extract_inner("rate_limit_exempt").unwrap_or_default();
+        let exempt = exempt
+            .iter()
+            .filter_map(|ip| {
+                let net = proxy::parse_proxy(ip);
+                if net.is_none() {
+                    log::error!("Ignoring invalid rate limit exemption: {}", ip);
+                }
+                net
+            })
+            .collect();
+        Self(exempt)
+    }
+}
+
+/// Request guard applying the [RateLimitGuard] governor, unless the client IP
+/// (resolved through the [trusted proxies](proxy)) is in [RateLimitExempt].
+///
+/// Over the limit, it fails like the governor, so the [too_many_requests]
+/// catcher answers.
+pub struct RateLimit;
+
+#[rocket::async_trait]
+impl<'r> rocket::request::FromRequest<'r> for RateLimit {
+    type Error = LimitError;
+
+    async fn from_request(
+        request: &'r rocket::Request<'_>,
+    ) -> rocket::request::Outcome<Self, Self::Error> {
+        let rocket = request.rocket();
+        let ip = rocket
+            .state::<proxy::TrustedProxies>()
+            .and_then(|proxies| proxies.client_ip(request));
+        let exempt = match (rocket.state::<RateLimitExempt>(), ip) {
+            (Some(exempt), Some(ip)) => exempt.0.iter().any(|net| net.contains(&ip)),
+            _ => false,
+        };
+        if exempt {
+            return rocket::request::Outcome::Success(RateLimit);
+        }
+        request
+            .guard::<RocketGovernor<'r, RateLimitGuard>>()
+            .await
+            .map(|_| RateLimit)
     }
 }
 
+/// Reads the [RateLimitGuard] quota from the figment, as (requests per
+/// second, burst), failing if any of the values is zero or invalid.
+fn rate_limit_quota(
+    figment: &rocket::figment::Figment,
+) -> Result<(NonZeroU32, NonZeroU32), ()> {
+    let value = |key: &str, default: u32| match figment.extract_inner::<NonZeroU32>(key) {
+        Ok(value) => Ok(value),
+        Err(e) if e.missing() => Ok(NonZeroU32::new(default).unwrap()),
+        Err(e) => {
+            log::error!("Invalid {}, it must be a positive integer: {}", key, e);
+            Err(())
+        }
+    };
+    Ok((value("rate_limit_per_second", 4)?, value("rate_limit_burst", 15)?))
+}
+
+/// Fairing that loads the [RateLimitGuard] quota and the [RateLimitExempt]
+/// addresses from the figment, failing the launch if any of the quota values
+/// is zero or invalid.
+fn load_rate_limit_quota() -> fairing::AdHoc {
+    fairing::AdHoc::try_on_ignite("Load rate limit quota", |rocket| async {
+        let figment = rocket.figment();
+        match rate_limit_quota(figment) {
+            Ok((per_second, burst)) => {
+                log::info!("Rate limit: {} requests per second, burst {}", per_second, burst);
+                if RATE_LIMIT_QUOTA.set((per_second, burst)).is_err() {
+                    log::warn!("Rate limit quota already loaded, keeping the first one");
+                }
+                let exempt = RateLimitExempt::from(figment);
+                Ok(rocket.manage(exempt))
+            }
+            Err(()) => Err(rocket),
+        }
+    })
+}
+
 /// Expected JSON body for the POST /log/:token/ route
 #[derive(Deserialize)]
 #[serde(crate = "rocket::serde")]
@@ -78,13 +272,46 @@ struct LogData {
     amps: f64,
     volts: Option<f64>,
     watts: f64,
+    temperature_c: Option<f64>,
+    power_factor: Option<f64>,
+}
+
+/// Parses the JSON body of a single reading, limited to the `log` data limit
+/// (see [limits]).
+#[rocket::async_trait]
+impl<'r> rocket::data::FromData<'r> for LogData {
+    type Error = String;
+
+    async fn from_data(
+        request: &'r rocket::Request<'_>,
+        data: rocket::data::Data<'r>,
+    ) -> rocket::data::Outcome<'r, Self> {
+        let body = match limits::read_body(
+            data,
+            request.limits(),
+            "log",
+            limits::DEFAULT_LOG_LIMIT,
+        )
+        .await
+        {
+            Ok(body) => body,
+            Err(error) => return rocket::data::Outcome::Error(error),
+        };
+        match serde_json::from_str(&body) {
+            Ok(log) => rocket::data::Outcome::Success(log),
+            Err(e) if e.is_data() => {
+                rocket::data::Outcome::Error((Status::UnprocessableEntity, e.to_string()))
+            }
+            Err(e) => rocket::data::Outcome::Error((Status::BadRequest, e.to_string())),
+        }
+    }
 }
 
 /// User-Agent header
 #[derive(Debug)]
 struct UserAgent<'a>(&'a str);
 
-/// Client IP address
+/// Client IP address, resolved through the [trusted proxies](proxy)
 #[derive(Debug)]
 struct ClientIP(String);
 
@@ -96,7 +323,7 @@ impl<'r> rocket::request::FromRequest<'r> for UserAgent<'r> {
         request: &'r rocket::Request<'_>,
     ) -> rocket::request::Outcome<Self, Self::Error> {
         let agent = request.headers().get_one("User-Agent").unwrap_or("Unknown");
-        log::info!("User-Agent: {}", agent);
+        log::info!("User-Agent: {}{}", agent, RequestId::in_logs());
         rocket::request::Outcome::Success(UserAgent(agent))
     }
 }
@@ -109,65 +336,493 @@ impl<'r> rocket::request::FromRequest<'r> for ClientIP {
         request: &'r rocket::Request<'_>,
     ) -> rocket::request::Outcome<Self, Self::Error> {
         let ip = request
-            .client_ip()
+            .rocket()
+            .state::<proxy::TrustedProxies>()
+            .and_then(|proxies| proxies.client_ip(request))
             .map(|ip| ip.to_string())
             .unwrap_or("Unknown".to_string());
         rocket::request::Outcome::Success(ClientIP(ip))
     }
 }
 
+/// Fairing that loads the [print_table::DISPLAY_PRECISION] from the
+/// `display_precision` in the figment, failing the launch if it is invalid.
+fn load_display_precision() -> fairing::AdHoc {
+    fairing::AdHoc::try_on_ignite("Load display precision", |rocket| async {
+        let Ok(precision) = display_precision(rocket.figment()) else {
+            return Err(rocket);
+        };
+        if print_table::DISPLAY_PRECISION.set(precision).is_err() {
+            log::warn!("Display precision already loaded, keeping the first one");
+        }
+        Ok(rocket)
+    })
+}
+
+/// Reads the `display_precision` from the figment, failing if it is over 15
+/// decimal places or invalid.
+fn display_precision(figment: &rocket::figment::Figment) -> Result<u32, ()> {
+    match figment.extract_inner::<u32>("display_precision") {
+        Ok(precision) if precision <= 15 => Ok(precision),
+        Err(e) if e.missing() => Ok(print_table::DEFAULT_DISPLAY_PRECISION),
+        Ok(precision) => {
+            log::error!("Invalid display_precision {}, it must be at most 15", precision);
+            Err(())
+        }
+        Err(e) => {
+            log::error!("Invalid display_precision: {}", e);
+            Err(())
+        }
+    }
+}
+
+/// Fairing that builds the [http_client::HTTP_CLIENT] shared by the outbound
+/// calls, with the `http_timeout_secs` in the figment.
+fn load_http_client() -> fairing::AdHoc {
+    fairing::AdHoc::on_ignite("Build HTTP client", |rocket| async {
+        let client = http_client::from_figment(rocket.figment());
+        if http_client::HTTP_CLIENT.set(client).is_err() {
+            log::warn!("HTTP client already built, keeping the first one");
+        }
+        rocket
+    })
+}
+
+/// Fairing that runs the pending DB migrations, failing the launch with an
+/// explanation if they cannot be applied (e.g., a migration was modified after
+/// being applied).
+fn run_migrations() -> fairing::AdHoc {
+    fairing::AdHoc::try_on_ignite("Run DB migrations", |rocket| async {
+        let Some(db) = Logs::fetch(&rocket) else {
+            return Err(rocket);
+        };
+        let migrator = sqlx::migrate!("./migrations");
+        match db.migrate(&migrator).await {
+            Ok(()) => Ok(rocket),
+            Err(e) => {
+                log::error!(
+                    "Could not migrate the database: {}",
+                    db::describe_migrate_error(&migrator, &e)
+                );
+                Err(rocket)
+            }
+        }
+    })
+}
+
 /************************* ROUTES *************************/
 
 /// Route POST /log/:token/ will INSERT value into the database (if token is valid and rate limit is not exceeded)
+///
+/// A retry with the same `Idempotency-Key` header as a recent request is
+/// answered without inserting the reading again, or with a 409 Conflict while
+/// that request is pending, see [idempotency].
+///
+/// If enabled, readings whose watts do not match amps * volts are flagged or
+/// rejected, see [consistency]. Such readings, and those without volts, are
+/// stored with data quality flags, see [quality].
+///
+/// The body is limited to the `log` data limit, 16 KiB by default.
 #[post("/log/<_>", data = "<log>", rank = 2)]
+#[allow(clippy::too_many_arguments)]
 async fn post_token(
     token: &ValidDbToken,
-    log: Json<LogData>,
+    log: LogData,
     ip: ClientIP,
     ua: UserAgent<'_>,
-    mut db: Connection<Logs>,
-    _ratelimit: RocketGovernor<'_, RateLimitGuard>,
-) -> String {
+    idempotency_key: idempotency::IdempotencyKey,
+    idempotency_cache: &State<idempotency::IdempotencyCache>,
+    watts_check: consistency::WattsCheck,
+    live: &State<stream::LiveReadings>,
+    request_id: &request_id::RequestId,
+    db: &State<Logs>,
+    _write: drain::WriteInProgress,
+    _ratelimit: RateLimit,
+) -> Result<String, (Status, String)> {
     let volts = log.volts.unwrap_or(220.0f64);
-    let _rows = sqlx::query!(
-        "INSERT INTO energy_log (token, amps, volts, watts, user_agent, client_ip) VALUES (?, ?, ?, ?, ?, ?)",
-        token,
-        log.amps,
-        volts,
-        log.watts,
-        ua.0,
-        ip.0
-    )
-    .execute(&mut **db)
-    .await
-    .unwrap()
-    .rows_affected();
+    let suspect = match watts_check.check(log.amps, volts, log.watts) {
+        consistency::Consistency::Consistent => false,
+        consistency::Consistency::Suspect => true,
+        consistency::Consistency::Rejected => {
+            return Err((
+                Status::UnprocessableEntity,
+                "The watts do not match amps * volts".to_string(),
+            ))
+        }
+    };
 
-    log::info!("Inserted row from IP {:?} and UA {:?}", ip, ua);
+    let attempt = match &idempotency_key.0 {
+        Some(key) => match idempotency_cache.begin(token.full_token(), key) {
+            idempotency::Begin::First(attempt) => Some(attempt),
+            idempotency::Begin::Pending => {
+                log::info!(
+                    "Retried reading with idempotency key {:?} still pending (request {})",
+                    key,
+                    request_id
+                );
+                return Err((
+                    Status::Conflict,
+                    "The reading is still being logged, please retry".to_string(),
+                ));
+            }
+            idempotency::Begin::Succeeded => {
+                log::info!(
+                    "Skipping retried reading with idempotency key {:?} (request {})",
+                    key,
+                    request_id
+                );
+                return Ok("OK".to_string());
+            }
+        },
+        None => None,
+    };
+
+    let flags = quality::ReadingFlags::observe(log.volts, suspect, None, chrono::Utc::now()).bits();
+    // In a transaction, as sqlx steps a failed statement once more after
+    // returning the error, which could insert the reading after all
+    let result = async {
+        let mut tx = db.for_token(token.full_token()).begin().await?;
+        sqlx::query!(
+            "INSERT INTO energy_log (token, amps, volts, watts, temperature_c, power_factor, flags, user_agent, client_ip, source) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, 'post_token')",
+            token,
+            log.amps,
+            volts,
+            log.watts,
+            log.temperature_c,
+            log.power_factor,
+            flags,
+            ua.0,
+            ip.0
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await
+    }
+    .await;
+    if let Err(e) = result {
+        // Dropping the attempt records it as failed, so that a retry inserts
+        // the reading
+        log::error!("Could not insert the reading (request {}): {}", request_id, e);
+        return Err((
+            Status::ServiceUnavailable,
+            "Could not log the reading, please retry".to_string(),
+        ));
+    }
+
+    log::info!(
+        "Inserted row from IP {:?} and UA {:?} (request {})",
+        ip,
+        ua,
+        request_id
+    );
+    if let Some(attempt) = attempt {
+        attempt.succeeded();
+    }
+
+    live.publish(
+        token.full_token(),
+        stream::LiveReading {
+            token: token.simplified(),
+            datetime: chrono::Utc::now(),
+            amps: log.amps,
+            volts,
+            watts: log.watts,
+            temperature_c: log.temperature_c,
+            power_factor: log.power_factor,
+        },
+    );
 
-    format!("OK")
+    Ok("OK".to_string())
 }
 
-#[get("/log/<_>/check")]
-async fn check_token_valid(
+/// Route POST /log/:token/influx will INSERT every line of an InfluxDB line
+/// protocol body into the database, in a single transaction.
+///
+/// If any line is malformed or lacks the `amps`/`watts` fields, nothing is
+/// inserted and a 422 is returned.
+///
+/// The body is limited to the `influx` data limit, 1 MiB by default.
+#[post("/log/<_>/influx?<precision>", data = "<body>")]
+#[allow(clippy::too_many_arguments)]
+async fn post_influx(
+    token: &ValidDbToken,
+    body: rocket::data::Data<'_>,
+    limits: &rocket::data::Limits,
+    precision: Option<influx::Precision>,
+    ip: ClientIP,
+    ua: UserAgent<'_>,
+    live: &State<stream::LiveReadings>,
+    request_id: &request_id::RequestId,
+    db: &State<Logs>,
+    _write: drain::WriteInProgress,
+    _ratelimit: RateLimit,
+) -> Result<String, (Status, String)> {
+    let body = limits::read_body(body, limits, "influx", limits::DEFAULT_INFLUX_LIMIT).await?;
+    let readings = influx::parse_lines(&body, precision.unwrap_or_default())
+        .map_err(|e| (Status::UnprocessableEntity, e))?;
+
+    let received = chrono::Utc::now();
+    let result = async {
+        let mut tx = db.for_token(token.full_token()).begin().await?;
+        for reading in &readings {
+            let volts = reading.volts.unwrap_or(220.0f64);
+            let flags =
+                quality::ReadingFlags::observe(reading.volts, false, reading.timestamp, received).bits();
+            sqlx::query!(
+                "INSERT INTO energy_log (token, amps, volts, watts, created_at, flags, user_agent, client_ip, source) VALUES (?, ?, ?, ?, COALESCE(?, CURRENT_TIMESTAMP), ?, ?, ?, 'post_influx')",
+                token,
+                reading.amps,
+                volts,
+                reading.watts,
+                reading.timestamp,
+                flags,
+                ua.0,
+                ip.0
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await
+    }
+    .await;
+    if let Err(e) = result {
+        log::error!("Could not insert the readings (request {}): {}", request_id, e);
+        return Err((
+            Status::ServiceUnavailable,
+            "Could not log the readings, please retry".to_string(),
+        ));
+    }
+
+    let now = chrono::Utc::now();
+    for reading in &readings {
+        live.publish(
+            token.full_token(),
+            stream::LiveReading {
+                token: token.simplified(),
+                datetime: reading.timestamp.map_or(now, |ts| ts.and_utc()),
+                amps: reading.amps,
+                volts: reading.volts.unwrap_or(220.0f64),
+                watts: reading.watts,
+                temperature_c: None,
+                power_factor: None,
+            },
+        );
+    }
+
+    log::info!(
+        "Inserted {} rows from IP {:?} and UA {:?} (request {})",
+        readings.len(),
+        ip,
+        ua,
+        request_id
+    );
+
+    Ok("OK".to_string())
+}
+
+/// The number of rows inserted per transaction by the CSV import
+const IMPORT_BATCH_SIZE: usize = 500;
+
+/// Route POST /log/:token/import will INSERT the historical readings of a CSV
+/// body (see [csv_import]) with their own timestamps, in transactions of 500
+/// rows.
+///
+/// Invalid lines are skipped, as are readings already logged for the token at
+/// the same time, so an import can be safely retried. The response reports
+/// how many rows were inserted and skipped.
+///
+/// The body is limited to the `csv` data limit, 16 MiB by default.
+#[post("/log/<_>/import", data = "<body>")]
+#[allow(clippy::too_many_arguments)]
+async fn post_import(
     token: &ValidDbToken,
-) -> String {
-    format!("Token {} is valid", token.simplified())
+    body: rocket::data::Data<'_>,
+    limits: &rocket::data::Limits,
+    ip: ClientIP,
+    ua: UserAgent<'_>,
+    request_id: &request_id::RequestId,
+    db: &State<Logs>,
+    _write: drain::WriteInProgress,
+    _ratelimit: RateLimit,
+) -> Result<Json<serde_json::Value>, (Status, String)> {
+    let body = limits::read_body(body, limits, "csv", limits::DEFAULT_CSV_LIMIT).await?;
+
+    let (readings, invalid) = csv_import::parse_lines(&body);
+
+    // The readings logged before sharding, or before a shard was added, are
+    // not in the shard of the token, so the NOT EXISTS below misses them
+    let mut inserted = 0;
+    let result = async {
+        let mut logged = std::collections::HashSet::new();
+        let timestamps = readings.iter().map(|reading| reading.timestamp);
+        if let (Some(first), Some(last)) = (timestamps.clone().min(), timestamps.max()) {
+            for db in db.databases() {
+                logged.extend(
+                    sqlx::query_scalar!(
+                        "SELECT created_at FROM main.energy_log WHERE token = ? AND created_at BETWEEN ? AND ?",
+                        token,
+                        first,
+                        last
+                    )
+                    .fetch_all(db)
+                    .await?,
+                );
+            }
+        }
+
+        let shard = db.for_token(token.full_token());
+        for batch in readings.chunks(IMPORT_BATCH_SIZE) {
+            let mut tx = shard.begin().await?;
+            let mut batch_inserted = 0;
+            for reading in batch.iter().filter(|reading| !logged.contains(&reading.timestamp)) {
+                let volts = reading.volts.unwrap_or(220.0f64);
+                // Historical readings are back-dated by design, so that is not flagged
+                let flags = quality::ReadingFlags::observe(reading.volts, false, None, chrono::Utc::now()).bits();
+                batch_inserted += sqlx::query!(
+                    "INSERT INTO energy_log (token, amps, volts, watts, created_at, flags, user_agent, client_ip, source)
+                    SELECT ?, ?, ?, ?, ?, ?, ?, ?, 'post_import'
+                    WHERE NOT EXISTS (SELECT 1 FROM energy_log WHERE token = ? AND created_at = ?)",
+                    token,
+                    reading.amps,
+                    volts,
+                    reading.watts,
+                    reading.timestamp,
+                    flags,
+                    ua.0,
+                    ip.0,
+                    token,
+                    reading.timestamp
+                )
+                .execute(&mut *tx)
+                .await?
+                .rows_affected() as usize;
+            }
+            tx.commit().await?;
+            inserted += batch_inserted;
+        }
+        Ok::<_, sqlx::Error>(())
+    }
+    .await;
+    // The batches already committed stay, and are skipped as duplicates when
+    // the import is retried
+    if let Err(e) = result {
+        log::error!(
+            "Could not import the readings after {} rows (request {}): {}",
+            inserted,
+            request_id,
+            e
+        );
+        return Err((
+            Status::ServiceUnavailable,
+            "Could not import the readings, please retry".to_string(),
+        ));
+    }
+
+    log::info!(
+        "Imported {} of {} rows from IP {:?} and UA {:?} (request {})",
+        inserted,
+        readings.len(),
+        ip,
+        ua,
+        request_id
+    );
+
+    Ok(Json(serde_json::json!({
+        "inserted": inserted,
+        "skipped_duplicates": readings.len() - inserted,
+        "skipped_invalid": invalid,
+    })))
+}
+
+/// Route GET /log/:token/check will confirm that the token is valid, and report
+/// when it last logged a reading, so that provisioning scripts can check the
+/// sensor is actually posting data.
+///
+/// The reading is looked up in the database of the token, and only in all of
+/// them if it has none, as the readings logged before sharding, or before a
+/// shard was added, are elsewhere.
+#[get("/log/<_>/check")]
+async fn check_token_valid(token: &ValidDbToken, db: &State<LogsRead>) -> Json<serde_json::Value> {
+    let mut last_reading = None;
+    for db in [db.for_token(token.full_token()), &**db] {
+        last_reading = sqlx::query!(
+            "SELECT MAX(created_at) as \"last_reading: chrono::NaiveDateTime\" FROM energy_log WHERE token = ?",
+            token
+        )
+        .fetch_one(db)
+        .await
+        .unwrap()
+        .last_reading;
+        if last_reading.is_some() {
+            break;
+        }
+    }
+    let last_reading = last_reading.map(|dt| dt.and_utc());
+
+    Json(serde_json::json!({
+        "token": token.simplified(),
+        "valid": true,
+        "last_reading": last_reading,
+        "seconds_since_last_reading": last_reading.map(|dt| (chrono::Utc::now() - dt).num_seconds()),
+    }))
+}
+
+/// Responder that makes the browser save the inner response as a file with
+/// the given name, if any, instead of rendering it.
+struct Download<R>(Option<String>, R);
+
+impl<'r, 'o: 'r, R: rocket::response::Responder<'r, 'o>> rocket::response::Responder<'r, 'o>
+    for Download<R>
+{
+    fn respond_to(self, request: &'r rocket::Request<'_>) -> rocket::response::Result<'o> {
+        let mut response = self.1.respond_to(request)?;
+        if let Some(filename) = self.0 {
+            response.set_raw_header(
+                "Content-Disposition",
+                format!("attachment; filename=\"{}\"", filename),
+            );
+        }
+        Ok(response)
+    }
+}
+
+/// Returns the name for a downloaded report of the location between the
+/// given dates, keeping only characters that are safe in a file name.
+fn report_filename(location: &str, start: &str, end: &str, extension: &str) -> String {
+    let name: String = format!("energy-{}-{}_{}", location, start, end)
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect();
+    format!("{}.{}", name, extension)
 }
 
 /// Route GET /log/:token/html will return the data in HTML format
-#[get("/log/<_>/html?<page>&<count>&<start>&<end>&<interval>&<tz>", rank = 1)]
+///
+/// With `theme=dark`, the page and the embedded plot use a dark color scheme.
+///
+/// With `download=1`, the browser is asked to save the page as a file named
+/// after the location and the date range.
+#[get(
+    "/log/<_>/html?<page>&<count>&<start>&<end>&<range>&<interval>&<tz>&<theme>&<download>",
+    rank = 1
+)]
+#[allow(clippy::too_many_arguments)]
 async fn list_table_html(
     page: Option<i32>,
     count: Option<i32>,
     start: HtmlInputParseableDateTime,
     end: HtmlInputParseableDateTime,
+    range: Option<form::Range>,
     interval: Option<i32>,
     tz: form::Tz,
+    theme: Option<print_table::Theme>,
+    download: Option<&str>,
     token: &ValidViewToken,
-    mut db: Connection<Logs>,
-    _ratelimit: RocketGovernor<'_, RateLimitGuard>,
-) -> (ContentType, String) {
+    max_count: MaxPageCount,
+    query_timeout: db::QueryTimeout,
+    mut db: Connection<LogsRead>,
+    _ratelimit: RateLimit,
+) -> Result<Download<(ContentType, String)>, (Status, String)> {
     let pagination = Pagination {
         start,
         end,
@@ -175,16 +830,32 @@ async fn list_table_html(
         page,
         count,
         tz: tz.0,
+        range,
+        max_count,
     };
-    let pagination_result = pagination.result();
+    let mut pagination_result = pagination.result();
+    // The embedded plot uses the default interval of the token too
+    pagination_result.interval = print_table::resolve_interval(
+        &mut db,
+        token,
+        interval,
+        &pagination_result.start,
+        &pagination_result.end,
+    )
+    .await;
 
-    let (rows, has_next) =
-        get_paginated_rows_for_token(&mut db, &token, &pagination_result, &tz.0).await;
+    let (rows, has_next) = query_timeout
+        .run(get_paginated_rows_for_token(&mut db, token, &pagination_result, &tz.0))
+        .await?;
 
+    let theme = theme.unwrap_or_default();
     let mut result = String::new();
-    result.push_str("<!DOCTYPE html><html><head><meta charset=\"utf-8\"/><title>Consumption info</title></head><body><table>");
+    result.push_str(&format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"/><meta name=\"color-scheme\" content=\"{}\"/><title>Consumption info</title></head><body><table>",
+        theme
+    ));
     result.push_str(
-        "<tr><th>Location (token id/ua)</th><th>Date</th><th>Amps</th><th>Volts</th><th>Watts</th></tr>\n",
+        "<tr><th>Location (token id/ua)</th><th>Date</th><th>Amps</th><th>Volts</th><th>Watts</th><th>Temperature (°C)</th><th>Power factor</th></tr>\n",
     );
     for row in rows {
         result.push_str(&row.to_html());
@@ -193,11 +864,12 @@ async fn list_table_html(
 
     if has_next {
         result.push_str(&format!(
-            "<a href=\"/log/{}/html?page={}&count={}&tz={}\">Next</a>",
+            "<a href=\"/log/{}/html?page={}&count={}&tz={}&theme={}\">Next</a>",
             token.full_token(),
-            pagination_result.page + 1,
+            pagination_result.page.saturating_add(1),
             pagination_result.count,
             tz.0,
+            theme,
         ));
     }
 
@@ -207,6 +879,7 @@ async fn list_table_html(
             "
     <form action=\"/log/{}/html\" method=\"get\">
         <input type=\"hidden\" name=\"tz\" value=\"{}\" />
+        <input type=\"hidden\" name=\"theme\" value=\"{}\" />
         <input type=\"hidden\" name=\"page\" value=\"{}\" />
         <input type=\"hidden\" name=\"count\" value=\"{}\" />
         <label for=\"start\">Start:</label>
@@ -219,6 +892,7 @@ async fn list_table_html(
     </form>",
             token.full_token(),
             tz.0,
+            theme,
             pagination_result.page,
             pagination_result.count,
             pagination.start.to_datetime_local(),
@@ -232,34 +906,76 @@ async fn list_table_html(
     result.push_str(
         format!(
             "<hr />
-    <img src=\"/log/{}/svg?tz={}&start={}&end={}&interval={}\" alt=\"Energy consumption\" />\n",
+    <img src=\"/log/{}/svg?tz={}&start={}&end={}&interval={}&theme={}\" alt=\"Energy consumption\" />\n",
             token.full_token(),
             tz.0,
             pagination_result.start.with_timezone(&tz.0).format("%Y-%m-%dT%H:%M"),
             pagination_result.end.with_timezone(&tz.0).format("%Y-%m-%dT%H:%M"),
             pagination_result.interval,
+            theme,
         )
         .as_str(),
     );
 
     result.push_str("</body></html>\n");
 
-    (ContentType::HTML, result)
+    let filename = match download {
+        Some("1" | "true" | "yes" | "on") => Some(report_filename(
+            &print_table::get_location_for_view_token(&mut db, token).await,
+            &pagination_result.start.with_timezone(&tz.0).format("%Y%m%d").to_string(),
+            &pagination_result.end.with_timezone(&tz.0).format("%Y%m%d").to_string(),
+            "html",
+        )),
+        _ => None,
+    };
+
+    Ok(Download(filename, (ContentType::HTML, result)))
 }
 
 /// Route GET /log/:token/json will return the data in JSON format
-#[get("/log/<_>/json?<page>&<count>&<start>&<end>&<interval>&<tz>", rank = 1)]
+///
+/// If `interval` (in seconds) is given, instead of the raw rows it returns one
+/// row per bucket, with the averages and the `max_amps` and `max_watts` in it,
+/// as the SVG plot does. The buckets are not paginated, and their datetimes are
+/// in UTC.
+///
+/// With `bucket=hour|day|month` instead, the buckets are aligned to the local
+/// hours, days or months of `tz`, e.g., to match billing periods.
+///
+/// The `data_age_secs` field has the seconds since the most recent reading.
+///
+/// The JSON is compact unless `pretty=true` is given.
+///
+/// It supports conditional requests, see the [conditional] module.
+#[get(
+    "/log/<_>/json?<page>&<count>&<start>&<end>&<range>&<interval>&<bucket>&<tz>&<pretty>&<normalize>",
+    rank = 1
+)]
+#[allow(clippy::too_many_arguments)]
 async fn list_table_json(
     page: Option<i32>,
     count: Option<i32>,
     start: HtmlInputParseableDateTime,
     end: HtmlInputParseableDateTime,
+    range: Option<form::Range>,
     interval: Option<i32>,
+    bucket: Option<CalendarBucket>,
     tz: form::Tz,
+    pretty: Option<bool>,
+    normalize: Option<print_table::Normalize>,
     token: &ValidViewToken,
-    mut db: Connection<Logs>,
-    _ratelimit: RocketGovernor<'_, RateLimitGuard>,
-) -> rocket::response::content::RawJson<String> {
+    conditional: Conditional,
+    max_count: MaxPageCount,
+    query_timeout: db::QueryTimeout,
+    mut db: Connection<LogsRead>,
+    _ratelimit: RateLimit,
+) -> Result<Cached<rocket::response::content::RawJson<String>>, (Status, String)> {
+    let freshness = conditional.freshness(&mut db, token).await;
+    if freshness.is_not_modified() {
+        return Ok(Cached::NotModified(freshness));
+    }
+
+    let pretty = pretty.unwrap_or(false);
     let pagination = Pagination {
         start,
         end,
@@ -267,107 +983,2163 @@ async fn list_table_json(
         page,
         count,
         tz: tz.0,
+        range,
+        max_count,
     }
     .result();
 
-    let (rows, has_next) = get_paginated_rows_for_token(&mut db, &token, &pagination, &tz.0).await;
+    if let Some(bucket) = bucket {
+        let (mut avg, mut max) = query_timeout
+            .run(get_calendar_rows_for_token(
+                &mut db,
+                token,
+                &pagination.start,
+                &pagination.end,
+                bucket,
+                &tz.0,
+            ))
+            .await?;
+        print_table::normalize_rows(&mut avg, normalize);
+        print_table::normalize_rows(&mut max, normalize);
+        let rows: Vec<_> = avg
+            .iter()
+            .zip(max.iter())
+            .map(|(avg, max)| avg.to_bucket_json(max))
+            .collect();
+        let result = serde_json::json!({
+            "rows": rows,
+            "bucket": bucket.to_string(),
+            "data_age_secs": freshness.data_age_secs(),
+            "next": ""
+        });
+
+        return Ok(Cached::Fresh(freshness, json_body(&result, pretty)));
+    }
+
+    if interval.is_some() {
+        let (mut avg, mut max) = query_timeout
+            .run(get_avg_max_rows_for_token(
+                &mut db,
+                token,
+                &pagination.start,
+                &pagination.end,
+                pagination.interval,
+            ))
+            .await?;
+        print_table::normalize_rows(&mut avg, normalize);
+        print_table::normalize_rows(&mut max, normalize);
+        let rows: Vec<_> = avg
+            .iter()
+            .zip(max.iter())
+            .map(|(avg, max)| avg.to_bucket_json(max))
+            .collect();
+        let result = serde_json::json!({
+            "rows": rows,
+            "interval": pagination.interval,
+            "data_age_secs": freshness.data_age_secs(),
+            "next": ""
+        });
+
+        return Ok(Cached::Fresh(freshness, json_body(&result, pretty)));
+    }
+
+    let (mut rows, has_next) = query_timeout
+        .run(get_paginated_rows_for_token(&mut db, token, &pagination, &tz.0))
+        .await?;
+    print_table::normalize_rows(&mut rows, normalize);
 
     let next_url = if has_next {
         format!(
             "/log/{}/json?page={}&count={}",
             token.full_token(),
-            pagination.page + 1,
+            pagination.page.saturating_add(1),
             pagination.count
         )
     } else {
         "".to_string()
     };
 
-    let result = serde_json::json!({
-        "rows": rows,
-        "next": next_url
-    });
+    let result = JsonRowsPage {
+        data_age_secs: freshness.data_age_secs(),
+        next: next_url,
+        rows: &rows,
+    };
 
-    rocket::response::content::RawJson(serde_json::to_string_pretty(&result).unwrap())
+    Ok(Cached::Fresh(freshness, json_body(&result, pretty)))
 }
 
-/// Route GET /log/:token/html will return the data in HTML format
-#[get("/log/<_>/svg?<start>&<end>&<interval>&<tz>", rank = 1)]
-async fn list_table_svg(
+/// A page of rows of the [JSON route](list_table_json), serialized without
+/// going through a [serde_json::Value]
+#[derive(serde::Serialize)]
+struct JsonRowsPage<'a> {
+    data_age_secs: Option<i64>,
+    next: String,
+    rows: &'a [RowInfo],
+}
+
+/// Serializes the response of the JSON routes, compact unless `pretty`
+fn json_body<T: serde::Serialize>(
+    value: &T,
+    pretty: bool,
+) -> rocket::response::content::RawJson<String> {
+    let body = if pretty {
+        serde_json::to_string_pretty(value)
+    } else {
+        serde_json::to_string(value)
+    };
+    rocket::response::content::RawJson(body.expect("the JSON responses always serialize"))
+}
+
+/// Route GET /log/:token/ndjson will return the rows as newline-delimited JSON
+/// (`application/x-ndjson`), one JSON object per line, as in the JSON route.
+///
+/// There is no `next` link: request the following `page` until fewer than
+/// `count` lines are returned.
+///
+/// It supports conditional requests, see the [conditional] module.
+#[get("/log/<_>/ndjson?<page>&<count>&<start>&<end>&<range>&<tz>&<normalize>", rank = 1)]
+#[allow(clippy::too_many_arguments)]
+async fn list_table_ndjson(
+    page: Option<i32>,
+    count: Option<i32>,
     start: HtmlInputParseableDateTime,
     end: HtmlInputParseableDateTime,
-    interval: Option<i32>,
+    range: Option<form::Range>,
     tz: form::Tz,
+    normalize: Option<print_table::Normalize>,
     token: &ValidViewToken,
-    mut db: Connection<Logs>,
-    _ratelimit: RocketGovernor<'_, RateLimitGuard>,
-) -> (ContentType, String) {
-    let start = start.with_tz(tz.0, true).with_default(chrono::Utc::now() - chrono::Duration::days(1)).utc();
-    let end = end
-        .with_tz(tz.0, false)
-        .with_default(chrono::Utc::now())
-        .utc();
-    let interval = interval.unwrap_or(300);
+    conditional: Conditional,
+    max_count: MaxPageCount,
+    query_timeout: db::QueryTimeout,
+    mut db: Connection<LogsRead>,
+    _ratelimit: RateLimit,
+) -> Result<Cached<(ContentType, TextStream![String])>, (Status, String)> {
+    let freshness = conditional.freshness(&mut db, token).await;
+    if freshness.is_not_modified() {
+        return Ok(Cached::NotModified(freshness));
+    }
 
-    let (avg, max) = get_avg_max_rows_for_token(&mut db, &token, &start, &end, interval).await;
+    let pagination = Pagination {
+        start,
+        end,
+        interval: None,
+        page,
+        count,
+        tz: tz.0,
+        range,
+        max_count,
+    }
+    .result();
+    let (mut rows, _) = query_timeout
+        .run(get_paginated_rows_for_token(&mut db, token, &pagination, &tz.0))
+        .await?;
+    print_table::normalize_rows(&mut rows, normalize);
 
-    match print_table::to_svg_plot(avg, max, &tz.0) {
-        Ok(svg) => (ContentType::SVG, svg),
-        Err(e) if e.downcast_ref::<NoRowsError>().is_some() => (
-            ContentType::Plain,
-            "No data found for the given request".to_string(),
-        ),
-        Err(e) => {
-            log::error!("Error generating SVG: {:?}", e);
-            (ContentType::Plain, "Error generating SVG".to_string())
+    let lines = TextStream! {
+        for row in rows {
+            yield format!("{}\n", serde_json::to_string(&row).unwrap());
         }
+    };
+    Ok(Cached::Fresh(freshness, (ContentType::new("application", "x-ndjson"), lines)))
+}
+
+/// Route GET /log/:token/latest will return only the most recent reading as a
+/// JSON object, or a 404 if the token has no data yet
+#[get("/log/<_>/latest?<tz>", rank = 1)]
+async fn latest_reading(
+    tz: form::Tz,
+    token: &ValidViewToken,
+    mut db: Connection<LogsRead>,
+    _ratelimit: RateLimit,
+) -> Option<Json<RowInfo>> {
+    get_latest_row_for_token(&mut db, token, &tz.0)
+        .await
+        .map(Json)
+}
+
+/// Route GET /log/:token/locations will return the distinct locations of the
+/// sensors the view token gives access to, sorted by name, e.g., to build a
+/// location picker.
+#[get("/log/<_>/locations", rank = 1)]
+async fn list_locations(
+    token: &ValidViewToken,
+    mut db: Connection<LogsRead>,
+    _ratelimit: RateLimit,
+) -> Json<serde_json::Value> {
+    let locations = sqlx::query_scalar!(
+        "SELECT DISTINCT u.location
+        FROM view_token_sensors v
+        INNER JOIN tokens t ON t.token = v.token
+        INNER JOIN users u ON u.id = t.user_id
+        WHERE v.view_token = ?
+        ORDER BY u.location",
+        token
+    )
+    .fetch_all(&mut **db)
+    .await
+    .unwrap();
+
+    Json(serde_json::json!({ "locations": locations }))
+}
+
+/// The maximum distance between the requested instant and the reading returned
+/// by the GET /log/:token/at route, from `nearest_reading_tolerance_secs` in
+/// the figment (5 minutes by default).
+struct NearestReadingTolerance(i64);
+
+#[rocket::async_trait]
+impl<'r> rocket::request::FromRequest<'r> for NearestReadingTolerance {
+    type Error = ();
+
+    async fn from_request(
+        request: &'r rocket::Request<'_>,
+    ) -> rocket::request::Outcome<Self, Self::Error> {
+        let tolerance = request
+            .rocket()
+            .figment()
+            .extract_inner("nearest_reading_tolerance_secs")
+            .unwrap_or(300);
+        rocket::request::Outcome::Success(NearestReadingTolerance(tolerance))
     }
 }
 
-/// Route GET / will return a simple PONG message. By default we don't advertise
-/// the functionality of the application to the world.
-#[get("/")]
-async fn index(_ratelimit: RocketGovernor<'_, RateLimitGuard>) -> String {
-    log::info!("Got to index!");
-    "PONG".to_string()
+/// Route GET /log/:token/at will return the reading nearest to `timestamp` as
+/// a JSON object, with the offset in seconds from the requested instant, or a
+/// 404 if there is no reading close enough.
+#[get("/log/<_>/at?<timestamp>&<tz>", rank = 1)]
+async fn reading_at(
+    timestamp: HtmlInputParseableDateTime,
+    tz: form::Tz,
+    token: &ValidViewToken,
+    tolerance: NearestReadingTolerance,
+    mut db: Connection<LogsRead>,
+    _ratelimit: RateLimit,
+) -> Result<Json<serde_json::Value>, (Status, String)> {
+    if timestamp.is_none() {
+        return Err((Status::BadRequest, "Missing timestamp".to_string()));
+    }
+    let at = timestamp.with_tz(tz.0, true).utc();
+
+    let (row, offset) = get_nearest_row_for_token(&mut db, token, &at, tolerance.0, &tz.0)
+        .await
+        .ok_or((
+            Status::NotFound,
+            format!("No reading within {} seconds of {}", tolerance.0, at),
+        ))?;
+
+    Ok(Json(serde_json::json!({
+        "row": row,
+        "offset_secs": offset,
+    })))
 }
 
-/// Main function to launch the Rocket application
-///
-/// This runs the migrations (which are embedded into the binary), attaches the
-/// [AliveCheckFairing](alive_check::AliveCheckFairing), and the
-/// [car::fairing::EVChargeFairing] (with the [tessie
-/// implementation](car::tessie)); and mounts the routes and catchers.
-#[launch]
-async fn rocket() -> _ {
-    // Check if we are being called with the `consolidate_logs` argument, in which case we run the consolidation function
-    // instead of starting the Rocket server
-    if std::env::args().nth(1).is_some() {
-        crate::cli::consolidate_logs::consolidate_logs_cli().await;
-        std::process::exit(0);
+/// How many buckets a window is split in by the peak route, to approximate a
+/// rolling window with the bucketed data.
+const PEAK_BUCKETS_PER_WINDOW: i64 = 15;
+
+/// The window of the peak demand, unless `window_secs` is given
+const DEFAULT_PEAK_WINDOW_SECS: i64 = 900;
+
+/// Route GET /log/:token/peak will return the highest average consumption
+/// over any window of `window_secs` (15 minutes by default) within the range,
+/// and when it happened, as used for demand charges.
+#[get("/log/<_>/peak?<start>&<end>&<range>&<window_secs>&<tz>", rank = 1)]
+#[allow(clippy::too_many_arguments)]
+async fn peak_demand(
+    start: HtmlInputParseableDateTime,
+    end: HtmlInputParseableDateTime,
+    range: Option<form::Range>,
+    window_secs: Option<i64>,
+    tz: form::Tz,
+    token: &ValidViewToken,
+    query_timeout: db::QueryTimeout,
+    mut db: Connection<LogsRead>,
+    _ratelimit: RateLimit,
+) -> Result<Json<serde_json::Value>, (Status, String)> {
+    let window_secs = window_secs.unwrap_or(DEFAULT_PEAK_WINDOW_SECS);
+    if window_secs <= 0 || window_secs > i32::MAX as i64 {
+        return Err((Status::BadRequest, "Invalid window_secs".to_string()));
     }
 
-    rocket::build()
-        .attach(Logs::init())
-        .attach(fairing::AdHoc::on_ignite(
-            "Run DB migrations",
-            |rocket| async {
-                let db = Logs::fetch(&rocket).expect("DB connection");
-                sqlx::migrate!("./migrations").run(&**db).await.unwrap();
-                rocket
-            },
-        ))
-        .attach(alive_check::AliveCheckFairing::new())
-        .attach(car::fairing::EVChargeFairing::<car::tessie::Handler>::new())
-        .mount(
-            "/",
-            routes![
-                index,
-                list_table_html,
-                list_table_json,
-                list_table_svg,
-                post_token
-            ],
-        )
-        .register("/", catchers![rocket_governor_catcher])
+    let start = start.with_tz(tz.0, true).with_default(form::default_start(range.as_ref())).utc();
+    let end = end.with_tz(tz.0, false).with_default(chrono::Utc::now()).utc();
+    let interval = (window_secs / PEAK_BUCKETS_PER_WINDOW).max(1) as i32;
+
+    let (avg, _max) = query_timeout
+        .run(get_avg_max_rows_for_token(&mut db, token, &start, &end, interval))
+        .await?;
+    let peak = print_table::peak_window(&avg, window_secs)
+        .map_err(|e| (Status::InternalServerError, format!("Invalid reading datetime: {}", e)))?
+        .ok_or((
+            Status::NotFound,
+            "No data found for the given request".to_string(),
+        ))?;
+
+    Ok(Json(serde_json::json!({
+        "window_secs": window_secs,
+        "start": peak.start.with_timezone(&tz.0).to_rfc3339(),
+        "end": peak.end.with_timezone(&tz.0).to_rfc3339(),
+        "amps": peak.amps,
+        "watts": peak.watts,
+    })))
+}
+
+/// Route GET /log/:token/count will return how many readings the range has,
+/// as `{"count": N}`, so that a client can size its pagination before
+/// fetching the readings themselves.
+#[get("/log/<_>/count?<start>&<end>&<range>&<tz>", rank = 1)]
+#[allow(clippy::too_many_arguments)]
+async fn count_readings(
+    start: HtmlInputParseableDateTime,
+    end: HtmlInputParseableDateTime,
+    range: Option<form::Range>,
+    tz: form::Tz,
+    token: &ValidViewToken,
+    query_timeout: db::QueryTimeout,
+    mut db: Connection<LogsRead>,
+    _ratelimit: RateLimit,
+) -> Result<Json<serde_json::Value>, (Status, String)> {
+    let start = start.with_tz(tz.0, true).with_default(form::default_start(range.as_ref())).utc();
+    let end = end.with_tz(tz.0, false).with_default(chrono::Utc::now()).utc();
+
+    let count = query_timeout
+        .run(print_table::count_rows_for_token(&mut db, token, &start, &end))
+        .await?;
+    Ok(Json(serde_json::json!({ "count": count })))
+}
+
+/// Route GET /log/:token/report will return a rollup of the range in a single
+/// call, e.g., for a daily or weekly email: the number of readings, the
+/// energy in kWh (see [print_table::summarize]), the average load, the
+/// highest reading and when it happened, and the peak demand over 15 minutes
+/// as in the peak route.
+#[get("/log/<_>/report?<start>&<end>&<range>&<tz>", rank = 1)]
+#[allow(clippy::too_many_arguments)]
+async fn summary_report(
+    start: HtmlInputParseableDateTime,
+    end: HtmlInputParseableDateTime,
+    range: Option<form::Range>,
+    tz: form::Tz,
+    token: &ValidViewToken,
+    query_timeout: db::QueryTimeout,
+    mut db: Connection<LogsRead>,
+    _ratelimit: RateLimit,
+) -> Result<Json<serde_json::Value>, (Status, String)> {
+    let start = start.with_tz(tz.0, true).with_default(form::default_start(range.as_ref())).utc();
+    let end = end.with_tz(tz.0, false).with_default(chrono::Utc::now()).utc();
+
+    let rows = query_timeout
+        .run(print_table::get_summary_rows_for_token(&mut db, token, &start, &end))
+        .await?;
+    let summary = print_table::summarize(&rows).ok_or((
+        Status::NotFound,
+        "No data found for the given request".to_string(),
+    ))?;
+
+    let interval = (DEFAULT_PEAK_WINDOW_SECS / PEAK_BUCKETS_PER_WINDOW) as i32;
+    let (avg, _max) = query_timeout
+        .run(get_avg_max_rows_for_token(&mut db, token, &start, &end, interval))
+        .await?;
+    let peak_demand = print_table::peak_window(&avg, DEFAULT_PEAK_WINDOW_SECS)
+        .map_err(|e| (Status::InternalServerError, format!("Invalid reading datetime: {}", e)))?
+        .map(|peak| {
+            serde_json::json!({
+                "window_secs": DEFAULT_PEAK_WINDOW_SECS,
+                "start": peak.start.with_timezone(&tz.0).to_rfc3339(),
+                "end": peak.end.with_timezone(&tz.0).to_rfc3339(),
+                "amps": print_table::round_for_display(peak.amps),
+                "watts": print_table::round_for_display(peak.watts),
+            })
+        });
+
+    Ok(Json(serde_json::json!({
+        "start": start.with_timezone(&tz.0).to_rfc3339(),
+        "end": end.with_timezone(&tz.0).to_rfc3339(),
+        "readings": summary.readings,
+        "energy_kwh": print_table::round_for_display(summary.energy_kwh),
+        "avg_amps": print_table::round_for_display(summary.avg_amps),
+        "avg_watts": print_table::round_for_display(summary.avg_watts),
+        "max_amps": print_table::round_for_display(summary.max_amps),
+        "max_amps_at": summary.max_amps_at.with_timezone(&tz.0).to_rfc3339(),
+        "peak_demand": peak_demand,
+    })))
+}
+
+/// Route GET /log/:token/histogram will return how many readings fall into
+/// each bin of `bin_amps` (1 A by default) within the range, as the bin
+/// `edges` and their `counts`, e.g., to size breakers or spot bimodal loads.
+#[get("/log/<_>/histogram?<start>&<end>&<range>&<bin_amps>&<tz>", rank = 1)]
+#[allow(clippy::too_many_arguments)]
+async fn amps_histogram(
+    start: HtmlInputParseableDateTime,
+    end: HtmlInputParseableDateTime,
+    range: Option<form::Range>,
+    bin_amps: Option<f64>,
+    tz: form::Tz,
+    token: &ValidViewToken,
+    query_timeout: db::QueryTimeout,
+    mut db: Connection<LogsRead>,
+    _ratelimit: RateLimit,
+) -> Result<Json<print_table::Histogram>, (Status, String)> {
+    let bin_amps = bin_amps.unwrap_or(1.0);
+    if !bin_amps.is_finite() || bin_amps <= 0.0 {
+        return Err((Status::BadRequest, "Invalid bin_amps".to_string()));
+    }
+
+    let start = start.with_tz(tz.0, true).with_default(form::default_start(range.as_ref())).utc();
+    let end = end.with_tz(tz.0, false).with_default(chrono::Utc::now()).utc();
+    let amps = query_timeout
+        .run(print_table::get_amps_for_token(&mut db, token, &start, &end))
+        .await?;
+    if amps.is_empty() {
+        return Err((
+            Status::NotFound,
+            "No data found for the given request".to_string(),
+        ));
+    }
+
+    print_table::histogram(&amps, bin_amps).map(Json).ok_or((
+        Status::BadRequest,
+        format!(
+            "More than {} bins, use a larger bin_amps",
+            print_table::MAX_HISTOGRAM_BINS
+        ),
+    ))
+}
+
+/// Route GET /log/:token/svg will return a plot of the data in SVG format
+///
+/// If no `interval` is given, the default interval of the token is used, or
+/// it is chosen from the range with [print_table::auto_interval] to keep the
+/// plot readable (see [print_table::resolve_interval]).
+///
+/// The avg amps line can be smoothed with an N-point moving average by
+/// passing `smooth=N`, and the max amps line too if `smooth_max=true`.
+///
+/// The plot uses a light theme unless `theme=dark` is given, and is 1400x500
+/// unless `width` and `height` are given.
+///
+/// Passing `budget=N` draws a horizontal reference line at N amps, e.g., the
+/// `max_amps` budget of the car charge control. It is not drawn with
+/// `normalize=watts`, which plots amps × volts in watts instead of the amps.
+///
+/// The time axis labels are spaced and formatted from the span of the plot,
+/// from seconds for short ranges to dates for long ones (see
+/// [print_table::TimeTicks]). `ticks=N` overrides how many labels there are at
+/// most, and `tick_format` their strftime format, e.g., `tick_format=%d/%m`.
+///
+/// If the most recent reading is stale, the title tells how old it is. If
+/// there is no data in the range, it is still a valid SVG, labeled "No data".
+///
+/// It supports conditional requests, see the [conditional] module.
+#[get(
+    "/log/<_>/svg?<start>&<end>&<range>&<interval>&<tz>&<smooth>&<smooth_max>&<theme>&<width>&<height>&<budget>&<ticks>&<tick_format>&<normalize>",
+    rank = 1
+)]
+#[allow(clippy::too_many_arguments)]
+async fn list_table_svg(
+    start: HtmlInputParseableDateTime,
+    end: HtmlInputParseableDateTime,
+    range: Option<form::Range>,
+    interval: Option<i32>,
+    tz: form::Tz,
+    smooth: Option<usize>,
+    smooth_max: Option<bool>,
+    theme: Option<print_table::Theme>,
+    width: Option<f64>,
+    height: Option<f64>,
+    budget: Option<f64>,
+    ticks: Option<usize>,
+    tick_format: Option<String>,
+    normalize: Option<print_table::Normalize>,
+    token: &ValidViewToken,
+    conditional: Conditional,
+    query_timeout: db::QueryTimeout,
+    mut db: Connection<LogsRead>,
+    _ratelimit: RateLimit,
+) -> Result<Cached<(ContentType, String)>, (Status, String)> {
+    let freshness = conditional.freshness(&mut db, token).await;
+    if freshness.is_not_modified() {
+        return Ok(Cached::NotModified(freshness));
+    }
+
+    let start = start.with_tz(tz.0, true).with_default(form::default_start(range.as_ref())).utc();
+    let end = end
+        .with_tz(tz.0, false)
+        .with_default(chrono::Utc::now())
+        .utc();
+    let interval = print_table::resolve_interval(&mut db, token, interval, &start, &end).await;
+
+    let (avg, max) = query_timeout
+        .run(get_avg_max_rows_for_token(&mut db, token, &start, &end, interval))
+        .await?;
+
+    let options = print_table::PlotOptions {
+        theme: theme.unwrap_or_default(),
+        smooth,
+        smooth_max: smooth_max.unwrap_or(false),
+        stale_age_secs: freshness.stale_age_secs(),
+        budget_amps: budget.filter(|budget| budget.is_finite()),
+        normalize,
+        ..Default::default()
+    }
+    .with_size(width, height)
+    .with_ticks(ticks, tick_format);
+
+    let response = match print_table::to_svg_plot(avg, max, &tz.0, &options) {
+        Ok(svg) => (ContentType::SVG, svg),
+        Err(e) if e.downcast_ref::<NoRowsError>().is_some() => {
+            (ContentType::SVG, print_table::no_data_svg(&options))
+        }
+        Err(e) => {
+            log::error!("Error generating SVG: {:?}{}", e, RequestId::in_logs());
+            (ContentType::Plain, "Error generating SVG".to_string())
+        }
+    };
+
+    Ok(Cached::Fresh(freshness, response))
+}
+
+/// Route GET /log/:token will return the data as JSON, HTML or SVG, depending
+/// on the `Accept` header, with the same query parameters as the
+/// [JSON](list_table_json), [HTML](list_table_html) and [SVG](list_table_svg)
+/// routes.
+///
+/// JSON is preferred when the client accepts anything (e.g., `*/*` or no
+/// `Accept` header), and a 406 is returned if it accepts none of them.
+#[get(
+    "/log/<_>?<page>&<count>&<start>&<end>&<range>&<interval>&<bucket>&<tz>&<pretty>&<normalize>",
+    format = "json",
+    rank = 3
+)]
+#[allow(clippy::too_many_arguments)]
+async fn negotiated_json(
+    page: Option<i32>,
+    count: Option<i32>,
+    start: HtmlInputParseableDateTime,
+    end: HtmlInputParseableDateTime,
+    range: Option<form::Range>,
+    interval: Option<i32>,
+    bucket: Option<CalendarBucket>,
+    tz: form::Tz,
+    pretty: Option<bool>,
+    normalize: Option<print_table::Normalize>,
+    token: &ValidViewToken,
+    conditional: Conditional,
+    max_count: MaxPageCount,
+    query_timeout: db::QueryTimeout,
+    db: Connection<LogsRead>,
+    ratelimit: RateLimit,
+) -> Result<Cached<rocket::response::content::RawJson<String>>, (Status, String)> {
+    list_table_json(
+        page, count, start, end, range, interval, bucket, tz, pretty, normalize, token,
+        conditional, max_count, query_timeout, db, ratelimit,
+    )
+    .await
+}
+
+/// Route GET /log/:token with `Accept: text/html`, see [negotiated_json]
+#[get(
+    "/log/<_>?<page>&<count>&<start>&<end>&<range>&<interval>&<tz>&<theme>&<download>",
+    format = "html",
+    rank = 4
+)]
+#[allow(clippy::too_many_arguments)]
+async fn negotiated_html(
+    page: Option<i32>,
+    count: Option<i32>,
+    start: HtmlInputParseableDateTime,
+    end: HtmlInputParseableDateTime,
+    range: Option<form::Range>,
+    interval: Option<i32>,
+    tz: form::Tz,
+    theme: Option<print_table::Theme>,
+    download: Option<&str>,
+    token: &ValidViewToken,
+    max_count: MaxPageCount,
+    query_timeout: db::QueryTimeout,
+    db: Connection<LogsRead>,
+    ratelimit: RateLimit,
+) -> Result<Download<(ContentType, String)>, (Status, String)> {
+    list_table_html(
+        page, count, start, end, range, interval, tz, theme, download, token, max_count,
+        query_timeout, db, ratelimit,
+    )
+    .await
+}
+
+/// Route GET /log/:token with `Accept: image/svg+xml`, see [negotiated_json]
+#[get(
+    "/log/<_>?<start>&<end>&<range>&<interval>&<tz>&<smooth>&<smooth_max>&<theme>&<width>&<height>&<budget>&<ticks>&<tick_format>&<normalize>",
+    format = "image/svg+xml",
+    rank = 5
+)]
+#[allow(clippy::too_many_arguments)]
+async fn negotiated_svg(
+    start: HtmlInputParseableDateTime,
+    end: HtmlInputParseableDateTime,
+    range: Option<form::Range>,
+    interval: Option<i32>,
+    tz: form::Tz,
+    smooth: Option<usize>,
+    smooth_max: Option<bool>,
+    theme: Option<print_table::Theme>,
+    width: Option<f64>,
+    height: Option<f64>,
+    budget: Option<f64>,
+    ticks: Option<usize>,
+    tick_format: Option<String>,
+    normalize: Option<print_table::Normalize>,
+    token: &ValidViewToken,
+    conditional: Conditional,
+    query_timeout: db::QueryTimeout,
+    db: Connection<LogsRead>,
+    ratelimit: RateLimit,
+) -> Result<Cached<(ContentType, String)>, (Status, String)> {
+    list_table_svg(
+        start, end, range, interval, tz, smooth, smooth_max, theme, width, height, budget, ticks,
+        tick_format, normalize, token, conditional, query_timeout, db, ratelimit,
+    )
+    .await
+}
+
+/// Route GET /log/:token when the `Accept` header allows none of the
+/// representations, see [negotiated_json]. Unknown and expired tokens are
+/// still not found or gone.
+#[get("/log/<_>", rank = 6)]
+async fn not_acceptable(_token: &ValidViewToken) -> (Status, &'static str) {
+    (
+        Status::NotAcceptable,
+        "Accept application/json, text/html or image/svg+xml",
+    )
+}
+
+/// The maximum number of tokens that can be compared in a single plot
+const MAX_COMPARE_TOKENS: usize = 5;
+
+/// Route GET /log/compare/svg will plot the avg amps of several view tokens
+/// (comma-separated in `tokens`) as one line each, to compare circuits.
+/// Without an `interval`, each line uses the default interval of its token.
+///
+/// At most 5 tokens are accepted to bound the cost of the queries.
+#[get("/log/compare/svg?<tokens>&<start>&<end>&<range>&<interval>&<tz>&<theme>&<normalize>")]
+#[allow(clippy::too_many_arguments)]
+async fn compare_svg(
+    tokens: &str,
+    start: HtmlInputParseableDateTime,
+    end: HtmlInputParseableDateTime,
+    range: Option<form::Range>,
+    interval: Option<i32>,
+    tz: form::Tz,
+    theme: Option<print_table::Theme>,
+    normalize: Option<print_table::Normalize>,
+    query_timeout: db::QueryTimeout,
+    mut db: Connection<Logs>,
+    mut read_db: Connection<LogsRead>,
+    _ratelimit: RateLimit,
+) -> Result<(ContentType, String), (Status, String)> {
+    let tokens: Vec<&str> = tokens
+        .split(',')
+        .map(str::trim)
+        .filter(|t| !t.is_empty())
+        .collect();
+    if tokens.is_empty() || tokens.len() > MAX_COMPARE_TOKENS {
+        return Err((
+            Status::BadRequest,
+            format!("Expected between 1 and {} tokens", MAX_COMPARE_TOKENS),
+        ));
+    }
+
+    let start = start.with_tz(tz.0, true).with_default(form::default_start(range.as_ref())).utc();
+    let end = end
+        .with_tz(tz.0, false)
+        .with_default(chrono::Utc::now())
+        .utc();
+
+    let mut series = Vec::new();
+    for token in tokens {
+        let token = token::validate_view_token(&mut db, token.to_string())
+            .await
+            .map_err(|status| (status, "Invalid or expired token".to_string()))?;
+        let interval =
+            print_table::resolve_interval(&mut read_db, &token, interval, &start, &end).await;
+        let (avg, _max) = query_timeout
+            .run(get_avg_max_rows_for_token(&mut read_db, &token, &start, &end, interval))
+            .await?;
+        series.push((token, avg));
+    }
+
+    let options = print_table::PlotOptions {
+        theme: theme.unwrap_or_default(),
+        normalize,
+        ..Default::default()
+    };
+
+    match print_table::to_compare_svg_plot(series, &tz.0, &options) {
+        Ok(svg) => Ok((ContentType::SVG, svg)),
+        Err(e) if e.downcast_ref::<NoRowsError>().is_some() => {
+            Ok((ContentType::SVG, print_table::no_data_svg(&options)))
+        }
+        Err(e) => {
+            log::error!("Error generating SVG: {:?}{}", e, RequestId::in_logs());
+            Ok((ContentType::Plain, "Error generating SVG".to_string()))
+        }
+    }
+}
+
+/// Catcher for requests with a view token that has expired, so that users of a
+/// share link can tell it apart from a mistyped one.
+#[catch(410)]
+fn expired_token() -> &'static str {
+    "This link has expired. Please ask the owner of the data for a new one.\n"
+}
+
+/// Body for the responses of rate-limited requests, so that sensors can parse
+/// how long to back off.
+struct RateLimited<'r>(&'r LimitError);
+
+impl<'r, 'o: 'r> rocket::response::Responder<'r, 'o> for RateLimited<'r> {
+    fn respond_to(self, request: &'r rocket::Request<'_>) -> rocket::response::Result<'o> {
+        // Keep the rate limit headers added by rocket_governor
+        let mut response = self.0.respond_to(request)?;
+        let retry_after_secs = match self.0 {
+            // The wait is rounded down, so ask for at least a second
+            LimitError::GovernedRequest(wait_time, _) => {
+                let retry_after_secs = (*wait_time).max(1);
+                response.set_raw_header("Retry-After", retry_after_secs.to_string());
+                Some(retry_after_secs)
+            }
+            _ => None,
+        };
+
+        let body = serde_json::json!({
+            "error": "rate_limited",
+            "retry_after_secs": retry_after_secs,
+        })
+        .to_string();
+        response.set_header(ContentType::JSON);
+        response.set_sized_body(body.len(), std::io::Cursor::new(body));
+        Ok(response)
+    }
+}
+
+/// Catcher for rate-limited requests, answering with a JSON body and a
+/// `Retry-After` header with the seconds to wait.
+#[catch(429)]
+fn too_many_requests<'r>(request: &'r rocket::Request) -> RateLimited<'r> {
+    let cached: &Result<(), LimitError> = request.local_cache(|| Err(LimitError::Error));
+    match cached {
+        Err(limit_error) => RateLimited(limit_error),
+        Ok(()) => RateLimited(&LimitError::Error),
+    }
+}
+
+/// Route GET / will return a simple PONG message. By default we don't advertise
+/// the functionality of the application to the world.
+#[get("/")]
+async fn index(_ratelimit: RateLimit) -> String {
+    log::info!("Got to index!{}", RequestId::in_logs());
+    "PONG".to_string()
+}
+
+/// Route GET /healthz will check the database is reachable with a `SELECT 1`
+/// on the [Logs] pool, for the readiness checks of load balancers and
+/// orchestrators, unlike [index], which does not touch it.
+///
+/// It answers 200 `{"status":"ok"}` if the query succeeds. If it fails, e.g.,
+/// no connection can be opened within the `connect_timeout` of the pool, or
+/// the database file is gone, it answers 503 `{"status":"unavailable"}` and
+/// logs the error. It is not rate limited, as it is meant to be polled.
+#[get("/healthz")]
+async fn healthz(db: &State<Logs>) -> (Status, Json<serde_json::Value>) {
+    match sqlx::query!("SELECT 1 as ok").fetch_one(&****db).await {
+        Ok(_) => (Status::Ok, Json(serde_json::json!({ "status": "ok" }))),
+        Err(e) => {
+            log::error!(
+                "Health check failed, the database is unreachable: {}{}",
+                e,
+                RequestId::in_logs()
+            );
+            (
+                Status::ServiceUnavailable,
+                Json(serde_json::json!({ "status": "unavailable" })),
+            )
+        }
+    }
+}
+
+/// Main function to launch the Rocket application
+///
+/// This builds the application with [build] from the default figment
+/// (Rocket.toml and the `ROCKET_` environment variables).
+///
+/// If the launch fails (e.g., a fairing such as the DB migrations fails), the
+/// reason is logged and the process exits with status 1, instead of panicking.
+#[rocket::main]
+async fn main() {
+    // Check if we are being called with a subcommand (e.g., `consolidate_logs`), in which case we run it
+    // instead of starting the Rocket server
+    let command = std::env::args().nth(1);
+    if let Some(command) = command {
+        match command.as_str() {
+            "consolidate_logs" => crate::cli::consolidate_logs::consolidate_logs_cli().await,
+            "create-token" => crate::cli::create_token::create_token_cli().await,
+            "backup" => crate::cli::backup::backup_cli().await,
+            "export-json" => crate::cli::json_dump::export_json_cli().await,
+            "import-json" => crate::cli::json_dump::import_json_cli().await,
+            _ => {
+                eprintln!(
+                    "Unknown command {:?}, expected consolidate_logs, create-token, backup, export-json or import-json",
+                    command
+                );
+                std::process::exit(1);
+            }
+        }
+        std::process::exit(0);
+    }
+
+    if let Err(e) = build(rocket::Config::figment()).launch().await {
+        // This also logs the details, such as which fairings failed
+        log::error!("Rocket failed to launch: {}", e.pretty_print());
+        std::process::exit(1);
+    }
+}
+
+/// Builds the Rocket application from the given figment
+///
+/// This runs the migrations (which are embedded into the binary), attaches the
+/// [AliveCheckFairing](alive_check::AliveCheckFairing), and the
+/// [car::fairing::EVChargeFairing] (with the handler selected in the
+/// configuration, by default the [tessie implementation](car::tessie)); and
+/// mounts the routes and catchers.
+///
+/// Taking the figment as a parameter allows running the application against
+/// another database, e.g., with a `rocket::local` client, by merging a
+/// `databases.sqlite_logs.url` and disabling the EV charge control with
+/// `ev_handler = "none"`, as the tests do (see the `testing` module). An
+/// in-memory database must be a shared-cache one
+/// (`file:<name>?mode=memory&cache=shared`), so that the connections of both
+/// pools see the same database.
+fn build(figment: rocket::figment::Figment) -> rocket::Rocket<rocket::Build> {
+    let rocket = rocket::custom(db::with_read_pool_default(figment))
+        .attach(config::validate_on_ignite())
+        .attach(Logs::init())
+        .attach(LogsRead::init())
+        .attach(load_rate_limit_quota())
+        .attach(load_display_precision())
+        .attach(load_http_client())
+        .attach(run_migrations())
+        .attach(fairing::AdHoc::on_ignite(
+            "Load trusted proxies",
+            |rocket| async {
+                let proxies = proxy::TrustedProxies::from(rocket.figment());
+                rocket.manage(proxies)
+            },
+        ))
+        .manage(stream::LiveReadings::new())
+        .manage(car::IngestedReadings::new())
+        .manage(idempotency::IdempotencyCache::new())
+        .attach(drain::DrainFairing::new())
+        .attach(request_id::RequestIdFairing)
+        .attach(access_log::AccessLogFairing::if_enabled())
+        .attach(cors::CorsFairing::if_enabled())
+        .attach(alive_check::AliveCheckFairing::new())
+        .attach(retention::RetentionFairing::new())
+        .attach(threshold_alert::ThresholdAlertFairing::new())
+        .attach(sampling_rate::SamplingRateFairing::new())
+        .attach(car::selected_handler_fairing())
+        .mount(
+            "/",
+            request_id::scoped(routes![
+                index,
+                healthz,
+                check_token_valid,
+                list_table_html,
+                list_table_json,
+                list_table_ndjson,
+                negotiated_json,
+                negotiated_html,
+                negotiated_svg,
+                not_acceptable,
+                list_table_svg,
+                compare_svg,
+                stream::stream_readings,
+                latest_reading,
+                list_locations,
+                reading_at,
+                peak_demand,
+                count_readings,
+                summary_report,
+                amps_histogram,
+                post_token,
+                post_influx,
+                post_import,
+                admin::create_view_token,
+                admin::list_view_tokens,
+                admin::overview,
+                admin::location_rows,
+                admin::create_token_alias,
+                admin::set_default_interval,
+                admin::delete_rows,
+                admin::delete_rows_unauthorized,
+                car::routes::car_debug,
+                car::routes::car_state,
+                car::routes::set_override,
+                car::routes::clear_override
+            ]),
+        )
+        .register("/", catchers![too_many_requests, expired_token])
+        .register("/admin", catchers![admin::unauthorized]);
+
+    #[cfg(feature = "mqtt")]
+    let rocket = rocket.attach(mqtt::MqttFairing::new());
+
+    rocket
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[rocket::async_test]
+    async fn latest_returns_the_newest_reading() {
+        let app = testing::client().await;
+        app.insert_reading("2026-10-16 10:00:00", 1.0, 230.0, 230.0).await;
+        app.insert_reading("2026-10-16 12:00:00", 3.0, 230.0, 690.0).await;
+        app.insert_reading("2026-10-16 11:00:00", 2.0, 230.0, 460.0).await;
+
+        let response = app.get(format!("/log/{}/latest", app.token)).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let latest: serde_json::Value = response.into_json().await.unwrap();
+        assert_eq!(latest["amps"], 3.0);
+        assert_eq!(latest["datetime"], "2026-10-16 12:00:00 UTC");
+    }
+
+    #[rocket::async_test]
+    async fn latest_is_not_found_without_readings() {
+        let app = testing::client().await;
+
+        let response = app.get(format!("/log/{}/latest", app.token)).dispatch().await;
+        assert_eq!(response.status(), Status::NotFound);
+    }
+
+    #[rocket::async_test]
+    async fn temperature_and_power_factor_are_read_back() {
+        let app = testing::client().await;
+
+        let response = app
+            .post(format!("/log/{}", app.token))
+            .header(ContentType::JSON)
+            .body(r#"{"amps": 2, "volts": 230, "watts": 414, "temperature_c": 21.5, "power_factor": 0.9}"#)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let response = app
+            .post(format!("/log/{}", app.token))
+            .header(ContentType::JSON)
+            .body(r#"{"amps": 1, "volts": 230, "watts": 230}"#)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+
+        let response = app.get(format!("/log/{}/json?range=1d", app.token)).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let page: serde_json::Value = response.into_json().await.unwrap();
+        let rows = page["rows"].as_array().unwrap();
+        assert_eq!(rows.len(), 2);
+        let with_extras = rows.iter().find(|row| row["amps"] == 2.0).unwrap();
+        assert_eq!(with_extras["temperature_c"], 21.5);
+        assert_eq!(with_extras["power_factor"], 0.9);
+        let without = rows.iter().find(|row| row["amps"] == 1.0).unwrap();
+        assert!(without.get("temperature_c").is_none());
+        assert!(without.get("power_factor").is_none());
+    }
+
+    #[rocket::async_test]
+    async fn check_reports_how_long_ago_the_token_logged() {
+        let app = testing::client().await;
+        let minute_ago = chrono::Utc::now() - chrono::Duration::seconds(60);
+        app.insert_reading(&minute_ago.format("%Y-%m-%d %H:%M:%S").to_string(), 1.0, 230.0, 230.0)
+            .await;
+
+        let response = app.get(format!("/log/{}/check", app.token)).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let check: serde_json::Value = response.into_json().await.unwrap();
+        assert_eq!(check["valid"], true);
+        let seconds = check["seconds_since_last_reading"].as_i64().unwrap();
+        assert!((60..65).contains(&seconds), "{}", seconds);
+    }
+
+    #[rocket::async_test]
+    async fn check_reports_a_token_that_never_logged() {
+        let app = testing::client().await;
+
+        let response = app.get(format!("/log/{}/check", app.token)).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let check: serde_json::Value = response.into_json().await.unwrap();
+        assert_eq!(check["valid"], true);
+        assert!(check["last_reading"].is_null());
+        assert!(check["seconds_since_last_reading"].is_null());
+    }
+
+    #[rocket::async_test]
+    async fn compare_plots_one_line_per_token() {
+        let app = testing::client().await;
+        let garage = app.create_token("garage").await;
+        for (minute, amps) in [(0, 1.0), (5, 2.0), (10, 1.5)] {
+            let created_at = format!("2024-01-01 10:{:02}:00", minute);
+            app.insert_reading(&created_at, amps, 230.0, amps * 230.0).await;
+            app.insert_reading_for(&garage, &created_at, amps * 4.0, 230.0, amps * 920.0)
+                .await;
+        }
+
+        let response = app
+            .get(format!(
+                "/log/compare/svg?tokens={},{}&start=2024-01-01T09:00&end=2024-01-01T12:00&tz=UTC",
+                app.token, garage
+            ))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(ContentType::SVG));
+        let svg = response.into_string().await.unwrap();
+        assert!(svg.contains(&format!(
+            "{} ({})",
+            testing::LOCATION,
+            token::simplify_token_string(&app.token)
+        )));
+        assert!(svg.contains(&format!("garage ({})", token::simplify_token_string(&garage))));
+    }
+
+    #[rocket::async_test]
+    async fn dark_theme_styles_the_plot_and_passes_through_the_page() {
+        let app = testing::client().await;
+        app.insert_reading("2024-01-01 10:00:00", 1.0, 230.0, 230.0).await;
+        app.insert_reading("2024-01-01 10:05:00", 2.0, 230.0, 460.0).await;
+        let range = "start=2024-01-01T09:00&end=2024-01-01T12:00&tz=UTC";
+
+        let svg = app
+            .get(format!("/log/{}/svg?{}", app.token, range))
+            .dispatch()
+            .await
+            .into_string()
+            .await
+            .unwrap();
+        assert!(svg.contains(".poloto_background{fill:AliceBlue;}"));
+
+        let svg = app
+            .get(format!("/log/{}/svg?{}&theme=dark", app.token, range))
+            .dispatch()
+            .await
+            .into_string()
+            .await
+            .unwrap();
+        assert!(svg.contains(".poloto_background{fill:#262626;}"));
+        assert!(svg.contains(".poloto_text{fill: white;}"));
+
+        let html = app
+            .get(format!("/log/{}/html?{}&theme=dark", app.token, range))
+            .dispatch()
+            .await
+            .into_string()
+            .await
+            .unwrap();
+        assert!(html.contains("&theme=dark"));
+    }
+
+    #[rocket::async_test]
+    async fn exported_readings_are_stored_as_is() {
+        let app = testing::client().await;
+
+        let response = app
+            .post(format!("/log/{}", app.token))
+            .header(ContentType::JSON)
+            .body(r#"{"amps": -3.5, "volts": 230, "watts": -805}"#)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+
+        let response = app.get(format!("/log/{}/latest", app.token)).dispatch().await;
+        let latest: serde_json::Value = response.into_json().await.unwrap();
+        assert_eq!(latest["amps"], -3.5);
+        assert_eq!(latest["watts"], -805.0);
+    }
+
+    #[rocket::async_test]
+    async fn at_returns_the_nearest_reading_within_the_tolerance() {
+        let app = testing::client().await;
+        app.insert_reading("2024-01-01 10:00:00", 1.0, 230.0, 230.0).await;
+        app.insert_reading("2024-01-01 10:03:00", 2.0, 230.0, 460.0).await;
+
+        let at = |timestamp: &str| {
+            app.get(format!("/log/{}/at?timestamp={}&tz=UTC", app.token, timestamp))
+                .dispatch()
+        };
+
+        let nearest: serde_json::Value = at("2024-01-01T10:02").await.into_json().await.unwrap();
+        assert_eq!(nearest["row"]["amps"], 2.0);
+        assert_eq!(nearest["offset_secs"], 60);
+
+        let nearest: serde_json::Value = at("2024-01-01T10:01").await.into_json().await.unwrap();
+        assert_eq!(nearest["row"]["amps"], 1.0);
+        assert_eq!(nearest["offset_secs"], -60);
+
+        // 6 minutes after the last reading, over the default 5 minute tolerance
+        assert_eq!(at("2024-01-01T10:09").await.status(), Status::NotFound);
+
+        let response = app.get(format!("/log/{}/at", app.token)).dispatch().await;
+        assert_eq!(response.status(), Status::BadRequest);
+    }
+
+    #[rocket::async_test]
+    async fn width_and_height_change_the_view_box() {
+        let app = testing::client().await;
+        app.insert_reading("2024-01-01 10:00:00", 1.0, 230.0, 230.0).await;
+        app.insert_reading("2024-01-01 10:05:00", 2.0, 230.0, 460.0).await;
+
+        for (size, view_box) in [
+            ("", "0 0 1400 500"),
+            ("&width=600&height=300", "0 0 600 300"),
+            // Clamped to the minimum size
+            ("&width=10", "0 0 300 500"),
+        ] {
+            let svg = app
+                .get(format!(
+                    "/log/{}/svg?start=2024-01-01T09:00&end=2024-01-01T12:00&tz=UTC{}",
+                    app.token, size
+                ))
+                .dispatch()
+                .await
+                .into_string()
+                .await
+                .unwrap();
+            assert!(svg.contains(&format!(r#"viewBox="{}""#, view_box)), "{}", size);
+        }
+    }
+
+    #[rocket::async_test]
+    async fn a_failed_import_asks_to_retry() {
+        let app = testing::client().await;
+        sqlx::query(
+            "CREATE TRIGGER fail_insert BEFORE INSERT ON energy_log
+            BEGIN SELECT RAISE(ABORT, 'database is locked'); END",
+        )
+        .execute(app.db())
+        .await
+        .unwrap();
+
+        let response = app
+            .post(format!("/log/{}/import", app.token))
+            .header(ContentType::CSV)
+            .body("timestamp,amps,volts,watts\n2024-01-31 23:00:00,3.0,230,690\n")
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::ServiceUnavailable);
+        assert_eq!(
+            response.into_string().await.unwrap(),
+            "Could not import the readings, please retry"
+        );
+    }
+
+    #[rocket::async_test]
+    async fn import_inserts_the_csv_readings_with_their_timestamps() {
+        let app = testing::client().await;
+        app.insert_reading("2024-01-31 22:59:00", 3.2, 230.0, 736.0).await;
+
+        let response = app
+            .post(format!("/log/{}/import", app.token))
+            .header(ContentType::CSV)
+            .body(
+                "timestamp,amps,volts,watts\n\
+                2024-01-31 22:59:00,3.2,230,736\n\
+                2024-01-31T23:00:00Z,3.0,,690\n\
+                2024-02-01T00:01:00+01:00,2.0,230,460\n\
+                1706742120,1.0,230,230\n\
+                not a reading\n",
+            )
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let report: serde_json::Value = response.into_json().await.unwrap();
+        assert_eq!(
+            report,
+            serde_json::json!({"inserted": 3, "skipped_duplicates": 1, "skipped_invalid": 1})
+        );
+
+        let rows: Vec<(String, f64)> = sqlx::query_as(
+            "SELECT created_at, volts FROM energy_log WHERE token = ? ORDER BY created_at",
+        )
+        .bind(&app.token)
+        .fetch_all(app.db())
+        .await
+        .unwrap();
+        assert_eq!(
+            rows,
+            vec![
+                ("2024-01-31 22:59:00".to_string(), 230.0),
+                ("2024-01-31 23:00:00".to_string(), 220.0),
+                ("2024-01-31 23:01:00".to_string(), 230.0),
+                ("2024-01-31 23:02:00".to_string(), 230.0),
+            ]
+        );
+    }
+
+    #[rocket::async_test]
+    async fn retries_with_the_same_idempotency_key_insert_once() {
+        let app = testing::client().await;
+        let post = |key: &'static str| {
+            app.post(format!("/log/{}", app.token))
+                .header(ContentType::JSON)
+                .header(rocket::http::Header::new("Idempotency-Key", key))
+                .body(r#"{"amps": 2.5, "volts": 230, "watts": 575}"#)
+                .dispatch()
+        };
+
+        let first = post("reading-1").await;
+        assert_eq!(first.status(), Status::Ok);
+        let first = first.into_string().await;
+        let retry = post("reading-1").await;
+        assert_eq!(retry.status(), Status::Ok);
+        assert_eq!(retry.into_string().await, first);
+
+        let count = || async {
+            sqlx::query_scalar!("SELECT COUNT(*) FROM energy_log WHERE token = ?", app.token)
+                .fetch_one(app.db())
+                .await
+                .unwrap()
+        };
+        assert_eq!(count().await, 1);
+
+        assert_eq!(post("reading-2").await.status(), Status::Ok);
+        assert_eq!(count().await, 2);
+    }
+
+    #[rocket::async_test]
+    async fn retries_while_the_first_request_is_pending_conflict() {
+        let app = testing::client().await;
+        let post = || {
+            app.post(format!("/log/{}", app.token))
+                .header(ContentType::JSON)
+                .header(rocket::http::Header::new("Idempotency-Key", "reading-1"))
+                .body(r#"{"amps": 2.5, "volts": 230, "watts": 575}"#)
+                .dispatch()
+        };
+
+        // As if another request with the key were inserting the reading
+        let cache = app.client.rocket().state::<idempotency::IdempotencyCache>().unwrap();
+        let idempotency::Begin::First(attempt) = cache.begin(&app.token, "reading-1") else {
+            panic!("the key should be new");
+        };
+        assert_eq!(post().await.status(), Status::Conflict);
+        attempt.succeeded();
+        assert_eq!(post().await.status(), Status::Ok);
+
+        let count = sqlx::query_scalar!("SELECT COUNT(*) FROM energy_log WHERE token = ?", app.token)
+            .fetch_one(app.db())
+            .await
+            .unwrap();
+        assert_eq!(count, 0);
+    }
+
+    #[rocket::async_test]
+    async fn a_retry_after_a_failed_insert_inserts_the_reading() {
+        let app = testing::client().await;
+        let post = || {
+            app.post(format!("/log/{}", app.token))
+                .header(ContentType::JSON)
+                .header(rocket::http::Header::new("Idempotency-Key", "reading-1"))
+                .body(r#"{"amps": 2.5, "volts": 230, "watts": 575}"#)
+                .dispatch()
+        };
+
+        sqlx::query(
+            "CREATE TRIGGER fail_insert BEFORE INSERT ON energy_log
+            BEGIN SELECT RAISE(ABORT, 'database is locked'); END",
+        )
+        .execute(app.db())
+        .await
+        .unwrap();
+        assert_eq!(post().await.status(), Status::ServiceUnavailable);
+        sqlx::query("DROP TRIGGER fail_insert")
+            .execute(app.db())
+            .await
+            .unwrap();
+
+        assert_eq!(post().await.status(), Status::Ok);
+        let count = sqlx::query_scalar!("SELECT COUNT(*) FROM energy_log WHERE token = ?", app.token)
+            .fetch_one(app.db())
+            .await
+            .unwrap();
+        assert_eq!(count, 1);
+    }
+
+    #[rocket::async_test]
+    async fn a_failed_influx_batch_asks_to_retry() {
+        let app = testing::client().await;
+        sqlx::query(
+            "CREATE TRIGGER fail_insert BEFORE INSERT ON energy_log
+            BEGIN SELECT RAISE(ABORT, 'database is locked'); END",
+        )
+        .execute(app.db())
+        .await
+        .unwrap();
+
+        let response = app
+            .post(format!("/log/{}/influx", app.token))
+            .body("power amps=2.5,volts=230,watts=575\npower amps=3,volts=230,watts=690\n")
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::ServiceUnavailable);
+        assert_eq!(
+            response.into_string().await.unwrap(),
+            "Could not log the readings, please retry"
+        );
+    }
+
+    #[test]
+    fn a_quota_of_one_per_second_throttles_the_second_request() {
+        let figment = testing::figment()
+            .merge(("rate_limit_per_second", 1))
+            .merge(("rate_limit_burst", 1));
+        let (per_second, burst) = rate_limit_quota(&figment).unwrap();
+        assert_eq!((per_second.get(), burst.get()), (1, 1));
+
+        // The governor registry is global to the process, so the quota is
+        // checked with a limiter of its own
+        let limiter = governor::RateLimiter::direct(Quota::per_second(per_second).allow_burst(burst));
+        assert!(limiter.check().is_ok());
+        assert!(limiter.check().is_err());
+    }
+
+    #[test]
+    fn the_rate_limit_quota_defaults_and_rejects_zero() {
+        let (per_second, burst) = rate_limit_quota(&testing::figment()).unwrap();
+        assert_eq!((per_second.get(), burst.get()), (4, 15));
+
+        for key in ["rate_limit_per_second", "rate_limit_burst"] {
+            assert!(rate_limit_quota(&testing::figment().merge((key, 0))).is_err());
+            assert!(rate_limit_quota(&testing::figment().merge((key, "many"))).is_err());
+        }
+    }
+
+    #[rocket::async_test]
+    async fn a_zero_rate_limit_quota_fails_the_launch() {
+        let figment = testing::figment().merge(("rate_limit_burst", 0));
+        let error = build(figment).ignite().await.expect_err("the launch should fail");
+        assert!(matches!(
+            error.kind(),
+            rocket::error::ErrorKind::FailedFairings(_)
+        ));
+    }
+
+    #[test]
+    fn report_filenames_have_no_path_characters() {
+        assert_eq!(
+            report_filename("../Home/Garage 1", "20240101", "20240131", "html"),
+            "energy-___Home_Garage_1-20240101_20240131.html"
+        );
+    }
+
+    #[rocket::async_test]
+    async fn download_asks_to_save_the_html_page() {
+        let app = testing::client().await;
+        let token = app.create_token("Home/Garage").await;
+        app.insert_reading_for(&token, "2024-01-01 10:00:00", 1.0, 230.0, 230.0)
+            .await;
+        let uri = format!(
+            "/log/{}/html?start=2024-01-01T00:00&end=2024-01-02T00:00&tz=UTC",
+            token
+        );
+
+        let response = app.get(&uri).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.headers().get_one("Content-Disposition"), None);
+
+        let response = app.get(format!("{}&download=1", uri)).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(ContentType::HTML));
+        assert_eq!(
+            response.headers().get_one("Content-Disposition"),
+            Some(r#"attachment; filename="energy-Home_Garage-20240101_20240102.html""#)
+        );
+    }
+
+    #[rocket::async_test]
+    async fn json_with_an_interval_returns_buckets_of_the_raw_rows() {
+        let app = testing::client().await;
+        for (created_at, amps) in [
+            ("2024-01-01 10:00:00", 1.0),
+            ("2024-01-01 10:02:00", 3.0),
+            ("2024-01-01 10:05:00", 4.0),
+            ("2024-01-01 10:07:00", 8.0),
+        ] {
+            app.insert_reading(created_at, amps, 230.0, amps * 230.0).await;
+        }
+        let uri = format!(
+            "/log/{}/json?start=2024-01-01T10:00&end=2024-01-01T10:10&tz=UTC",
+            app.token
+        );
+
+        let raw: serde_json::Value = app.get(&uri).dispatch().await.into_json().await.unwrap();
+        let raw: Vec<_> = raw["rows"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|row| row["amps"].as_f64().unwrap())
+            .collect();
+        assert_eq!(raw, vec![8.0, 4.0, 3.0, 1.0]);
+
+        let bucketed: serde_json::Value = app
+            .get(format!("{}&interval=300", uri))
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        assert_eq!(bucketed["interval"], 300);
+        let buckets: Vec<_> = bucketed["rows"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|row| (row["amps"].as_f64().unwrap(), row["max_amps"].as_f64().unwrap()))
+            .collect();
+        // Newest first, as the raw rows
+        assert_eq!(buckets, vec![(6.0, 8.0), (2.0, 3.0)]);
+    }
+
+    #[rocket::async_test]
+    async fn the_configured_max_page_count_limits_the_rows() {
+        let app = testing::client_with(testing::figment().merge(("max_page_count", 2))).await;
+        for minute in 0..5 {
+            let created_at = format!("2024-01-01 10:0{}:00", minute);
+            app.insert_reading(&created_at, 1.0, 230.0, 230.0).await;
+        }
+
+        let page: serde_json::Value = app
+            .get(format!(
+                "/log/{}/json?start=2024-01-01T10:00&end=2024-01-01T11:00&tz=UTC&count=1000",
+                app.token
+            ))
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        assert_eq!(page["rows"].as_array().unwrap().len(), 2);
+        assert_ne!(page["next"], "");
+    }
+
+    #[rocket::async_test]
+    async fn import_skips_the_readings_logged_before_sharding() {
+        let dir = testing::TempDir::new("import-shards");
+        let (url, shards) = (dir.path("logs.db"), [dir.path("shard-0.db")]);
+        let app = testing::client_with(
+            testing::figment()
+                .merge(("databases.sqlite_logs.url", &url))
+                .merge(("databases.sqlite_logs.shards", &shards))
+                .merge(("databases.sqlite_logs_read.url", &url))
+                .merge(("databases.sqlite_logs_read.shards", &shards)),
+        )
+        .await;
+        sqlx::query("INSERT INTO main.energy_log (token, amps, volts, watts, created_at) VALUES (?, 3.2, 230, 736, ?)")
+            .bind(&app.token)
+            .bind("2024-01-31 22:59:00")
+            .execute(app.db())
+            .await
+            .unwrap();
+
+        let response = app
+            .post(format!("/log/{}/import", app.token))
+            .header(ContentType::CSV)
+            .body("2024-01-31 22:59:00,3.2,230,736\n2024-01-31 23:00:00,3.0,230,690\n")
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let report: serde_json::Value = response.into_json().await.unwrap();
+        assert_eq!(
+            report,
+            serde_json::json!({"inserted": 1, "skipped_duplicates": 1, "skipped_invalid": 0})
+        );
+
+        let rows: Vec<String> = sqlx::query_scalar("SELECT created_at FROM energy_log ORDER BY created_at")
+            .fetch_all(app.db())
+            .await
+            .unwrap();
+        assert_eq!(rows, vec!["2024-01-31 22:59:00", "2024-01-31 23:00:00"]);
+    }
+
+    #[rocket::async_test]
+    async fn check_reports_the_last_reading_of_a_sharded_token() {
+        let dir = testing::TempDir::new("check-shards");
+        let (url, shards) = (dir.path("logs.db"), [dir.path("shard-0.db")]);
+        let app = testing::client_with(
+            testing::figment()
+                .merge(("databases.sqlite_logs.url", &url))
+                .merge(("databases.sqlite_logs.shards", &shards))
+                .merge(("databases.sqlite_logs_read.url", &url))
+                .merge(("databases.sqlite_logs_read.shards", &shards)),
+        )
+        .await;
+        let last_reading = || async {
+            let check: serde_json::Value = app
+                .get(format!("/log/{}/check", app.token))
+                .dispatch()
+                .await
+                .into_json()
+                .await
+                .unwrap();
+            check["last_reading"].clone()
+        };
+
+        // Logged before sharding
+        sqlx::query("INSERT INTO main.energy_log (token, amps, volts, watts, created_at) VALUES (?, 3.2, 230, 736, ?)")
+            .bind(&app.token)
+            .bind("2024-01-31 22:59:00")
+            .execute(app.db())
+            .await
+            .unwrap();
+        assert_eq!(last_reading().await, "2024-01-31T22:59:00Z");
+
+        let response = app
+            .post(format!("/log/{}", app.token))
+            .header(ContentType::JSON)
+            .body(r#"{"amps": 4, "volts": 230, "watts": 920}"#)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_ne!(last_reading().await, "2024-01-31T22:59:00Z");
+    }
+
+    #[rocket::async_test]
+    async fn exceeding_the_rate_limit_answers_with_json_and_retry_after() {
+        let app = testing::client().await;
+
+        // The default burst is 15 requests
+        let mut response = app.get("/").dispatch().await;
+        for _ in 0..20 {
+            if response.status() == Status::TooManyRequests {
+                break;
+            }
+            response = app.get("/").dispatch().await;
+        }
+        assert_eq!(response.status(), Status::TooManyRequests);
+        assert_eq!(response.content_type(), Some(ContentType::JSON));
+        let retry_after: u64 = response
+            .headers()
+            .get_one("Retry-After")
+            .expect("the Retry-After header should be set")
+            .parse()
+            .unwrap();
+        assert!(retry_after >= 1);
+        let body: serde_json::Value = response.into_json().await.unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({"error": "rate_limited", "retry_after_secs": retry_after})
+        );
+    }
+
+    #[rocket::async_test]
+    async fn peak_finds_the_window_of_a_spike() {
+        let app = testing::client().await;
+        for minute in 0..60 {
+            let amps = if (30..35).contains(&minute) { 20.0 } else { 1.0 };
+            let created_at = format!("2024-01-01 10:{:02}:00", minute);
+            app.insert_reading(&created_at, amps, 230.0, amps * 230.0).await;
+        }
+
+        let peak: serde_json::Value = app
+            .get(format!(
+                "/log/{}/peak?start=2024-01-01T10:00&end=2024-01-01T11:00&window_secs=300&tz=UTC",
+                app.token
+            ))
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        assert_eq!(
+            peak,
+            serde_json::json!({
+                "window_secs": 300,
+                "start": "2024-01-01T10:30:00+00:00",
+                "end": "2024-01-01T10:34:00+00:00",
+                "amps": 20.0,
+                "watts": 4600.0,
+            })
+        );
+
+        // A window twice as long averages the spike with the base load
+        let peak: serde_json::Value = app
+            .get(format!(
+                "/log/{}/peak?start=2024-01-01T10:00&end=2024-01-01T11:00&window_secs=600&tz=UTC",
+                app.token
+            ))
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        assert_eq!(peak["amps"], 10.5);
+    }
+
+    #[rocket::async_test]
+    async fn peak_rejects_invalid_windows_and_empty_ranges() {
+        let app = testing::client().await;
+        let peak = |query: &str| app.get(format!("/log/{}/peak?{}", app.token, query)).dispatch();
+
+        assert_eq!(peak("window_secs=0").await.status(), Status::BadRequest);
+        assert_eq!(peak("window_secs=300").await.status(), Status::NotFound);
+    }
+
+    #[rocket::async_test]
+    async fn ndjson_has_one_row_object_per_line() {
+        let app = testing::client().await;
+        for (minute, amps) in [(0, 1.0), (1, 2.0), (2, 3.0)] {
+            let created_at = format!("2024-01-01 10:0{}:00", minute);
+            app.insert_reading(&created_at, amps, 230.0, amps * 230.0).await;
+        }
+
+        let response = app
+            .get(format!(
+                "/log/{}/ndjson?start=2024-01-01T10:00&end=2024-01-01T11:00&tz=UTC",
+                app.token
+            ))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(
+            response.content_type(),
+            Some(ContentType::new("application", "x-ndjson"))
+        );
+        let body = response.into_string().await.unwrap();
+        let lines: Vec<&str> = body.lines().collect();
+        assert_eq!(lines.len(), 3);
+        for (line, amps) in lines.iter().zip([3.0, 2.0, 1.0]) {
+            let row: serde_json::Value = serde_json::from_str(line).unwrap();
+            assert_eq!(row["amps"], amps);
+            assert_eq!(row["watts"], amps * 230.0);
+            assert_eq!(row["location"], testing::LOCATION);
+            assert_eq!(row["token"], app.token.as_str());
+            assert!(row["datetime"].is_string());
+        }
+    }
+
+    #[rocket::async_test]
+    async fn day_buckets_align_to_local_midnight() {
+        let app = testing::client().await;
+        // Madrid is UTC+1 in January, so its January 2nd starts at 23:00 UTC
+        for (created_at, amps) in [
+            ("2024-01-01 22:30:00", 2.0),
+            ("2024-01-01 23:30:00", 4.0),
+            ("2024-01-02 10:00:00", 6.0),
+        ] {
+            app.insert_reading(created_at, amps, 230.0, amps * 230.0).await;
+        }
+
+        let days: serde_json::Value = app
+            .get(format!(
+                "/log/{}/json?start=2024-01-01T00:00&end=2024-01-03T00:00&tz=Europe/Madrid&bucket=day",
+                app.token
+            ))
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        assert_eq!(days["bucket"], "day");
+        let days: Vec<_> = days["rows"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|row| (row["datetime"].as_str().unwrap(), row["amps"].as_f64().unwrap()))
+            .collect();
+        assert_eq!(
+            days,
+            vec![
+                ("2024-01-01 23:00:00 UTC", 5.0),
+                ("2023-12-31 23:00:00 UTC", 2.0)
+            ]
+        );
+    }
+
+    #[rocket::async_test]
+    async fn a_relative_range_selects_the_recent_readings() {
+        let app = testing::client().await;
+        let ago = |hours| {
+            (chrono::Utc::now() - chrono::Duration::hours(hours))
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string()
+        };
+        app.insert_reading(&ago(2), 1.0, 230.0, 230.0).await;
+        app.insert_reading(&ago(30), 2.0, 230.0, 460.0).await;
+
+        let amps = |range: &'static str| {
+            let app = &app;
+            async move {
+                let page: serde_json::Value = app
+                    .get(format!("/log/{}/json?range={}&tz=UTC", app.token, range))
+                    .dispatch()
+                    .await
+                    .into_json()
+                    .await
+                    .unwrap();
+                page["rows"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|row| row["amps"].as_f64().unwrap())
+                    .collect::<Vec<_>>()
+            }
+        };
+        assert_eq!(amps("24h").await, vec![1.0]);
+        assert_eq!(amps("2d").await, vec![1.0, 2.0]);
+    }
+
+    #[rocket::async_test]
+    async fn the_accept_header_selects_the_representation() {
+        use rocket::http::{Accept, Header};
+
+        let app = testing::client().await;
+        app.insert_reading("2024-01-01 10:00:00", 1.0, 230.0, 230.0).await;
+        let uri = format!(
+            "/log/{}?start=2024-01-01T09:00&end=2024-01-01T12:00&tz=UTC",
+            app.token
+        );
+
+        for (accept, expected) in [
+            (Accept::JSON, ContentType::JSON),
+            (Accept::HTML, ContentType::HTML),
+            (Accept::SVG, ContentType::SVG),
+            (Accept::Any, ContentType::JSON),
+        ] {
+            let response = app.get(&uri).header(accept.clone()).dispatch().await;
+            assert_eq!(response.status(), Status::Ok, "{}", accept);
+            assert_eq!(response.content_type(), Some(expected), "{}", accept);
+        }
+
+        let response = app.get(&uri).dispatch().await;
+        assert_eq!(response.content_type(), Some(ContentType::JSON));
+        let response = app
+            .get(&uri)
+            .header(Header::new("Accept", "text/html, application/json;q=0.5"))
+            .dispatch()
+            .await;
+        assert_eq!(response.content_type(), Some(ContentType::HTML));
+        let response = app.get(&uri).header(Accept::CSV).dispatch().await;
+        assert_eq!(response.status(), Status::NotAcceptable);
+    }
+
+    #[rocket::async_test]
+    async fn negotiated_reads_of_invalid_tokens_are_not_found_or_gone() {
+        use rocket::http::Accept;
+
+        let app = testing::client().await;
+        sqlx::query!(
+            "INSERT INTO view_tokens (token, user_id, view_token_valid_until)
+            SELECT 'expired-view-token', user_id, datetime('now', '-1 days') FROM tokens WHERE token = ?",
+            app.token
+        )
+        .execute(app.db())
+        .await
+        .unwrap();
+
+        for accept in [Accept::JSON, Accept::CSV] {
+            let status = |token: &str| {
+                let request = app.get(format!("/log/{}", token)).header(accept.clone());
+                async move { request.dispatch().await.status() }
+            };
+            assert_eq!(status("unknown-view-token").await, Status::NotFound, "{}", accept);
+            assert_eq!(status("expired-view-token").await, Status::Gone, "{}", accept);
+        }
+    }
+
+    #[test]
+    fn reads_the_display_precision() {
+        use rocket::figment::Figment;
+
+        assert_eq!(display_precision(&Figment::new()), Ok(3));
+        assert_eq!(display_precision(&Figment::new().merge(("display_precision", 1))), Ok(1));
+        assert_eq!(display_precision(&Figment::new().merge(("display_precision", 16))), Err(()));
+        assert_eq!(display_precision(&Figment::new().merge(("display_precision", -1))), Err(()));
+    }
+
+    #[rocket::async_test]
+    async fn messy_floats_render_to_the_display_precision() {
+        let app = testing::client().await;
+        app.insert_reading("2024-01-01 10:00:00", 3.200000000000001, 230.0, 736.0000000000002)
+            .await;
+        let range = "start=2024-01-01T09:00&end=2024-01-01T12:00&tz=UTC";
+
+        let page: serde_json::Value = app
+            .get(format!("/log/{}/json?{}", app.token, range))
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        assert_eq!(page["rows"][0]["amps"], 3.2);
+        assert_eq!(page["rows"][0]["watts"], 736.0);
+
+        let html = app
+            .get(format!("/log/{}/html?{}", app.token, range))
+            .dispatch()
+            .await
+            .into_string()
+            .await
+            .unwrap();
+        assert!(html.contains("<td>3.2</td>"), "{}", html);
+        assert!(!html.contains("3.2000"));
+    }
+
+    #[rocket::async_test]
+    async fn lists_the_locations_a_view_token_can_see() {
+        let app = testing::client().await;
+        // Another sensor of the same user, an aliased sensor elsewhere, and
+        // an unrelated one
+        sqlx::query(
+            "INSERT INTO tokens (token, user_id) SELECT 'second-sensor', user_id FROM tokens WHERE token = ?",
+        )
+        .bind(&app.token)
+        .execute(app.db())
+        .await
+        .unwrap();
+        let old = app.create_token("old-house").await;
+        sqlx::query("INSERT INTO token_aliases (alias, token) VALUES (?, ?)")
+            .bind(&old)
+            .bind(&app.token)
+            .execute(app.db())
+            .await
+            .unwrap();
+        let unrelated = app.create_token("neighbour").await;
+
+        let locations = |token: String| {
+            let app = &app;
+            async move {
+                let response = app.get(format!("/log/{}/locations", token)).dispatch().await;
+                assert_eq!(response.status(), Status::Ok);
+                let locations: serde_json::Value = response.into_json().await.unwrap();
+                locations["locations"].clone()
+            }
+        };
+        assert_eq!(
+            locations(app.token.clone()).await,
+            serde_json::json!(["old-house", testing::LOCATION])
+        );
+        assert_eq!(locations(unrelated).await, serde_json::json!(["neighbour"]));
+    }
+
+    #[rocket::async_test]
+    async fn the_histogram_counts_a_known_distribution() {
+        let app = testing::client().await;
+        // A bimodal load: idle around 0.5 A and a heater around 8 A
+        for (minute, amps) in [0.4, 0.5, 0.6, 8.1, 8.2, 7.9].into_iter().enumerate() {
+            let created_at = format!("2024-01-01 10:0{}:00", minute);
+            app.insert_reading(&created_at, amps, 230.0, amps * 230.0).await;
+        }
+        let uri = format!(
+            "/log/{}/histogram?start=2024-01-01T09:00&end=2024-01-01T12:00&tz=UTC",
+            app.token
+        );
+
+        let histogram: serde_json::Value =
+            app.get(&uri).dispatch().await.into_json().await.unwrap();
+        let edges: Vec<f64> = (0..=9).map(f64::from).collect();
+        assert_eq!(histogram["edges"], serde_json::json!(edges));
+        assert_eq!(histogram["counts"], serde_json::json!([3, 0, 0, 0, 0, 0, 0, 1, 2]));
+
+        let histogram: serde_json::Value = app
+            .get(format!("{}&bin_amps=4", uri))
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        assert_eq!(histogram["edges"], serde_json::json!([0.0, 4.0, 8.0, 12.0]));
+        assert_eq!(histogram["counts"], serde_json::json!([3, 1, 2]));
+
+        let status = app.get(format!("{}&bin_amps=0", uri)).dispatch().await.status();
+        assert_eq!(status, Status::BadRequest);
+    }
+
+    #[rocket::async_test]
+    async fn an_empty_range_plots_a_no_data_svg() {
+        let app = testing::client().await;
+        let other = app.create_token("other").await;
+        let range = "start=2024-01-01T09:00&end=2024-01-01T12:00&tz=UTC";
+
+        for (uri, view_box) in [
+            (
+                format!("/log/{}/svg?{}&width=600&height=300", app.token, range),
+                r#"viewBox="0 0 600 300""#,
+            ),
+            (
+                format!("/log/compare/svg?tokens={},{}&{}", app.token, other, range),
+                r#"viewBox="0 0 1400 500""#,
+            ),
+        ] {
+            let response = app.get(&uri).dispatch().await;
+            assert_eq!(response.status(), Status::Ok, "{}", uri);
+            assert_eq!(response.content_type(), Some(ContentType::SVG), "{}", uri);
+            let svg = response.into_string().await.unwrap();
+            assert!(svg.starts_with(r#"<svg xmlns="http://www.w3.org/2000/svg""#), "{}", svg);
+            assert!(svg.ends_with("</svg>"), "{}", svg);
+            assert!(svg.contains(view_box), "{}", svg);
+            assert!(svg.contains(">No data</text>"), "{}", svg);
+        }
+    }
+
+    #[rocket::async_test]
+    async fn the_budget_is_drawn_as_a_line_at_its_amps() {
+        let app = testing::client().await;
+        for (minute, amps) in [10.0, 40.0, 20.0, 0.0].into_iter().enumerate() {
+            let created_at = format!("2024-01-01 10:0{}:00", minute);
+            app.insert_reading(&created_at, amps, 230.0, amps * 230.0).await;
+        }
+        let uri = format!(
+            "/log/{}/svg?start=2024-01-01T09:00&end=2024-01-01T12:00&tz=UTC&interval=60",
+            app.token
+        );
+
+        let svg = app.get(&uri).dispatch().await.into_string().await.unwrap();
+        assert!(!svg.contains("budget"));
+        let svg = app
+            .get(format!("{}&budget=30", uri))
+            .dispatch()
+            .await
+            .into_string()
+            .await
+            .unwrap();
+        assert!(svg.contains(">budget (30 A)</text>"));
+
+        // The y of the "30" tick on the amps axis
+        let tick = svg
+            .split("<tspan ")
+            .find(|tspan| tspan.split("</tspan>").next().unwrap().ends_with(">30"))
+            .expect("the amps axis should have a 30 tick");
+        let tick_y = tick.split("y=\"").nth(1).unwrap().split('"').next().unwrap();
+        // The budget is the third line, after the max and avg amps
+        let path = svg
+            .split(r#"<g id="poloto_plot2""#)
+            .nth(1)
+            .and_then(|plot| plot.split("d=\"").nth(1))
+            .and_then(|d| d.split('"').next())
+            .expect("the budget line should be plotted");
+        let ys: Vec<_> = path
+            .split(['M', 'L'])
+            .filter_map(|point| point.split_whitespace().nth(1))
+            .collect();
+        assert_eq!(ys, vec![tick_y, tick_y], "{}", path);
+    }
+
+    #[rocket::async_test]
+    async fn compact_json_has_the_same_data_as_pretty_json() {
+        let app = testing::client().await;
+        for minute in 0..3 {
+            let created_at = format!("2024-01-01 10:0{}:00", minute);
+            app.insert_reading(&created_at, 1.5 + minute as f64, 230.0, 345.0).await;
+        }
+        let uri = format!(
+            "/log/{}/json?start=2024-01-01T09:00&end=2024-01-01T12:00&tz=UTC",
+            app.token
+        );
+
+        for (query, rows) in [("", 3), ("&interval=60", 3), ("&bucket=hour", 1)] {
+            let body = |pretty: &'static str| {
+                let uri = format!("{}{}{}", uri, query, pretty);
+                let app = &app;
+                async move { app.get(uri).dispatch().await.into_string().await.unwrap() }
+            };
+            let compact = body("").await;
+            let pretty = body("&pretty=true").await;
+
+            assert!(!compact.contains('\n'), "{}", compact);
+            assert!(pretty.contains("\n  "), "{}", pretty);
+            assert_eq!(body("&pretty=false").await, compact);
+            let compact: serde_json::Value = serde_json::from_str(&compact).unwrap();
+            let pretty: serde_json::Value = serde_json::from_str(&pretty).unwrap();
+            assert_eq!(compact, pretty);
+            assert_eq!(compact["rows"].as_array().unwrap().len(), rows, "{}", query);
+        }
+    }
+
+    #[rocket::async_test]
+    async fn a_modified_migration_fails_the_launch() {
+        let figment = testing::figment();
+        // Keeps the in-memory database alive
+        let app = testing::client_with(figment.clone()).await;
+        sqlx::query("UPDATE _sqlx_migrations SET checksum = x'00' WHERE version = 2")
+            .execute(app.db())
+            .await
+            .unwrap();
+
+        let error = build(figment).ignite().await.expect_err("the launch should fail");
+        let rocket::error::ErrorKind::FailedFairings(failed) = error.kind() else {
+            panic!("unexpected error: {}", error);
+        };
+        let failed: Vec<_> = failed.iter().map(|fairing| fairing.name).collect();
+        assert_eq!(failed, vec!["Run DB migrations"]);
+    }
+
+    #[rocket::async_test]
+    async fn the_report_summarizes_the_range() {
+        let app = testing::client().await;
+        // A consolidated minute of 50 Wh, then a reading every minute and a
+        // reading after a 2-hour outage
+        app.insert_reading("2024-01-01 09:00:00", 8.0, 230.0, 1840.0).await;
+        sqlx::query("UPDATE energy_log SET wh = 50 WHERE created_at = '2024-01-01 09:00:00'")
+            .execute(app.db())
+            .await
+            .unwrap();
+        app.insert_reading("2024-01-01 10:00:00", 10.0, 230.0, 2300.0).await;
+        app.insert_reading("2024-01-01 10:01:00", 20.0, 230.0, 4600.0).await;
+        app.insert_reading("2024-01-01 10:02:00", 6.0, 230.0, 1380.0).await;
+        app.insert_reading("2024-01-01 12:02:00", 4.0, 230.0, 920.0).await;
+        // Out of the range
+        app.insert_reading("2024-01-02 10:00:00", 30.0, 230.0, 6900.0).await;
+
+        let response = app
+            .get(format!(
+                "/log/{}/report?start=2024-01-01T00:00&end=2024-01-02T00:00&tz=UTC",
+                app.token
+            ))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let report: serde_json::Value = response.into_json().await.unwrap();
+        assert_eq!(report["readings"], 5);
+        // 50 Wh, plus 2300 W and 4600 W for a minute each, plus 1380 W held
+        // for at most 5 minutes over the outage
+        assert_eq!(report["energy_kwh"], 0.28);
+        assert_eq!(report["avg_amps"], 9.6);
+        assert_eq!(report["avg_watts"], 2208.0);
+        assert_eq!(report["max_amps"], 20.0);
+        assert_eq!(report["max_amps_at"], "2024-01-01T10:01:00+00:00");
+        assert_eq!(report["peak_demand"]["window_secs"], 900);
+        assert_eq!(report["start"], "2024-01-01T00:00:00+00:00");
+
+        let response = app
+            .get(format!(
+                "/log/{}/report?start=2023-01-01T00:00&end=2023-01-02T00:00&tz=UTC",
+                app.token
+            ))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::NotFound);
+    }
+
+    #[rocket::async_test]
+    async fn count_respects_the_range() {
+        let app = testing::client().await;
+        let other = app.create_token("garage").await;
+        for minute in 0..5 {
+            let created_at = format!("2024-01-01 10:0{}:00", minute);
+            app.insert_reading(&created_at, 1.0, 230.0, 230.0).await;
+        }
+        app.insert_reading("2024-01-02 10:00:00", 1.0, 230.0, 230.0).await;
+        // Not readable with the token
+        app.insert_reading_for(&other, "2024-01-01 10:00:00", 1.0, 230.0, 230.0).await;
+
+        let count = |range: &'static str| {
+            let app = &app;
+            async move {
+                let response = app
+                    .get(format!("/log/{}/count?{}&tz=UTC", app.token, range))
+                    .dispatch()
+                    .await;
+                assert_eq!(response.status(), Status::Ok);
+                response.into_json::<serde_json::Value>().await.unwrap()["count"].clone()
+            }
+        };
+
+        assert_eq!(count("start=2024-01-01T00:00&end=2024-01-03T00:00").await, 6);
+        assert_eq!(count("start=2024-01-01T00:00&end=2024-01-02T00:00").await, 5);
+        assert_eq!(count("start=2024-01-01T10:01&end=2024-01-01T10:03").await, 3);
+        assert_eq!(count("start=2023-01-01T00:00&end=2023-01-02T00:00").await, 0);
+    }
+
+    #[rocket::async_test]
+    async fn the_time_labels_follow_the_span() {
+        let app = testing::client().await;
+        for day in 1..=22 {
+            let created_at = format!("2024-01-{:02} 10:00:00", day);
+            app.insert_reading(&created_at, 5.0, 230.0, 1150.0).await;
+        }
+        for minute in 0..40 {
+            let created_at = format!("2024-02-01 10:{:02}:00", minute);
+            app.insert_reading(&created_at, 5.0, 230.0, 1150.0).await;
+        }
+        let svg = |range: &str| {
+            let app = &app;
+            let uri = format!("/log/{}/svg?{}&tz=UTC&interval=60", app.token, range);
+            async move { app.get(uri).dispatch().await.into_string().await.unwrap() }
+        };
+
+        // Dates every 3 days over 3 weeks
+        let weeks = svg("start=2024-01-01T00:00&end=2024-01-23T00:00").await;
+        for label in ["Jan 03", "Jan 06", "Jan 21"] {
+            assert!(weeks.contains(&format!(">{}</tspan>", label)), "{}", weeks);
+        }
+        assert!(!weeks.contains(":00</tspan>"), "{}", weeks);
+
+        // Minutes every 5 minutes over 40 minutes
+        let minutes = svg("start=2024-02-01T10:00&end=2024-02-01T10:40").await;
+        for label in ["10:00", "10:05", "10:35"] {
+            assert!(minutes.contains(&format!(">{}</tspan>", label)), "{}", minutes);
+        }
+        assert!(!minutes.contains(">Feb"), "{}", minutes);
+    }
+
+    #[rocket::async_test]
+    async fn healthz_checks_the_database() {
+        let app = testing::client().await;
+        let response = app.get("/healthz").dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let body: serde_json::Value = response.into_json().await.unwrap();
+        assert_eq!(body, serde_json::json!({ "status": "ok" }));
+
+        // As if the database had become unreachable
+        app.db().close().await;
+        let response = app.get("/healthz").dispatch().await;
+        assert_eq!(response.status(), Status::ServiceUnavailable);
+        let body: serde_json::Value = response.into_json().await.unwrap();
+        assert_eq!(body, serde_json::json!({ "status": "unavailable" }));
+    }
+
+    #[rocket::async_test]
+    async fn exempt_gateways_are_not_throttled() {
+        let app = testing::client_with(
+            testing::figment().merge(("rate_limit_exempt", ["192.0.2.0/24"])),
+        )
+        .await;
+        let status = |remote: &str| {
+            let request = app.client.get("/").remote(remote.parse().unwrap());
+            async move { request.dispatch().await.status() }
+        };
+
+        // Well past the default burst of 15 requests
+        for _ in 0..30 {
+            assert_eq!(status("192.0.2.10:40000").await, Status::Ok);
+        }
+        let mut throttled = false;
+        for _ in 0..30 {
+            if status("198.51.100.20:40000").await == Status::TooManyRequests {
+                throttled = true;
+                break;
+            }
+        }
+        assert!(throttled);
+    }
+
+    #[rocket::async_test]
+    async fn invalid_rate_limit_exemptions_fail_the_launch() {
+        for exempt in ["gateway", "10.0.0.0/33"] {
+            let figment = testing::figment().merge(("rate_limit_exempt", [exempt]));
+            let error = build(figment).ignite().await.expect_err("the launch should fail");
+            assert!(matches!(
+                error.kind(),
+                rocket::error::ErrorKind::FailedFairings(_)
+            ));
+        }
+    }
 }
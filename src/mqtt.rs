@@ -0,0 +1,461 @@
+//! Ingest of readings published to an MQTT broker.
+//!
+//! This module is only built with the `mqtt` feature. It contains the
+//! [MqttFairing] fairing, which subscribes to a topic pattern with the sensor
+//! token in it, and inserts every reading published there just like the
+//! POST /log/:token route does, so sensors already publishing to MQTT do not
+//! need an HTTP bridge.
+//!
+//! It is disabled unless `mqtt_url` is set in the figment configuration
+//! (Rocket.toml):
+//!
+//! ```toml
+//! mqtt_url = "mqtt://broker.local:1883"
+//! # The `+` level of the topic is the sensor token
+//! mqtt_topic = "energy/+/reading"
+//! mqtt_username = "amp-sensor-backend"
+//! mqtt_password = "secret"
+//! ```
+//!
+//! The payloads are the same JSON objects as the body of POST /log/:token,
+//! e.g., `{"amps": 3.2, "volts": 230, "watts": 736}`. Readings for unknown
+//! tokens or with invalid payloads are logged and dropped. If the connection
+//! to the broker is lost, the task reconnects and subscribes again.
+//!
+//! Like the HTTP ingest, these readings trigger the EV charge check, through
+//! the [IngestedReadings] channel.
+
+use std::time::Duration;
+
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    figment::Figment,
+    tokio::sync::Mutex,
+};
+use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS};
+use std::sync::Arc;
+
+use crate::car::IngestedReadings;
+use crate::consistency::{Consistency, WattsCheck};
+use crate::quality::ReadingFlags;
+use crate::stream::{LiveReading, LiveReadings};
+use crate::token::simplify_token_string;
+
+/// The topic subscribed to, unless `mqtt_topic` is configured
+const DEFAULT_TOPIC: &str = "energy/+/reading";
+
+/// The client ID, unless `mqtt_client_id` is configured
+const DEFAULT_CLIENT_ID: &str = "amp-sensor-backend";
+
+/// The port of the broker, if the URL does not have one
+const DEFAULT_PORT: u16 = 1883;
+
+/// How long to wait before reconnecting to the broker after an error
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// The connection settings read from the figment
+pub struct MqttSettings {
+    host: String,
+    port: u16,
+    topic: String,
+    client_id: String,
+    credentials: Option<(String, String)>,
+}
+
+impl MqttSettings {
+    /// Read the settings from the figment.
+    ///
+    /// Returns `Ok(None)` if no `mqtt_url` is configured, and an error if any
+    /// of the settings is invalid.
+    pub fn from_figment(figment: &Figment) -> Result<Option<Self>, String> {
+        let url: String = match figment.extract_inner("mqtt_url") {
+            Ok(url) => url,
+            Err(e) if e.missing() => return Ok(None),
+            Err(e) => return Err(format!("Invalid mqtt_url: {}", e)),
+        };
+        let url = reqwest::Url::parse(&url).map_err(|e| format!("Invalid mqtt_url: {}", e))?;
+        if !["mqtt", "tcp"].contains(&url.scheme()) {
+            return Err(format!(
+                "Invalid mqtt_url scheme {:?}, it must be mqtt:// or tcp://",
+                url.scheme()
+            ));
+        }
+        let host = url
+            .host_str()
+            .ok_or_else(|| "Invalid mqtt_url, it has no host".to_string())?
+            .to_string();
+
+        let optional = |key: &str| match figment.extract_inner::<String>(key) {
+            Ok(value) => Ok(Some(value)),
+            Err(e) if e.missing() => Ok(None),
+            Err(e) => Err(format!("Invalid {}: {}", key, e)),
+        };
+        let topic = optional("mqtt_topic")?.unwrap_or_else(|| DEFAULT_TOPIC.to_string());
+        let levels: Vec<&str> = topic.split('/').collect();
+        if levels.iter().filter(|level| **level == "+").count() != 1 || topic.contains('#') {
+            return Err(format!(
+                "Invalid mqtt_topic {:?}, it must have exactly one + level for the token, and no #",
+                topic
+            ));
+        }
+        let credentials = match (optional("mqtt_username")?, optional("mqtt_password")?) {
+            (Some(username), password) => Some((username, password.unwrap_or_default())),
+            (None, Some(_)) => return Err("mqtt_password is set without mqtt_username".to_string()),
+            (None, None) => None,
+        };
+
+        Ok(Some(Self {
+            host,
+            port: url.port().unwrap_or(DEFAULT_PORT),
+            topic,
+            client_id: optional("mqtt_client_id")?.unwrap_or_else(|| DEFAULT_CLIENT_ID.to_string()),
+            credentials,
+        }))
+    }
+
+    /// Returns the token in the `+` level of the topic, if the topic matches
+    /// the subscribed pattern
+    fn token_from_topic<'a>(&self, topic: &'a str) -> Option<&'a str> {
+        let pattern: Vec<&str> = self.topic.split('/').collect();
+        let levels: Vec<&str> = topic.split('/').collect();
+        if pattern.len() != levels.len() {
+            return None;
+        }
+        let mut token = None;
+        for (expected, level) in pattern.into_iter().zip(levels) {
+            match expected {
+                "+" => token = Some(level),
+                _ if expected == level => {}
+                _ => return None,
+            }
+        }
+        token.filter(|token| !token.is_empty())
+    }
+}
+
+/// This fairing subscribes to the broker when the Rocket app lifts off, and
+/// inserts the readings published to the topic.
+pub struct MqttFairing {
+    /// This stores the task that is spawned to receive the readings
+    task: Arc<Mutex<Option<rocket::tokio::task::JoinHandle<()>>>>,
+}
+
+impl MqttFairing {
+    pub fn new() -> Self {
+        Self {
+            task: Arc::new(Mutex::new(None)),
+        }
+    }
+}
+
+/// Inserts a reading published to `topic`, as POST /log/:token would.
+async fn ingest(
+    db: &crate::db::SqlitePool,
+    settings: &MqttSettings,
+    watts_check: &WattsCheck,
+    live: &LiveReadings,
+    ingested: &IngestedReadings,
+    topic: &str,
+    payload: &[u8],
+) -> Result<(), String> {
+    let token = settings
+        .token_from_topic(topic)
+        .ok_or_else(|| format!("Topic {:?} does not match {:?}", topic, settings.topic))?;
+    let count = sqlx::query_scalar!("SELECT COUNT(*) FROM tokens WHERE token = ?", token)
+        .fetch_one(&**db)
+        .await
+        .map_err(|e| e.to_string())?;
+    if count == 0 {
+        return Err(format!("Unknown token {}", simplify_token_string(token)));
+    }
+
+    let log: crate::LogData =
+        serde_json::from_slice(payload).map_err(|e| format!("Invalid payload: {}", e))?;
+    let volts = log.volts.unwrap_or(220.0f64);
+    let suspect = match watts_check.check(log.amps, volts, log.watts) {
+        Consistency::Consistent => false,
+        Consistency::Suspect => true,
+        Consistency::Rejected => return Err("The watts do not match amps * volts".to_string()),
+    };
+
+    let flags = ReadingFlags::observe(log.volts, suspect, None, chrono::Utc::now()).bits();
+    sqlx::query!(
+        "INSERT INTO energy_log (token, amps, volts, watts, temperature_c, power_factor, flags, user_agent, source) VALUES (?, ?, ?, ?, ?, ?, ?, 'mqtt', 'mqtt')",
+        token,
+        log.amps,
+        volts,
+        log.watts,
+        log.temperature_c,
+        log.power_factor,
+        flags,
+    )
+    .execute(db.for_token(token))
+    .await
+    .map_err(|e| e.to_string())?;
+    log::info!(
+        "Inserted row from MQTT topic for {}",
+        simplify_token_string(token)
+    );
+
+    live.publish(
+        token,
+        LiveReading {
+            token: simplify_token_string(token),
+            datetime: chrono::Utc::now(),
+            amps: log.amps,
+            volts,
+            watts: log.watts,
+            temperature_c: log.temperature_c,
+            power_factor: log.power_factor,
+        },
+    );
+    ingested.notify(token);
+    Ok(())
+}
+
+#[rocket::async_trait]
+impl Fairing for MqttFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "MQTT Ingest",
+            kind: Kind::Liftoff | Kind::Shutdown,
+        }
+    }
+
+    async fn on_liftoff(&self, rocket: &rocket::Rocket<rocket::Orbit>) -> () {
+        let settings = match MqttSettings::from_figment(rocket.figment()) {
+            Ok(Some(settings)) => settings,
+            Ok(None) => return,
+            Err(e) => {
+                log::error!("MQTT ingest is disabled: {}", e);
+                return;
+            }
+        };
+        log::info!(
+            "Subscribing to {:?} on the MQTT broker {}:{}",
+            settings.topic,
+            settings.host,
+            settings.port
+        );
+
+        let mut options = MqttOptions::new(&settings.client_id, &settings.host, settings.port);
+        options.set_keep_alive(Duration::from_secs(30));
+        if let Some((username, password)) = &settings.credentials {
+            options.set_credentials(username, password);
+        }
+        let (client, mut eventloop) = AsyncClient::new(options, 10);
+
+        let db_conn = crate::alive_check::get_database::<crate::Logs>(rocket).await;
+        let watts_check = WattsCheck::from(rocket.figment());
+        let live = rocket
+            .state::<LiveReadings>()
+            .cloned()
+            .unwrap_or_else(LiveReadings::new);
+        let ingested = rocket
+            .state::<IngestedReadings>()
+            .cloned()
+            .unwrap_or_else(IngestedReadings::new);
+        let task = rocket::tokio::task::spawn(async move {
+            loop {
+                match eventloop.poll().await {
+                    // Subscribe again on every connection, as the session is
+                    // not kept by the broker
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                        log::info!("Connected to the MQTT broker");
+                        if let Err(e) = client.subscribe(&settings.topic, QoS::AtLeastOnce).await {
+                            log::error!("Failed to subscribe to the MQTT topic: {}", e);
+                        }
+                    }
+                    Ok(Event::Incoming(Packet::Publish(publish))) => {
+                        let result = ingest(
+                            &db_conn,
+                            &settings,
+                            &watts_check,
+                            &live,
+                            &ingested,
+                            &publish.topic,
+                            &publish.payload,
+                        )
+                        .await;
+                        if let Err(e) = result {
+                            log::warn!("Dropping MQTT reading on {:?}: {}", publish.topic, e);
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        log::error!(
+                            "MQTT connection error, reconnecting in {}s: {}",
+                            RECONNECT_DELAY.as_secs(),
+                            e
+                        );
+                        rocket::tokio::time::sleep(RECONNECT_DELAY).await;
+                    }
+                }
+            }
+        });
+
+        if let Some(old) = self.task.lock().await.replace(task) {
+            old.abort();
+        }
+    }
+
+    /// When the rocket is shutting down, we need to abort the MQTT task.
+    async fn on_shutdown(&self, _: &rocket::Rocket<rocket::Orbit>) -> () {
+        if let Some(task) = self.task.lock().await.take() {
+            task.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use rocket::tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use rocket::tokio::net::{TcpListener, TcpStream};
+
+    /// Reads an MQTT packet, returning its type and flags, and its body
+    async fn read_packet(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+        let header = stream.read_u8().await.unwrap();
+        let mut length = 0usize;
+        for shift in (0..4).map(|i| 7 * i) {
+            let byte = stream.read_u8().await.unwrap();
+            length |= usize::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        let mut body = vec![0; length];
+        stream.read_exact(&mut body).await.unwrap();
+        (header, body)
+    }
+
+    /// Writes an MQTT packet with the given type and flags, and body
+    async fn write_packet(stream: &mut TcpStream, header: u8, body: &[u8]) {
+        let mut packet = vec![header];
+        let mut length = body.len();
+        loop {
+            let byte = (length % 128) as u8;
+            length /= 128;
+            if length == 0 {
+                packet.push(byte);
+                break;
+            }
+            packet.push(byte | 0x80);
+        }
+        packet.extend_from_slice(body);
+        stream.write_all(&packet).await.unwrap();
+    }
+
+    /// Accepts the connection of the MQTT task, and publishes the payloads to
+    /// their topics once it subscribed, as a broker would
+    async fn publish(listener: &TcpListener, messages: &[(&str, &str)]) -> TcpStream {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let (connect, _) = read_packet(&mut stream).await;
+        assert_eq!(connect, 0x10);
+        write_packet(&mut stream, 0x20, &[0, 0]).await;
+
+        let (subscribe, body) = read_packet(&mut stream).await;
+        assert_eq!(subscribe, 0x82);
+        write_packet(&mut stream, 0x90, &[body[0], body[1], 1]).await;
+
+        for (topic, payload) in messages {
+            let mut publish = (topic.len() as u16).to_be_bytes().to_vec();
+            publish.extend_from_slice(topic.as_bytes());
+            publish.extend_from_slice(payload.as_bytes());
+            write_packet(&mut stream, 0x30, &publish).await;
+        }
+        stream
+    }
+
+    #[test]
+    fn the_token_is_the_plus_level_of_the_topic() {
+        let figment = Figment::new().merge(("mqtt_url", "mqtt://localhost"));
+        let settings = MqttSettings::from_figment(&figment).unwrap().unwrap();
+
+        assert_eq!(settings.port, DEFAULT_PORT);
+        assert_eq!(settings.token_from_topic("energy/abc/reading"), Some("abc"));
+        assert_eq!(settings.token_from_topic("energy//reading"), None);
+        assert_eq!(settings.token_from_topic("energy/abc/status"), None);
+        assert_eq!(settings.token_from_topic("energy/abc/reading/x"), None);
+
+        let figment = figment.merge(("mqtt_topic", "energy/#"));
+        assert!(MqttSettings::from_figment(&figment).is_err());
+    }
+
+    #[rocket::async_test]
+    async fn a_published_reading_is_logged_and_checks_the_car() {
+        let broker = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let figment = testing::admin_figment()
+            .merge(("mqtt_url", format!("mqtt://{}", broker.local_addr().unwrap())))
+            .merge(("ev_handler", "simulate"))
+            .merge(("charger_location", "43.363056,-8.838417"))
+            .merge(("simulation.amps", 6))
+            .merge(("max_amps", 20))
+            .merge(("max_amps_car", 16));
+        let app = testing::client_with(figment).await;
+
+        let topic = format!("energy/{}/reading", app.token);
+        let _stream = publish(
+            &broker,
+            &[(&topic, r#"{"amps": 10, "volts": 230, "watts": 2300}"#)],
+        )
+        .await;
+
+        // The reading is inserted, then the car is checked, in the background
+        let mut home = serde_json::Value::Null;
+        for _ in 0..50 {
+            rocket::tokio::time::sleep(Duration::from_millis(100)).await;
+            let response = app
+                .get("/car/state")
+                .header(testing::admin_authorization())
+                .dispatch()
+                .await;
+            home = response.into_json::<serde_json::Value>().await.unwrap()["home"].clone();
+            if !home.is_null() {
+                break;
+            }
+        }
+        assert_eq!(home["avg_amps"], 10.0);
+
+        let (amps, source): (f64, String) =
+            sqlx::query_as("SELECT amps, source FROM energy_log WHERE token = ?")
+                .bind(&app.token)
+                .fetch_one(app.db())
+                .await
+                .unwrap();
+        assert_eq!(amps, 10.0);
+        assert_eq!(source, "mqtt");
+    }
+    #[rocket::async_test]
+    async fn a_reading_for_a_short_unknown_token_does_not_stop_the_ingest() {
+        let broker = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let figment = testing::figment()
+            .merge(("mqtt_url", format!("mqtt://{}", broker.local_addr().unwrap())));
+        let app = testing::client_with(figment).await;
+
+        let topic = format!("energy/{}/reading", app.token);
+        let _stream = publish(
+            &broker,
+            &[
+                ("energy/abc/reading", r#"{"amps": 1}"#),
+                ("energy/ñ/reading", r#"{"amps": 2}"#),
+                (&topic, r#"{"amps": 10, "volts": 230, "watts": 2300}"#),
+            ],
+        )
+        .await;
+
+        let mut amps = Vec::new();
+        for _ in 0..50 {
+            rocket::tokio::time::sleep(Duration::from_millis(100)).await;
+            amps = sqlx::query_scalar::<_, f64>("SELECT amps FROM energy_log")
+                .fetch_all(app.db())
+                .await
+                .unwrap();
+            if !amps.is_empty() {
+                break;
+            }
+        }
+        assert_eq!(amps, vec![10.0]);
+    }
+}
@@ -6,16 +6,50 @@
 //!
 //! The rows are returned as a vector of [RowInfo] structs, and a boolean that
 //! indicates if there are more rows to be fetched.
+//!
+//! The queries select the tokens reachable from the view token in a subquery,
+//! instead of joining `view_tokens` directly, so that SQLite can use the
+//! `(token, created_at)` index on `energy_log` rather than scanning the whole
+//! time range for every token.
+
+use std::sync::OnceLock;
 
 use chrono::{DateTime, NaiveDateTime};
 use rocket_db_pools::Connection;
 use serde::Serialize;
 
 use crate::{
-    form::HtmlInputParseableDateTime,
+    form::{default_start, HtmlInputParseableDateTime, Range},
+    quality::ReadingFlags,
+    request_id::RequestId,
     token::{DbToken, Token, ValidViewToken},
 };
 
+/// The maximum number of rows per page, unless configured otherwise
+pub const DEFAULT_MAX_PAGE_COUNT: i32 = 10_000;
+
+/// Request guard with the maximum number of rows per page, read from the
+/// `max_page_count` key of the figment configuration (Rocket.toml).
+///
+/// This protects the public read routes from requests with a huge `count`.
+pub struct MaxPageCount(pub i32);
+
+#[rocket::async_trait]
+impl<'r> rocket::request::FromRequest<'r> for MaxPageCount {
+    type Error = ();
+
+    async fn from_request(
+        request: &'r rocket::Request<'_>,
+    ) -> rocket::request::Outcome<Self, Self::Error> {
+        let max_count: i32 = request
+            .rocket()
+            .figment()
+            .extract_inner("max_page_count")
+            .unwrap_or(DEFAULT_MAX_PAGE_COUNT);
+        rocket::request::Outcome::Success(MaxPageCount(max_count.max(1)))
+    }
+}
+
 pub struct Pagination {
     pub page: Option<i32>,
     pub count: Option<i32>,
@@ -23,6 +57,10 @@ pub struct Pagination {
     pub end: HtmlInputParseableDateTime,
     pub tz: chrono_tz::Tz,
     pub interval: Option<i32>,
+    /// If set, the default start is this long ago instead of one day ago
+    pub range: Option<Range>,
+    /// The requested count is clamped to this value
+    pub max_count: MaxPageCount,
 }
 
 pub struct PaginationResult {
@@ -35,28 +73,46 @@ pub struct PaginationResult {
 }
 
 impl Pagination {
+    /// Resolves the requested pagination into the values used to query.
+    ///
+    /// | start, end    | page      | count     | resulting page, count, offset      |
+    /// |---------------|-----------|-----------|------------------------------------|
+    /// | both given    | missing   | missing   | 1, `max_count`, 0                  |
+    /// | `range` given | missing   | missing   | 1, `max_count`, 0                  |
+    /// | otherwise     | missing   | missing   | 1, 10, 0                           |
+    /// | any           | `p`       | `c`       | `p`, `c`, `(p - 1) * c`            |
+    /// | any           | `p <= 0`  | any       | 1, as above, 0                     |
+    /// | any           | any       | `c <= 0`  | as above, 1, `page - 1`            |
+    ///
+    /// The count is always clamped to `[1, max_count]`, and the offset
+    /// saturates instead of overflowing for huge page numbers.
     pub fn result(&self) -> PaginationResult {
-        let page = self.page.unwrap_or(1);
+        let page = self.page.unwrap_or(1).max(1);
         let default_count = {
-            if self.start.is_some() && self.end.is_some() {
+            if (self.start.is_some() && self.end.is_some()) || self.range.is_some() {
                 10000000
             } else {
                 10
             }
         };
-        let count = self.count.unwrap_or(default_count);
+        let count = self
+            .count
+            .unwrap_or(default_count)
+            .clamp(1, self.max_count.0);
         let start = self
             .start
             .with_tz(self.tz, true)
-            .with_default(chrono::Utc::now() - chrono::Duration::days(1))
+            .with_default(default_start(self.range.as_ref()))
             .utc();
         let end = self
             .end
             .with_tz(self.tz, false)
             .with_default(chrono::Utc::now())
             .utc();
-        let interval = self.interval.unwrap_or(300);
-        let offset = (page - 1) * count;
+        let interval = self
+            .interval
+            .unwrap_or_else(|| auto_interval(&start, &end));
+        let offset = (page - 1).saturating_mul(count);
 
         PaginationResult {
             page,
@@ -69,6 +125,30 @@ impl Pagination {
     }
 }
 
+/// The number of decimal places of the amps, volts and watts shown in the
+/// HTML and JSON output, unless `display_precision` is configured
+pub const DEFAULT_DISPLAY_PRECISION: u32 = 3;
+
+/// The configured `display_precision`, loaded when the Rocket app is ignited
+pub static DISPLAY_PRECISION: OnceLock<u32> = OnceLock::new();
+
+/// Rounds a value for display to the configured number of decimal places, so
+/// averages such as `3.200000000000001` show as `3.2`. The stored values keep
+/// their full precision.
+pub(crate) fn round_for_display(value: f64) -> f64 {
+    let precision = DISPLAY_PRECISION
+        .get()
+        .copied()
+        .unwrap_or(DEFAULT_DISPLAY_PRECISION);
+    round_to(value, precision)
+}
+
+/// Rounds a value to the number of decimal places
+fn round_to(value: f64, precision: u32) -> f64 {
+    let factor = 10f64.powi(precision as i32);
+    (value * factor).round() / factor
+}
+
 pub struct RowInfo {
     location: String,
     token: DbToken,
@@ -77,18 +157,50 @@ pub struct RowInfo {
     amps: f64,
     volts: f64,
     watts: f64,
+    temperature_c: Option<f64>,
+    power_factor: Option<f64>,
+    source: Option<String>,
+    flags: ReadingFlags,
+    /// For the rows of a bucket, the average (or maximum) of amps × volts
+    /// over its readings, which is not the product of the averages
+    volt_amps: Option<f64>,
 }
 
+/// Serializes the row directly, with the fields in the same (alphabetical)
+/// order they had when it went through a [serde_json::Value], and the optional
+/// readings only if present.
 impl Serialize for RowInfo {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: serde::Serializer,
     {
-        self.to_json().serialize(serializer)
+        use serde::ser::SerializeMap;
+
+        let mut map = serializer.serialize_map(None)?;
+        map.serialize_entry("amps", &round_for_display(self.amps))?;
+        map.serialize_entry("datetime", &self.datetime)?;
+        if !self.flags.is_empty() {
+            map.serialize_entry("flags", &self.flags)?;
+        }
+        map.serialize_entry("location", &self.location)?;
+        if let Some(power_factor) = self.power_factor {
+            map.serialize_entry("power_factor", &round_for_display(power_factor))?;
+        }
+        if let Some(source) = &self.source {
+            map.serialize_entry("source", source)?;
+        }
+        if let Some(temperature_c) = self.temperature_c {
+            map.serialize_entry("temperature_c", &round_for_display(temperature_c))?;
+        }
+        map.serialize_entry("token", self.token.full_token())?;
+        map.serialize_entry("volts", &round_for_display(self.volts))?;
+        map.serialize_entry("watts", &round_for_display(self.watts))?;
+        map.end()
     }
 }
 
 impl RowInfo {
+    #[allow(clippy::too_many_arguments)]
     fn new(
         location: &str,
         token: DbToken,
@@ -107,46 +219,137 @@ impl RowInfo {
             amps,
             volts,
             watts,
+            temperature_c: None,
+            power_factor: None,
+            source: None,
+            flags: ReadingFlags::default(),
+            volt_amps: None,
         }
     }
 
+    /// Sets the optional readings that only some sensors report
+    fn with_extras(mut self, temperature_c: Option<f64>, power_factor: Option<f64>) -> Self {
+        self.temperature_c = temperature_c;
+        self.power_factor = power_factor;
+        self
+    }
+
+    /// Sets the route or tool that inserted the reading, unknown for the
+    /// readings logged before it was recorded
+    fn with_source(mut self, source: Option<String>) -> Self {
+        self.source = source;
+        self
+    }
+
+    /// Sets the quality flags observed when the reading was logged (see
+    /// [crate::quality])
+    fn with_flags(mut self, flags: ReadingFlags) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    /// Sets the aggregated amps × volts of a bucket
+    fn with_volt_amps(mut self, volt_amps: f64) -> Self {
+        self.volt_amps = Some(volt_amps);
+        self
+    }
+
+    /// Returns amps × volts, aggregated over the readings for a bucket
+    fn volt_amps(&self) -> f64 {
+        self.volt_amps.unwrap_or(self.amps * self.volts)
+    }
+
+    /// Replaces the watts with amps × volts, which may differ from the
+    /// reported ones, e.g., for a miscalibrated clamp
+    pub fn normalize_to_watts(&mut self) {
+        self.watts = self.volt_amps();
+    }
+
     /// Returns the row as an HTML table row
     pub fn to_html(&self) -> String {
         format!(
-            "<tr><td>{} ({}/{})</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+            "<tr><td>{} ({}/{})</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>\n",
             self.location,
             self.token.simplified(),
             self.ua,
             self.datetime,
-            self.amps,
-            self.volts,
-            self.watts
+            round_for_display(self.amps),
+            round_for_display(self.volts),
+            round_for_display(self.watts),
+            self.temperature_c
+                .map_or_else(String::new, |t| round_for_display(t).to_string()),
+            self.power_factor
+                .map_or_else(String::new, |pf| round_for_display(pf).to_string()),
         )
     }
 
+    /// Returns a bucket as a JSON object, with the averages from this row and
+    /// the maximums from `max`, as returned by [get_avg_max_rows_for_token].
+    pub fn to_bucket_json(&self, max: &RowInfo) -> serde_json::Value {
+        let mut json = self.to_json();
+        json["max_amps"] = round_for_display(max.amps).into();
+        json["max_watts"] = round_for_display(max.watts).into();
+        json
+    }
+
     /// Returns the row as a JSON object
     pub fn to_json(&self) -> serde_json::Value {
-        serde_json::json!({
-            "location": self.location,
-            "token": self.token.full_token(),
-            "datetime": self.datetime,
-            "amps": self.amps,
-            "volts": self.volts,
-            "watts": self.watts
-        })
+        serde_json::to_value(self).expect("a row always serializes")
     }
 }
 
+/// A reading as fetched for a page of [RowInfo]
+struct PageRow {
+    amps: f64,
+    volts: f64,
+    watts: f64,
+    temperature_c: Option<f64>,
+    power_factor: Option<f64>,
+    source: Option<String>,
+    flags: i64,
+    created_at: chrono::NaiveDateTime,
+    user_agent: Option<String>,
+    token: String,
+    location: String,
+}
+
+/// Converts the rows fetched for a page, one more than `count` if there is a
+/// next page, into the [RowInfo] of the page and whether there is a next one.
+fn into_page(db_rows: Vec<PageRow>, count: i32, tz: &chrono_tz::Tz) -> (Vec<RowInfo>, bool) {
+    let has_next = db_rows.len() > count as usize;
+    let rows = db_rows
+        .into_iter()
+        .take(count as usize)
+        .map(|row| {
+            let ua = row.user_agent.as_deref().unwrap_or("Unknown");
+            RowInfo::new(
+                &row.location,
+                DbToken(row.token),
+                &row.created_at,
+                tz,
+                ua,
+                row.amps,
+                row.volts,
+                row.watts,
+            )
+            .with_extras(row.temperature_c, row.power_factor)
+            .with_source(row.source)
+            .with_flags(ReadingFlags::from_bits(row.flags))
+        })
+        .collect();
+
+    (rows, has_next)
+}
+
 /// Returns the rows from the database for a given token and page as tuple with
 /// a vector of [RowInfo] structs and a boolean that indicates if there are more
 /// rows to be fetched.
 pub async fn get_paginated_rows_for_token(
-    db: &mut Connection<crate::Logs>,
+    db: &mut Connection<crate::LogsRead>,
     token: &ValidViewToken,
     pagination: &PaginationResult,
     tz: &chrono_tz::Tz,
 ) -> (Vec<RowInfo>, bool) {
-    let mut rows = Vec::new();
     let PaginationResult {
         page: _,
         interval: _,
@@ -161,16 +364,18 @@ pub async fn get_paginated_rows_for_token(
     let start = start.format("%Y-%m-%d %H:%M:%S").to_string();
     let end = end.format("%Y-%m-%d %H:%M:%S").to_string();
 
-    let db_rows = sqlx::query!(
-        "SELECT amps, volts, watts, energy_log.created_at as created_at, user_agent, client_ip, energy_log.token as token, u.location as location 
+    let db_rows = sqlx::query_as!(
+        PageRow,
+        "SELECT amps, volts, watts, temperature_c, power_factor, source, flags, energy_log.created_at as created_at, user_agent, energy_log.token as token, u.location as location 
         FROM energy_log
         INNER JOIN tokens t
         ON t.token = energy_log.token
         INNER JOIN users u
         ON u.id = t.user_id
-        INNER JOIN view_tokens vt
-        ON vt.user_id = u.id
-        WHERE vt.token = ?
+        WHERE energy_log.token IN (
+            SELECT token FROM view_token_sensors
+            WHERE view_token = ?
+        )
         AND energy_log.created_at BETWEEN ? AND ?
         ORDER BY created_at DESC
         LIMIT ?
@@ -185,33 +390,228 @@ pub async fn get_paginated_rows_for_token(
     .await
     .unwrap();
 
-    // Return only the rows that the user requested
-    let db_rows_split = if db_rows.len() > count as usize {
-        &db_rows[..count as usize]
-    } else {
-        &db_rows
-    };
+    into_page(db_rows, count, tz)
+}
 
-    for row in db_rows_split {
-        let ua = row
-            .user_agent
-            .as_ref()
-            .map(|s| s.as_str())
-            .unwrap_or("Unknown");
-        rows.push(RowInfo::new(
+/// Returns the sensor tokens of the users with the given location, as
+/// [get_paginated_rows_for_location] reads them, without their aliases.
+pub async fn get_tokens_for_location(db: &mut Connection<crate::LogsRead>, location: &str) -> Vec<String> {
+    sqlx::query_scalar!(
+        "SELECT t.token FROM tokens t
+        INNER JOIN users u
+        ON u.id = t.user_id
+        WHERE u.location = ?
+        ORDER BY t.token",
+        location
+    )
+    .fetch_all(&mut ***db)
+    .await
+    .unwrap()
+}
+
+/// Like [get_paginated_rows_for_token], but for the readings of every sensor
+/// token of the users with the given location, and of their aliases, instead
+/// of those a view token gives access to.
+///
+/// This bypasses the view token scoping, so it is only for the admin routes.
+pub async fn get_paginated_rows_for_location(
+    db: &mut Connection<crate::LogsRead>,
+    location: &str,
+    pagination: &PaginationResult,
+    tz: &chrono_tz::Tz,
+) -> (Vec<RowInfo>, bool) {
+    let count = pagination.count;
+    let db_count = count + 1;
+    let start = pagination.start.format("%Y-%m-%d %H:%M:%S").to_string();
+    let end = pagination.end.format("%Y-%m-%d %H:%M:%S").to_string();
+
+    let db_rows = sqlx::query_as!(
+        PageRow,
+        "SELECT amps, volts, watts, temperature_c, power_factor, source, flags, energy_log.created_at as created_at, user_agent, energy_log.token as token, u.location as location
+        FROM energy_log
+        INNER JOIN tokens t
+        ON t.token = energy_log.token
+        INNER JOIN users u
+        ON u.id = t.user_id
+        WHERE energy_log.token IN (
+            SELECT t.token FROM tokens t
+            INNER JOIN users u
+            ON u.id = t.user_id
+            WHERE u.location = ?1
+            UNION
+            SELECT ta.alias FROM token_aliases ta
+            INNER JOIN tokens t
+            ON t.token = ta.token
+            INNER JOIN users u
+            ON u.id = t.user_id
+            WHERE u.location = ?1
+        )
+        AND energy_log.created_at BETWEEN ?2 AND ?3
+        ORDER BY created_at DESC
+        LIMIT ?4
+        OFFSET ?5",
+        location,
+        start,
+        end,
+        db_count,
+        pagination.offset
+    )
+    .fetch_all(&mut ***db)
+    .await
+    .unwrap();
+
+    into_page(db_rows, count, tz)
+}
+
+/// Returns how many readings [get_paginated_rows_for_token] would page
+/// through for a view token between the given timestamps, without fetching
+/// them.
+pub async fn count_rows_for_token<Tz: chrono::TimeZone>(
+    db: &mut Connection<crate::LogsRead>,
+    token: &ValidViewToken,
+    start: &DateTime<Tz>,
+    end: &DateTime<Tz>,
+) -> i64 {
+    let start = start.naive_utc().format("%Y-%m-%d %H:%M:%S").to_string();
+    let end = end.naive_utc().format("%Y-%m-%d %H:%M:%S").to_string();
+    sqlx::query_scalar!(
+        r#"SELECT COUNT(*) as "count!: i64" FROM energy_log
+        INNER JOIN tokens t
+        ON t.token = energy_log.token
+        INNER JOIN users u
+        ON u.id = t.user_id
+        WHERE energy_log.token IN (
+            SELECT token FROM view_token_sensors
+            WHERE view_token = ?
+        )
+        AND energy_log.created_at BETWEEN ? AND ?"#,
+        token,
+        start,
+        end
+    )
+    .fetch_one(&mut ***db)
+    .await
+    .unwrap()
+}
+
+/// Returns the location (user) a view token gives access to
+pub async fn get_location_for_view_token(
+    db: &mut Connection<crate::LogsRead>,
+    token: &ValidViewToken,
+) -> String {
+    sqlx::query!(
+        "SELECT u.location FROM view_tokens vt
+        INNER JOIN users u
+        ON u.id = vt.user_id
+        WHERE vt.token = ?",
+        token
+    )
+    .fetch_one(&mut ***db)
+    .await
+    .unwrap()
+    .location
+}
+
+/// Returns the most recent row from the database for a given token, or `None`
+/// if the token has not logged any data yet.
+pub async fn get_latest_row_for_token(
+    db: &mut Connection<crate::LogsRead>,
+    token: &ValidViewToken,
+    tz: &chrono_tz::Tz,
+) -> Option<RowInfo> {
+    let row = sqlx::query!(
+        "SELECT amps, volts, watts, temperature_c, power_factor, source, flags, energy_log.created_at as created_at, user_agent, energy_log.token as token, u.location as location
+        FROM energy_log
+        INNER JOIN tokens t
+        ON t.token = energy_log.token
+        INNER JOIN users u
+        ON u.id = t.user_id
+        WHERE energy_log.token IN (
+            SELECT token FROM view_token_sensors
+            WHERE view_token = ?
+        )
+        ORDER BY created_at DESC, energy_log.id DESC
+        LIMIT 1",
+        token
+    )
+    .fetch_optional(&mut ***db)
+    .await
+    .unwrap()?;
+
+    Some(
+        RowInfo::new(
             &row.location,
-            DbToken(row.token.to_string()),
+            DbToken(row.token),
             &row.created_at,
             tz,
-            ua,
+            row.user_agent.as_deref().unwrap_or("Unknown"),
             row.amps,
             row.volts,
             row.watts,
-        ));
-    }
-    let has_next = db_rows.len() > count as usize;
+        )
+        .with_extras(row.temperature_c, row.power_factor)
+        .with_source(row.source)
+        .with_flags(ReadingFlags::from_bits(row.flags)),
+    )
+}
 
-    (rows, has_next)
+/// Returns the row nearest to the given instant for a given token, along with
+/// the signed offset in seconds from the instant to the reading (negative if
+/// the reading is earlier), or `None` if there is no reading within
+/// `tolerance_secs` of it.
+///
+/// On a tie, the earlier reading is returned.
+pub async fn get_nearest_row_for_token(
+    db: &mut Connection<crate::LogsRead>,
+    token: &ValidViewToken,
+    at: &DateTime<chrono::Utc>,
+    tolerance_secs: i64,
+    tz: &chrono_tz::Tz,
+) -> Option<(RowInfo, i64)> {
+    let tolerance = chrono::Duration::seconds(tolerance_secs);
+    let at = at.naive_utc();
+    let start = at - tolerance;
+    let end = at + tolerance;
+
+    let row = sqlx::query!(
+        "SELECT amps, volts, watts, temperature_c, power_factor, source, flags, energy_log.created_at as created_at, user_agent, energy_log.token as token, u.location as location
+        FROM energy_log
+        INNER JOIN tokens t
+        ON t.token = energy_log.token
+        INNER JOIN users u
+        ON u.id = t.user_id
+        WHERE energy_log.token IN (
+            SELECT token FROM view_token_sensors
+            WHERE view_token = ?
+        ) AND energy_log.created_at BETWEEN ? AND ?
+        ORDER BY ABS(strftime('%s', energy_log.created_at) - strftime('%s', ?)) ASC, created_at ASC
+        LIMIT 1",
+        token,
+        start,
+        end,
+        at
+    )
+    .fetch_optional(&mut ***db)
+    .await
+    .unwrap()?;
+
+    let offset = (row.created_at - at).num_seconds();
+    Some((
+        RowInfo::new(
+            &row.location,
+            DbToken(row.token),
+            &row.created_at,
+            tz,
+            row.user_agent.as_deref().unwrap_or("Unknown"),
+            row.amps,
+            row.volts,
+            row.watts,
+        )
+        .with_extras(row.temperature_c, row.power_factor)
+        .with_source(row.source)
+        .with_flags(ReadingFlags::from_bits(row.flags)),
+        offset,
+    ))
 }
 
 /// Returns the rows from the database for a given token and page as tuple with
@@ -219,7 +619,7 @@ pub async fn get_paginated_rows_for_token(
 /// vectors: one with the averages and one with the maximums given the window
 /// interval passed as a parameter.
 pub async fn get_avg_max_rows_for_token<Tz: chrono::TimeZone>(
-    db: &mut Connection<crate::Logs>,
+    db: &mut Connection<crate::LogsRead>,
     token: &ValidViewToken,
     start: &DateTime<Tz>,
     end: &DateTime<Tz>,
@@ -231,15 +631,16 @@ pub async fn get_avg_max_rows_for_token<Tz: chrono::TimeZone>(
     let end = end.naive_utc();
 
     let db_rows = sqlx::query!(
-        "SELECT AVG(amps) as amps, MAX(amps) as max_amps, AVG(volts) as volts, AVG(watts) as watts, MAX(watts) as max_watts, energy_log.created_at as created_at, user_agent, client_ip, energy_log.token as token, u.location as location 
+        "SELECT AVG(amps) as \"amps!: f64\", MAX(amps) as \"max_amps!: f64\", AVG(volts) as \"volts!: f64\", AVG(watts) as \"watts!: f64\", MAX(watts) as \"max_watts!: f64\", AVG(amps * volts) as \"volt_amps!: f64\", MAX(amps * volts) as \"max_volt_amps!: f64\", energy_log.created_at as \"created_at?\", user_agent, client_ip, energy_log.token as \"token?\", u.location as \"location?\" 
         FROM energy_log
         INNER JOIN tokens t
         ON t.token = energy_log.token
         INNER JOIN users u
         ON u.id = t.user_id
-        INNER JOIN view_tokens vt
-        ON vt.user_id = u.id
-        WHERE vt.token = ? AND energy_log.created_at BETWEEN ? AND ?
+        WHERE energy_log.token IN (
+            SELECT token FROM view_token_sensors
+            WHERE view_token = ?
+        ) AND energy_log.created_at BETWEEN ? AND ?
         GROUP BY strftime('%s', energy_log.created_at) / ?
         ORDER BY created_at DESC",
         token,
@@ -254,8 +655,7 @@ pub async fn get_avg_max_rows_for_token<Tz: chrono::TimeZone>(
     for row in db_rows {
         let ua = row
             .user_agent
-            .as_ref()
-            .map(|s| s.as_str())
+            .as_deref()
             .unwrap_or("Unknown");
         match (row.location.clone(), row.token.clone(), row.created_at) {
             (Some(location), Some(token), Some(created_at)) => {
@@ -268,7 +668,7 @@ pub async fn get_avg_max_rows_for_token<Tz: chrono::TimeZone>(
                     row.amps,
                     row.volts,
                     row.watts,
-                ));
+                ).with_volt_amps(row.volt_amps));
                 max_rows.push(RowInfo::new(
                     &location,
                     DbToken(token.to_string()),
@@ -278,10 +678,10 @@ pub async fn get_avg_max_rows_for_token<Tz: chrono::TimeZone>(
                     row.max_amps,
                     row.volts,
                     row.max_watts,
-                ));
+                ).with_volt_amps(row.max_volt_amps));
             }
             (_, _, _) => {
-                log::warn!("Location is None for row {:?}", row);
+                log::warn!("Location is None for row {:?}{}", row, RequestId::in_logs());
             }
         }
     }
@@ -289,11 +689,381 @@ pub async fn get_avg_max_rows_for_token<Tz: chrono::TimeZone>(
     (rows, max_rows)
 }
 
-fn datetime_to_timestamp(datetime: &str) -> f64 {
-    NaiveDateTime::parse_from_str(datetime, "%Y-%m-%d %H:%M:%S %Z")
-        .expect("DateTime format failed")
-        .and_utc()
-        .timestamp() as f64
+/// A bucket aligned to the calendar in the requested timezone, for reports
+/// that must match billing periods, unlike the fixed-width intervals.
+#[derive(Debug, Clone, Copy, PartialEq, rocket::FromFormField)]
+pub enum CalendarBucket {
+    Hour,
+    Day,
+    Month,
+}
+
+impl std::fmt::Display for CalendarBucket {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CalendarBucket::Hour => write!(f, "hour"),
+            CalendarBucket::Day => write!(f, "day"),
+            CalendarBucket::Month => write!(f, "month"),
+        }
+    }
+}
+
+impl CalendarBucket {
+    /// Returns the start of the bucket containing `datetime`, in the local
+    /// time of `tz`.
+    ///
+    /// If the local start does not exist because of a DST change (e.g., a
+    /// midnight skipped to 01:00), the bucket starts an hour later.
+    pub(crate) fn start_of(self, datetime: &DateTime<chrono::Utc>, tz: &chrono_tz::Tz) -> DateTime<chrono::Utc> {
+        use chrono::{Datelike, TimeZone, Timelike};
+
+        let local = datetime.with_timezone(tz).naive_local();
+        let start = match self {
+            CalendarBucket::Hour => local.date().and_hms_opt(local.hour(), 0, 0),
+            CalendarBucket::Day => local.date().and_hms_opt(0, 0, 0),
+            CalendarBucket::Month => local.date().with_day(1).and_then(|d| d.and_hms_opt(0, 0, 0)),
+        }
+        .expect("valid start of bucket");
+        tz.from_local_datetime(&start)
+            .earliest()
+            .or_else(|| tz.from_local_datetime(&(start + chrono::Duration::hours(1))).earliest())
+            .map_or(*datetime, |start| start.with_timezone(&chrono::Utc))
+    }
+}
+
+/// The width in seconds of the slots pre-aggregated by SQLite for
+/// [get_calendar_rows_for_token]. Every UTC offset is a multiple of it, so no
+/// slot straddles two calendar buckets.
+const CALENDAR_SLOT_SECS: i32 = 900;
+
+/// The running totals of a calendar bucket
+struct CalendarTotals {
+    location: String,
+    token: String,
+    ua: String,
+    amps: f64,
+    volts: f64,
+    watts: f64,
+    volt_amps: f64,
+    count: i64,
+    max_amps: f64,
+    max_watts: f64,
+    max_volt_amps: f64,
+}
+
+/// Like [get_avg_max_rows_for_token], but with the buckets aligned to the
+/// local hours, days or months of the timezone instead of fixed intervals.
+///
+/// SQLite does not know about timezones, so it only aggregates the readings
+/// in 15-minute slots, and the slots are merged into their calendar bucket
+/// here. The datetimes of the buckets are their start, in UTC.
+pub async fn get_calendar_rows_for_token<Tz: chrono::TimeZone>(
+    db: &mut Connection<crate::LogsRead>,
+    token: &ValidViewToken,
+    start: &DateTime<Tz>,
+    end: &DateTime<Tz>,
+    bucket: CalendarBucket,
+    tz: &chrono_tz::Tz,
+) -> (Vec<RowInfo>, Vec<RowInfo>) {
+    let start = start.naive_utc();
+    let end = end.naive_utc();
+
+    let db_rows = sqlx::query!(
+        "SELECT SUM(amps) as \"amps!: f64\", MAX(amps) as \"max_amps!: f64\", SUM(volts) as \"volts!: f64\", SUM(watts) as \"watts!: f64\", MAX(watts) as \"max_watts!: f64\", SUM(amps * volts) as \"volt_amps!: f64\", MAX(amps * volts) as \"max_volt_amps!: f64\", COUNT(*) as \"count!: i64\", energy_log.created_at as \"created_at?\", user_agent, energy_log.token as \"token?\", u.location as \"location?\"
+        FROM energy_log
+        INNER JOIN tokens t
+        ON t.token = energy_log.token
+        INNER JOIN users u
+        ON u.id = t.user_id
+        WHERE energy_log.token IN (
+            SELECT token FROM view_token_sensors
+            WHERE view_token = ?
+        ) AND energy_log.created_at BETWEEN ? AND ?
+        GROUP BY strftime('%s', energy_log.created_at) / ?",
+        token,
+        start,
+        end,
+        CALENDAR_SLOT_SECS
+    )
+    .fetch_all(&mut ***db)
+    .await
+    .unwrap();
+
+    let mut buckets: std::collections::BTreeMap<DateTime<chrono::Utc>, CalendarTotals> =
+        std::collections::BTreeMap::new();
+    for row in db_rows {
+        let (Some(location), Some(token), Some(created_at)) = (row.location, row.token, row.created_at)
+        else {
+            log::warn!(
+                "Location is None for a slot of {} readings{}",
+                row.count,
+                RequestId::in_logs()
+            );
+            continue;
+        };
+        let bucket_start = bucket.start_of(&created_at.and_utc(), tz);
+        let totals = buckets.entry(bucket_start).or_insert_with(|| CalendarTotals {
+            location,
+            token,
+            ua: row.user_agent.unwrap_or_else(|| "Unknown".to_string()),
+            amps: 0.0,
+            volts: 0.0,
+            watts: 0.0,
+            volt_amps: 0.0,
+            count: 0,
+            max_amps: f64::MIN,
+            max_watts: f64::MIN,
+            max_volt_amps: f64::MIN,
+        });
+        totals.amps += row.amps;
+        totals.volts += row.volts;
+        totals.watts += row.watts;
+        totals.volt_amps += row.volt_amps;
+        totals.count += row.count;
+        totals.max_amps = totals.max_amps.max(row.max_amps);
+        totals.max_watts = totals.max_watts.max(row.max_watts);
+        totals.max_volt_amps = totals.max_volt_amps.max(row.max_volt_amps);
+    }
+
+    let mut rows = Vec::new();
+    let mut max_rows = Vec::new();
+    for (bucket_start, totals) in buckets.into_iter().rev() {
+        let count = totals.count as f64;
+        let volts = totals.volts / count;
+        let row = |amps, watts, volt_amps| {
+            RowInfo::new(
+                &totals.location,
+                DbToken(totals.token.clone()),
+                &bucket_start.naive_utc(),
+                &chrono_tz::UTC,
+                &totals.ua,
+                amps,
+                volts,
+                watts,
+            )
+            .with_volt_amps(volt_amps)
+        };
+        rows.push(row(totals.amps / count, totals.watts / count, totals.volt_amps / count));
+        max_rows.push(row(totals.max_amps, totals.max_watts, totals.max_volt_amps));
+    }
+
+    (rows, max_rows)
+}
+
+/// Parses the datetime of a row back into a UNIX timestamp. The fractional
+/// seconds are accepted, as the readings logged before they were truncated
+/// may still have them.
+fn datetime_to_timestamp(datetime: &str) -> Result<f64, chrono::ParseError> {
+    let datetime = NaiveDateTime::parse_from_str(datetime, "%Y-%m-%d %H:%M:%S%.f %Z")?;
+    Ok(datetime.and_utc().timestamp() as f64)
+}
+
+/// The highest rolling average found by [peak_window]
+pub struct Peak {
+    /// The timestamp of the first bucket in the window
+    pub start: DateTime<chrono::Utc>,
+    /// The timestamp of the last bucket in the window
+    pub end: DateTime<chrono::Utc>,
+    pub amps: f64,
+    pub watts: f64,
+}
+
+/// Finds the window of `window_secs` with the highest average watts, given the
+/// rows averaged per bucket as returned by [get_avg_max_rows_for_token].
+///
+/// The window slides over the buckets with data, so gaps in the data do not
+/// count as zero consumption. Returns None if there are no rows, or an error
+/// if the datetime of a row cannot be parsed.
+pub fn peak_window(avg_rows: &[RowInfo], window_secs: i64) -> Result<Option<Peak>, chrono::ParseError> {
+    let mut buckets: Vec<(i64, f64, f64)> = avg_rows
+        .iter()
+        .map(|r| Ok((datetime_to_timestamp(&r.datetime)? as i64, r.amps, r.watts)))
+        .collect::<Result<_, chrono::ParseError>>()?;
+    buckets.sort_by_key(|&(timestamp, _, _)| timestamp);
+
+    let mut peak: Option<Peak> = None;
+    let (mut first, mut sum_amps, mut sum_watts) = (0, 0.0, 0.0);
+    for (last, &(timestamp, amps, watts)) in buckets.iter().enumerate() {
+        sum_amps += amps;
+        sum_watts += watts;
+        while timestamp - buckets[first].0 >= window_secs {
+            sum_amps -= buckets[first].1;
+            sum_watts -= buckets[first].2;
+            first += 1;
+        }
+
+        let len = (last - first + 1) as f64;
+        if peak.as_ref().is_none_or(|peak| sum_watts / len > peak.watts) {
+            let (Some(start), Some(end)) = (
+                DateTime::from_timestamp(buckets[first].0, 0),
+                DateTime::from_timestamp(timestamp, 0),
+            ) else {
+                return Ok(None);
+            };
+            peak = Some(Peak {
+                start,
+                end,
+                amps: sum_amps / len,
+                watts: sum_watts / len,
+            });
+        }
+    }
+    Ok(peak)
+}
+
+/// Returns the raw amps readings of the sensors of a view token between the
+/// given timestamps, for [histogram].
+pub async fn get_amps_for_token<Tz: chrono::TimeZone>(
+    db: &mut Connection<crate::LogsRead>,
+    token: &ValidViewToken,
+    start: &DateTime<Tz>,
+    end: &DateTime<Tz>,
+) -> Vec<f64> {
+    let start = start.naive_utc();
+    let end = end.naive_utc();
+    sqlx::query_scalar!(
+        "SELECT amps FROM energy_log
+        WHERE token IN (
+            SELECT token FROM view_token_sensors
+            WHERE view_token = ?
+        ) AND created_at BETWEEN ? AND ?",
+        token,
+        start,
+        end
+    )
+    .fetch_all(&mut ***db)
+    .await
+    .unwrap()
+}
+
+/// The maximum number of bins of a [histogram], to bound the response size
+pub const MAX_HISTOGRAM_BINS: usize = 1000;
+
+/// The distribution of readings over contiguous bins of `bin_amps` each
+#[derive(Debug, Serialize)]
+pub struct Histogram {
+    pub bin_amps: f64,
+    /// The `counts.len() + 1` edges of the bins, in amps
+    pub edges: Vec<f64>,
+    /// The number of readings in each bin, including its lower edge
+    pub counts: Vec<u64>,
+}
+
+/// Counts the readings in bins of `bin_amps`, aligned to multiples of it, from
+/// the lowest to the highest reading.
+///
+/// Returns None if there are no readings, or if they would need more than
+/// [MAX_HISTOGRAM_BINS] bins.
+pub fn histogram(amps: &[f64], bin_amps: f64) -> Option<Histogram> {
+    let bin_of = |amps: f64| (amps / bin_amps).floor() as i64;
+    let first = amps.iter().copied().map(bin_of).min()?;
+    let last = amps.iter().copied().map(bin_of).max()?;
+    let len = usize::try_from(last - first + 1).ok()?;
+    if len > MAX_HISTOGRAM_BINS {
+        return None;
+    }
+
+    let mut counts = vec![0; len];
+    for &value in amps {
+        counts[(bin_of(value) - first) as usize] += 1;
+    }
+    let edges = (first..=last + 1)
+        .map(|bin| round_for_display(bin as f64 * bin_amps))
+        .collect();
+    Some(Histogram {
+        bin_amps,
+        edges,
+        counts,
+    })
+}
+
+/// A reading as needed by [summarize]
+pub struct SummaryRow {
+    pub token: String,
+    pub created_at: NaiveDateTime,
+    pub amps: f64,
+    pub watts: f64,
+    /// The watt-hours of the minute, for the rows written by
+    /// `consolidate_logs`
+    pub wh: Option<f64>,
+}
+
+/// Returns the readings of the sensors of a view token between the given
+/// timestamps, sorted by sensor and time, for [summarize].
+pub async fn get_summary_rows_for_token<Tz: chrono::TimeZone>(
+    db: &mut Connection<crate::LogsRead>,
+    token: &ValidViewToken,
+    start: &DateTime<Tz>,
+    end: &DateTime<Tz>,
+) -> Vec<SummaryRow> {
+    let start = start.naive_utc();
+    let end = end.naive_utc();
+    sqlx::query_as!(
+        SummaryRow,
+        "SELECT token, created_at, amps, watts, wh FROM energy_log
+        WHERE token IN (
+            SELECT token FROM view_token_sensors
+            WHERE view_token = ?
+        ) AND created_at BETWEEN ? AND ?
+        ORDER BY token, created_at",
+        token,
+        start,
+        end
+    )
+    .fetch_all(&mut ***db)
+    .await
+    .unwrap()
+}
+
+/// The longest a reading is assumed to hold until the next one of the same
+/// sensor when integrating the energy, so that an offline sensor does not
+/// count its last reading for the whole outage
+pub const MAX_READING_HOLD_SECS: i64 = 300;
+
+/// The rollup of the readings of a range, as returned by [summarize]
+pub struct Summary {
+    pub readings: usize,
+    pub energy_kwh: f64,
+    pub avg_amps: f64,
+    pub avg_watts: f64,
+    pub max_amps: f64,
+    pub max_amps_at: DateTime<chrono::Utc>,
+}
+
+/// Summarizes the readings, sorted by sensor and time as returned by
+/// [get_summary_rows_for_token].
+///
+/// The energy is integrated per sensor: each reading holds its watts until
+/// the next reading of the sensor, for at most [MAX_READING_HOLD_SECS], and
+/// the last one holds for no time. The consolidated rows bring their own
+/// watt-hours instead. Returns None if there are no readings.
+pub fn summarize(rows: &[SummaryRow]) -> Option<Summary> {
+    let peak = rows.iter().max_by(|a, b| a.amps.total_cmp(&b.amps))?;
+    let count = rows.len() as f64;
+
+    let mut wh = 0.0;
+    for (i, row) in rows.iter().enumerate() {
+        if let Some(row_wh) = row.wh {
+            wh += row_wh;
+            continue;
+        }
+        let Some(next) = rows.get(i + 1).filter(|next| next.token == row.token) else {
+            continue;
+        };
+        let held_secs = (next.created_at - row.created_at)
+            .num_seconds()
+            .min(MAX_READING_HOLD_SECS);
+        wh += row.watts * held_secs as f64 / 3600.0;
+    }
+
+    Some(Summary {
+        readings: rows.len(),
+        energy_kwh: wh / 1000.0,
+        avg_amps: rows.iter().map(|row| row.amps).sum::<f64>() / count,
+        avg_watts: rows.iter().map(|row| row.watts).sum::<f64>() / count,
+        max_amps: peak.amps,
+        max_amps_at: peak.created_at.and_utc(),
+    })
 }
 
 /// Create an error type for to_svg_plot when there are no rows to plot
@@ -308,70 +1078,851 @@ impl std::fmt::Display for NoRowsError {
 
 impl std::error::Error for NoRowsError {}
 
+/// The bucket sizes (in seconds) that [auto_interval] chooses from
+const AUTO_INTERVALS: &[i64] = &[
+    10, 30, 60, 120, 300, 600, 900, 1800, 3600, 7200, 10800, 21600, 43200, 86400,
+];
+
+/// The maximum number of points [auto_interval] aims to plot
+const AUTO_INTERVAL_MAX_POINTS: i64 = 500;
+
+/// Picks a bucket size for the plot between `start` and `end`, so that it has
+/// at most 500 points (and usually no less than 200).
+///
+/// The bucket size is rounded up to a human-friendly value, e.g., a day is
+/// plotted in 5-minute buckets and a month in 2-hour buckets.
+pub fn auto_interval<Tz: chrono::TimeZone>(start: &DateTime<Tz>, end: &DateTime<Tz>) -> i32 {
+    let range = (end.clone() - start.clone()).num_seconds().abs();
+    let min_interval = (range + AUTO_INTERVAL_MAX_POINTS - 1) / AUTO_INTERVAL_MAX_POINTS;
+    let interval = AUTO_INTERVALS
+        .iter()
+        .copied()
+        .find(|&interval| interval >= min_interval)
+        .unwrap_or(min_interval);
+    interval.clamp(1, i32::MAX as i64) as i32
+}
+
+/// Returns the default interval of the sensor tokens of the view token, as set
+/// with the admin routes, or `None` if none of them has one.
+///
+/// If the view token reads several sensors, the largest is used, so that every
+/// bucket can have readings of each of them.
+pub async fn get_default_interval_for_token(
+    db: &mut Connection<crate::LogsRead>,
+    token: &ValidViewToken,
+) -> Option<i32> {
+    sqlx::query_scalar!(
+        r#"SELECT MAX(default_interval_secs) as "interval: i32" FROM tokens
+        WHERE token IN (
+            SELECT token FROM view_token_sensors
+            WHERE view_token = ?
+        )"#,
+        token
+    )
+    .fetch_one(&mut ***db)
+    .await
+    .unwrap()
+}
+
+/// Resolves the interval of the plots and aggregations: the requested one, or
+/// else the default interval of the token (see
+/// [get_default_interval_for_token]), unless the range needs larger buckets to
+/// stay readable (see [auto_interval]).
+pub async fn resolve_interval(
+    db: &mut Connection<crate::LogsRead>,
+    token: &ValidViewToken,
+    interval: Option<i32>,
+    start: &DateTime<chrono::Utc>,
+    end: &DateTime<chrono::Utc>,
+) -> i32 {
+    if let Some(interval) = interval {
+        return interval;
+    }
+    let auto = auto_interval(start, end);
+    match get_default_interval_for_token(db, token).await {
+        Some(default) => default.max(auto),
+        None => auto,
+    }
+}
+
+/// How the read routes normalize the consumption, with `normalize=watts`
+#[derive(Debug, Clone, Copy, PartialEq, rocket::FromFormField)]
+pub enum Normalize {
+    /// As amps × volts (the stored volts, or the assumed 220 V), so that
+    /// circuits on different voltages compare on one scale
+    Watts,
+}
+
+/// Normalizes the rows as requested, see [RowInfo::normalize_to_watts]
+pub fn normalize_rows(rows: &mut [RowInfo], normalize: Option<Normalize>) {
+    if normalize == Some(Normalize::Watts) {
+        rows.iter_mut().for_each(RowInfo::normalize_to_watts);
+    }
+}
+
+/// The color theme of the SVG plot
+#[derive(Debug, Clone, Copy, Default, PartialEq, rocket::FromFormField)]
+pub enum Theme {
+    #[default]
+    Light,
+    Dark,
+}
+
+impl std::fmt::Display for Theme {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Theme::Light => write!(f, "light"),
+            Theme::Dark => write!(f, "dark"),
+        }
+    }
+}
+
+/// The default dimensions (width, height) of the SVG plot, in pixels
+const DEFAULT_PLOT_SIZE: (f64, f64) = (1400.0, 500.0);
+
+/// The smallest and largest dimensions (width, height) allowed for the plot
+const MIN_PLOT_SIZE: (f64, f64) = (300.0, 200.0);
+const MAX_PLOT_SIZE: (f64, f64) = (4000.0, 2000.0);
+
+/// The most labels the time axis may be asked for with `ticks`
+const MAX_TICKS: usize = 50;
+
+/// The span of the time axis around a single point, in seconds
+const SINGLE_POINT_SPAN_SECS: f64 = 3600.0;
+
+/// The steps the time axis ticks can be spaced by, in seconds, from the finest
+const TICK_STEPS_SECS: &[i64] = &[
+    1, 2, 5, 10, 15, 30,
+    60, 2 * 60, 5 * 60, 10 * 60, 15 * 60, 30 * 60,
+    3600, 2 * 3600, 3 * 3600, 6 * 3600, 12 * 3600,
+    86400, 2 * 86400, 3 * 86400, 7 * 86400, 14 * 86400, 28 * 86400,
+];
+
+/// The spacing and the label format of the ticks of the time axis
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimeTicks {
+    /// The seconds between two ticks
+    pub step_secs: i64,
+
+    /// The strftime format of the labels
+    pub format: String,
+}
+
+impl TimeTicks {
+    /// Chooses the finest step that keeps the labels over `span` seconds to at
+    /// most `max_ticks`, and a label format precise enough for that step: the
+    /// seconds for sub-minute steps, the time of day within a day, and only
+    /// the date for steps of a day or more.
+    pub fn for_span(span: f64, max_ticks: usize) -> Self {
+        const FOUR_WEEKS: i64 = 28 * 86400;
+        let min_step = span.abs() / max_ticks.max(1) as f64;
+        let step_secs = TICK_STEPS_SECS
+            .iter()
+            .copied()
+            .find(|&step| step as f64 >= min_step)
+            .unwrap_or_else(|| (min_step / FOUR_WEEKS as f64).ceil() as i64 * FOUR_WEEKS);
+
+        let format = if step_secs < 60 {
+            "%H:%M:%S"
+        } else if step_secs < 86400 && span < 86400.0 {
+            "%H:%M"
+        } else if step_secs < 86400 {
+            "D%d %H:%M"
+        } else if span < 365.0 * 86400.0 {
+            "%b %d"
+        } else {
+            "%Y-%m-%d"
+        };
+        Self {
+            step_secs,
+            format: format.to_string(),
+        }
+    }
+}
+
+/// Returns whether `format` is a valid strftime format for the tick labels,
+/// which would otherwise fail when rendering
+fn is_valid_tick_format(format: &str) -> bool {
+    use chrono::format::{Item, StrftimeItems};
+
+    !format.is_empty() && !StrftimeItems::new(format).any(|item| matches!(item, Item::Error))
+}
+
+/// Options to customize the SVG plot
+#[derive(Debug)]
+pub struct PlotOptions {
+    /// The color theme, light by default
+    pub theme: Theme,
+
+    /// The width of the plot, 1400px by default
+    pub width: f64,
+
+    /// The height of the plot, 500px by default
+    pub height: f64,
+
+    /// If set, plot the N-point moving average of the avg amps line
+    pub smooth: Option<usize>,
+
+    /// Whether the moving average is applied to the max amps line too
+    pub smooth_max: bool,
+
+    /// If set, the data is stale and the title notes how old it is
+    pub stale_age_secs: Option<i64>,
+
+    /// If set, a horizontal line is drawn at these amps, e.g., the budget
+    pub budget_amps: Option<f64>,
+
+    /// The most labels on the time axis, about one every 140px (3 to 10) by
+    /// default
+    pub max_ticks: Option<usize>,
+
+    /// The strftime format of the time axis labels, chosen from the span of
+    /// the plot by default (see [TimeTicks::for_span])
+    pub tick_format: Option<String>,
+
+    /// If set, plot amps × volts in watts instead of the amps
+    pub normalize: Option<Normalize>,
+}
+
+impl Default for PlotOptions {
+    fn default() -> Self {
+        Self {
+            theme: Theme::default(),
+            width: DEFAULT_PLOT_SIZE.0,
+            height: DEFAULT_PLOT_SIZE.1,
+            smooth: None,
+            smooth_max: false,
+            stale_age_secs: None,
+            budget_amps: None,
+            max_ticks: None,
+            tick_format: None,
+            normalize: None,
+        }
+    }
+}
+
+impl PlotOptions {
+    /// Sets the dimensions of the plot, if given, clamped to sane values
+    pub fn with_size(mut self, width: Option<f64>, height: Option<f64>) -> Self {
+        if let Some(width) = width {
+            self.width = width.clamp(MIN_PLOT_SIZE.0, MAX_PLOT_SIZE.0);
+        }
+        if let Some(height) = height {
+            self.height = height.clamp(MIN_PLOT_SIZE.1, MAX_PLOT_SIZE.1);
+        }
+        self
+    }
+
+    /// Sets the number of labels and their format on the time axis, if given.
+    ///
+    /// The number is clamped to a sane range, and an invalid format is
+    /// ignored in favor of the one chosen from the span.
+    pub fn with_ticks(mut self, ticks: Option<usize>, format: Option<String>) -> Self {
+        self.max_ticks = ticks.map(|ticks| ticks.clamp(2, MAX_TICKS));
+        self.tick_format = format.filter(|format| is_valid_tick_format(format));
+        self
+    }
+
+    /// Returns the ticks of the time axis over `span` seconds
+    fn time_ticks(&self, span: f64) -> TimeTicks {
+        let max_ticks = self
+            .max_ticks
+            .unwrap_or_else(|| (self.width / 140.0).floor().clamp(3.0, 10.0) as usize);
+        let mut ticks = TimeTicks::for_span(span, max_ticks);
+        if let Some(format) = &self.tick_format {
+            ticks.format = format.clone();
+        }
+        ticks
+    }
+
+    /// The unit of the plotted values, as in the title and the axis label
+    fn unit(&self) -> &'static str {
+        match self.normalize {
+            Some(Normalize::Watts) => "Watts",
+            None => "Amps",
+        }
+    }
+}
+
+/// Returns the points as (timestamp, amps) sorted by timestamp, or as
+/// (timestamp, amps × volts) if normalized to watts
+fn to_points(
+    rows: &[RowInfo],
+    normalize: Option<Normalize>,
+) -> Result<Vec<(f64, f64)>, chrono::ParseError> {
+    let mut points: Vec<(f64, f64)> = rows
+        .iter()
+        .map(|r| {
+            let value = match normalize {
+                Some(Normalize::Watts) => r.volt_amps(),
+                None => r.amps,
+            };
+            Ok((datetime_to_timestamp(&r.datetime)?, value))
+        })
+        .collect::<Result<_, chrono::ParseError>>()?;
+    points.sort_by(|a, b| a.0.total_cmp(&b.0));
+    Ok(points)
+}
+
+/// Applies an N-point trailing simple moving average to the points, which
+/// must be sorted by timestamp.
+///
+/// The first points average over as many previous points as are available.
+fn moving_average(points: &[(f64, f64)], n: usize) -> Vec<(f64, f64)> {
+    if n <= 1 {
+        return points.to_vec();
+    }
+    points
+        .iter()
+        .enumerate()
+        .map(|(i, &(x, _))| {
+            let window = &points[i.saturating_sub(n - 1)..=i];
+            let sum: f64 = window.iter().map(|&(_, y)| y).sum();
+            (x, sum / window.len() as f64)
+        })
+        .collect()
+}
+
 pub fn to_svg_plot<TZ: chrono::TimeZone>(
     avg_rows: Vec<RowInfo>,
     max_rows: Vec<RowInfo>,
     tz: &TZ,
+    options: &PlotOptions,
+) -> anyhow::Result<String>
+where
+    <TZ as chrono::TimeZone>::Offset: std::fmt::Display,
+{
+    use poloto::build;
+
+    if avg_rows.is_empty() {
+        return Err(NoRowsError.into());
+    }
+
+    let mut amps = to_points(&avg_rows, options.normalize)?;
+    let mut max_amps = to_points(&max_rows, options.normalize)?;
+    if let Some(n) = options.smooth {
+        amps = moving_average(&amps, n);
+        if options.smooth_max {
+            max_amps = moving_average(&max_amps, n);
+        }
+    }
+    let (first, last) = (amps.first().unwrap().0, amps.last().unwrap().0);
+
+    // The budget is a constant line across the whole plot, in amps, so it
+    // is left out of the plots normalized to watts
+    let budget = options
+        .budget_amps
+        .filter(|_| options.normalize.is_none())
+        .map(|budget| (format!("budget ({} A)", budget), vec![(first, budget), (last, budget)]));
+
+    let unit = options.unit().to_lowercase();
+    let (max_label, avg_label) = (format!("max {}", unit), format!("avg {}", unit));
+    let mut p = vec![
+        build::plot(max_label.as_str()).line(build::cloned(max_amps.iter())),
+        build::plot(avg_label.as_str()).line(build::cloned(amps.iter())),
+    ];
+    if let Some((label, points)) = &budget {
+        p.push(build::plot(label.as_str()).line(build::cloned(points.iter())));
+    }
+
+    render_plot(p, (first, last), tz, options)
+}
+
+/// Plots the avg amps of several tokens as one line each, labeled with the
+/// location and the simplified token.
+///
+/// Tokens without rows in the range are left out of the plot, and if none of
+/// them has rows, a [NoRowsError] is returned.
+pub fn to_compare_svg_plot<TZ: chrono::TimeZone>(
+    series: Vec<(ValidViewToken, Vec<RowInfo>)>,
+    tz: &TZ,
+    options: &PlotOptions,
 ) -> anyhow::Result<String>
 where
     <TZ as chrono::TimeZone>::Offset: std::fmt::Display,
 {
     use poloto::build;
 
-    if avg_rows.len() < 1 {
+    let series: Vec<(String, Vec<(f64, f64)>)> = series
+        .into_iter()
+        .filter(|(_, rows)| !rows.is_empty())
+        .map(|(token, rows)| {
+            let label = format!("{} ({})", rows[0].location, token.simplified());
+            Ok((label, to_points(&rows, options.normalize)?))
+        })
+        .collect::<Result<_, chrono::ParseError>>()?;
+    if series.is_empty() {
         return Err(NoRowsError.into());
     }
 
-    let first_timestamp = datetime_to_timestamp(&avg_rows.first().unwrap().datetime);
+    let first = series
+        .iter()
+        .map(|(_, points)| points.first().unwrap().0)
+        .fold(f64::INFINITY, f64::min);
+    let last = series
+        .iter()
+        .map(|(_, points)| points.last().unwrap().0)
+        .fold(f64::NEG_INFINITY, f64::max);
 
-    let amps: Vec<(f64, f64)> = avg_rows
+    let plots: Vec<_> = series
         .iter()
-        .map(|r| (datetime_to_timestamp(&r.datetime), r.amps))
-        .collect::<Vec<_>>();
-    let iter = amps.iter();
-
-    let p = poloto::plots!(
-        poloto::build::plot("max amps").line(build::cloned(
-            max_rows
-                .iter()
-                .map(|r| (datetime_to_timestamp(&r.datetime), r.amps))
-        )),
-        poloto::build::plot("avg amps").line(build::cloned(iter))
-    );
-
-    // Configure ticks so that we don't overflow the labels (i.e., at most 10 labels in total)
-    // Calculate last - first and divide by 10 to get the tick interval
-    let tick_interval = (amps.last().unwrap().0 - first_timestamp) / 10.0;
-    let tick = tick_interval.abs().ceil();
-
-    // Round to the nearest 30 minutes
-    let tick = f64::max(3.0, (tick / 1800.0).ceil() * 1800.0);
+        .map(|(label, points)| build::plot(label.as_str()).line(build::cloned(points.iter())))
+        .collect();
+
+    render_plot(plots, (first, last), tz, options)
+}
+
+/// Returns a valid SVG of the plot size with a "No data" label, for the plot
+/// routes to answer with when there are no rows in the range, so that an
+/// embedding `<img>` shows the message instead of a broken image.
+pub fn no_data_svg(options: &PlotOptions) -> String {
+    let (background, foreground) = match options.theme {
+        Theme::Light => ("#ffffff", "#000000"),
+        Theme::Dark => ("#262626", "#ffffff"),
+    };
+    format!(
+        concat!(
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="{w}" height="{h}" viewBox="0 0 {w} {h}">"#,
+            r#"<rect width="100%" height="100%" fill="{bg}"/>"#,
+            r#"<text x="50%" y="50%" text-anchor="middle" dominant-baseline="middle" font-family="sans-serif" font-size="24" fill="{fg}">No data</text>"#,
+            "</svg>"
+        ),
+        w = options.width,
+        h = options.height,
+        bg = background,
+        fg = foreground,
+    )
+}
+
+/// Formats an age in seconds as a short human-readable duration, e.g., `2h 5m`
+fn format_age(secs: i64) -> String {
+    let (days, hours, minutes) = (secs / 86400, secs % 86400 / 3600, secs % 3600 / 60);
+    match (days, hours) {
+        (0, 0) => format!("{}m", minutes),
+        (0, _) => format!("{}h {}m", hours, minutes),
+        _ => format!("{}d {}h", days, hours),
+    }
+}
+
+/// Renders the plots as an SVG with the time on the X axis, spanning the
+/// `(first, last)` timestamps, and the amps on the Y axis.
+fn render_plot<P, TZ>(
+    plots: P,
+    (first, last): (f64, f64),
+    tz: &TZ,
+    options: &PlotOptions,
+) -> anyhow::Result<String>
+where
+    P: poloto::build::PlotIterator<L = (f64, f64)>,
+    TZ: chrono::TimeZone,
+    <TZ as chrono::TimeZone>::Offset: std::fmt::Display,
+{
+    use chrono::Offset;
+
+    // A single point has no span, which poloto cannot scale the axis to, so
+    // center it in an axis of a nominal span instead
+    let (first, last) = if last > first {
+        (first, last)
+    } else {
+        (first - SINGLE_POINT_SPAN_SECS / 2.0, first + SINGLE_POINT_SPAN_SECS / 2.0)
+    };
+
+    let ticks = options.time_ticks(last - first);
+    let step = ticks.step_secs as f64;
+
+    // Align the ticks to the local time, so that, e.g., daily ticks fall on
+    // the local midnight, and start them right before the first point, as
+    // poloto walks the ticks from the beginning of the iterator
+    let first_datetime = chrono::DateTime::<chrono::Utc>::from_timestamp(first as i64, 0)
+        .unwrap_or_default()
+        .with_timezone(tz);
+    let offset = f64::from(first_datetime.offset().fix().local_minus_utc());
+    let start = ((first + offset) / step).floor() * step - offset;
 
     let xticks =
-        poloto::ticks::TickDistribution::new(std::iter::successors(Some(0.0), |w| Some(w + tick)))
-            .with_tick_fmt(|&v| {
+        poloto::ticks::TickDistribution::new(std::iter::successors(Some(start), move |w| Some(w + step)))
+            .with_tick_fmt(move |&v| {
                 format!(
                     "{}",
                     chrono::DateTime::<chrono::Utc>::from_timestamp(v as i64, 0)
                         .unwrap()
                         .with_timezone(tz)
-                        .format("D%d %H:%M")
+                        .format(&ticks.format)
                 )
             });
 
+    let dim = [options.width, options.height];
     let data = poloto::frame()
-        .with_viewbox([1400.0, 500.0])
+        .with_viewbox(dim)
         .build()
-        .data(p)
+        .data(poloto::plots!(plots, poloto::build::markers([first, last], [])))
         .map_xticks(|_| xticks);
 
-    data.build_and_label(("Amps over time", "Time", "Amps"))
-        .append_to(
-            poloto::header()
-                .with_dim([1400.0, 500.0])
-                .with_viewbox([1400.0, 500.0])
-                .light_theme(),
-        )
+    let header = poloto::header().with_dim(dim).with_viewbox(dim);
+    let header = match options.theme {
+        Theme::Light => header.light_theme(),
+        Theme::Dark => header.dark_theme(),
+    };
+
+    let unit = options.unit();
+    let title = match options.stale_age_secs {
+        Some(age) => format!("{} over time (last reading {} ago)", unit, format_age(age)),
+        None => format!("{} over time", unit),
+    };
+
+    data.build_and_label((title, "Time", unit))
+        .append_to(header)
         .render_string()
         .map_err(anyhow::Error::new)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_row_datetimes_with_or_without_subsecs() {
+        assert_eq!(datetime_to_timestamp("2026-10-16 18:06:55 UTC"), Ok(1792174015.0));
+        assert_eq!(
+            datetime_to_timestamp("2026-10-16 18:06:55.953095915 UTC"),
+            Ok(1792174015.0)
+        );
+        assert!(datetime_to_timestamp("yesterday").is_err());
+    }
+
+    #[rocket::async_test]
+    async fn paginated_query_uses_the_token_and_time_index() {
+        let app = crate::testing::client().await;
+
+        // The query of get_paginated_rows_for_token
+        let plan: Vec<String> = sqlx::query_as(
+            "EXPLAIN QUERY PLAN
+            SELECT amps, volts, watts, temperature_c, power_factor, source, flags, energy_log.created_at as created_at, user_agent, energy_log.token as token, u.location as location
+            FROM energy_log
+            INNER JOIN tokens t
+            ON t.token = energy_log.token
+            INNER JOIN users u
+            ON u.id = t.user_id
+            WHERE energy_log.token IN (
+                SELECT token FROM view_token_sensors
+                WHERE view_token = ?
+            )
+            AND energy_log.created_at BETWEEN ? AND ?
+            ORDER BY created_at DESC
+            LIMIT ?
+            OFFSET ?",
+        )
+        .bind(&app.token)
+        .bind("2026-10-01 00:00:00")
+        .bind("2026-10-16 00:00:00")
+        .bind(101)
+        .bind(0)
+        .fetch_all(app.db())
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|row: (i64, i64, i64, String)| row.3)
+        .collect();
+
+        assert!(
+            plan.iter().any(|step| step.contains(
+                "SEARCH energy_log USING INDEX idx_energy_log_token_created (token=? AND created_at>? AND created_at<?)"
+            )),
+            "{:#?}",
+            plan
+        );
+    }
+
+    #[test]
+    fn the_source_is_listed_when_it_is_known() {
+        let row = |source: Option<&str>| {
+            RowInfo::new(
+                "home",
+                DbToken("token".to_string()),
+                &chrono::NaiveDateTime::default(),
+                &chrono_tz::UTC,
+                "sensor",
+                1.0,
+                230.0,
+                230.0,
+            )
+            .with_source(source.map(str::to_string))
+            .to_json()
+        };
+
+        assert_eq!(row(Some("post_import"))["source"], "post_import");
+        assert!(row(None).get("source").is_none());
+    }
+
+    #[test]
+    fn renders_a_row_as_an_html_table_row() {
+        let row = RowInfo::new(
+            "home",
+            DbToken("abcdefghijkl".to_string()),
+            &chrono::NaiveDateTime::default(),
+            &chrono_tz::UTC,
+            "sensor",
+            1.23456,
+            230.0,
+            284.0,
+        )
+        .with_source(Some("post_token".to_string()));
+
+        assert_eq!(
+            row.to_html(),
+            "<tr><td>home (abcd...ijkl/sensor)</td><td>1970-01-01 00:00:00 UTC</td>\
+             <td>1.235</td><td>230</td><td>284</td><td></td><td></td></tr>\n"
+        );
+        let row = row.with_extras(Some(21.5), Some(0.95));
+        assert!(row.to_html().ends_with("<td>21.5</td><td>0.95</td></tr>\n"));
+    }
+
+    #[test]
+    fn smooths_with_a_trailing_moving_average() {
+        let points = [(0.0, 1.0), (60.0, 2.0), (120.0, 3.0), (180.0, 4.0), (240.0, 11.0)];
+
+        assert_eq!(
+            moving_average(&points, 3),
+            vec![(0.0, 1.0), (60.0, 1.5), (120.0, 2.0), (180.0, 3.0), (240.0, 6.0)]
+        );
+        assert_eq!(moving_average(&points, 1), points.to_vec());
+    }
+
+    #[test]
+    fn auto_interval_plots_a_sensible_number_of_buckets() {
+        let end = chrono::Utc::now();
+        for days in [1, 7, 30, 365] {
+            let start = end - chrono::Duration::days(days);
+            let interval = auto_interval(&start, &end);
+            let buckets = (end - start).num_seconds() / i64::from(interval);
+            assert!((200..=500).contains(&buckets), "{} days: {} buckets of {}s", days, buckets, interval);
+        }
+
+        let start = end - chrono::Duration::days(30);
+        assert_eq!(auto_interval(&start, &end), 7200);
+    }
+
+    /// A pagination in UTC with the given query parameters, limited to the
+    /// default maximum count
+    fn pagination(
+        page: Option<i32>,
+        count: Option<i32>,
+        start: Option<&str>,
+        end: Option<&str>,
+    ) -> Pagination {
+        let datetime = |value: Option<&str>| {
+            HtmlInputParseableDateTime::Naive(value.map(|value| {
+                chrono::NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M").unwrap()
+            }))
+        };
+        Pagination {
+            page,
+            count,
+            start: datetime(start),
+            end: datetime(end),
+            tz: chrono_tz::UTC,
+            interval: None,
+            range: None,
+            max_count: MaxPageCount(DEFAULT_MAX_PAGE_COUNT),
+        }
+    }
+
+    #[test]
+    fn oversized_counts_are_clamped_to_the_maximum() {
+        let (start, end) = (Some("2024-01-01T00:00"), Some("2024-02-01T00:00"));
+
+        let result = pagination(Some(2), Some(10_000_000), start, end).result();
+        assert_eq!(result.count, DEFAULT_MAX_PAGE_COUNT);
+        assert_eq!(result.offset, DEFAULT_MAX_PAGE_COUNT);
+
+        // Also the default count of a full range
+        assert_eq!(pagination(None, None, start, end).result().count, DEFAULT_MAX_PAGE_COUNT);
+
+        let mut configured = pagination(None, Some(500), start, end);
+        configured.max_count = MaxPageCount(100);
+        assert_eq!(configured.result().count, 100);
+    }
+
+    #[test]
+    fn pagination_resolves_the_documented_matrix() {
+        let (start, end) = (Some("2024-01-01T00:00"), Some("2024-02-01T00:00"));
+        let max = DEFAULT_MAX_PAGE_COUNT;
+
+        // (page, count, start, end) -> (page, count, offset)
+        let cases = [
+            ((None, None, start, end), (1, max, 0)),
+            ((None, None, start, None), (1, 10, 0)),
+            ((None, None, None, end), (1, 10, 0)),
+            ((None, None, None, None), (1, 10, 0)),
+            ((Some(3), Some(20), None, None), (3, 20, 40)),
+            ((Some(3), Some(20), start, end), (3, 20, 40)),
+            ((Some(0), Some(20), None, None), (1, 20, 0)),
+            ((Some(-5), None, None, None), (1, 10, 0)),
+            ((Some(-5), Some(20), start, end), (1, 20, 0)),
+            ((Some(4), Some(0), None, None), (4, 1, 3)),
+            ((Some(4), Some(-3), start, end), (4, 1, 3)),
+            ((Some(i32::MAX), None, start, end), (i32::MAX, max, i32::MAX)),
+        ];
+        for ((page, count, start, end), expected) in cases {
+            let result = pagination(page, count, start, end).result();
+            assert_eq!(
+                (result.page, result.count, result.offset),
+                expected,
+                "page {:?}, count {:?}, start {:?}, end {:?}",
+                page,
+                count,
+                start,
+                end
+            );
+        }
+
+        let mut range = pagination(None, None, None, None);
+        range.range = Some("7d".parse().unwrap());
+        let result = range.result();
+        assert_eq!((result.page, result.count, result.offset), (1, max, 0));
+    }
+
+    #[test]
+    fn calendar_buckets_start_at_local_boundaries() {
+        let madrid = chrono_tz::Europe::Madrid;
+        let at = |s: &str| {
+            chrono::NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S")
+                .unwrap()
+                .and_utc()
+        };
+
+        // 00:30 on January 2nd in Madrid (UTC+1)
+        let datetime = at("2024-01-01 23:30:00");
+        assert_eq!(CalendarBucket::Hour.start_of(&datetime, &madrid), at("2024-01-01 23:00:00"));
+        assert_eq!(CalendarBucket::Day.start_of(&datetime, &madrid), at("2024-01-01 23:00:00"));
+        assert_eq!(CalendarBucket::Month.start_of(&datetime, &madrid), at("2023-12-31 23:00:00"));
+        // Still the first day in UTC
+        assert_eq!(
+            CalendarBucket::Day.start_of(&datetime, &chrono_tz::UTC),
+            at("2024-01-01 00:00:00")
+        );
+        // In summer time (UTC+2)
+        assert_eq!(
+            CalendarBucket::Day.start_of(&at("2024-07-01 12:00:00"), &madrid),
+            at("2024-06-30 22:00:00")
+        );
+    }
+
+    #[test]
+    fn a_range_of_24h_ends_now() {
+        let before = chrono::Utc::now();
+        let result = Pagination {
+            range: Some("24h".parse().unwrap()),
+            ..pagination(None, None, None, None)
+        }
+        .result();
+        let after = chrono::Utc::now();
+
+        let day = chrono::Duration::hours(24);
+        assert!(before - day <= result.start && result.start <= after - day);
+        assert!(before <= result.end && result.end <= after);
+        // Not the 10 latest rows as without a range
+        assert_eq!(result.count, DEFAULT_MAX_PAGE_COUNT);
+
+        // An explicit start still wins
+        let result = Pagination {
+            range: Some("24h".parse().unwrap()),
+            ..pagination(None, None, Some("2024-01-01T00:00"), None)
+        }
+        .result();
+        assert_eq!(result.start.to_rfc3339(), "2024-01-01T00:00:00+00:00");
+    }
+
+    #[test]
+    fn rounds_to_the_precision() {
+        assert_eq!(round_to(3.200000000000001, 3), 3.2);
+        assert_eq!(round_to(1.23456, 2), 1.23);
+        assert_eq!(round_to(1.5, 0), 2.0);
+        assert_eq!(round_to(-0.0004, 3), -0.0);
+    }
+
+    #[test]
+    fn histogram_counts_the_readings_per_bin() {
+        let amps = [0.2, 0.9, 1.0, 1.5, 1.99, 3.0, -0.5];
+        let histogram = histogram(&amps, 1.0).unwrap();
+        assert_eq!(histogram.edges, vec![-1.0, 0.0, 1.0, 2.0, 3.0, 4.0]);
+        assert_eq!(histogram.counts, vec![1, 2, 3, 0, 1]);
+
+        let histogram = super::histogram(&[0.1, 0.3, 0.6], 0.25).unwrap();
+        assert_eq!(histogram.edges, vec![0.0, 0.25, 0.5, 0.75]);
+        assert_eq!(histogram.counts, vec![1, 1, 1]);
+
+        assert!(super::histogram(&[], 1.0).is_none());
+        assert!(super::histogram(&[0.0, 2000.0], 1.0).is_none());
+    }
+
+    #[test]
+    fn ticks_are_chosen_from_the_span() {
+        let ticks = |span: f64, format: &str, step_secs: i64| {
+            assert_eq!(
+                TimeTicks::for_span(span, 10),
+                TimeTicks {
+                    step_secs,
+                    format: format.to_string()
+                }
+            );
+        };
+
+        ticks(120.0, "%H:%M:%S", 15);
+        ticks(40.0 * 60.0, "%H:%M", 5 * 60);
+        ticks(3.0 * 86400.0, "D%d %H:%M", 12 * 3600);
+        ticks(21.0 * 86400.0, "%b %d", 3 * 86400);
+        // 73 days, rounded up to 4-week multiples
+        ticks(2.0 * 365.0 * 86400.0, "%Y-%m-%d", 3 * 28 * 86400);
+    }
+
+    #[test]
+    fn the_tick_options_are_sanitized() {
+        let options = PlotOptions::default().with_ticks(Some(500), Some("%Q".to_string()));
+        assert_eq!(options.max_ticks, Some(MAX_TICKS));
+        assert_eq!(options.tick_format, None);
+
+        let options = PlotOptions::default().with_ticks(Some(4), Some("%d/%m".to_string()));
+        let ticks = options.time_ticks(21.0 * 86400.0);
+        assert_eq!(ticks.format, "%d/%m");
+        // 21 days over 4 labels
+        assert_eq!(ticks.step_secs, 7 * 86400);
+    }
+
+    #[rocket::async_test]
+    async fn normalized_watts_are_amps_times_volts() {
+        let app = crate::testing::client().await;
+        // The stored watts differ from amps × volts
+        app.insert_reading("2024-01-01 10:00:00", 2.0, 230.0, 470.0).await;
+        app.insert_reading("2024-01-01 10:01:00", 3.5, 120.0, 400.0).await;
+        let json = |query: &str| {
+            let uri = format!(
+                "/log/{}/json?start=2024-01-01T00:00&end=2024-01-02T00:00&tz=UTC{}",
+                app.token, query
+            );
+            let app = &app;
+            async move {
+                let response = app.get(uri).dispatch().await;
+                response.into_json::<serde_json::Value>().await.unwrap()["rows"].clone()
+            }
+        };
+        let watts = |rows: &serde_json::Value, key: &str| -> Vec<f64> {
+            let rows = rows.as_array().unwrap();
+            rows.iter().map(|row| row[key].as_f64().unwrap()).collect()
+        };
+
+        assert_eq!(watts(&json("").await, "watts"), [400.0, 470.0]);
+        assert_eq!(watts(&json("&normalize=watts").await, "watts"), [420.0, 460.0]);
+
+        let buckets = json("&interval=3600&normalize=watts").await;
+        assert_eq!(watts(&buckets, "watts"), [440.0]);
+        assert_eq!(watts(&buckets, "max_watts"), [460.0]);
+        let days = json("&bucket=day&normalize=watts").await;
+        assert_eq!(watts(&days, "watts"), [440.0]);
+        assert_eq!(watts(&days, "max_watts"), [460.0]);
+
+        let uri = format!("/log/{}/svg?start=2024-01-01T00:00&tz=UTC&normalize=watts", app.token);
+        let svg = app.get(uri).dispatch().await.into_string().await.unwrap();
+        assert!(svg.contains("Watts over time"), "{}", svg);
+    }
+}
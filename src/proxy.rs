@@ -0,0 +1,164 @@
+//! Resolution of the client IP address behind trusted reverse proxies.
+//!
+//! By default we rely on Rocket's [client_ip](rocket::Request::client_ip),
+//! which trusts the configured `ip_header` from any peer. When deploying
+//! behind a reverse proxy, you can instead list the proxies in the figment
+//! configuration (Rocket.toml), as IP addresses or CIDR ranges:
+//!
+//! ```toml
+//! trusted_proxies = ["127.0.0.1", "10.0.0.0/8"]
+//! ```
+//!
+//! Then the `X-Forwarded-For` and `X-Real-IP` headers are only honored when
+//! the request comes from one of those proxies, and ignored otherwise.
+
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+
+/// The list of trusted proxies, managed as Rocket state.
+///
+/// If it is `None`, no proxies were configured and we keep Rocket's default
+/// behavior.
+pub struct TrustedProxies(Option<Vec<IpNet>>);
+
+impl From<&rocket::figment::Figment> for TrustedProxies {
+    fn from(figment: &rocket::figment::Figment) -> Self {
+        let proxies: Vec<String> = match figment.extract_inner("trusted_proxies") {
+            Ok(proxies) => proxies,
+            Err(_) => return Self(None),
+        };
+
+        let proxies = proxies
+            .iter()
+            .filter_map(|proxy| {
+                let net = parse_proxy(proxy);
+                if net.is_none() {
+                    log::error!("Ignoring invalid trusted proxy: {}", proxy);
+                }
+                net
+            })
+            .collect();
+
+        Self(Some(proxies))
+    }
+}
+
+/// Parses a trusted proxy, given either as a CIDR or as a single IP address
+pub(crate) fn parse_proxy(proxy: &str) -> Option<IpNet> {
+    proxy
+        .parse::<IpNet>()
+        .or_else(|_| proxy.parse::<IpAddr>().map(IpNet::from))
+        .ok()
+}
+
+impl TrustedProxies {
+    fn is_trusted(proxies: &[IpNet], ip: &IpAddr) -> bool {
+        proxies.iter().any(|net| net.contains(ip))
+    }
+
+    /// Resolve the address of the client that originated the request.
+    ///
+    /// If the peer is a trusted proxy, we walk `X-Forwarded-For` from the
+    /// right, skipping any other trusted proxies, and return the first
+    /// untrusted address. If there is no such header, we fall back to
+    /// `X-Real-IP`, and finally to the peer address itself.
+    pub fn client_ip(&self, request: &rocket::Request<'_>) -> Option<IpAddr> {
+        let proxies = match &self.0 {
+            Some(proxies) => proxies,
+            None => return request.client_ip(),
+        };
+
+        let remote = request.remote()?.ip();
+        if !Self::is_trusted(proxies, &remote) {
+            return Some(remote);
+        }
+
+        let forwarded: Vec<IpAddr> = request
+            .headers()
+            .get("X-Forwarded-For")
+            .flat_map(|value| value.split(','))
+            .filter_map(|ip| ip.trim().parse().ok())
+            .collect();
+        if let Some(first) = forwarded.first() {
+            let client = forwarded
+                .iter()
+                .rev()
+                .find(|ip| !Self::is_trusted(proxies, ip))
+                .unwrap_or(first);
+            return Some(*client);
+        }
+
+        request
+            .headers()
+            .get_one("X-Real-IP")
+            .and_then(|ip| ip.trim().parse().ok())
+            .or(Some(remote))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::http::Header;
+    use rocket::local::blocking::Client;
+
+    fn proxies(proxies: &[&str]) -> TrustedProxies {
+        let figment = rocket::figment::Figment::new().merge(("trusted_proxies", proxies));
+        TrustedProxies::from(&figment)
+    }
+
+    fn client() -> Client {
+        Client::untracked(rocket::build()).unwrap()
+    }
+
+    fn ip(ip: &str) -> Option<IpAddr> {
+        Some(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn parses_addresses_and_ranges() {
+        assert_eq!(parse_proxy("10.0.0.0/8"), "10.0.0.0/8".parse().ok());
+        assert_eq!(parse_proxy("127.0.0.1"), "127.0.0.1/32".parse().ok());
+        assert_eq!(parse_proxy("::1"), "::1/128".parse().ok());
+        assert_eq!(parse_proxy("localhost"), None);
+    }
+
+    #[test]
+    fn trusted_proxy_forwards_the_client_ip() {
+        let proxies = proxies(&["10.0.0.0/8"]);
+        let client = client();
+        let request = client
+            .get("/")
+            .remote("10.0.0.2:1234".parse().unwrap())
+            .header(Header::new("X-Forwarded-For", "203.0.113.7, 10.0.0.3"));
+
+        // The other trusted proxy in the chain is skipped
+        assert_eq!(proxies.client_ip(request.inner()), ip("203.0.113.7"));
+    }
+
+    #[test]
+    fn trusted_proxy_falls_back_to_the_real_ip_header() {
+        let proxies = proxies(&["127.0.0.1"]);
+        let client = client();
+        let request = client
+            .get("/")
+            .remote("127.0.0.1:1234".parse().unwrap())
+            .header(Header::new("X-Real-IP", "203.0.113.7"));
+
+        assert_eq!(proxies.client_ip(request.inner()), ip("203.0.113.7"));
+    }
+
+    #[test]
+    fn untrusted_peer_headers_are_ignored() {
+        let proxies = proxies(&["10.0.0.0/8"]);
+        let client = client();
+        let request = client
+            .get("/")
+            .remote("198.51.100.4:1234".parse().unwrap())
+            .header(Header::new("X-Forwarded-For", "203.0.113.7"))
+            .header(Header::new("X-Real-IP", "203.0.113.8"));
+
+        assert_eq!(proxies.client_ip(request.inner()), ip("198.51.100.4"));
+    }
+}
@@ -0,0 +1,159 @@
+//! Data quality flags of the readings.
+//!
+//! Rather than silently coercing the readings that are incomplete or look
+//! wrong, the ingest routes record the conditions they observed in the
+//! `flags` column of `energy_log`, as a bitset. The read routes list them by
+//! name, e.g., `"flags":["defaulted_volts"]`, so that downstream tooling can
+//! filter the low-quality rows:
+//!
+//! - `defaulted_volts`: the reading had no volts, so the assumed 220 V were
+//!   stored
+//! - `suspect_watts`: the watts do not match amps * volts (see [consistency])
+//! - `backdated`: the reading carried its own timestamp, more than
+//!   [BACKDATED_TOLERANCE_SECS] before it was received (except for the
+//!   historical readings of POST /log/:token/import)
+//!
+//! [consistency]: crate::consistency
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use serde::ser::SerializeSeq;
+use serde::Serialize;
+
+/// How far before its arrival a reading may be timestamped without being
+/// flagged as back-dated, to allow for clock skew and buffering sensors
+pub const BACKDATED_TOLERANCE_SECS: i64 = 300;
+
+/// The set of quality flags of a reading, stored as an integer
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ReadingFlags(i64);
+
+impl ReadingFlags {
+    pub const SUSPECT_WATTS: ReadingFlags = ReadingFlags(1);
+    pub const DEFAULTED_VOLTS: ReadingFlags = ReadingFlags(1 << 1);
+    pub const BACKDATED: ReadingFlags = ReadingFlags(1 << 2);
+
+    /// The name of each flag, as shown on the read routes
+    const NAMES: [(ReadingFlags, &'static str); 3] = [
+        (Self::DEFAULTED_VOLTS, "defaulted_volts"),
+        (Self::SUSPECT_WATTS, "suspect_watts"),
+        (Self::BACKDATED, "backdated"),
+    ];
+
+    /// Computes the flags of a reading received at `received`.
+    ///
+    /// `volts` and `timestamp` are as reported by the sensor, before the
+    /// defaults are applied, and `suspect` is the result of the watts check.
+    pub fn observe(
+        volts: Option<f64>,
+        suspect: bool,
+        timestamp: Option<NaiveDateTime>,
+        received: DateTime<Utc>,
+    ) -> Self {
+        let mut flags = ReadingFlags::default();
+        if volts.is_none() {
+            flags.insert(Self::DEFAULTED_VOLTS);
+        }
+        if suspect {
+            flags.insert(Self::SUSPECT_WATTS);
+        }
+        if timestamp.is_some_and(|timestamp| {
+            (received.naive_utc() - timestamp).num_seconds() > BACKDATED_TOLERANCE_SECS
+        }) {
+            flags.insert(Self::BACKDATED);
+        }
+        flags
+    }
+
+    /// The flags as stored in the database
+    pub fn from_bits(bits: i64) -> Self {
+        ReadingFlags(bits)
+    }
+
+    /// The value to store in the database
+    pub fn bits(&self) -> i64 {
+        self.0
+    }
+
+    pub fn insert(&mut self, flags: ReadingFlags) {
+        self.0 |= flags.0;
+    }
+
+    pub fn contains(&self, flags: ReadingFlags) -> bool {
+        self.0 & flags.0 == flags.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// The names of the flags that are set, ignoring unknown bits
+    pub fn names(&self) -> impl Iterator<Item = &'static str> + '_ {
+        Self::NAMES
+            .iter()
+            .filter(|(flag, _)| self.contains(*flag))
+            .map(|(_, name)| *name)
+    }
+}
+
+/// Serializes the flags as the list of their names
+impl Serialize for ReadingFlags {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let mut seq = serializer.serialize_seq(None)?;
+        for name in self.names() {
+            seq.serialize_element(name)?;
+        }
+        seq.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use rocket::http::{ContentType, Status};
+
+    async fn post_and_flags(app: &testing::TestApp, body: &str) -> (i64, serde_json::Value) {
+        let response = app
+            .post(format!("/log/{}", app.token))
+            .header(ContentType::JSON)
+            .body(body)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let stored = sqlx::query_scalar("SELECT flags FROM energy_log ORDER BY id DESC LIMIT 1")
+            .fetch_one(app.db())
+            .await
+            .unwrap();
+        let latest = app.get(format!("/log/{}/latest", app.token)).dispatch().await;
+        let latest: serde_json::Value = latest.into_json().await.unwrap();
+        (stored, latest["flags"].clone())
+    }
+
+    #[rocket::async_test]
+    async fn a_reading_without_volts_is_flagged_as_defaulted() {
+        let app = testing::client().await;
+
+        let (stored, shown) = post_and_flags(&app, r#"{"amps": 10, "watts": 2200}"#).await;
+        assert_eq!(stored, ReadingFlags::DEFAULTED_VOLTS.bits());
+        assert_eq!(shown, serde_json::json!(["defaulted_volts"]));
+
+        let body = r#"{"amps": 10, "volts": 230, "watts": 2300}"#;
+        let (stored, shown) = post_and_flags(&app, body).await;
+        assert_eq!(stored, 0);
+        assert_eq!(shown, serde_json::Value::Null);
+    }
+
+    #[test]
+    fn old_timestamps_are_flagged_as_backdated() {
+        let received = Utc::now();
+        let observe = |secs| {
+            let timestamp = received.naive_utc() - chrono::Duration::seconds(secs);
+            ReadingFlags::observe(Some(230.0), false, Some(timestamp), received)
+        };
+        assert!(observe(BACKDATED_TOLERANCE_SECS).is_empty());
+        assert_eq!(observe(BACKDATED_TOLERANCE_SECS + 1), ReadingFlags::BACKDATED);
+    }
+}
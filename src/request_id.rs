@@ -0,0 +1,210 @@
+//! Correlation IDs, to follow a single request through the logs.
+//!
+//! Every request gets a [RequestId]: the one in its `X-Request-Id` header, if
+//! it is sensible (up to 64 letters, digits, `-`, `_`, `.` or `:`), or a new
+//! random one otherwise. It is echoed back in the `X-Request-Id` response
+//! header, and included in the logs written on behalf of the request, and in
+//! the JSON access log.
+//!
+//! The routes mounted with [scoped] run in the [scope](RequestId::scope) of
+//! their request, guards included, and so does the EV charge check a reading
+//! triggers, so that their logs, down to the calls to the car API, include the
+//! ID with [RequestId::in_logs].
+
+use rocket::http::Header;
+use rocket::route::{Handler, Outcome, Route};
+
+/// The name of the request and response header
+const HEADER: &str = "X-Request-Id";
+
+/// The longest incoming ID that is kept
+const MAX_LEN: usize = 64;
+
+/// The length of the generated IDs
+const GENERATED_LEN: usize = 16;
+
+/// The correlation ID of a request, cached in the request
+#[derive(Debug, Clone)]
+pub struct RequestId(String);
+
+impl std::fmt::Display for RequestId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl RequestId {
+    /// Returns the ID of the request, taking it from the header or generating
+    /// it the first time.
+    pub fn of<'r>(request: &'r rocket::Request<'_>) -> &'r RequestId {
+        request.local_cache(|| {
+            let incoming = request.headers().get_one(HEADER).filter(|id| {
+                !id.is_empty()
+                    && id.len() <= MAX_LEN
+                    && id
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || "-_.:".contains(c))
+            });
+            match incoming {
+                Some(id) => RequestId(id.to_string()),
+                None => RequestId(generate()),
+            }
+        })
+    }
+}
+
+rocket::tokio::task_local! {
+    /// The ID of the request that the current task is working on behalf of
+    static CURRENT: RequestId;
+}
+
+impl RequestId {
+    /// Runs the future on behalf of this request, see [RequestId::in_logs]
+    pub async fn scope<F: std::future::Future>(self, future: F) -> F::Output {
+        CURRENT.scope(self, future).await
+    }
+
+    /// Returns ` (request <id>)` to append to the log lines written on behalf
+    /// of a request, or an empty string outside of a [scope](Self::scope)
+    pub fn in_logs() -> String {
+        CURRENT
+            .try_with(|id| format!(" (request {})", id))
+            .unwrap_or_default()
+    }
+}
+
+/// A route handler that runs the wrapped one in the scope of the request
+#[derive(Clone)]
+struct ScopedHandler(Box<dyn Handler>);
+
+#[rocket::async_trait]
+impl Handler for ScopedHandler {
+    async fn handle<'r>(&self, request: &'r rocket::Request<'_>, data: rocket::Data<'r>) -> Outcome<'r> {
+        RequestId::of(request)
+            .clone()
+            .scope(self.0.handle(request, data))
+            .await
+    }
+}
+
+/// Wraps the handlers of the routes to run in the [scope](RequestId::scope)
+/// of their request, so that the logs of the routes and of their guards
+/// include the ID with [RequestId::in_logs].
+pub fn scoped(routes: Vec<Route>) -> Vec<Route> {
+    routes
+        .into_iter()
+        .map(|mut route| {
+            route.handler = Box::new(ScopedHandler(route.handler));
+            route
+        })
+        .collect()
+}
+
+/// Generates a random alphanumeric ID
+fn generate() -> String {
+    use rand::Rng;
+    rand::thread_rng()
+        .sample_iter(&rand::distributions::Alphanumeric)
+        .take(GENERATED_LEN)
+        .map(char::from)
+        .collect()
+}
+
+#[rocket::async_trait]
+impl<'r> rocket::request::FromRequest<'r> for &'r RequestId {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(
+        request: &'r rocket::Request<'_>,
+    ) -> rocket::request::Outcome<Self, Self::Error> {
+        rocket::request::Outcome::Success(RequestId::of(request))
+    }
+}
+
+/// Fairing that assigns the [RequestId] when the request arrives, and echoes
+/// it in the response
+pub struct RequestIdFairing;
+
+#[rocket::async_trait]
+impl rocket::fairing::Fairing for RequestIdFairing {
+    fn info(&self) -> rocket::fairing::Info {
+        rocket::fairing::Info {
+            name: "Request ID",
+            kind: rocket::fairing::Kind::Request | rocket::fairing::Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, request: &mut rocket::Request<'_>, _: &mut rocket::Data<'_>) {
+        RequestId::of(request);
+    }
+
+    async fn on_response<'r>(
+        &self,
+        request: &'r rocket::Request<'_>,
+        response: &mut rocket::Response<'r>,
+    ) {
+        response.set_header(Header::new(HEADER, RequestId::of(request).to_string()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[rocket::async_test]
+    async fn the_logs_in_the_scope_of_a_request_include_its_id() {
+        let id = RequestId("reading-42".to_string());
+
+        let in_logs = id.scope(async { RequestId::in_logs() }).await;
+        assert_eq!(in_logs, " (request reading-42)");
+        assert_eq!(RequestId::in_logs(), "");
+    }
+
+    #[rocket::get("/in-logs")]
+    fn in_logs() -> String {
+        RequestId::in_logs()
+    }
+
+    #[rocket::async_test]
+    async fn the_scoped_routes_log_the_id_of_their_request() {
+        let rocket = rocket::custom(testing::figment())
+            .attach(RequestIdFairing)
+            .mount("/", scoped(rocket::routes![in_logs]));
+        let client = rocket::local::asynchronous::Client::untracked(rocket)
+            .await
+            .unwrap();
+
+        let response = client
+            .get("/in-logs")
+            .header(Header::new(HEADER, "reading-42"))
+            .dispatch()
+            .await;
+        assert_eq!(response.into_string().await.unwrap(), " (request reading-42)");
+    }
+
+    #[rocket::async_test]
+    async fn the_request_id_is_echoed_in_the_response() {
+        let app = testing::client().await;
+        let uri = format!("/log/{}/latest", app.token);
+
+        let response = app
+            .get(&uri)
+            .header(Header::new(HEADER, "reading-42"))
+            .dispatch()
+            .await;
+        assert_eq!(response.headers().get_one(HEADER), Some("reading-42"));
+
+        // Missing or invalid IDs are replaced with a generated one
+        for incoming in [None, Some("not valid"), Some(&"x".repeat(MAX_LEN + 1)[..])] {
+            let mut request = app.get(&uri);
+            if let Some(incoming) = incoming {
+                request = request.header(Header::new(HEADER, incoming.to_string()));
+            }
+            let response = request.dispatch().await;
+            let id = response.headers().get_one(HEADER).expect("an ID should be generated");
+            assert_eq!(id.len(), GENERATED_LEN);
+            assert!(id.chars().all(|c| c.is_ascii_alphanumeric()), "{}", id);
+        }
+    }
+}
@@ -0,0 +1,118 @@
+//! A simple data retention fairing.
+//!
+//! This module contains the [RetentionFairing] fairing, that periodically
+//! deletes the raw readings older than a configured number of days, to keep
+//! the SQLite database bounded for deployments that do not care about long
+//! history (or that keep it elsewhere via the consolidate_logs CLI).
+//!
+//! It is disabled unless `raw_retention_days` is set in the figment
+//! configuration (Rocket.toml).
+
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    tokio::sync::Mutex,
+};
+use std::sync::Arc;
+
+/// How often the old rows are purged
+const CLEANUP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
+/// This fairing deletes the readings older than `raw_retention_days` once on
+/// liftoff and then every hour.
+pub struct RetentionFairing {
+    /// This stores the task that is spawned to delete the old rows
+    task: Arc<Mutex<Option<rocket::tokio::task::JoinHandle<()>>>>,
+}
+
+impl RetentionFairing {
+    pub fn new() -> Self {
+        Self {
+            task: Arc::new(Mutex::new(None)),
+        }
+    }
+}
+
+/// Delete the rows older than the given number of days, from the main
+/// database and every shard, returning how many rows were deleted.
+pub async fn delete_old_rows(db: &crate::db::SqlitePool, days: u32) -> Result<u64, sqlx::Error> {
+    let modifier = format!("-{} days", days);
+    let mut deleted = 0;
+    for db in db.databases() {
+        deleted += sqlx::query!(
+            "DELETE FROM main.energy_log WHERE created_at < datetime('now', ?)",
+            modifier
+        )
+        .execute(db)
+        .await?
+        .rows_affected();
+    }
+    Ok(deleted)
+}
+
+#[rocket::async_trait]
+impl Fairing for RetentionFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Raw Data Retention",
+            kind: Kind::Liftoff | Kind::Shutdown,
+        }
+    }
+
+    async fn on_liftoff(&self, rocket: &rocket::Rocket<rocket::Orbit>) -> () {
+        let days: u32 = match rocket.figment().extract_inner("raw_retention_days") {
+            Ok(0) | Err(_) => return,
+            Ok(days) => days,
+        };
+        log::info!("Keeping raw readings for {} days", days);
+
+        let db_conn = crate::alive_check::get_database::<crate::Logs>(rocket).await;
+        let task = rocket::tokio::task::spawn(async move {
+            loop {
+                match delete_old_rows(&db_conn, days).await {
+                    Ok(count) => log::info!("Deleted {} rows older than {} days", count, days),
+                    Err(e) => log::error!("Failed to delete old rows: {:?}", e),
+                }
+                rocket::tokio::time::sleep(CLEANUP_INTERVAL).await;
+            }
+        });
+
+        if let Some(old) = self.task.lock().await.replace(task) {
+            old.abort();
+        }
+    }
+
+    /// When the rocket is shutting down, we need to abort the cleanup task.
+    async fn on_shutdown(&self, _: &rocket::Rocket<rocket::Orbit>) -> () {
+        if let Some(task) = self.task.lock().await.take() {
+            task.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[rocket::async_test]
+    async fn deletes_only_the_rows_older_than_the_retention() {
+        let app = testing::client().await;
+        let days_ago = |days| {
+            (chrono::Utc::now() - chrono::Duration::days(days))
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string()
+        };
+        app.insert_reading(&days_ago(45), 1.0, 230.0, 230.0).await;
+        app.insert_reading(&days_ago(31), 2.0, 230.0, 460.0).await;
+        app.insert_reading(&days_ago(29), 3.0, 230.0, 690.0).await;
+        app.insert_reading(&days_ago(0), 4.0, 230.0, 920.0).await;
+
+        assert_eq!(delete_old_rows(app.logs(), 30).await.unwrap(), 2);
+
+        let kept: Vec<f64> = sqlx::query_scalar!("SELECT amps FROM energy_log ORDER BY created_at")
+            .fetch_all(app.db())
+            .await
+            .unwrap();
+        assert_eq!(kept, vec![3.0, 4.0]);
+    }
+}
@@ -0,0 +1,183 @@
+//! A warning for sensors logging far more often than expected.
+//!
+//! This module contains the [SamplingRateFairing] fairing, that periodically
+//! counts the recent readings of every sensor token, and logs a warning when
+//! one of them goes over the expected cadence, e.g., because of a firmware bug
+//! that would otherwise silently bloat the database.
+//!
+//! It is disabled unless `max_readings_per_minute` is set in the figment
+//! configuration (Rocket.toml):
+//!
+//! ```toml
+//! # Warn when a token logs more than this many readings per minute
+//! max_readings_per_minute = 6
+//! ```
+//!
+//! The warning is logged once when a token crosses the rate, and again only
+//! after it has gone back under it.
+
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    tokio::sync::Mutex,
+};
+use std::{collections::HashSet, sync::Arc};
+
+/// How often the rates are checked
+const CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// The window the readings are counted over, in seconds
+const WINDOW_SECS: u32 = 300;
+
+/// This fairing checks every minute whether any token logged more than
+/// `max_readings_per_minute` on average over the last 5 minutes.
+pub struct SamplingRateFairing {
+    /// This stores the task that is spawned to check the rates
+    task: Arc<Mutex<Option<rocket::tokio::task::JoinHandle<()>>>>,
+}
+
+impl SamplingRateFairing {
+    pub fn new() -> Self {
+        Self {
+            task: Arc::new(Mutex::new(None)),
+        }
+    }
+}
+
+/// Returns the tokens with more than `max_per_minute` readings per minute over
+/// the window, with their rate.
+async fn over_frequent_tokens(
+    db: &sqlx::SqlitePool,
+    max_per_minute: f64,
+) -> Result<Vec<(String, f64)>, sqlx::Error> {
+    let modifier = format!("-{} seconds", WINDOW_SECS);
+    let rows = sqlx::query!(
+        "SELECT token, COUNT(*) as \"count!: i64\"
+        FROM energy_log
+        WHERE created_at > datetime('now', ?)
+        GROUP BY token",
+        modifier
+    )
+    .fetch_all(db)
+    .await?;
+    let minutes = f64::from(WINDOW_SECS) / 60.0;
+    Ok(rows
+        .into_iter()
+        .map(|row| (row.token, row.count as f64 / minutes))
+        .filter(|(_, per_minute)| *per_minute > max_per_minute)
+        .collect())
+}
+
+/// Warns about the tokens over `max_per_minute` that were not already in
+/// `over`, which is updated to the tokens currently over the rate.
+///
+/// Returns the tokens that were warned about.
+async fn check_rates(
+    db: &sqlx::SqlitePool,
+    max_per_minute: f64,
+    over: &mut HashSet<String>,
+) -> Result<Vec<String>, sqlx::Error> {
+    let tokens = over_frequent_tokens(db, max_per_minute).await?;
+
+    let mut warned = Vec::new();
+    let mut still_over = HashSet::new();
+    for (token, per_minute) in tokens {
+        if !over.contains(&token) {
+            log::warn!(
+                "Sensor {} is logging {:.1} readings per minute, over the expected {}",
+                crate::token::simplify_token_string(&token),
+                per_minute,
+                max_per_minute
+            );
+            warned.push(token.clone());
+        }
+        still_over.insert(token);
+    }
+    *over = still_over;
+    Ok(warned)
+}
+
+#[rocket::async_trait]
+impl Fairing for SamplingRateFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Sampling Rate Warning",
+            kind: Kind::Liftoff | Kind::Shutdown,
+        }
+    }
+
+    async fn on_liftoff(&self, rocket: &rocket::Rocket<rocket::Orbit>) -> () {
+        let max_per_minute: f64 = match rocket.figment().extract_inner("max_readings_per_minute") {
+            Ok(max) => max,
+            Err(_) => return,
+        };
+        log::info!(
+            "Warning about sensors logging more than {} readings per minute",
+            max_per_minute
+        );
+
+        let db_conn = crate::alive_check::get_database::<crate::Logs>(rocket).await;
+        let task = rocket::tokio::task::spawn(async move {
+            let mut over = HashSet::new();
+            loop {
+                rocket::tokio::time::sleep(CHECK_INTERVAL).await;
+                if let Err(e) = check_rates(&db_conn, max_per_minute, &mut over).await {
+                    log::error!("Failed to check the sampling rates: {:?}", e);
+                }
+            }
+        });
+
+        if let Some(old) = self.task.lock().await.replace(task) {
+            old.abort();
+        }
+    }
+
+    /// When the rocket is shutting down, we need to abort the check task.
+    async fn on_shutdown(&self, _: &rocket::Rocket<rocket::Orbit>) -> () {
+        if let Some(task) = self.task.lock().await.take() {
+            task.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    /// The UTC timestamp `secs_ago` seconds ago, as stored by the ingest routes
+    fn ago(secs_ago: i64) -> String {
+        (chrono::Utc::now() - chrono::Duration::seconds(secs_ago))
+            .format("%Y-%m-%d %H:%M:%S")
+            .to_string()
+    }
+
+    #[rocket::async_test]
+    async fn an_over_frequent_token_is_warned_about_once() {
+        let app = testing::client().await;
+        let quiet = app.create_token(testing::LOCATION).await;
+        // 20 readings in the last 5 minutes are 4 per minute
+        for i in 0..20 {
+            app.insert_reading(&ago(i * 10), 1.0, 230.0, 230.0).await;
+        }
+        app.insert_reading_for(&quiet, &ago(30), 1.0, 230.0, 230.0).await;
+        // Readings older than the window are not counted
+        for i in 0..20 {
+            app.insert_reading_for(&quiet, &ago(600 + i), 1.0, 230.0, 230.0).await;
+        }
+
+        let mut over = HashSet::new();
+        let warned = check_rates(app.db(), 2.0, &mut over).await.unwrap();
+        assert_eq!(warned, vec![app.token.clone()]);
+
+        // Still over the rate, but it was already warned about
+        let warned = check_rates(app.db(), 2.0, &mut over).await.unwrap();
+        assert!(warned.is_empty());
+        assert!(over.contains(&app.token));
+
+        // Under a higher rate, it is warned about again once it goes over
+        assert!(check_rates(app.db(), 10.0, &mut over).await.unwrap().is_empty());
+        assert!(over.is_empty());
+        let warned = check_rates(app.db(), 2.0, &mut over).await.unwrap();
+        assert_eq!(warned, vec![app.token.clone()]);
+    }
+}
@@ -0,0 +1,151 @@
+//! Live stream of readings as Server-Sent Events.
+//!
+//! The ingest routes publish every inserted reading to a [LiveReadings]
+//! broadcast channel, and each GET /log/:token/stream subscriber forwards the
+//! readings from the sensors its view token gives access to.
+//!
+//! Publishing never blocks the ingest path: if a subscriber falls too far
+//! behind, it misses readings from the channel and we close its stream, so
+//! the client can reconnect.
+
+use std::collections::HashSet;
+
+use rocket::response::stream::{Event, EventStream};
+use rocket::tokio::sync::broadcast;
+use rocket::{get, Shutdown, State};
+use rocket_db_pools::Connection;
+use serde::Serialize;
+
+use crate::request_id::RequestId;
+use crate::token::ValidViewToken;
+use crate::{LogsRead, RateLimit};
+
+/// How many readings are buffered for slow subscribers before they lag behind
+const CHANNEL_CAPACITY: usize = 64;
+
+/// A reading as sent to the stream subscribers
+#[derive(Debug, Clone, Serialize)]
+pub struct LiveReading {
+    /// The simplified sensor token
+    pub token: String,
+    pub datetime: chrono::DateTime<chrono::Utc>,
+    pub amps: f64,
+    pub volts: f64,
+    pub watts: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature_c: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub power_factor: Option<f64>,
+}
+
+/// The broadcast channel of inserted readings, managed as Rocket state
+///
+/// Cloning it gives another handle to the same channel.
+#[derive(Clone)]
+pub struct LiveReadings {
+    sender: broadcast::Sender<(String, LiveReading)>,
+}
+
+impl LiveReadings {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+        Self { sender }
+    }
+
+    /// Publish a reading inserted for the given sensor token.
+    ///
+    /// This does nothing if nobody is subscribed.
+    pub fn publish(&self, token: &str, reading: LiveReading) {
+        let _ = self.sender.send((token.to_string(), reading));
+    }
+}
+
+/// Route GET /log/:token/stream will send each new reading for the view token
+/// as a Server-Sent Event with a JSON payload, as soon as it is inserted.
+#[get("/log/<_>/stream", rank = 1)]
+pub async fn stream_readings(
+    token: &ValidViewToken,
+    live: &State<LiveReadings>,
+    mut db: Connection<LogsRead>,
+    mut shutdown: Shutdown,
+    _ratelimit: RateLimit,
+) -> EventStream![] {
+    let tokens: HashSet<String> = sqlx::query!(
+        "SELECT token as \"token!\" FROM view_token_sensors WHERE view_token = ?",
+        token
+    )
+    .fetch_all(&mut **db)
+    .await
+    .unwrap()
+    .into_iter()
+    .map(|row| row.token)
+    .collect();
+    let mut receiver = live.sender.subscribe();
+    // The events are sent after the route returns, out of the request scope
+    let in_logs = RequestId::in_logs();
+
+    EventStream! {
+        loop {
+            let (sensor_token, reading) = rocket::tokio::select! {
+                message = receiver.recv() => match message {
+                    Ok(message) => message,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        log::warn!("Closing a slow live stream, {} readings behind{}", skipped, in_logs);
+                        break;
+                    }
+                },
+                _ = &mut shutdown => break,
+            };
+            if tokens.contains(&sensor_token) {
+                yield Event::json(&reading);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing;
+    use rocket::http::{ContentType, Status};
+    use rocket::tokio::io::AsyncReadExt;
+    use std::time::Duration;
+
+    #[rocket::async_test]
+    async fn subscribers_receive_the_posted_readings() {
+        let app = testing::client().await;
+        let other = app.create_token("garage").await;
+
+        let mut stream = app.get(format!("/log/{}/stream", app.token)).dispatch().await;
+        assert_eq!(stream.status(), Status::Ok);
+        assert_eq!(stream.content_type(), Some(ContentType::EventStream));
+
+        // Readings of sensors the view token has no access to are skipped
+        for (token, body) in [
+            (&other, r#"{"amps": 9, "volts": 230, "watts": 2070}"#),
+            (&app.token, r#"{"amps": 2.5, "volts": 230, "watts": 575}"#),
+        ] {
+            let response = app
+                .post(format!("/log/{}", token))
+                .header(ContentType::JSON)
+                .body(body)
+                .dispatch()
+                .await;
+            assert_eq!(response.status(), Status::Ok);
+        }
+
+        let mut buffer = vec![0; 1024];
+        let read = rocket::tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buffer))
+            .await
+            .expect("the event should be received")
+            .unwrap();
+        let event = std::str::from_utf8(&buffer[..read]).unwrap();
+        let data = event
+            .lines()
+            .find_map(|line| line.strip_prefix("data:"))
+            .expect("the event should have data");
+        let reading: serde_json::Value = serde_json::from_str(data).unwrap();
+        assert_eq!(reading["amps"], 2.5);
+        assert_eq!(reading["watts"], 575.0);
+    }
+}
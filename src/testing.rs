@@ -0,0 +1,309 @@
+//! Test harness running the application against an in-memory database.
+//!
+//! [client] builds the application with [build](crate::build), with both the
+//! [Logs](crate::Logs) and [LogsRead](crate::LogsRead) pools pointing to the
+//! same shared-cache in-memory SQLite database, so the read routes see what
+//! the ingest routes write, and with the EV charge control disabled. The
+//! migrations run on ignite, as they do in production.
+
+use rocket::figment::Figment;
+use rocket::local::asynchronous::{Client, LocalRequest};
+use rocket_db_pools::{sqlx, Database};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::cli::create_token::create_token;
+use crate::Logs;
+
+/// Location of the user of the seeded token
+pub const LOCATION: &str = "test";
+
+/// A local client of the application, and the token seeded into its database
+pub struct TestApp {
+    pub client: Client,
+    pub token: String,
+    /// The address the requests come from
+    pub remote: SocketAddr,
+}
+
+impl TestApp {
+    /// A GET request from the [remote](Self::remote) address
+    pub fn get(&self, uri: impl AsRef<str>) -> LocalRequest<'_> {
+        self.client.get(uri.as_ref().to_string()).remote(self.remote)
+    }
+
+    /// A POST request from the [remote](Self::remote) address
+    pub fn post(&self, uri: impl AsRef<str>) -> LocalRequest<'_> {
+        self.client.post(uri.as_ref().to_string()).remote(self.remote)
+    }
+
+    /// The primary database of the application
+    pub fn logs(&self) -> &Logs {
+        Logs::fetch(self.client.rocket()).expect("the database should be attached")
+    }
+
+    /// The primary pool of the application
+    pub fn db(&self) -> &sqlx::SqlitePool {
+        self.logs()
+    }
+
+    /// Seeds another token for the location, which is also a view token of
+    /// its readings, as the one of [client_with].
+    pub async fn create_token(&self, location: &str) -> String {
+        seed_token(self.db(), location).await
+    }
+
+    /// Logs a reading of the seeded token at the given UTC `created_at`
+    /// (`%Y-%m-%d %H:%M:%S`), bypassing the ingest routes.
+    pub async fn insert_reading(&self, created_at: &str, amps: f64, volts: f64, watts: f64) {
+        self.insert_reading_for(&self.token, created_at, amps, volts, watts)
+            .await
+    }
+
+    /// Like [insert_reading](Self::insert_reading), for another token
+    pub async fn insert_reading_for(
+        &self,
+        token: &str,
+        created_at: &str,
+        amps: f64,
+        volts: f64,
+        watts: f64,
+    ) {
+        sqlx::query!(
+            "INSERT INTO energy_log (token, amps, volts, watts, created_at) VALUES (?, ?, ?, ?, ?)",
+            token,
+            amps,
+            volts,
+            watts,
+            created_at
+        )
+        .execute(self.db())
+        .await
+        .expect("the reading should be inserted");
+    }
+}
+
+/// The `admin_token` of the applications built with [admin_figment]
+pub const ADMIN_TOKEN: &str = "test-admin-token";
+
+/// The `Authorization` header with the [ADMIN_TOKEN]
+pub fn admin_authorization() -> rocket::http::Header<'static> {
+    rocket::http::Header::new("Authorization", format!("Bearer {}", ADMIN_TOKEN))
+}
+
+/// Each application gets its own database, as the tests run concurrently
+/// within the same process, where the shared cache is shared by name.
+static DATABASES: AtomicUsize = AtomicUsize::new(0);
+
+/// Each application also sends its requests from its own loopback address,
+/// as the rate limit needs the client IP, and the governor keeps its limits
+/// per address for the whole process.
+static REMOTES: AtomicUsize = AtomicUsize::new(1);
+
+/// The address of the next application
+fn next_remote() -> SocketAddr {
+    let n = REMOTES.fetch_add(1, Ordering::Relaxed);
+    let ip = Ipv4Addr::new(127, (n >> 16) as u8, (n >> 8) as u8, n as u8);
+    SocketAddr::new(IpAddr::V4(ip), 40000)
+}
+
+/// The figment of a test application, which can be further merged into
+/// before passing it to [client_with].
+pub fn figment() -> Figment {
+    database_figment().merge(("ev_handler", "none"))
+}
+
+/// Like [figment], but leaving the `ev_handler` unset, as in a deployment
+/// without any EV setup.
+pub fn database_figment() -> Figment {
+    let url = format!(
+        "sqlite:file:memdb{}?mode=memory&cache=shared",
+        DATABASES.fetch_add(1, Ordering::Relaxed)
+    );
+    Figment::from(rocket::Config::debug_default())
+        .merge(("log_level", "off"))
+        .merge(("databases.sqlite_logs.url", &url))
+        .merge(("databases.sqlite_logs_read.url", &url))
+}
+
+/// The default [figment], with the admin routes enabled with the
+/// [ADMIN_TOKEN]
+pub fn admin_figment() -> Figment {
+    figment().merge(("admin_token", ADMIN_TOKEN))
+}
+
+/// Builds a test application with the default [figment].
+pub async fn client() -> TestApp {
+    client_with(figment()).await
+}
+
+/// Builds a test application from the figment, and seeds a token for the
+/// [LOCATION], which is also a view token of its readings.
+pub async fn client_with(figment: Figment) -> TestApp {
+    client_of(crate::build(figment)).await
+}
+
+/// Like [client_with], for an application that was further built upon, e.g.,
+/// to attach a fairing observing the requests.
+pub async fn client_of(rocket: rocket::Rocket<rocket::Build>) -> TestApp {
+    let client = Client::tracked(rocket)
+        .await
+        .expect("the test application should ignite");
+    let db = Logs::fetch(client.rocket()).expect("the database should be attached");
+    let token = seed_token(db, LOCATION).await;
+    TestApp {
+        client,
+        token,
+        remote: next_remote(),
+    }
+}
+
+/// Creates a user for the location, with a token which is also a view token
+/// of its readings.
+async fn seed_token(db: &sqlx::SqlitePool, location: &str) -> String {
+    let (user_id, token) = create_token(db, location)
+        .await
+        .expect("the token should be seeded");
+    sqlx::query!(
+        "INSERT INTO view_tokens (token, user_id) VALUES (?, ?)",
+        token,
+        user_id
+    )
+    .execute(db)
+    .await
+    .expect("the view token should be seeded");
+    token
+}
+
+/// A directory for the database files of a test, removed with its contents
+/// when dropped.
+///
+/// The removal is best effort, as SQLite may still be closing the files of a
+/// pool in the background.
+pub struct TempDir(std::path::PathBuf);
+
+impl TempDir {
+    /// Creates an empty directory with the name, unique to this process
+    pub fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("amp-sensor-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).expect("the directory should be created");
+        Self(dir)
+    }
+
+    /// The path of a file in the directory
+    pub fn path(&self, file: &str) -> String {
+        self.0.join(file).to_str().expect("a UTF-8 path").to_string()
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// A local HTTP server standing in for an external service, such as the
+/// Tessie API, which answers every request with the same response and records
+/// their request lines (e.g., `POST /path?query HTTP/1.1`).
+pub struct MockServer {
+    pub url: String,
+    requests: std::sync::Arc<std::sync::Mutex<Vec<String>>>,
+}
+
+impl MockServer {
+    /// Starts a server answering every request with the status and JSON body
+    pub async fn start(status: u16, body: impl Into<String>) -> Self {
+        use rocket::tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let listener = rocket::tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorded = requests.clone();
+        let body: std::sync::Arc<str> = body.into().into();
+        rocket::tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let recorded = recorded.clone();
+                let body = body.clone();
+                rocket::tokio::spawn(async move {
+                    let mut stream = BufReader::new(stream);
+                    let mut line = String::new();
+                    stream.read_line(&mut line).await.unwrap();
+                    recorded.lock().unwrap().push(line.trim_end().to_string());
+                    // Skip the headers, as the requests have no body
+                    while !matches!(line.as_str(), "\r\n" | "") {
+                        line.clear();
+                        stream.read_line(&mut line).await.unwrap();
+                    }
+                    let response = format!(
+                        "HTTP/1.1 {} Mock\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        status,
+                        body.len(),
+                        body
+                    );
+                    stream.get_mut().write_all(response.as_bytes()).await.unwrap();
+                });
+            }
+        });
+        Self { url, requests }
+    }
+
+    /// Starts a server that accepts every request but never answers, as a
+    /// hung external service
+    pub async fn silent() -> Self {
+        use rocket::tokio::io::{AsyncBufReadExt, BufReader};
+
+        let listener = rocket::tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorded = requests.clone();
+        rocket::tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let recorded = recorded.clone();
+                rocket::tokio::spawn(async move {
+                    let mut stream = BufReader::new(stream);
+                    let mut line = String::new();
+                    stream.read_line(&mut line).await.unwrap();
+                    recorded.lock().unwrap().push(line.trim_end().to_string());
+                    // Keep the connection open until the client gives up
+                    while stream.read_line(&mut line).await.is_ok_and(|read| read > 0) {}
+                });
+            }
+        });
+        Self { url, requests }
+    }
+
+    /// The request lines received so far
+    pub fn requests(&self) -> Vec<String> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::http::{ContentType, Status};
+
+    #[rocket::async_test]
+    async fn posted_readings_can_be_read_back() {
+        let app = client().await;
+
+        let response = app
+            .post(format!("/log/{}", app.token))
+            .header(ContentType::JSON)
+            .body(r#"{"amps": 2.5, "volts": 230, "watts": 575}"#)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+
+        let response = app
+            .get(format!("/log/{}/latest", app.token))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let latest: serde_json::Value = response.into_json().await.unwrap();
+        assert_eq!(latest["amps"], 2.5);
+        assert_eq!(latest["watts"], 575.0);
+        assert_eq!(latest["location"], LOCATION);
+    }
+}
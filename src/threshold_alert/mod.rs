@@ -0,0 +1,269 @@
+//! A consumption threshold alert fairing.
+//!
+//! This module contains the [ThresholdAlertFairing] fairing, that
+//! periodically checks the recent average amps of every sensor token, and
+//! sends a message via the same webhook as the
+//! [AliveCheckFairing](crate::alive_check::AliveCheckFairing) when it goes
+//! over a limit.
+//!
+//! It is disabled unless `threshold_amps` is set in the figment configuration
+//! (Rocket.toml):
+//!
+//! ```toml
+//! # Alert when the average over the window goes over this many amps
+//! threshold_amps = 25.0
+//! # The window to average, in seconds (300 by default)
+//! threshold_window_secs = 300
+//! ```
+//!
+//! The alert is only sent once when a token crosses the threshold, and it is
+//! sent again only after the average has gone back under it.
+
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    tokio::sync::Mutex,
+};
+use std::{collections::HashSet, sync::Arc};
+
+/// How often the averages are checked
+const CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// The window averaged unless `threshold_window_secs` is configured
+const DEFAULT_WINDOW_SECS: u32 = 300;
+
+/// This fairing checks every minute whether any token's average amps over the
+/// configured window are over `threshold_amps`, and sends a webhook when one
+/// crosses it.
+pub struct ThresholdAlertFairing {
+    /// This stores the task that is spawned to check the averages
+    task: Arc<Mutex<Option<rocket::tokio::task::JoinHandle<()>>>>,
+}
+
+impl ThresholdAlertFairing {
+    pub fn new() -> Self {
+        Self {
+            task: Arc::new(Mutex::new(None)),
+        }
+    }
+}
+
+/// The recent average of a sensor token
+struct TokenAverage {
+    token: String,
+    location: String,
+    avg_amps: f64,
+}
+
+/// Returns the average amps of every token with readings in the last
+/// `window_secs` seconds.
+async fn recent_averages(
+    db: &sqlx::SqlitePool,
+    window_secs: u32,
+) -> Result<Vec<TokenAverage>, sqlx::Error> {
+    let modifier = format!("-{} seconds", window_secs);
+    let rows = sqlx::query!(
+        "SELECT e.token, u.location, AVG(e.amps) as \"avg_amps!: f64\"
+        FROM energy_log e
+        INNER JOIN tokens t ON t.token = e.token
+        INNER JOIN users u ON u.id = t.user_id
+        WHERE e.created_at > datetime('now', ?)
+        GROUP BY e.token",
+        modifier
+    )
+    .fetch_all(db)
+    .await?;
+    Ok(rows
+        .into_iter()
+        .map(|row| TokenAverage {
+            token: row.token,
+            location: row.location,
+            avg_amps: row.avg_amps,
+        })
+        .collect())
+}
+
+/// Returns the averages that just crossed the threshold, and updates the set
+/// of tokens over it.
+///
+/// Tokens already over the threshold are not returned again until their
+/// average goes back under it, or they stop logging readings.
+fn new_breaches(
+    over: &mut HashSet<String>,
+    averages: Vec<TokenAverage>,
+    threshold_amps: f64,
+) -> Vec<TokenAverage> {
+    let mut still_over = HashSet::new();
+    let mut breaches = Vec::new();
+    for average in averages {
+        if average.avg_amps <= threshold_amps {
+            continue;
+        }
+        still_over.insert(average.token.clone());
+        if !over.contains(&average.token) {
+            breaches.push(average);
+        }
+    }
+    *over = still_over;
+    breaches
+}
+
+/// Checks the recent averages once, and sends a webhook for every token that
+/// just crossed the threshold.
+async fn check_thresholds(
+    db: &sqlx::SqlitePool,
+    client: &reqwest::Client,
+    over: &mut HashSet<String>,
+    window_secs: u32,
+    threshold_amps: f64,
+    webhook_url: &str,
+) {
+    let averages = match recent_averages(db, window_secs).await {
+        Ok(averages) => averages,
+        Err(e) => {
+            log::error!("Failed to check the consumption threshold: {:?}", e);
+            return;
+        }
+    };
+
+    for breach in new_breaches(over, averages, threshold_amps) {
+        let token = crate::token::simplify_token_string(&breach.token);
+        log::warn!(
+            "Average of {} A at {} ({}) is over the threshold of {} A",
+            breach.avg_amps,
+            breach.location,
+            token,
+            threshold_amps
+        );
+        let payload = serde_json::json!({
+            "event": "threshold_exceeded",
+            "location": breach.location,
+            "token": token,
+            "avg_amps": breach.avg_amps,
+            "threshold_amps": threshold_amps,
+            "window_secs": window_secs,
+        });
+        crate::alive_check::send_webhook(client, webhook_url, Some(&payload)).await;
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for ThresholdAlertFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Consumption Threshold Alert",
+            kind: Kind::Liftoff | Kind::Shutdown,
+        }
+    }
+
+    async fn on_liftoff(&self, rocket: &rocket::Rocket<rocket::Orbit>) -> () {
+        let threshold_amps: f64 = match rocket.figment().extract_inner("threshold_amps") {
+            Ok(threshold) => threshold,
+            Err(_) => return,
+        };
+        let window_secs: u32 = rocket
+            .figment()
+            .extract_inner("threshold_window_secs")
+            .unwrap_or(DEFAULT_WINDOW_SECS);
+        let webhook_url: String = rocket.figment().extract_inner("webhook_url").unwrap_or_default();
+        let client = crate::http_client::shared().clone();
+        log::info!(
+            "Alerting when the average over {} seconds exceeds {} A",
+            window_secs,
+            threshold_amps
+        );
+
+        let db_conn = crate::alive_check::get_database::<crate::Logs>(rocket).await;
+        let task = rocket::tokio::task::spawn(async move {
+            let mut over = HashSet::new();
+            loop {
+                rocket::tokio::time::sleep(CHECK_INTERVAL).await;
+                check_thresholds(
+                    &db_conn,
+                    &client,
+                    &mut over,
+                    window_secs,
+                    threshold_amps,
+                    &webhook_url,
+                )
+                .await;
+            }
+        });
+
+        if let Some(old) = self.task.lock().await.replace(task) {
+            old.abort();
+        }
+    }
+
+    /// When the rocket is shutting down, we need to abort the check task.
+    async fn on_shutdown(&self, _: &rocket::Rocket<rocket::Orbit>) -> () {
+        if let Some(task) = self.task.lock().await.take() {
+            task.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, MockServer};
+
+    /// The current time, as stored by SQLite
+    fn now() -> String {
+        chrono::Utc::now().format("%Y-%m-%d %H:%M:%S").to_string()
+    }
+
+    /// Checks the thresholds of the application once, at 25 A over 5 minutes
+    async fn check(app: &testing::TestApp, over: &mut HashSet<String>, webhook: &MockServer) {
+        let client = crate::http_client::shared();
+        check_thresholds(app.db(), client, over, 300, 25.0, &webhook.url).await;
+    }
+
+    #[rocket::async_test]
+    async fn crossing_the_threshold_fires_once() {
+        let app = testing::client().await;
+        let webhook = MockServer::start(200, "{}").await;
+        let mut over = HashSet::new();
+
+        app.insert_reading(&now(), 30.0, 230.0, 6900.0).await;
+        check(&app, &mut over, &webhook).await;
+        assert_eq!(webhook.requests(), vec!["POST / HTTP/1.1"]);
+
+        // Still over, so it does not fire again
+        app.insert_reading(&now(), 28.0, 230.0, 6440.0).await;
+        check(&app, &mut over, &webhook).await;
+        assert_eq!(webhook.requests().len(), 1);
+    }
+
+    #[rocket::async_test]
+    async fn staying_under_the_threshold_is_silent() {
+        let app = testing::client().await;
+        let webhook = MockServer::start(200, "{}").await;
+        let mut over = HashSet::new();
+
+        app.insert_reading(&now(), 10.0, 230.0, 2300.0).await;
+        app.insert_reading(&now(), 20.0, 230.0, 4600.0).await;
+        check(&app, &mut over, &webhook).await;
+        assert!(webhook.requests().is_empty());
+        assert!(over.is_empty());
+    }
+
+    #[test]
+    fn going_back_under_rearms_the_alert() {
+        let average = |avg_amps| {
+            vec![TokenAverage {
+                token: "token".to_string(),
+                location: "test".to_string(),
+                avg_amps,
+            }]
+        };
+        let mut over = HashSet::new();
+
+        assert_eq!(new_breaches(&mut over, average(30.0), 25.0).len(), 1);
+        assert_eq!(new_breaches(&mut over, average(30.0), 25.0).len(), 0);
+        assert_eq!(new_breaches(&mut over, average(20.0), 25.0).len(), 0);
+        assert_eq!(new_breaches(&mut over, average(30.0), 25.0).len(), 1);
+        // Tokens without recent readings are forgotten too
+        assert_eq!(new_breaches(&mut over, vec![], 25.0).len(), 0);
+        assert!(over.is_empty());
+    }
+}
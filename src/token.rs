@@ -1,8 +1,10 @@
 use rocket_db_pools::Connection;
 use sqlx::{Encode, Type};
 
+use crate::request_id::RequestId;
+
 pub trait Token {
-    fn full_token<'a>(&'a self) -> &'a str;
+    fn full_token(&self) -> &str;
     fn simplified(&self) -> String {
         simplify_token_string(self.full_token())
     }
@@ -14,12 +16,13 @@ pub trait Token {
 pub struct DbToken(pub String);
 
 impl Token for DbToken {
-    fn full_token<'a>(&'a self) -> &'a str {
+    fn full_token(&self) -> &str {
         &self.0
     }
 }
 
-/// This struct is used to store the token that is passed in the URL.
+/// This struct is used to store the token that is passed in the URL, or in a
+/// header (see [token_from_request]).
 ///
 /// The second argument is a private unit struct, which is used to statically
 /// ensure that the token can only be created by its `FromRequest`
@@ -27,7 +30,7 @@ impl Token for DbToken {
 pub struct ValidDbToken(pub DbToken, ());
 
 impl Token for ValidDbToken {
-    fn full_token<'a>(&'a self) -> &'a str {
+    fn full_token(&self) -> &str {
         self.0.full_token()
     }
 }
@@ -96,7 +99,8 @@ impl std::fmt::Display for ValidDbToken {
     }
 }
 
-/// This struct is used to store a view-token passed in the URL.
+/// This struct is used to store a view-token passed in the URL, or in a header
+/// (see [token_from_request]).
 ///
 /// The second argument is a private unit struct, which is used to statically
 /// ensure that the token can only be created by its `FromRequest`
@@ -104,7 +108,7 @@ impl std::fmt::Display for ValidDbToken {
 pub struct ValidViewToken(pub DbToken, ());
 
 impl Token for ValidViewToken {
-    fn full_token<'a>(&'a self) -> &'a str {
+    fn full_token(&self) -> &str {
         self.0.full_token()
     }
 }
@@ -141,16 +145,84 @@ impl std::fmt::Display for ValidViewToken {
     }
 }
 
+/// Generates a new random token, suitable for both sensor and view tokens.
+///
+/// The token is made of 43 alphanumeric characters (over 256 bits of entropy)
+/// from a cryptographically secure generator, so it is URL-safe.
+pub fn generate_token() -> String {
+    use rand::Rng;
+    rand::thread_rng()
+        .sample_iter(&rand::distributions::Alphanumeric)
+        .take(43)
+        .map(char::from)
+        .collect()
+}
+
 /// This function returns a cleaned up version of the token, showing only the
 /// first and last 4 characters.
+///
+/// Tokens with fewer than 8 characters, which may come from an untrusted
+/// source such as an MQTT topic, are not shown at all.
 pub fn simplify_token_string(token: &str) -> String {
+    let chars: Vec<char> = token.chars().collect();
+    if chars.len() < 8 {
+        return "...".to_string();
+    }
     let mut result = String::new();
-    result.push_str(&token[..4]);
+    result.extend(&chars[..4]);
     result.push_str("...");
-    result.push_str(&token[token.len() - 4..]);
+    result.extend(&chars[chars.len() - 4..]);
     result
 }
 
+/// The path segment to use in place of the token when it is sent in a header
+const PATH_TOKEN_PLACEHOLDER: &str = "-";
+
+/// Returns the token for the request, from the path (e.g., `/log/<token>/json`)
+/// or, to keep it out of URLs (and thus of proxy logs and browser history) if
+/// the client supports it, from the `Authorization: Bearer` or `X-Api-Token`
+/// header when the path has the [PATH_TOKEN_PLACEHOLDER] (e.g., `/log/-/json`).
+///
+/// Fails with a 404 Not Found without a token, and with a 400 Bad Request if
+/// both the path and a header have one, as either could be the intended one.
+fn token_from_request(request: &rocket::Request<'_>) -> Result<String, rocket::http::Status> {
+    let headers = request.headers();
+    let header = headers
+        .get_one("Authorization")
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .or_else(|| headers.get_one("X-Api-Token"))
+        .map(str::trim)
+        .filter(|token| !token.is_empty());
+
+    match (request.routed_segment(1), header) {
+        (Some(PATH_TOKEN_PLACEHOLDER), Some(token)) => Ok(token.to_string()),
+        (Some(PATH_TOKEN_PLACEHOLDER), None) | (None, _) => {
+            log::info!("No token found{}", RequestId::in_logs());
+            Err(rocket::http::Status::NotFound)
+        }
+        (Some(_), Some(_)) => {
+            log::info!("Token found in both the path and a header{}", RequestId::in_logs());
+            Err(rocket::http::Status::BadRequest)
+        }
+        (Some(segment), None) => Ok(segment.to_string()),
+    }
+}
+
+/// The outcome of a token guard: invalid tokens forward with their status, so
+/// that the route answers as if it did not exist, but a 400 Bad Request fails
+/// the request, as no other route would accept it either.
+fn token_outcome<T>(
+    result: &Result<T, rocket::http::Status>,
+) -> rocket::request::Outcome<&T, ()> {
+    match result {
+        Ok(token) => rocket::request::Outcome::Success(token),
+        Err(status) if *status == rocket::http::Status::BadRequest => {
+            rocket::request::Outcome::Error((*status, ()))
+        }
+        Err(status) => rocket::request::Outcome::Forward(*status),
+    }
+}
+
 #[rocket::async_trait]
 impl<'r> rocket::request::FromRequest<'r> for &'r ValidDbToken {
     type Error = ();
@@ -164,35 +236,65 @@ impl<'r> rocket::request::FromRequest<'r> for &'r ValidDbToken {
                     .guard::<Connection<crate::Logs>>()
                     .await
                     .expect("Failed to get db connection");
-                let token = request.routed_segment(1).map(|s| s.to_string());
-                match token {
-                    Some(token) => {
-                        let rows = sqlx::query!(
-                            "SELECT COUNT(*) as count FROM tokens WHERE token = ?",
-                            token
-                        );
-                        let count = rows.fetch_one(&mut **db).await.unwrap().count;
-                        log::info!("Token count in DB: {}", count);
-                        if count == 0 {
-                            return None;
-                        }
-                        Some(ValidDbToken(DbToken(token), ()))
-                    }
-                    _ => {
-                        log::info!("No token found");
-                        None
-                    }
+                let token = token_from_request(request)?;
+                let rows = sqlx::query!(
+                    "SELECT COUNT(*) as count FROM tokens WHERE token = ?",
+                    token
+                );
+                let count = rows.fetch_one(&mut **db).await.unwrap().count;
+                log::info!("Token count in DB: {}{}", count, RequestId::in_logs());
+                if count == 0 {
+                    return Err(rocket::http::Status::NotFound);
                 }
+                Ok(ValidDbToken(DbToken(token), ()))
             })
             .await;
 
-        match result {
-            Some(token) => rocket::request::Outcome::Success(token),
-            None => rocket::request::Outcome::Forward(rocket::http::Status::NotFound),
-        }
+        token_outcome(result)
     }
 }
 
+/// Checks that a view token exists and has not expired, updating the time it
+/// was last accessed.
+///
+/// This is what the [ValidViewToken] request guard uses for the token in the
+/// URL, exposed for the routes that receive several view tokens at once.
+/// Unknown tokens fail with a 404 Not Found, and expired ones with a 410 Gone.
+pub async fn validate_view_token(
+    db: &mut Connection<crate::Logs>,
+    token: String,
+) -> Result<ValidViewToken, rocket::http::Status> {
+    // Count expired tokens too, to tell them apart from unknown ones
+    let rows = sqlx::query!(
+        "SELECT COUNT(*) as count, COALESCE(SUM(view_token_valid_until is null OR view_token_valid_until > datetime(\"NOW\")), 0) as \"valid_count!: i64\" FROM view_tokens WHERE token = ?",
+        token
+    );
+    let row = rows.fetch_one(&mut ***db).await.unwrap();
+    log::info!(
+        "Token count in DB: {} ({} valid){}",
+        row.count,
+        row.valid_count,
+        RequestId::in_logs()
+    );
+    if row.count == 0 {
+        return Err(rocket::http::Status::NotFound);
+    }
+    if row.valid_count == 0 {
+        log::info!("View token has expired{}", RequestId::in_logs());
+        return Err(rocket::http::Status::Gone);
+    }
+    let now = chrono::Utc::now().format("%Y-%m-%d %H:%M:%S").to_string();
+    // Update last accessed time
+    sqlx::query!(
+        "UPDATE view_tokens SET last_accessed_at = ? WHERE token = ?",
+        now,
+        token
+    )
+    .execute(&mut ***db)
+    .await
+    .unwrap();
+    Ok(ValidViewToken(DbToken(token), ()))
+}
 
 #[rocket::async_trait]
 impl<'r> rocket::request::FromRequest<'r> for &'r ValidViewToken {
@@ -207,38 +309,96 @@ impl<'r> rocket::request::FromRequest<'r> for &'r ValidViewToken {
                     .guard::<Connection<crate::Logs>>()
                     .await
                     .expect("Failed to get db connection");
-                let token = request.routed_segment(1).map(|s| s.to_string());
-                match token {
-                    Some(token) => {
-                        let rows = sqlx::query!(
-                            "SELECT COUNT(*) as count FROM view_tokens WHERE token = ? AND (view_token_valid_until is null OR view_token_valid_until > datetime(\"NOW\"))",
-                            token
-                        );
-                        let count = rows.fetch_one(&mut **db).await.unwrap().count;
-                        log::info!("Token count in DB: {}", count);
-                        if count == 0 {
-                            return None;
-                        }
-                        let now = chrono::Utc::now().format("%Y-%m-%d %H:%M:%S").to_string();
-                        // Update last accessed time
-                        sqlx::query!(
-                            "UPDATE view_tokens SET last_accessed_at = ? WHERE token = ?",
-                            now,
-                            token
-                        ).execute(&mut **db).await.unwrap();
-                        Some(ValidViewToken(DbToken(token), ()))
-                    }
-                    _ => {
-                        log::info!("No token found");
-                        None
-                    }
-                }
+                let token = token_from_request(request)?;
+                validate_view_token(&mut db, token).await
             })
             .await;
 
-        match result {
-            Some(token) => rocket::request::Outcome::Success(token),
-            None => rocket::request::Outcome::Forward(rocket::http::Status::NotFound),
+        // Expired tokens forward with a 410 Gone instead of a 404 Not Found
+        token_outcome(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing;
+    use rocket::http::Status;
+
+    #[test]
+    fn simplified_tokens_keep_the_first_and_last_characters() {
+        assert_eq!(super::simplify_token_string("abcdefghijklmnop"), "abcd...mnop");
+        assert_eq!(super::simplify_token_string("ñandú-ñandú"), "ñand...andú");
+        assert_eq!(super::simplify_token_string("abc"), "...");
+        assert_eq!(super::simplify_token_string(""), "...");
+    }
+
+    #[rocket::async_test]
+    async fn view_tokens_are_not_found_valid_or_gone() {
+        let app = testing::client().await;
+        sqlx::query!(
+            "INSERT INTO view_tokens (token, user_id, view_token_valid_until)
+            SELECT 'expired-view-token', user_id, datetime('now', '-1 days') FROM tokens WHERE token = ?",
+            app.token
+        )
+        .execute(app.db())
+        .await
+        .unwrap();
+
+        for route in ["json", "svg", "latest"] {
+            let status = |token: &str| {
+                let request = app.get(format!("/log/{}/{}", token, route));
+                async move { request.dispatch().await.status() }
+            };
+            assert_eq!(status("unknown-view-token").await, Status::NotFound, "{}", route);
+            assert_eq!(status("expired-view-token").await, Status::Gone, "{}", route);
+        }
+        let response = app.get(format!("/log/{}/json", app.token)).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+    }
+
+    #[rocket::async_test]
+    async fn tokens_are_accepted_in_the_header_or_the_path() {
+        use rocket::http::{ContentType, Header};
+
+        let app = testing::client().await;
+        let post = |path: &str, header: Option<Header<'static>>| {
+            let mut request = app
+                .post(format!("/log/{}", path))
+                .header(ContentType::JSON)
+                .body(r#"{"amps": 2.5, "volts": 230, "watts": 575}"#);
+            if let Some(header) = header {
+                request = request.header(header);
+            }
+            request.dispatch()
+        };
+        let bearer = Header::new("Authorization", format!("Bearer {}", app.token));
+        let api_token = Header::new("X-Api-Token", app.token.clone());
+
+        assert_eq!(post(&app.token, None).await.status(), Status::Ok);
+        assert_eq!(post("-", Some(bearer.clone())).await.status(), Status::Ok);
+        assert_eq!(post("-", Some(api_token.clone())).await.status(), Status::Ok);
+        // The header is only read in place of the token in the path
+        assert_eq!(post("unknown", Some(bearer.clone())).await.status(), Status::BadRequest);
+        assert_eq!(post(&app.token, Some(api_token.clone())).await.status(), Status::BadRequest);
+        assert_ne!(post("-", None).await.status(), Status::Ok);
+
+        for header in [bearer, api_token] {
+            let response = app.get("/log/-/latest").header(header.clone()).dispatch().await;
+            assert_eq!(response.status(), Status::Ok);
+            let response = app
+                .get(format!("/log/{}/latest", app.token))
+                .header(header)
+                .dispatch()
+                .await;
+            assert_eq!(response.status(), Status::BadRequest);
         }
+        let response = app.get(format!("/log/{}/latest", app.token)).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM energy_log WHERE token = ?")
+            .bind(&app.token)
+            .fetch_one(app.db())
+            .await
+            .unwrap();
+        assert_eq!(count, 3);
     }
 }